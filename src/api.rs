@@ -1,56 +1,139 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use axum::{
-    extract::{Path, Request, State},
-    http::StatusCode,
+    body::{to_bytes, Body},
+    extract::{ConnectInfo, MatchedPath, Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
     middleware::{self, Next},
     response::{
         sse::{Event, KeepAlive, Sse},
         IntoResponse, Response,
     },
-    routing::{delete, get, post, put},
-    Json, Router,
+    routing::{delete, get, patch, post, put},
+    Extension, Json, Router,
 };
 use serde::{Deserialize, Serialize};
-use tokio_stream::wrappers::BroadcastStream;
-use tokio_stream::StreamExt;
+use tracing::{info, warn};
 
+use crate::artwork::SharedArtworkCache;
+use crate::audit::{self, AuditFilter, AuditRecord, AuditResult, SharedAuditLog};
+use crate::auth::{self, ApiToken, AuthLimiter, Scope, TokenStore};
 use crate::connection::ConnectionState;
+use crate::events::{Delivery, EventCursor, SequencedEvent};
+use crate::history::{self, HistoryFilter, SortOrder};
+use crate::jingle::JingleRules;
+use crate::library::{self, LibraryQuery, LibraryTrack, ScanSummary, SharedLibrary};
 use crate::metrics::{self, get_metrics, HealthStatus};
-use crate::player::{PlayerEvent, PlayerHandle};
-use crate::queue::{SharedQueue, Track};
+use crate::player::{PlaybackMode, PlayerEvent, PlayerHandle, QueueChangeKind, TrackPosition};
+use crate::playlist::{self, EnqueueMode, Playlist, PlaylistSummary, SharedPlaylists};
+use crate::playlist_format::{self, PlaylistEntry, PlaylistFormat};
+use crate::quarantine::QuarantinedTrack;
+use crate::queue::{
+    MetadataOverride, MoveTarget, Queue, QueueOp, QueueOpOutcome, SharedQueue, Track, TrackSource,
+};
+use crate::report::{self, ReportFormat};
+use crate::requests::{ListenerRequest, RequestError, SharedRequests, TrackStatus, MAX_NAME_LEN};
+use crate::shuffle::{self, ShuffleMode};
+use crate::ws;
+use crate::scheduler::{InsertSpec, ScheduleEntry, ScheduleSpec, SharedSchedule, TimedInsert};
 
 #[derive(Clone)]
 pub struct AppState {
     pub queue: SharedQueue,
     pub player: PlayerHandle,
+    pub playlists: SharedPlaylists,
+    pub schedule: SharedSchedule,
     pub start_time: Instant,
     pub connection_state: Arc<std::sync::Mutex<ConnectionState>>,
     pub media_dir: Option<PathBuf>,
-    pub api_token: Option<String>,
+    /// Index of the media directory, if one is set
+    pub library: Option<SharedLibrary>,
+    /// API tokens; `None` disables authentication
+    pub tokens: Option<Arc<TokenStore>>,
+    pub auth_limiter: Arc<AuthLimiter>,
+    /// Record of every change made through the API
+    pub audit: SharedAuditLog,
+    /// Listener requests awaiting moderation; `None` disables requests
+    pub requests: Option<SharedRequests>,
+    /// Default minimum tracks between two by the same artist when shuffling
+    pub min_artist_gap: usize,
+    /// Cover art already read from track files
+    pub artwork: SharedArtworkCache,
 }
 
 pub fn router(state: AppState) -> Router {
+    // New routes should also be added to ws::METHODS, unless they serve
+    // binary bodies
     let authed_api = Router::new()
         .route("/api/queue", get(list_queue))
         .route("/api/queue", post(add_track))
         .route("/api/queue", delete(clear_queue))
         .route("/api/queue/next", post(add_track_next))
         .route("/api/queue/bulk", post(add_tracks_bulk))
+        .route("/api/queue/batch", post(batch_queue))
+        .route("/api/queue/import", post(import_playlist))
+        .route("/api/queue/export", get(export_playlist))
         .route("/api/queue/shuffle", post(shuffle_queue))
-        .route("/api/queue/{id}", delete(remove_track))
+        .route("/api/queue/{id}", delete(remove_track).patch(update_track_metadata))
         .route("/api/queue/{id}/position", put(move_track))
+        .route("/api/queue/{id}/artwork", get(queue_track_artwork))
+        .route("/api/playlists", get(list_playlists))
+        .route("/api/playlists", post(create_playlist))
+        .route("/api/playlists/{name}", get(get_playlist))
+        .route("/api/playlists/{name}", put(update_playlist))
+        .route("/api/playlists/{name}", delete(delete_playlist))
+        .route("/api/playlists/{name}/enqueue", post(enqueue_playlist))
+        .route("/api/schedule", get(list_schedule))
+        .route("/api/schedule", post(create_schedule_entry))
+        .route("/api/schedule/{id}", get(get_schedule_entry))
+        .route("/api/schedule/{id}", put(update_schedule_entry))
+        .route("/api/schedule/{id}", delete(delete_schedule_entry))
+        .route("/api/inserts", get(list_inserts))
+        .route("/api/inserts", post(create_insert))
+        .route("/api/inserts/{id}", delete(delete_insert))
+        .route("/api/jingles", get(get_jingle_rules))
+        .route("/api/jingles", put(set_jingle_rules))
+        .route("/api/playback", get(playback_state))
+        .route("/api/playback/mode", put(set_playback_mode))
+        .route("/api/playback/resume", post(resume_playback))
+        .route("/api/now-playing/metadata", patch(update_now_playing_metadata))
+        .route("/api/now-playing/artwork", get(now_playing_artwork))
         .route("/api/skip", post(skip_track))
         .route("/api/status", get(status))
         .route("/api/history", get(history))
+        .route("/api/history/stats", get(history_stats))
+        .route("/api/reports/plays", get(play_report))
+        .route("/api/library", get(search_library))
+        .route("/api/library/scan", post(scan_library))
+        .route("/api/library/{id}", get(get_library_track))
+        .route("/api/audit", get(audit_log))
+        .route("/api/quarantine", get(list_quarantine))
+        .route("/api/quarantine/{id}", delete(release_quarantined))
+        .route("/api/requests", get(list_requests))
+        .route("/api/requests/{id}/approve", post(approve_request))
+        .route("/api/requests/{id}", delete(reject_request))
         .route("/api/events", get(events_sse))
+        .route("/api/ws", get(ws::ws_handler))
+        // Auth runs first, so audit records know the token behind a request
+        .route_layer(middleware::from_fn_with_state(state.clone(), audit_middleware))
         .route_layer(middleware::from_fn_with_state(state.clone(), auth_middleware))
         .with_state(state.clone());
 
+    // Listener requests are open to anyone; their rules limit abuse. Submissions
+    // are still audited, under the listener actor.
     let public = Router::new()
+        .route("/api/listener/search", get(search_requestable))
+        .route(
+            "/api/listener/requests",
+            post(submit_request)
+                .layer(middleware::from_fn_with_state(state.clone(), audit_middleware))
+                .layer(middleware::from_fn(listener_actor)),
+        )
         .route("/health", get(health))
         .route("/ready", get(ready))
         .route("/metrics", get(metrics_handler))
@@ -61,32 +144,264 @@ pub fn router(state: AppState) -> Router {
 
 // --- Auth middleware ---
 
-async fn auth_middleware(
-    State(state): State<AppState>,
-    req: Request,
-    next: Next,
-) -> Result<Response, StatusCode> {
-    if let Some(ref expected) = state.api_token {
-        let authorised = req
-            .headers()
-            .get("authorization")
-            .and_then(|v| v.to_str().ok())
-            .and_then(|v| v.strip_prefix("Bearer "))
-            .map(|token| token == expected)
-            .unwrap_or(false);
-
-        if !authorised {
-            return Err(StatusCode::UNAUTHORIZED);
+async fn auth_middleware(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let Some(ref tokens) = state.tokens else {
+        return next.run(req).await;
+    };
+
+    let ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| req.uri().path().to_string());
+
+    // Locked-out clients are refused before their token is even checked.
+    // The lockout was logged when it started, so these aren't
+    if let Some(wait) = ip.and_then(|ip| state.auth_limiter.locked_for(ip, Instant::now())) {
+        let retry_after = (wait.as_secs_f64().ceil() as u64).to_string();
+        return (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, retry_after)]).into_response();
+    }
+
+    let secret = req
+        .headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let token = match secret.map(|s| tokens.authenticate(s)) {
+        Some(Some(token)) => token,
+        missing_or_invalid => {
+            let reason = match missing_or_invalid {
+                None => "missing_token",
+                Some(_) => "invalid_token",
+            };
+            auth_failure(&state, &req, ip, &route, reason, None, StatusCode::UNAUTHORIZED);
+            if let Some(ip) = ip {
+                if let Some(lockout) = state.auth_limiter.record_failure(ip, Instant::now()) {
+                    auth_lockout(&state, &req, ip, lockout);
+                }
+            }
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    };
+    if let Some(ip) = ip {
+        state.auth_limiter.record_success(ip);
+    }
+
+    let scope = auth::required_scope(req.method(), &route);
+    if !token.allows(scope) {
+        auth_failure(&state, &req, ip, &route, "forbidden", Some(&token.name), StatusCode::FORBIDDEN);
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let name = token.name.clone();
+    // For handlers whose scope depends on the body, see `require_scope`
+    req.extensions_mut().insert(token.clone());
+    auth::with_actor(name, next.run(req)).await
+}
+
+/// Refuse the request unless its token has `scope`, for requests that need
+/// more than their route does because of what the body asks for. Always
+/// allowed when authentication is off.
+fn require_scope(
+    token: &Option<Extension<ApiToken>>,
+    scope: Scope,
+) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    match token {
+        Some(Extension(token)) if !token.allows(scope) => Err(error_response(
+            StatusCode::FORBIDDEN,
+            &format!("This request needs the {:?} scope", scope).to_lowercase(),
+            7003,
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Count a rejected request and write it to the audit log.
+fn auth_failure(
+    state: &AppState,
+    req: &Request,
+    ip: Option<IpAddr>,
+    route: &str,
+    reason: &str,
+    token: Option<&str>,
+    status: StatusCode,
+) {
+    metrics::AUTH_FAILURES.with_label_values(&[reason]).inc();
+    let remote_addr = ip.map(|ip| ip.to_string());
+    warn!(
+        target: "audit",
+        action = "auth_failure",
+        reason,
+        remote_addr = remote_addr.as_deref().unwrap_or(""),
+        method = %req.method(),
+        route,
+        token = token.unwrap_or(""),
+        "API request rejected: {}",
+        reason
+    );
+    state.audit.write().unwrap().record(AuditRecord {
+        id: 0,
+        timestamp: 0,
+        actor: token.map(str::to_string),
+        remote_addr,
+        action: "auth_failure".to_string(),
+        method: req.method().to_string(),
+        path: req.uri().path().to_string(),
+        args: serde_json::json!({ "reason": reason }),
+        result: AuditResult {
+            status: status.as_u16(),
+            error: None,
+            code: None,
+        },
+    });
+}
+
+/// Count and log the start of a lockout, once for its whole length.
+fn auth_lockout(state: &AppState, req: &Request, ip: IpAddr, lockout: Duration) {
+    metrics::AUTH_FAILURES.with_label_values(&["locked_out"]).inc();
+    warn!(
+        target: "audit",
+        action = "auth_lockout",
+        remote_addr = %ip,
+        lockout_secs = lockout.as_secs(),
+        "Locking out {} for {}s after repeated authentication failures",
+        ip,
+        lockout.as_secs()
+    );
+    state.audit.write().unwrap().record(AuditRecord {
+        id: 0,
+        timestamp: 0,
+        actor: None,
+        remote_addr: Some(ip.to_string()),
+        action: "auth_lockout".to_string(),
+        method: req.method().to_string(),
+        path: req.uri().path().to_string(),
+        args: serde_json::json!({ "lockout_secs": lockout.as_secs() }),
+        result: AuditResult {
+            status: StatusCode::UNAUTHORIZED.as_u16(),
+            error: None,
+            code: None,
+        },
+    });
+}
+
+// --- Audit middleware ---
+
+/// Largest request body recorded; the same as the limit on JSON bodies.
+const AUDIT_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Actor recorded for listener requests, which carry no token.
+const LISTENER_ACTOR: &str = "listener";
+
+/// Record every request that may change state, with its arguments and
+/// outcome, in the audit log.
+async fn audit_middleware(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if req.method() == Method::GET || req.method() == Method::HEAD {
+        return next.run(req).await;
+    }
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let action = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|route| match ws::method_name(&method, route.as_str()) {
+            Some(name) => name.to_string(),
+            None => format!("{} {}", method, route.as_str()),
+        })
+        .unwrap_or_else(|| format!("{} {}", method, path));
+    let remote_addr = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string());
+
+    let mut args = Query::<HashMap<String, String>>::try_from_uri(req.uri())
+        .map(|Query(params)| {
+            params
+                .into_iter()
+                .map(|(k, v)| (k, serde_json::Value::String(v)))
+                .collect::<serde_json::Map<_, _>>()
+        })
+        .unwrap_or_default();
+
+    // The body is buffered so it can be both recorded and handled
+    let (parts, body) = req.into_parts();
+    let bytes = match to_bytes(body, AUDIT_BODY_LIMIT).await {
+        Ok(bytes) => bytes,
+        Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+    };
+    match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(serde_json::Value::Object(body)) => args.extend(body),
+        Ok(serde_json::Value::Null) => {}
+        Ok(body) => {
+            args.insert("body".to_string(), body);
+        }
+        Err(_) if bytes.is_empty() => {}
+        Err(_) => {
+            args.insert("body".to_string(), String::from_utf8_lossy(&bytes).into());
         }
     }
-    Ok(next.run(req).await)
+    let req = Request::from_parts(parts, Body::from(bytes));
+
+    let response = next.run(req).await;
+    let status = response.status();
+
+    // Error bodies are small; read them for the message and code
+    let (response, error, code) = if status.is_success() {
+        (response, None, None)
+    } else {
+        let (parts, body) = response.into_parts();
+        let bytes = to_bytes(body, AUDIT_BODY_LIMIT).await.unwrap_or_default();
+        let value: serde_json::Value = serde_json::from_slice(&bytes).unwrap_or_default();
+        let error = value["error"].as_str().map(str::to_string);
+        let code = value["code"].as_u64().map(|c| c as u32);
+        (Response::from_parts(parts, Body::from(bytes)), error, code)
+    };
+
+    let args = if args.is_empty() {
+        serde_json::Value::Null
+    } else {
+        audit::shorten_args(serde_json::Value::Object(args))
+    };
+    state.audit.write().unwrap().record(AuditRecord {
+        id: 0,
+        timestamp: 0,
+        actor: auth::current_actor(),
+        remote_addr,
+        action,
+        method: method.to_string(),
+        path,
+        args,
+        result: AuditResult {
+            status: status.as_u16(),
+            error,
+            code,
+        },
+    });
+
+    response
+}
+
+/// Record unauthenticated listener calls under a fixed actor name.
+async fn listener_actor(req: Request, next: Next) -> Response {
+    auth::with_actor(LISTENER_ACTOR.to_string(), next.run(req)).await
 }
 
 // --- Request/Response types ---
 
 #[derive(Deserialize)]
 struct AddTrackRequest {
-    path: String,
+    #[serde(default)]
+    path: Option<String>,
+    /// Alternative to `path`
+    #[serde(default)]
+    library_id: Option<u64>,
+    /// Title, artist and tags to use instead of the file's
+    #[serde(flatten)]
+    metadata: MetadataOverride,
 }
 
 #[derive(Deserialize)]
@@ -94,6 +409,11 @@ struct BulkAddRequest {
     #[serde(default)]
     paths: Vec<String>,
     #[serde(default)]
+    library_ids: Vec<u64>,
+    /// Tracks given like single adds, with optional metadata
+    #[serde(default)]
+    tracks: Vec<AddTrackRequest>,
+    #[serde(default)]
     directory: Option<String>,
     #[serde(default)]
     recursive: bool,
@@ -105,9 +425,155 @@ struct BulkAddResponse {
     errors: Vec<String>,
 }
 
+#[derive(Deserialize)]
+struct ImportRequest {
+    /// Playlist text supplied inline
+    #[serde(default)]
+    content: Option<String>,
+    /// Path of a playlist file on the server
+    #[serde(default)]
+    path: Option<String>,
+    #[serde(default)]
+    format: Option<PlaylistFormat>,
+    /// Directory relative entries in inline content are resolved against
+    #[serde(default)]
+    base_dir: Option<String>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ExportSource {
+    #[default]
+    Queue,
+    History,
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    format: Option<PlaylistFormat>,
+    #[serde(default)]
+    source: ExportSource,
+    since: Option<String>,
+    until: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct CreatePlaylistRequest {
+    name: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    paths: Vec<String>,
+}
+
+/// Fields left out are unchanged.
+#[derive(Deserialize)]
+struct UpdatePlaylistRequest {
+    /// An empty string removes the description
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    paths: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+struct EnqueuePlaylistRequest {
+    #[serde(default)]
+    mode: EnqueueMode,
+}
+
+/// Schedule entry with its evaluated timing.
+#[derive(Serialize)]
+struct ScheduleEntryResponse {
+    #[serde(flatten)]
+    entry: ScheduleEntry,
+    next_start: Option<String>,
+    active: bool,
+}
+
+#[derive(Serialize)]
+struct ScheduleResponse {
+    timezone: String,
+    entries: Vec<ScheduleEntryResponse>,
+}
+
+/// Timed insert with its next firing time.
+#[derive(Serialize)]
+struct TimedInsertResponse {
+    #[serde(flatten)]
+    insert: TimedInsert,
+    next_time: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct ShuffleRequest {
+    #[serde(default)]
+    mode: ShuffleMode,
+    /// Minimum tracks between two by the same artist
+    #[serde(default)]
+    min_artist_gap: Option<usize>,
+}
+
+/// Move to an absolute `position`, or next to another track with
+/// `before_id` or `after_id`.
 #[derive(Deserialize)]
 struct MoveTrackRequest {
-    position: usize,
+    #[serde(default)]
+    position: Option<usize>,
+    #[serde(default)]
+    before_id: Option<u64>,
+    #[serde(default)]
+    after_id: Option<u64>,
+}
+
+impl MoveTrackRequest {
+    fn target(&self) -> Result<MoveTarget, (StatusCode, Json<ErrorResponse>)> {
+        match (self.position, self.before_id, self.after_id) {
+            (Some(position), None, None) => Ok(MoveTarget::Position(position)),
+            (None, Some(anchor), None) => Ok(MoveTarget::Before(anchor)),
+            (None, None, Some(anchor)) => Ok(MoveTarget::After(anchor)),
+            _ => Err(error_response(
+                StatusCode::BAD_REQUEST,
+                "Provide exactly one of position, before_id or after_id",
+                7000,
+            )),
+        }
+    }
+}
+
+const MAX_BATCH_OPS: usize = 1000;
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum BatchOp {
+    Add {
+        path: String,
+    },
+    InsertAt {
+        path: String,
+        position: usize,
+    },
+    Remove {
+        id: u64,
+    },
+    Move {
+        id: u64,
+        #[serde(flatten)]
+        target: MoveTrackRequest,
+    },
+    Clear,
+}
+
+#[derive(Deserialize)]
+struct BatchRequest {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Serialize)]
+struct BatchResponse {
+    results: Vec<QueueOpOutcome>,
+    revision: u64,
 }
 
 #[derive(Serialize)]
@@ -116,6 +582,111 @@ struct StatusResponse {
     queue_length: usize,
     connection_state: String,
     uptime_seconds: u64,
+    playback_mode: PlaybackMode,
+    stopped: bool,
+}
+
+#[derive(Serialize)]
+struct PlaybackResponse {
+    mode: PlaybackMode,
+    stopped: bool,
+}
+
+#[derive(Deserialize)]
+struct PlaybackModeRequest {
+    mode: PlaybackMode,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    since: Option<String>,
+    until: Option<String>,
+    artist: Option<String>,
+    skipped: Option<bool>,
+    failed: Option<bool>,
+    source: Option<TrackSource>,
+    limit: Option<usize>,
+    cursor: Option<u64>,
+    #[serde(default)]
+    order: SortOrder,
+}
+
+impl HistoryQuery {
+    fn filter(&self) -> Result<HistoryFilter, (StatusCode, Json<ErrorResponse>)> {
+        Ok(HistoryFilter {
+            since: parse_timestamp_param(self.since.as_deref())?,
+            until: parse_timestamp_param(self.until.as_deref())?,
+            artist: self.artist.clone(),
+            skipped: self.skipped,
+            failed: self.failed,
+            source: self.source,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct LibrarySearchQuery {
+    q: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    genre: Option<String>,
+    limit: Option<usize>,
+    cursor: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct RequestableQuery {
+    q: Option<String>,
+    limit: Option<usize>,
+}
+
+/// A track listeners can request, named by its path within the media
+/// directory.
+#[derive(Serialize)]
+struct RequestableTrack {
+    path: String,
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artist: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SubmitRequestBody {
+    path: String,
+    /// Shown as "requested by" when the track plays
+    #[serde(default)]
+    name: Option<String>,
+}
+
+/// What a listener is told about their request.
+#[derive(Serialize)]
+struct RequestReceipt {
+    id: u64,
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artist: Option<String>,
+    status: &'static str,
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    since: Option<String>,
+    until: Option<String>,
+    actor: Option<String>,
+    action: Option<String>,
+    failed: Option<bool>,
+    limit: Option<usize>,
+    cursor: Option<u64>,
+    #[serde(default)]
+    order: SortOrder,
+}
+
+#[derive(Deserialize)]
+struct ReportQuery {
+    #[serde(default)]
+    format: ReportFormat,
+    from: Option<String>,
+    to: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -134,15 +705,28 @@ fn error_response(status: StatusCode, msg: &str, code: u32) -> (StatusCode, Json
     )
 }
 
-fn validate_ogg_file(
-    path: &str,
-    media_dir: &Option<PathBuf>,
-) -> Result<PathBuf, (StatusCode, Json<ErrorResponse>)> {
-    let path_buf = PathBuf::from(path);
+fn parse_timestamp_param(
+    value: Option<&str>,
+) -> Result<Option<u64>, (StatusCode, Json<ErrorResponse>)> {
+    match value {
+        None => Ok(None),
+        Some(v) => history::parse_timestamp(v).map(Some).ok_or_else(|| {
+            error_response(
+                StatusCode::BAD_REQUEST,
+                "Timestamps must be Unix seconds or RFC 3339",
+                7001,
+            )
+        }),
+    }
+}
 
-    // Canonicalise and check media_dir restriction
+/// Canonicalise `path` and check it lies within `media_dir`, if one is set.
+fn check_media_dir(
+    path: &std::path::Path,
+    media_dir: &Option<PathBuf>,
+) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
     if let Some(ref media_dir) = media_dir {
-        let canonical = path_buf
+        let canonical = path
             .canonicalize()
             .map_err(|_| error_response(StatusCode::NOT_FOUND, "File not found", 3010))?;
         let media_canonical = media_dir
@@ -162,6 +746,17 @@ fn validate_ogg_file(
             ));
         }
     }
+    Ok(())
+}
+
+fn validate_ogg_file(
+    path: &str,
+    media_dir: &Option<PathBuf>,
+) -> Result<PathBuf, (StatusCode, Json<ErrorResponse>)> {
+    let path_buf = PathBuf::from(path);
+
+    // Canonicalise and check media_dir restriction
+    check_media_dir(&path_buf, media_dir)?;
 
     if !path_buf.exists() {
         return Err(error_response(
@@ -190,96 +785,160 @@ fn validate_ogg_file(
         }
     }
 
-    Ok(path_buf)
+    // Queued paths are canonical, like the library's, so the watcher can match them
+    path_buf
+        .canonicalize()
+        .map_err(|_| error_response(StatusCode::NOT_FOUND, "File not found", 3010))
 }
 
-fn scan_directory(dir: &std::path::Path, recursive: bool) -> Vec<PathBuf> {
-    let mut files = Vec::new();
-    let entries = match std::fs::read_dir(dir) {
-        Ok(e) => e,
-        Err(_) => return files,
-    };
-
-    for entry in entries.flatten() {
-        let path = entry.path();
-        if path.is_dir() && recursive {
-            files.extend(scan_directory(&path, true));
-        } else if path.is_file() {
-            if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-                if ext == "ogg" || ext == "oga" {
-                    files.push(path);
-                }
-            }
-        }
+/// A file that may go into the queue: valid, and not quarantined.
+fn queueable_file(state: &AppState, path: &str) -> Result<PathBuf, (StatusCode, Json<ErrorResponse>)> {
+    let path = validate_ogg_file(path, &state.media_dir)?;
+    if state.player.is_quarantined(&path) {
+        return Err(error_response(StatusCode::CONFLICT, "Track is quarantined", 8401));
     }
-
-    files.sort();
-    files
+    Ok(path)
 }
 
 // --- Handlers ---
 
+// --- Queue revisions ---
+
+type ETag = [(HeaderName, String); 1];
+
+fn etag(revision: u64) -> ETag {
+    [(header::ETAG, format!("\"{}\"", revision))]
+}
+
+/// Reject the request with 412 if `If-Match` doesn't match the queue
+/// revision.
+fn check_if_match(
+    headers: &HeaderMap,
+    queue: &Queue,
+) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    let Some(value) = headers.get(header::IF_MATCH) else {
+        return Ok(());
+    };
+    let current = queue.revision().to_string();
+    let matches = value.to_str().unwrap_or("").split(',').any(|tag| {
+        let tag = tag.trim();
+        tag == "*" || tag.trim_start_matches("W/").trim_matches('"') == current
+    });
+    if matches {
+        Ok(())
+    } else {
+        Err(error_response(
+            StatusCode::PRECONDITION_FAILED,
+            "Queue has changed since it was read",
+            6003,
+        ))
+    }
+}
+
 async fn list_queue(State(state): State<AppState>) -> impl IntoResponse {
     let q = state.queue.read().await;
-    Json(q.list())
+    (etag(q.revision()), Json(q.list()))
+}
+
+/// The file an add request names, by path or library ID.
+fn requested_file(
+    state: &AppState,
+    req: &AddTrackRequest,
+) -> Result<PathBuf, (StatusCode, Json<ErrorResponse>)> {
+    match (&req.path, req.library_id) {
+        (Some(path), None) => queueable_file(state, path),
+        (None, Some(id)) => {
+            let path = library_track(state, id)?.path;
+            queueable_file(state, &path.to_string_lossy())
+        }
+        _ => Err(error_response(
+            StatusCode::BAD_REQUEST,
+            "Give either path or library_id",
+            7000,
+        )),
+    }
+}
+
+/// Read the requested file, with any metadata given in the request.
+fn requested_track(state: &AppState, req: AddTrackRequest) -> Result<Track, (StatusCode, Json<ErrorResponse>)> {
+    let mut track = Track::from_file(requested_file(state, &req)?);
+    if !req.metadata.is_empty() {
+        track.override_metadata(&valid_metadata(req.metadata)?);
+    }
+    Ok(track)
+}
+
+/// Check a metadata edit, which must change something.
+fn valid_metadata(metadata: MetadataOverride) -> Result<MetadataOverride, (StatusCode, Json<ErrorResponse>)> {
+    if metadata.is_empty() {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            "Give a title, artist or tags to change",
+            7000,
+        ));
+    }
+    metadata
+        .validate()
+        .map_err(|e| error_response(StatusCode::BAD_REQUEST, &e, 7000))
 }
 
 async fn add_track(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(req): Json<AddTrackRequest>,
-) -> Result<(StatusCode, Json<Track>), (StatusCode, Json<ErrorResponse>)> {
-    let path_buf = validate_ogg_file(&req.path, &state.media_dir)?;
-    let track = Track::from_file(path_buf);
-    let response = track.clone();
-
-    {
-        let mut q = state.queue.write().await;
-        q.push_back(track);
-        metrics::QUEUE_LENGTH.set(q.len() as i64);
-    }
+) -> Result<(StatusCode, ETag, Json<Track>), (StatusCode, Json<ErrorResponse>)> {
+    let track = requested_track(&state, req)?;
 
-    state.player.send_event(PlayerEvent::QueueChanged {
-        length: state.queue.read().await.len(),
-    });
+    let mut q = state.queue.write().await;
+    check_if_match(&headers, &q)?;
+    q.push_back(track.clone());
+    metrics::QUEUE_LENGTH.set(q.len() as i64);
+    state
+        .player
+        .send_event(PlayerEvent::tracks_added(&q, QueueChangeKind::Added, std::slice::from_ref(&track)));
 
-    Ok((StatusCode::CREATED, Json(response)))
+    Ok((StatusCode::CREATED, etag(q.revision()), Json(track)))
 }
 
 async fn add_track_next(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(req): Json<AddTrackRequest>,
-) -> Result<(StatusCode, Json<Track>), (StatusCode, Json<ErrorResponse>)> {
-    let path_buf = validate_ogg_file(&req.path, &state.media_dir)?;
-    let track = Track::from_file(path_buf);
-    let response = track.clone();
-
-    {
-        let mut q = state.queue.write().await;
-        q.push_front(track);
-        metrics::QUEUE_LENGTH.set(q.len() as i64);
-    }
+) -> Result<(StatusCode, ETag, Json<Track>), (StatusCode, Json<ErrorResponse>)> {
+    let track = requested_track(&state, req)?;
 
-    state.player.send_event(PlayerEvent::QueueChanged {
-        length: state.queue.read().await.len(),
-    });
+    let mut q = state.queue.write().await;
+    check_if_match(&headers, &q)?;
+    q.push_front(track.clone());
+    metrics::QUEUE_LENGTH.set(q.len() as i64);
+    state
+        .player
+        .send_event(PlayerEvent::tracks_added(&q, QueueChangeKind::Added, std::slice::from_ref(&track)));
 
-    Ok((StatusCode::CREATED, Json(response)))
+    Ok((StatusCode::CREATED, etag(q.revision()), Json(track)))
 }
 
 async fn add_tracks_bulk(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(req): Json<BulkAddRequest>,
-) -> (StatusCode, Json<BulkAddResponse>) {
+) -> Result<(StatusCode, ETag, Json<BulkAddResponse>), (StatusCode, Json<ErrorResponse>)> {
     let mut added = Vec::new();
     let mut errors = Vec::new();
 
-    // Collect paths from explicit list and directory scan
+    // Collect paths from explicit list, library and directory scan
     let mut all_paths: Vec<String> = req.paths;
+    for id in req.library_ids {
+        match library_track(&state, id) {
+            Ok(track) => all_paths.push(track.path.to_string_lossy().into_owned()),
+            Err((_, Json(err))) => errors.push(format!("Library track {}: {}", id, err.error)),
+        }
+    }
 
     if let Some(ref dir) = req.directory {
         let dir_path = PathBuf::from(dir);
         if dir_path.is_dir() {
-            let scanned = scan_directory(&dir_path, req.recursive);
+            let scanned = library::scan_directory(&dir_path, req.recursive);
             for p in scanned {
                 all_paths.push(p.to_string_lossy().to_string());
             }
@@ -289,27 +948,30 @@ async fn add_tracks_bulk(
     }
 
     for path_str in &all_paths {
-        match validate_ogg_file(path_str, &state.media_dir) {
-            Ok(path_buf) => {
-                let track = Track::from_file(path_buf);
-                added.push(track.clone());
-                state.queue.write().await.push_back(track);
-            }
+        match queueable_file(&state, path_str) {
+            Ok(path_buf) => added.push(Track::from_file(path_buf)),
             Err((_, Json(err))) => {
                 errors.push(format!("{}: {}", path_str, err.error));
             }
         }
     }
 
-    {
-        let q = state.queue.read().await;
-        metrics::QUEUE_LENGTH.set(q.len() as i64);
+    for (i, entry) in req.tracks.into_iter().enumerate() {
+        match requested_track(&state, entry) {
+            Ok(track) => added.push(track),
+            Err((_, Json(err))) => errors.push(format!("Track {}: {}", i, err.error)),
+        }
     }
 
+    // Add everything under one lock so the player never sees half a batch
+    let mut q = state.queue.write().await;
+    check_if_match(&headers, &q)?;
     if !added.is_empty() {
-        state.player.send_event(PlayerEvent::QueueChanged {
-            length: state.queue.read().await.len(),
-        });
+        q.extend_back(added.iter().cloned());
+        metrics::QUEUE_LENGTH.set(q.len() as i64);
+        state
+            .player
+            .send_event(PlayerEvent::tracks_added(&q, QueueChangeKind::Added, &added));
     }
 
     let status = if added.is_empty() && !errors.is_empty() {
@@ -318,50 +980,696 @@ async fn add_tracks_bulk(
         StatusCode::CREATED
     };
 
-    (status, Json(BulkAddResponse { added, errors }))
-}
-
-async fn shuffle_queue(State(state): State<AppState>) -> StatusCode {
-    let mut q = state.queue.write().await;
-    q.shuffle();
-    state.player.send_event(PlayerEvent::QueueChanged {
-        length: q.len(),
-    });
-    StatusCode::OK
+    Ok((status, etag(q.revision()), Json(BulkAddResponse { added, errors })))
 }
 
-async fn remove_track(
+async fn import_playlist(
     State(state): State<AppState>,
-    Path(id): Path<u64>,
-) -> Result<Json<Track>, (StatusCode, Json<ErrorResponse>)> {
-    let mut q = state.queue.write().await;
-    match q.remove(id) {
-        Some(track) => {
-            metrics::QUEUE_LENGTH.set(q.len() as i64);
-            state
-                .player
-                .send_event(PlayerEvent::QueueChanged { length: q.len() });
-            Ok(Json(track))
+    headers: HeaderMap,
+    Json(req): Json<ImportRequest>,
+) -> Result<(StatusCode, ETag, Json<BulkAddResponse>), (StatusCode, Json<ErrorResponse>)> {
+    let from_file = req.path.is_some();
+    let (content, base_dir, format) = match (req.content, req.path) {
+        (Some(content), None) => {
+            let base_dir = req.base_dir.map(PathBuf::from).or_else(|| state.media_dir.clone());
+            let format = req.format.unwrap_or_else(|| PlaylistFormat::detect(&content));
+            (content, base_dir, format)
         }
-        None => Err(error_response(
-            StatusCode::NOT_FOUND,
-            "Track not found",
-            6001,
-        )),
-    }
-}
-
-async fn move_track(
+        (None, Some(path)) => {
+            // Reading server-side files is only safe within the media directory, and only
+            // for files that look like playlists.
+            if state.media_dir.is_none() {
+                return Err(error_response(
+                    StatusCode::FORBIDDEN,
+                    "Importing by path needs a media directory",
+                    3014,
+                ));
+            }
+            let path = PathBuf::from(path);
+            let Some(path_format) = PlaylistFormat::from_path(&path) else {
+                return Err(error_response(
+                    StatusCode::BAD_REQUEST,
+                    "Playlist files must end in .m3u, .m3u8, .pls or .xspf",
+                    7000,
+                ));
+            };
+            check_media_dir(&path, &state.media_dir)?;
+            let path = path
+                .canonicalize()
+                .map_err(|_| error_response(StatusCode::NOT_FOUND, "File not found", 3010))?;
+            let content = tokio::fs::read_to_string(&path).await.map_err(|_| {
+                error_response(StatusCode::BAD_REQUEST, "Playlist not readable", 3011)
+            })?;
+            let format = req.format.unwrap_or(path_format);
+            (content, path.parent().map(|p| p.to_path_buf()), format)
+        }
+        _ => {
+            return Err(error_response(
+                StatusCode::BAD_REQUEST,
+                "Provide exactly one of content or path",
+                7000,
+            ))
+        }
+    };
+
+    let mut added = Vec::new();
+    let mut errors = Vec::new();
+
+    // Errors name entries by number for server-side files, so their lines are not echoed.
+    for (n, entry) in playlist_format::parse(&content, format).into_iter().enumerate() {
+        let label = if from_file {
+            format!("Entry {}", n + 1)
+        } else {
+            entry.location.clone()
+        };
+        let Some(path) = entry.resolve(base_dir.as_deref()) else {
+            errors.push(format!("{label}: not a local file"));
+            continue;
+        };
+        match queueable_file(&state, &path.to_string_lossy()) {
+            Ok(path_buf) => added.push(Track::from_file(path_buf)),
+            Err((_, Json(err))) => errors.push(format!("{label}: {}", err.error)),
+        }
+    }
+
+    let mut q = state.queue.write().await;
+    check_if_match(&headers, &q)?;
+    if !added.is_empty() {
+        q.extend_back(added.iter().cloned());
+        metrics::QUEUE_LENGTH.set(q.len() as i64);
+        state
+            .player
+            .send_event(PlayerEvent::tracks_added(&q, QueueChangeKind::Added, &added));
+    }
+
+    let status = if added.is_empty() && !errors.is_empty() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::CREATED
+    };
+
+    Ok((status, etag(q.revision()), Json(BulkAddResponse { added, errors })))
+}
+
+async fn export_playlist(
     State(state): State<AppState>,
-    Path(id): Path<u64>,
-    Json(req): Json<MoveTrackRequest>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let format = query.format.unwrap_or(PlaylistFormat::M3u8);
+
+    let entries: Vec<PlaylistEntry> = match query.source {
+        ExportSource::Queue => state
+            .queue
+            .read()
+            .await
+            .list()
+            .into_iter()
+            .map(|track| PlaylistEntry {
+                location: track.path.to_string_lossy().to_string(),
+                title: Some(track.title),
+                artist: track.artist,
+                duration_secs: None,
+            })
+            .collect(),
+        ExportSource::History => {
+            let filter = HistoryFilter {
+                since: parse_timestamp_param(query.since.as_deref())?,
+                until: parse_timestamp_param(query.until.as_deref())?,
+                ..Default::default()
+            };
+            let limit = query.limit.unwrap_or(history::DEFAULT_QUERY_LIMIT);
+            state
+                .player
+                .history
+                .read()
+                .unwrap()
+                .query(&filter, SortOrder::Asc, None, limit)
+                .entries
+                .into_iter()
+                .map(|entry| PlaylistEntry {
+                    location: entry.track.path.to_string_lossy().to_string(),
+                    title: Some(entry.track.title),
+                    artist: entry.track.artist,
+                    // A skipped play's duration is not the track length
+                    duration_secs: (!entry.skipped).then_some(entry.duration_secs),
+                })
+                .collect()
+        }
+    };
+
+    Ok((
+        [
+            ("content-type", format.content_type().to_string()),
+            (
+                "content-disposition",
+                format!("attachment; filename=\"playlist.{}\"", format.extension()),
+            ),
+        ],
+        playlist_format::render(&entries, format),
+    )
+        .into_response())
+}
+
+/// Validate every path and read its tags; fails on the first bad path.
+fn tracks_from_paths(
+    paths: &[String],
+    media_dir: &Option<PathBuf>,
+) -> Result<Vec<Track>, (StatusCode, Json<ErrorResponse>)> {
+    paths
+        .iter()
+        .map(|path| {
+            validate_ogg_file(path, media_dir)
+                .map(Track::from_file)
+                .map_err(|(status, Json(err))| {
+                    error_response(status, &format!("{}: {}", path, err.error), err.code)
+                })
+        })
+        .collect()
+}
+
+fn playlist_not_found() -> (StatusCode, Json<ErrorResponse>) {
+    error_response(StatusCode::NOT_FOUND, "Playlist not found", 8001)
+}
+
+async fn list_playlists(State(state): State<AppState>) -> Json<Vec<PlaylistSummary>> {
+    Json(state.playlists.read().await.list())
+}
+
+async fn get_playlist(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<Playlist>, (StatusCode, Json<ErrorResponse>)> {
+    state
+        .playlists
+        .read()
+        .await
+        .get(&name)
+        .cloned()
+        .map(Json)
+        .ok_or_else(playlist_not_found)
+}
+
+async fn create_playlist(
+    State(state): State<AppState>,
+    Json(req): Json<CreatePlaylistRequest>,
+) -> Result<(StatusCode, Json<Playlist>), (StatusCode, Json<ErrorResponse>)> {
+    if !playlist::valid_name(&req.name) {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            "Playlist names may only contain letters, digits, '-', '_' and '.'",
+            8003,
+        ));
+    }
+    let tracks = tracks_from_paths(&req.paths, &state.media_dir)?;
+
+    let mut playlists = state.playlists.write().await;
+    if playlists.contains(&req.name) {
+        return Err(error_response(
+            StatusCode::CONFLICT,
+            "Playlist already exists",
+            8002,
+        ));
+    }
+    let playlist = playlists.save(&req.name, req.description, tracks);
+    Ok((StatusCode::CREATED, Json(playlist)))
+}
+
+async fn update_playlist(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(req): Json<UpdatePlaylistRequest>,
+) -> Result<Json<Playlist>, (StatusCode, Json<ErrorResponse>)> {
+    let tracks = match req.paths {
+        Some(ref paths) => Some(tracks_from_paths(paths, &state.media_dir)?),
+        None => None,
+    };
+
+    let mut playlists = state.playlists.write().await;
+    let Some(existing) = playlists.get(&name) else {
+        return Err(playlist_not_found());
+    };
+    let description = match req.description {
+        Some(d) if d.is_empty() => None,
+        Some(d) => Some(d),
+        None => existing.description.clone(),
+    };
+    let tracks = tracks.unwrap_or_else(|| existing.tracks.clone());
+    Ok(Json(playlists.save(&name, description, tracks)))
+}
+
+async fn delete_playlist(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
 ) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    match state.playlists.write().await.delete(&name) {
+        Some(_) => Ok(StatusCode::NO_CONTENT),
+        None => Err(playlist_not_found()),
+    }
+}
+
+async fn enqueue_playlist(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    token: Option<Extension<ApiToken>>,
+    headers: HeaderMap,
+    body: Option<Json<EnqueuePlaylistRequest>>,
+) -> Result<(StatusCode, ETag, Json<BulkAddResponse>), (StatusCode, Json<ErrorResponse>)> {
+    let mode = body.map(|Json(req)| req.mode).unwrap_or_default();
+    // Replacing clears the queue, which is for admins
+    if mode == EnqueueMode::Replace {
+        require_scope(&token, Scope::Admin)?;
+    }
+    let tracks = state
+        .playlists
+        .read()
+        .await
+        .get(&name)
+        .map(|p| p.tracks.clone())
+        .ok_or_else(playlist_not_found)?;
+
+    // Files may have moved or been quarantined since the playlist was saved
+    let mut added = Vec::new();
+    let mut errors = Vec::new();
+    for track in tracks {
+        if !track.path.is_file() {
+            errors.push(format!("{}: File not found", track.path.display()));
+        } else if state.player.is_quarantined(&track.path) {
+            errors.push(format!("{}: Track is quarantined", track.path.display()));
+        } else {
+            added.push(track.with_new_id());
+        }
+    }
+    // Never clear the queue for a playlist with nothing to play
+    if added.is_empty() {
+        return Err(error_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Playlist has no playable tracks",
+            8004,
+        ));
+    }
+
     let mut q = state.queue.write().await;
-    if q.move_track(id, req.position) {
+    check_if_match(&headers, &q)?;
+    match mode {
+        EnqueueMode::Append => q.extend_back(added.iter().cloned()),
+        EnqueueMode::Prepend => q.extend_front(added.clone()),
+        EnqueueMode::Replace => q.replace(added.clone()),
+        EnqueueMode::Shuffle => {
+            use rand::seq::SliceRandom;
+            added.shuffle(&mut rand::rng());
+            added = shuffle::enforce_artist_gap(added, state.min_artist_gap);
+            q.extend_back(added.iter().cloned());
+        }
+    }
+    let kind = match mode {
+        EnqueueMode::Replace => QueueChangeKind::Replaced,
+        _ => QueueChangeKind::Added,
+    };
+    metrics::QUEUE_LENGTH.set(q.len() as i64);
+    state.player.send_event(PlayerEvent::tracks_added(&q, kind, &added));
+
+    Ok((StatusCode::CREATED, etag(q.revision()), Json(BulkAddResponse { added, errors })))
+}
+
+fn schedule_entry_not_found() -> (StatusCode, Json<ErrorResponse>) {
+    error_response(StatusCode::NOT_FOUND, "Schedule entry not found", 8101)
+}
+
+fn schedule_entry_response(
+    schedule: &crate::scheduler::Schedule,
+    entry: &ScheduleEntry,
+) -> ScheduleEntryResponse {
+    let now = chrono::Utc::now();
+    ScheduleEntryResponse {
+        next_start: schedule
+            .next_start(&entry.spec, now)
+            .map(|t| t.with_timezone(&schedule.timezone()).to_rfc3339()),
+        active: schedule.is_active(&entry.spec, now),
+        entry: entry.clone(),
+    }
+}
+
+/// Reject invalid specs and blocks that refer to unknown playlists.
+async fn check_schedule_spec(
+    state: &AppState,
+    spec: &ScheduleSpec,
+) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    spec.validate()
+        .map_err(|e| error_response(StatusCode::BAD_REQUEST, &e, 8102))?;
+    if !state.playlists.read().await.contains(&spec.playlist) {
+        return Err(playlist_not_found());
+    }
+    Ok(())
+}
+
+async fn list_schedule(State(state): State<AppState>) -> Json<ScheduleResponse> {
+    let schedule = state.schedule.read().await;
+    Json(ScheduleResponse {
+        timezone: schedule.timezone().name().to_string(),
+        entries: schedule
+            .list()
+            .iter()
+            .map(|e| schedule_entry_response(&schedule, e))
+            .collect(),
+    })
+}
+
+async fn get_schedule_entry(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Json<ScheduleEntryResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let schedule = state.schedule.read().await;
+    let entry = schedule.get(id).ok_or_else(schedule_entry_not_found)?;
+    Ok(Json(schedule_entry_response(&schedule, entry)))
+}
+
+async fn create_schedule_entry(
+    State(state): State<AppState>,
+    Json(spec): Json<ScheduleSpec>,
+) -> Result<(StatusCode, Json<ScheduleEntryResponse>), (StatusCode, Json<ErrorResponse>)> {
+    check_schedule_spec(&state, &spec).await?;
+    let mut schedule = state.schedule.write().await;
+    let entry = schedule.add(spec);
+    Ok((StatusCode::CREATED, Json(schedule_entry_response(&schedule, &entry))))
+}
+
+async fn update_schedule_entry(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(spec): Json<ScheduleSpec>,
+) -> Result<Json<ScheduleEntryResponse>, (StatusCode, Json<ErrorResponse>)> {
+    check_schedule_spec(&state, &spec).await?;
+    let mut schedule = state.schedule.write().await;
+    let entry = schedule.update(id, spec).ok_or_else(schedule_entry_not_found)?;
+    Ok(Json(schedule_entry_response(&schedule, &entry)))
+}
+
+async fn delete_schedule_entry(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    match state.schedule.write().await.remove(id) {
+        Some(_) => Ok(StatusCode::NO_CONTENT),
+        None => Err(schedule_entry_not_found()),
+    }
+}
+
+fn timed_insert_response(
+    schedule: &crate::scheduler::Schedule,
+    insert: &TimedInsert,
+) -> TimedInsertResponse {
+    TimedInsertResponse {
+        next_time: schedule
+            .next_insert(&insert.spec, chrono::Utc::now())
+            .map(|t| t.with_timezone(&schedule.timezone()).to_rfc3339()),
+        insert: insert.clone(),
+    }
+}
+
+async fn list_inserts(State(state): State<AppState>) -> Json<Vec<TimedInsertResponse>> {
+    let schedule = state.schedule.read().await;
+    Json(
+        schedule
+            .inserts()
+            .iter()
+            .map(|i| timed_insert_response(&schedule, i))
+            .collect(),
+    )
+}
+
+async fn create_insert(
+    State(state): State<AppState>,
+    Json(mut spec): Json<InsertSpec>,
+) -> Result<(StatusCode, Json<TimedInsertResponse>), (StatusCode, Json<ErrorResponse>)> {
+    spec.validate()
+        .map_err(|e| error_response(StatusCode::BAD_REQUEST, &e, 8104))?;
+    spec.path = validate_ogg_file(&spec.path.to_string_lossy(), &state.media_dir)?;
+
+    let mut schedule = state.schedule.write().await;
+    let insert = schedule.add_insert(spec);
+    Ok((StatusCode::CREATED, Json(timed_insert_response(&schedule, &insert))))
+}
+
+async fn delete_insert(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    match state.schedule.write().await.remove_insert(id) {
+        Some(_) => Ok(StatusCode::NO_CONTENT),
+        None => Err(error_response(
+            StatusCode::NOT_FOUND,
+            "Timed insert not found",
+            8103,
+        )),
+    }
+}
+
+async fn get_jingle_rules(State(state): State<AppState>) -> Json<JingleRules> {
+    Json(state.player.jingle_rules())
+}
+
+async fn set_jingle_rules(
+    State(state): State<AppState>,
+    Json(rules): Json<JingleRules>,
+) -> Result<Json<JingleRules>, (StatusCode, Json<ErrorResponse>)> {
+    if let Some(ref dir) = rules.directory {
+        if !dir.is_dir() {
+            return Err(error_response(
+                StatusCode::NOT_FOUND,
+                "Jingle directory not found",
+                3010,
+            ));
+        }
+        check_media_dir(dir, &state.media_dir)?;
+    }
+    state.player.set_jingle_rules(rules.clone());
+    Ok(Json(rules))
+}
+
+async fn shuffle_queue(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Option<Json<ShuffleRequest>>,
+) -> Result<(StatusCode, ETag), (StatusCode, Json<ErrorResponse>)> {
+    let req = body.map(|Json(req)| req).unwrap_or_default();
+    let min_artist_gap = req.min_artist_gap.unwrap_or(state.min_artist_gap);
+
+    if req.mode == ShuffleMode::Random && min_artist_gap == 0 {
+        let mut q = state.queue.write().await;
+        check_if_match(&headers, &q)?;
+        q.shuffle();
         state
             .player
-            .send_event(PlayerEvent::QueueChanged { length: q.len() });
-        Ok(StatusCode::OK)
+            .send_event(PlayerEvent::queue_reordered(&q, QueueChangeKind::Shuffled, &[]));
+        return Ok((StatusCode::OK, etag(q.revision())));
+    }
+
+    // Work on a snapshot so tag reads don't hold the queue lock
+    let tracks = {
+        let q = state.queue.read().await;
+        check_if_match(&headers, &q)?;
+        q.list()
+    };
+    let shuffled = match req.mode {
+        ShuffleMode::Random => {
+            use rand::seq::SliceRandom;
+            let mut tracks = tracks;
+            tracks.shuffle(&mut rand::rng());
+            tracks
+        }
+        ShuffleMode::ArtistSpread => {
+            shuffle::spread_by(tracks, |t| t.artist.as_ref().map(|a| a.to_lowercase()))
+        }
+        ShuffleMode::AlbumSpread => shuffle::spread_by(tracks, |t| t.album().map(str::to_lowercase)),
+        ShuffleMode::Weighted => {
+            let plays: HashMap<PathBuf, u64> = state
+                .player
+                .history
+                .read()
+                .unwrap()
+                .stats(&HistoryFilter::default())
+                .tracks
+                .into_iter()
+                .map(|t| (t.path, t.plays))
+                .collect();
+            shuffle::weighted(tracks, &plays)
+        }
+    };
+    let shuffled = shuffle::enforce_artist_gap(shuffled, min_artist_gap);
+    let ids: Vec<u64> = shuffled.iter().map(|t| t.id).collect();
+
+    let mut q = state.queue.write().await;
+    check_if_match(&headers, &q)?;
+    q.reorder(&ids);
+    state
+        .player
+        .send_event(PlayerEvent::queue_reordered(&q, QueueChangeKind::Shuffled, &[]));
+    Ok((StatusCode::OK, etag(q.revision())))
+}
+
+async fn remove_track(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+) -> Result<(ETag, Json<Track>), (StatusCode, Json<ErrorResponse>)> {
+    let mut q = state.queue.write().await;
+    check_if_match(&headers, &q)?;
+    let position = q.position(id);
+    match q.remove(id) {
+        Some(track) => {
+            metrics::QUEUE_LENGTH.set(q.len() as i64);
+            let removed = TrackPosition::new(id, position.unwrap_or_default());
+            state
+                .player
+                .send_event(PlayerEvent::queue_changed(&q, QueueChangeKind::Removed, vec![removed]));
+            Ok((etag(q.revision()), Json(track)))
+        }
+        None => Err(error_response(
+            StatusCode::NOT_FOUND,
+            "Track not found",
+            6001,
+        )),
+    }
+}
+
+async fn update_track_metadata(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+    Json(req): Json<MetadataOverride>,
+) -> Result<(ETag, Json<Track>), (StatusCode, Json<ErrorResponse>)> {
+    let edit = valid_metadata(req)?;
+    let mut q = state.queue.write().await;
+    check_if_match(&headers, &q)?;
+    let Some(position) = q.position(id) else {
+        return Err(error_response(StatusCode::NOT_FOUND, "Track not found", 6001));
+    };
+    q.update_tracks(|track| {
+        if track.id == id {
+            track.override_metadata(&edit);
+        }
+        track.id == id
+    });
+    let track = q.list().swap_remove(position);
+    state.player.send_event(PlayerEvent::queue_changed(
+        &q,
+        QueueChangeKind::Updated,
+        vec![TrackPosition { id, position, track: Some(track.clone()) }],
+    ));
+    Ok((etag(q.revision()), Json(track)))
+}
+
+async fn update_now_playing_metadata(
+    State(state): State<AppState>,
+    Json(req): Json<MetadataOverride>,
+) -> Result<Json<Track>, (StatusCode, Json<ErrorResponse>)> {
+    let edit = valid_metadata(req)?;
+    // Hold the queue so the saved current track matches what is on air
+    let mut q = state.queue.write().await;
+    let track = state
+        .player
+        .override_now_playing(&edit)
+        .ok_or_else(|| error_response(StatusCode::NOT_FOUND, "Nothing is playing", 6001))?;
+    q.set_current(Some(track.clone()));
+    state.player.send_event(PlayerEvent::NowPlayingUpdated(track.clone()));
+    Ok(Json(track))
+}
+
+async fn now_playing_artwork(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let track = state
+        .player
+        .now_playing()
+        .ok_or_else(|| error_response(StatusCode::NOT_FOUND, "Nothing is playing", 6001))?;
+    artwork_response(&state, track.path, &headers).await
+}
+
+async fn queue_track_artwork(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    // The track on air has left the queue but keeps its id
+    let path = match state.player.now_playing() {
+        Some(track) if track.id == id => Some(track.path),
+        _ => {
+            let q = state.queue.read().await;
+            q.position(id).map(|position| q.list().swap_remove(position).path)
+        }
+    };
+    let path = path.ok_or_else(|| error_response(StatusCode::NOT_FOUND, "Track not found", 6001))?;
+    artwork_response(&state, path, &headers).await
+}
+
+/// Serve a track's cover art, or 304 if `If-None-Match` names it.
+async fn artwork_response(
+    state: &AppState,
+    path: PathBuf,
+    headers: &HeaderMap,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let cache = state.artwork.clone();
+    let artwork = tokio::task::spawn_blocking(move || cache.get(&path))
+        .await
+        .ok()
+        .flatten()
+        .ok_or_else(|| error_response(StatusCode::NOT_FOUND, "Track has no artwork", 3015))?;
+
+    let tag = format!("\"{}\"", artwork.hash);
+    let unchanged = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.split(',').any(|t| {
+                let t = t.trim();
+                t == "*" || t.trim_start_matches("W/") == tag
+            })
+        });
+    // Clients revalidate each time, since what is playing changes
+    let caching = [(header::ETAG, tag), (header::CACHE_CONTROL, "no-cache".to_string())];
+    if unchanged {
+        return Ok((StatusCode::NOT_MODIFIED, caching).into_response());
+    }
+    Ok((
+        caching,
+        [(header::CONTENT_TYPE, artwork.mime_type.clone())],
+        artwork.data.clone(),
+    )
+        .into_response())
+}
+
+async fn move_track(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+    Json(req): Json<MoveTrackRequest>,
+) -> Result<(StatusCode, ETag), (StatusCode, Json<ErrorResponse>)> {
+    let mut q = state.queue.write().await;
+    check_if_match(&headers, &q)?;
+
+    let moved = match req.target()? {
+        MoveTarget::Position(position) => q.move_track(id, position),
+        MoveTarget::Before(anchor) | MoveTarget::After(anchor) => {
+            if q.contains(id) && !q.contains(anchor) {
+                return Err(error_response(
+                    StatusCode::NOT_FOUND,
+                    "Anchor track not found",
+                    6001,
+                ));
+            }
+            q.move_next_to(id, anchor, req.after_id.is_some())
+        }
+    };
+
+    if moved {
+        let position = q.position(id).unwrap_or_default();
+        state.player.send_event(PlayerEvent::queue_changed(
+            &q,
+            QueueChangeKind::Moved,
+            vec![TrackPosition::new(id, position)],
+        ));
+        Ok((StatusCode::OK, etag(q.revision())))
     } else {
         Err(error_response(
             StatusCode::NOT_FOUND,
@@ -371,14 +1679,96 @@ async fn move_track(
     }
 }
 
-async fn clear_queue(State(state): State<AppState>) -> StatusCode {
+async fn batch_queue(
+    State(state): State<AppState>,
+    token: Option<Extension<ApiToken>>,
+    headers: HeaderMap,
+    Json(req): Json<BatchRequest>,
+) -> Result<(ETag, Json<BatchResponse>), (StatusCode, Json<ErrorResponse>)> {
+    if req.ops.len() > MAX_BATCH_OPS {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            &format!("At most {} operations per batch", MAX_BATCH_OPS),
+            7000,
+        ));
+    }
+    // Clearing the queue is for admins, however it is asked for
+    if req.ops.iter().any(|op| matches!(op, BatchOp::Clear)) {
+        require_scope(&token, Scope::Admin)?;
+    }
+
+    // Check files before taking the lock; any failure rejects the batch
+    let op_error = |index: usize, (status, Json(err)): (StatusCode, Json<ErrorResponse>)| {
+        error_response(status, &format!("Operation {}: {}", index, err.error), err.code)
+    };
+    let mut ops = Vec::with_capacity(req.ops.len());
+    for (index, op) in req.ops.into_iter().enumerate() {
+        let op = match op {
+            BatchOp::Add { path } => {
+                let path = queueable_file(&state, &path).map_err(|e| op_error(index, e))?;
+                QueueOp::Add(Track::from_file(path))
+            }
+            BatchOp::InsertAt { path, position } => {
+                let path = queueable_file(&state, &path).map_err(|e| op_error(index, e))?;
+                QueueOp::InsertAt(Track::from_file(path), position)
+            }
+            BatchOp::Remove { id } => QueueOp::Remove(id),
+            BatchOp::Move { id, target } => {
+                QueueOp::Move(id, target.target().map_err(|e| op_error(index, e))?)
+            }
+            BatchOp::Clear => QueueOp::Clear,
+        };
+        ops.push(op);
+    }
+
+    let mut q = state.queue.write().await;
+    check_if_match(&headers, &q)?;
+    let results = q.apply_batch(ops).map_err(|e| {
+        error_response(
+            StatusCode::NOT_FOUND,
+            &format!("Operation {}: Track {} not found", e.index, e.missing_id),
+            6001,
+        )
+    })?;
+    metrics::QUEUE_LENGTH.set(q.len() as i64);
+    let added: Vec<Track> = results
+        .iter()
+        .filter_map(|r| match r {
+            QueueOpOutcome::Added { track, .. } => Some(track.clone()),
+            _ => None,
+        })
+        .collect();
+    state
+        .player
+        .send_event(PlayerEvent::queue_reordered(&q, QueueChangeKind::Batch, &added));
+
+    Ok((
+        etag(q.revision()),
+        Json(BatchResponse {
+            results,
+            revision: q.revision(),
+        }),
+    ))
+}
+
+async fn clear_queue(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, ETag), (StatusCode, Json<ErrorResponse>)> {
     let mut q = state.queue.write().await;
+    check_if_match(&headers, &q)?;
+    let removed: Vec<TrackPosition> = q
+        .list()
+        .iter()
+        .enumerate()
+        .map(|(position, t)| TrackPosition::new(t.id, position))
+        .collect();
     q.clear();
     metrics::QUEUE_LENGTH.set(0);
     state
         .player
-        .send_event(PlayerEvent::QueueChanged { length: 0 });
-    StatusCode::NO_CONTENT
+        .send_event(PlayerEvent::queue_changed(&q, QueueChangeKind::Cleared, removed));
+    Ok((StatusCode::NO_CONTENT, etag(q.revision())))
 }
 
 async fn skip_track(State(state): State<AppState>) -> StatusCode {
@@ -386,6 +1776,219 @@ async fn skip_track(State(state): State<AppState>) -> StatusCode {
     StatusCode::OK
 }
 
+// --- Library ---
+
+fn shared_library(state: &AppState) -> Result<&SharedLibrary, (StatusCode, Json<ErrorResponse>)> {
+    state.library.as_ref().ok_or_else(|| {
+        error_response(StatusCode::NOT_FOUND, "No media directory is configured", 8300)
+    })
+}
+
+fn library_track(state: &AppState, id: u64) -> Result<LibraryTrack, (StatusCode, Json<ErrorResponse>)> {
+    shared_library(state)?
+        .read()
+        .unwrap()
+        .get(id)
+        .cloned()
+        .ok_or_else(|| error_response(StatusCode::NOT_FOUND, "Library track not found", 8301))
+}
+
+async fn search_library(
+    State(state): State<AppState>,
+    Query(query): Query<LibrarySearchQuery>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorResponse>)> {
+    let search = LibraryQuery {
+        q: query.q,
+        artist: query.artist,
+        album: query.album,
+        genre: query.genre,
+    };
+    let limit = query.limit.unwrap_or(library::DEFAULT_QUERY_LIMIT);
+    let page = shared_library(&state)?
+        .read()
+        .unwrap()
+        .search(&search, query.cursor, limit);
+
+    let mut headers = HeaderMap::new();
+    headers.insert("x-total-count", HeaderValue::from(page.total));
+    if let Some(cursor) = page.next_cursor {
+        headers.insert("x-next-cursor", HeaderValue::from(cursor));
+    }
+    Ok((headers, Json(page.tracks)))
+}
+
+async fn get_library_track(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Json<LibraryTrack>, (StatusCode, Json<ErrorResponse>)> {
+    Ok(Json(library_track(&state, id)?))
+}
+
+async fn scan_library(
+    State(state): State<AppState>,
+) -> Result<Json<ScanSummary>, (StatusCode, Json<ErrorResponse>)> {
+    let library = shared_library(&state)?.clone();
+    let summary = tokio::task::spawn_blocking(move || library::rescan(&library))
+        .await
+        .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, "Library scan failed", 9999))?;
+    Ok(Json(summary))
+}
+
+// --- Listener requests ---
+
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+const MAX_SEARCH_LIMIT: usize = 50;
+
+fn request_box(state: &AppState) -> Result<&SharedRequests, (StatusCode, Json<ErrorResponse>)> {
+    state.requests.as_ref().ok_or_else(|| {
+        error_response(StatusCode::NOT_FOUND, "Listener requests are not enabled", 8200)
+    })
+}
+
+fn request_error(err: RequestError) -> Response {
+    let status = match err {
+        RequestError::TooManyPending
+        | RequestError::ClientCooldown { .. }
+        | RequestError::TrackCooldown { .. } => StatusCode::TOO_MANY_REQUESTS,
+        RequestError::AlreadyRequested | RequestError::RecentlyPlayed => StatusCode::CONFLICT,
+    };
+    let mut response = error_response(status, &err.to_string(), err.code().as_u32()).into_response();
+    if let Some(secs) = err.retry_after() {
+        response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs));
+    }
+    response
+}
+
+async fn search_requestable(
+    State(state): State<AppState>,
+    Query(query): Query<RequestableQuery>,
+) -> Result<Json<Vec<RequestableTrack>>, (StatusCode, Json<ErrorResponse>)> {
+    request_box(&state)?;
+    let search = LibraryQuery {
+        q: query.q,
+        ..LibraryQuery::default()
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT).clamp(1, MAX_SEARCH_LIMIT);
+    let library = shared_library(&state)?.read().unwrap();
+    let results = library
+        .search(&search, None, limit)
+        .tracks
+        .into_iter()
+        .map(|track| RequestableTrack {
+            path: track
+                .path
+                .strip_prefix(library.root())
+                .unwrap_or(&track.path)
+                .to_string_lossy()
+                .into_owned(),
+            title: track.title,
+            artist: track.artist,
+        })
+        .collect();
+    Ok(Json(results))
+}
+
+async fn submit_request(
+    State(state): State<AppState>,
+    connect_info: Option<Extension<ConnectInfo<SocketAddr>>>,
+    Json(req): Json<SubmitRequestBody>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let requests = request_box(&state)?;
+    let name = req.name.map(|n| n.trim().to_string()).filter(|n| !n.is_empty());
+    if let Some(ref name) = name {
+        if name.chars().count() > MAX_NAME_LEN || name.chars().any(char::is_control) {
+            return Err(error_response(
+                StatusCode::BAD_REQUEST,
+                &format!("Name must be at most {} characters", MAX_NAME_LEN),
+                7000,
+            ));
+        }
+    }
+
+    // Paths are relative to the media directory, which requests require
+    let full_path = state.media_dir.as_ref().map(|d| d.join(&req.path)).unwrap_or_default();
+    let path = queueable_file(&state, &full_path.to_string_lossy())?;
+    let track = Track::from_file(path);
+
+    // Requests without a known client address share one cooldown
+    let ip = connect_info
+        .map(|Extension(ConnectInfo(addr))| addr.ip())
+        .unwrap_or(IpAddr::from([0, 0, 0, 0]));
+    let now = history::unix_now();
+    let now_playing = state.player.now_playing().is_some_and(|t| t.path == track.path);
+    let status = TrackStatus {
+        last_played: if now_playing {
+            Some(now)
+        } else {
+            state.player.history.read().unwrap().last_played(&track.path)
+        },
+        queued: state.queue.read().await.list().iter().any(|t| t.path == track.path),
+    };
+
+    let result = requests.write().await.submit(track, name, ip, status, now);
+    match result {
+        Ok(request) => {
+            info!(id = request.id, remote_addr = %ip, "Listener requested {}", request.track.title);
+            let receipt = RequestReceipt {
+                id: request.id,
+                title: request.track.title.clone(),
+                artist: request.track.artist.clone(),
+                status: "pending",
+            };
+            state.player.send_event(PlayerEvent::RequestReceived(request));
+            Ok((StatusCode::CREATED, Json(receipt)).into_response())
+        }
+        Err(err) => Ok(request_error(err)),
+    }
+}
+
+async fn list_requests(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ListenerRequest>>, (StatusCode, Json<ErrorResponse>)> {
+    let requests = request_box(&state)?;
+    Ok(Json(requests.read().await.pending().to_vec()))
+}
+
+fn request_not_found() -> (StatusCode, Json<ErrorResponse>) {
+    error_response(StatusCode::NOT_FOUND, "Request not found", 8201)
+}
+
+async fn approve_request(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<(StatusCode, ETag, Json<Track>), (StatusCode, Json<ErrorResponse>)> {
+    let request = request_box(&state)?
+        .write()
+        .await
+        .take(id)
+        .ok_or_else(request_not_found)?;
+    let track = Track {
+        requested_by: Some(request.requested_by.unwrap_or_else(|| "a listener".to_string())),
+        ..request.track.with_new_id()
+    };
+
+    let mut q = state.queue.write().await;
+    q.push_back(track.clone());
+    metrics::QUEUE_LENGTH.set(q.len() as i64);
+    state
+        .player
+        .send_event(PlayerEvent::tracks_added(&q, QueueChangeKind::Added, std::slice::from_ref(&track)));
+
+    Ok((StatusCode::CREATED, etag(q.revision()), Json(track)))
+}
+
+async fn reject_request(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    request_box(&state)?
+        .write()
+        .await
+        .take(id)
+        .ok_or_else(request_not_found)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
 async fn status(State(state): State<AppState>) -> Json<StatusResponse> {
     let now_playing = state.player.now_playing();
     let queue_length = state.queue.read().await.len();
@@ -399,34 +2002,231 @@ async fn status(State(state): State<AppState>) -> Json<StatusResponse> {
         queue_length,
         connection_state,
         uptime_seconds: uptime,
+        playback_mode: state.player.playback_mode(),
+        stopped: state.player.is_stopped(),
+    })
+}
+
+fn playback_response(player: &PlayerHandle) -> Json<PlaybackResponse> {
+    Json(PlaybackResponse {
+        mode: player.playback_mode(),
+        stopped: player.is_stopped(),
     })
 }
 
-async fn history(State(state): State<AppState>) -> impl IntoResponse {
-    let history = state.player.history.read().unwrap();
-    Json(history.clone())
+async fn playback_state(State(state): State<AppState>) -> Json<PlaybackResponse> {
+    playback_response(&state.player)
+}
+
+async fn set_playback_mode(
+    State(state): State<AppState>,
+    Json(req): Json<PlaybackModeRequest>,
+) -> Json<PlaybackResponse> {
+    state.player.set_playback_mode(req.mode);
+    playback_response(&state.player)
+}
+
+async fn resume_playback(State(state): State<AppState>) -> Json<PlaybackResponse> {
+    state.player.resume();
+    playback_response(&state.player)
+}
+
+async fn history(
+    State(state): State<AppState>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorResponse>)> {
+    let filter = query.filter()?;
+    let limit = query.limit.unwrap_or(history::DEFAULT_QUERY_LIMIT);
+    let page = state
+        .player
+        .history
+        .read()
+        .unwrap()
+        .query(&filter, query.order, query.cursor, limit);
+
+    let mut headers = HeaderMap::new();
+    if let Some(cursor) = page.next_cursor {
+        headers.insert("x-next-cursor", HeaderValue::from(cursor));
+    }
+    Ok((headers, Json(page.entries)))
+}
+
+async fn audit_log(
+    State(state): State<AppState>,
+    Query(query): Query<AuditQuery>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorResponse>)> {
+    let filter = AuditFilter {
+        since: parse_timestamp_param(query.since.as_deref())?,
+        until: parse_timestamp_param(query.until.as_deref())?,
+        actor: query.actor,
+        action: query.action,
+        failed: query.failed,
+    };
+    let limit = query.limit.unwrap_or(audit::DEFAULT_QUERY_LIMIT);
+    // Older records are read from the log file, without holding the lock
+    // that every audited request needs
+    let snapshot = state.audit.read().unwrap().snapshot();
+    let page = tokio::task::spawn_blocking(move || {
+        snapshot.query(&filter, query.order, query.cursor, limit)
+    })
+    .await
+    .map_err(|_| error_response(StatusCode::INTERNAL_SERVER_ERROR, "Audit query failed", 9999))?;
+
+    let mut headers = HeaderMap::new();
+    if let Some(cursor) = page.next_cursor {
+        headers.insert("x-next-cursor", HeaderValue::from(cursor));
+    }
+    Ok((headers, Json(page.records)))
+}
+
+async fn list_quarantine(State(state): State<AppState>) -> Json<Vec<QuarantinedTrack>> {
+    Json(state.player.quarantine.read().unwrap().list().to_vec())
+}
+
+async fn release_quarantined(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    let released = state
+        .player
+        .quarantine
+        .write()
+        .unwrap()
+        .remove(id)
+        .ok_or_else(|| error_response(StatusCode::NOT_FOUND, "Quarantined track not found", 8400))?;
+    info!("Released {} from quarantine", released.track.path.display());
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn history_stats(
+    State(state): State<AppState>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<impl IntoResponse, (StatusCode, Json<ErrorResponse>)> {
+    let filter = query.filter()?;
+    let stats = state.player.history.read().unwrap().stats(&filter);
+    Ok(Json(stats))
+}
+
+async fn play_report(
+    State(state): State<AppState>,
+    Query(query): Query<ReportQuery>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    // Jingles are station branding and failed tracks were never heard;
+    // neither is a reportable performance
+    let filter = HistoryFilter {
+        since: parse_timestamp_param(query.from.as_deref())?,
+        until: parse_timestamp_param(query.to.as_deref())?,
+        failed: Some(false),
+        source: Some(TrackSource::Queue),
+        ..Default::default()
+    };
+    let entries: Vec<_> = state
+        .player
+        .history
+        .read()
+        .unwrap()
+        .entries()
+        .iter()
+        .filter(|e| filter.matches(e))
+        .cloned()
+        .collect();
+
+    let format = query.format;
+    let body = report::render(&report::build_records(&entries), format);
+
+    Ok((
+        [
+            ("content-type", format.content_type().to_string()),
+            (
+                "content-disposition",
+                format!("attachment; filename=\"plays.{}\"", format.extension()),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[derive(Deserialize, Default)]
+struct EventsQuery {
+    /// Comma-separated extras; `queue` adds the full queue to queue_changed
+    include: Option<String>,
+}
+
+impl EventsQuery {
+    fn includes(&self, name: &str) -> bool {
+        self.include
+            .as_deref()
+            .is_some_and(|v| v.split(',').any(|s| s.trim() == name))
+    }
+}
+
+/// Serialize an event, adding the queue snapshot when asked for.
+fn event_json(event: &PlayerEvent, include_queue: bool) -> Option<String> {
+    let mut value = serde_json::to_value(event).ok()?;
+    if include_queue {
+        if let (Some(snapshot), Some(data)) = (event.queue_snapshot(), value.get_mut("data")) {
+            data["queue"] = serde_json::to_value(snapshot).ok()?;
+        }
+    }
+    serde_json::to_string(&value).ok()
+}
+
+fn sse_event(event: &SequencedEvent, include_queue: bool) -> Option<Event> {
+    let json = event_json(&event.event, include_queue)?;
+    let event_name = match &event.event {
+        PlayerEvent::TrackStarted(_) => "track_started",
+        PlayerEvent::NowPlayingUpdated(_) => "now_playing_updated",
+        PlayerEvent::TrackFinished { .. } => "track_finished",
+        PlayerEvent::TrackSkipped { .. } => "track_skipped",
+        PlayerEvent::TrackFailed { .. } => "track_failed",
+        PlayerEvent::QueueChanged { .. } => "queue_changed",
+        PlayerEvent::PlaybackModeChanged { .. } => "playback_mode_changed",
+        PlayerEvent::PlaybackStopped => "playback_stopped",
+        PlayerEvent::PlaybackResumed => "playback_resumed",
+        PlayerEvent::ScheduleBlockStarted { .. } => "schedule_block_started",
+        PlayerEvent::ScheduleBlockEnded { .. } => "schedule_block_ended",
+        PlayerEvent::LibraryChanged { .. } => "library_changed",
+        PlayerEvent::RequestReceived(_) => "request_received",
+    };
+    Some(Event::default().id(event.id.to_string()).event(event_name).data(json))
+}
+
+fn sse_delivery(delivery: Delivery, include_queue: bool) -> Option<Event> {
+    match delivery {
+        Delivery::Event(event) => sse_event(&event, include_queue),
+        Delivery::Resync { id, reason } => {
+            let data = serde_json::json!({ "reason": reason }).to_string();
+            Some(Event::default().id(id.to_string()).event("resync").data(data))
+        }
+    }
 }
 
 async fn events_sse(
     State(state): State<AppState>,
-) -> Sse<impl tokio_stream::Stream<Item = Result<Event, Infallible>>> {
-    let rx = state.player.event_tx.subscribe();
-    let stream = BroadcastStream::new(rx).filter_map(|result| {
-        match result {
-            Ok(event) => {
-                let json = serde_json::to_string(&event).ok()?;
-                let event_name = match &event {
-                    PlayerEvent::TrackStarted(_) => "track_started",
-                    PlayerEvent::TrackFinished { .. } => "track_finished",
-                    PlayerEvent::TrackSkipped { .. } => "track_skipped",
-                    PlayerEvent::QueueChanged { .. } => "queue_changed",
-                };
-                Some(Ok(Event::default().event(event_name).data(json)))
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
+) -> Sse<impl futures::Stream<Item = Result<Event, Infallible>>> {
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    let cursor = EventCursor::new(&state.player, last_event_id);
+    let include_queue = query.includes("queue");
+    // Queue events only carry the queue while someone asks for it
+    let watch = if include_queue {
+        Some(state.queue.read().await.watch())
+    } else {
+        None
+    };
+
+    let stream = futures::stream::unfold((cursor, watch), move |(mut cursor, watch)| async move {
+        loop {
+            if let Some(event) = sse_delivery(cursor.next().await?, include_queue) {
+                return Some((Ok(event), (cursor, watch)));
             }
-            Err(_) => None,
         }
     });
-
     Sse::new(stream).keep_alive(KeepAlive::default())
 }
 
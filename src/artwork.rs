@@ -0,0 +1,427 @@
+// Cover art for tracks, from their comments or an image beside the file
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+
+use crate::queue::read_comment_list;
+
+/// Images looked for next to a track without embedded art, in order of
+/// preference. Names are matched case-insensitively.
+pub const COVER_FILES: &[&str] = &["cover.jpg", "folder.jpg", "cover.png", "folder.png"];
+
+/// Picture type of a front cover in a FLAC picture block.
+const FRONT_COVER: u32 = 3;
+
+/// Tracks remembered before their index is emptied. Images are bounded
+/// separately, by size.
+const MAX_CACHED_TRACKS: usize = 1024;
+
+/// Total size of the images kept in memory.
+const MAX_CACHED_BYTES: usize = 32 * 1024 * 1024;
+
+/// An image ready to serve.
+#[derive(Debug)]
+pub struct Artwork {
+    pub mime_type: String,
+    pub data: Bytes,
+    /// Hex SHA-256 of `data`, used as the ETag
+    pub hash: String,
+}
+
+impl Artwork {
+    fn new(mime_type: String, data: Vec<u8>) -> Self {
+        let hash = Sha256::digest(&data).iter().map(|b| format!("{:02x}", b)).collect();
+        Self { mime_type, data: Bytes::from(data), hash }
+    }
+}
+
+/// A decoded FLAC picture block, as carried by `METADATA_BLOCK_PICTURE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Picture {
+    pub picture_type: u32,
+    pub mime_type: String,
+    pub description: String,
+    pub data: Vec<u8>,
+}
+
+/// Decode a base64 `METADATA_BLOCK_PICTURE` comment value.
+///
+/// The block holds big-endian u32 fields: picture type, MIME type length
+/// and bytes, description length and bytes, width, height, colour depth,
+/// colour count, then the data length and image data.
+pub fn decode_picture_block(value: &str) -> Option<Picture> {
+    let block = BASE64.decode(value.trim()).ok()?;
+    let mut pos = 0;
+
+    let picture_type = read_u32(&block, &mut pos)?;
+    let mime_len = read_u32(&block, &mut pos)? as usize;
+    let mime_type = String::from_utf8(take(&block, &mut pos, mime_len)?.to_vec()).ok()?;
+    let description_len = read_u32(&block, &mut pos)? as usize;
+    let description = String::from_utf8_lossy(take(&block, &mut pos, description_len)?).into_owned();
+    // Width, height, depth and colour count
+    take(&block, &mut pos, 16)?;
+    let data_len = read_u32(&block, &mut pos)? as usize;
+    let data = take(&block, &mut pos, data_len)?.to_vec();
+
+    Some(Picture { picture_type, mime_type, description, data })
+}
+
+fn take<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Option<&'a [u8]> {
+    let end = pos.checked_add(len)?;
+    let slice = data.get(*pos..end)?;
+    *pos = end;
+    Some(slice)
+}
+
+fn read_u32(data: &[u8], pos: &mut usize) -> Option<u32> {
+    Some(u32::from_be_bytes(take(data, pos, 4)?.try_into().ok()?))
+}
+
+/// MIME type of an image from its first bytes.
+fn sniff_mime(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(b"GIF8") {
+        Some("image/gif")
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+/// The declared MIME type if it names an image subtype, else a guess from
+/// the data.
+fn image_mime(declared: Option<&str>, data: &[u8]) -> Option<String> {
+    match declared.map(|m| m.trim().to_ascii_lowercase()) {
+        Some(m) if m == "image/jpg" => Some("image/jpeg".to_string()),
+        Some(m) if m.starts_with("image/") && m.len() > "image/".len() => Some(m),
+        _ => sniff_mime(data).map(str::to_string),
+    }
+}
+
+/// Art stored in the track's comments, preferring the front cover.
+///
+/// Reads `METADATA_BLOCK_PICTURE`, then the older `COVERART` comment of
+/// plain base64 image data with its `COVERARTMIME`.
+pub fn embedded_artwork(path: &Path) -> Option<Artwork> {
+    let comments = read_comment_list(path)?;
+
+    let pictures: Vec<Picture> = comments
+        .iter()
+        .filter(|(key, _)| key == "METADATA_BLOCK_PICTURE")
+        .filter_map(|(_, value)| decode_picture_block(value))
+        // A MIME type of "-->" means the data is a URL, not an image
+        .filter(|p| p.mime_type != "-->" && !p.data.is_empty())
+        .collect();
+    let picture = pictures
+        .iter()
+        .find(|p| p.picture_type == FRONT_COVER)
+        .or_else(|| pictures.first());
+    if let Some(picture) = picture {
+        if let Some(mime) = image_mime(Some(&picture.mime_type), &picture.data) {
+            return Some(Artwork::new(mime, picture.data.clone()));
+        }
+    }
+
+    let value = comments.iter().find(|(key, _)| key == "COVERART")?;
+    let data = BASE64.decode(value.1.trim()).ok()?;
+    let declared = comments.iter().find(|(key, _)| key == "COVERARTMIME");
+    let mime = image_mime(declared.map(|(_, v)| v.as_str()), &data)?;
+    Some(Artwork::new(mime, data))
+}
+
+/// A `cover.jpg` or similar image in the track's directory.
+pub fn folder_artwork(path: &Path) -> Option<Artwork> {
+    let dir = path.parent()?;
+    let names: HashMap<String, PathBuf> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| Some((entry.file_name().to_str()?.to_lowercase(), entry.path())))
+        .collect();
+
+    COVER_FILES.iter().filter_map(|name| names.get(*name)).find_map(|image| {
+        let data = std::fs::read(image).ok()?;
+        let declared = match image.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("png") => "image/png",
+            _ => "image/jpeg",
+        };
+        let mime = sniff_mime(&data).unwrap_or(declared);
+        Some(Artwork::new(mime.to_string(), data))
+    })
+}
+
+/// Embedded art for a track, else an image beside it.
+pub fn find_artwork(path: &Path) -> Option<Artwork> {
+    embedded_artwork(path).or_else(|| folder_artwork(path))
+}
+
+/// When a track and its directory last changed. Adding or replacing a
+/// cover image changes the directory.
+type Stamp = (Option<SystemTime>, u64, Option<SystemTime>);
+
+fn stamp(path: &Path) -> Stamp {
+    let file = std::fs::metadata(path).ok();
+    let dir = path.parent().and_then(|d| std::fs::metadata(d).ok());
+    (
+        file.as_ref().and_then(|m| m.modified().ok()),
+        file.map(|m| m.len()).unwrap_or(0),
+        dir.and_then(|m| m.modified().ok()),
+    )
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    /// Which image each track has, by content hash
+    tracks: HashMap<PathBuf, (Stamp, Option<String>)>,
+    /// Images by content hash, so an album's tracks share one copy, with
+    /// their key in `by_recency`
+    images: HashMap<String, (Arc<Artwork>, u64)>,
+    /// Image hashes, least recently served first
+    by_recency: BTreeMap<u64, String>,
+    next_seen: u64,
+    /// Total size of `images`
+    bytes: usize,
+}
+
+impl CacheInner {
+    /// The image with `hash`, marked as just served.
+    fn image(&mut self, hash: &str) -> Option<Arc<Artwork>> {
+        let seen = self.next_seen;
+        let (art, last) = self.images.get_mut(hash)?;
+        self.by_recency.remove(last);
+        *last = seen;
+        self.next_seen += 1;
+        self.by_recency.insert(seen, hash.to_string());
+        Some(art.clone())
+    }
+
+    /// Keep `art`, dropping the least recently served images to stay within
+    /// `max_bytes`. An image larger than that is served but not kept.
+    fn insert(&mut self, art: Artwork, max_bytes: usize) -> Arc<Artwork> {
+        if let Some(kept) = self.image(&art.hash) {
+            return kept;
+        }
+        let art = Arc::new(art);
+        let size = art.data.len();
+        if size > max_bytes {
+            return art;
+        }
+        while self.bytes + size > max_bytes {
+            let Some((_, oldest)) = self.by_recency.pop_first() else {
+                break;
+            };
+            if let Some((old, _)) = self.images.remove(&oldest) {
+                self.bytes -= old.data.len();
+            }
+        }
+        let seen = self.next_seen;
+        self.next_seen += 1;
+        self.by_recency.insert(seen, art.hash.clone());
+        self.images.insert(art.hash.clone(), (art.clone(), seen));
+        self.bytes += size;
+        art
+    }
+}
+
+/// Art already read from disk. A track is looked at again once it or its
+/// directory changes, or once its image has been dropped to make room.
+#[derive(Debug)]
+pub struct ArtworkCache {
+    inner: Mutex<CacheInner>,
+    max_bytes: usize,
+}
+
+impl Default for ArtworkCache {
+    fn default() -> Self {
+        Self::new(MAX_CACHED_BYTES)
+    }
+}
+
+impl ArtworkCache {
+    /// Create a cache keeping at most `max_bytes` of images.
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            inner: Mutex::new(CacheInner::default()),
+            max_bytes,
+        }
+    }
+
+    /// Art for the track at `path`, reading it if needed. Blocks on disk.
+    pub fn get(&self, path: &Path) -> Option<Arc<Artwork>> {
+        let stamp = stamp(path);
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let cached = inner
+                .tracks
+                .get(path)
+                .filter(|(cached, _)| *cached == stamp)
+                .map(|(_, hash)| hash.clone());
+            match cached {
+                Some(None) => return None,
+                Some(Some(hash)) => {
+                    if let Some(art) = inner.image(&hash) {
+                        return Some(art);
+                    }
+                }
+                None => {}
+            }
+        }
+
+        let artwork = find_artwork(path);
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.tracks.len() >= MAX_CACHED_TRACKS {
+            inner.tracks.clear();
+        }
+        let artwork = artwork.map(|art| inner.insert(art, self.max_bytes));
+        inner
+            .tracks
+            .insert(path.to_path_buf(), (stamp, artwork.as_ref().map(|a| a.hash.clone())));
+        artwork
+    }
+}
+
+pub type SharedArtworkCache = Arc<ArtworkCache>;
+
+#[cfg(test)]
+pub(crate) mod test_images {
+    use super::*;
+
+    /// Start of a JPEG file, enough to be recognised.
+    pub const JPEG: &[u8] = &[0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, b'J', b'F', b'I', b'F'];
+    /// Start of a PNG file.
+    pub const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    /// Base64 `METADATA_BLOCK_PICTURE` value holding `data`.
+    pub fn picture_block(picture_type: u32, mime_type: &str, data: &[u8]) -> String {
+        let mut block = Vec::new();
+        block.extend_from_slice(&picture_type.to_be_bytes());
+        block.extend_from_slice(&(mime_type.len() as u32).to_be_bytes());
+        block.extend_from_slice(mime_type.as_bytes());
+        block.extend_from_slice(&5u32.to_be_bytes());
+        block.extend_from_slice(b"Cover");
+        for value in [500u32, 500, 24, 0] {
+            block.extend_from_slice(&value.to_be_bytes());
+        }
+        block.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        block.extend_from_slice(data);
+        BASE64.encode(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_images::*;
+    use super::*;
+    use crate::queue::test_files::write_ogg;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_decode_picture_block() {
+        let picture = decode_picture_block(&picture_block(3, "image/png", PNG)).unwrap();
+        assert_eq!(picture.picture_type, 3);
+        assert_eq!(picture.mime_type, "image/png");
+        assert_eq!(picture.description, "Cover");
+        assert_eq!(picture.data, PNG);
+
+        // Truncated blocks and bad base64 are rejected
+        let block = BASE64.decode(picture_block(3, "image/png", PNG)).unwrap();
+        assert!(decode_picture_block(&BASE64.encode(&block[..block.len() - 1])).is_none());
+        assert!(decode_picture_block("not base64!").is_none());
+    }
+
+    #[test]
+    fn test_embedded_artwork_prefers_front_cover() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("song.ogg");
+        let back = picture_block(4, "image/png", PNG);
+        let front = picture_block(3, "image/jpg", JPEG);
+        write_ogg(
+            &path,
+            &[("TITLE", "Song"), ("METADATA_BLOCK_PICTURE", &back), ("METADATA_BLOCK_PICTURE", &front)],
+            10,
+        );
+
+        let art = embedded_artwork(&path).unwrap();
+        assert_eq!(art.mime_type, "image/jpeg");
+        assert_eq!(&art.data[..], JPEG);
+        assert_eq!(art.hash.len(), 64);
+
+        // The older COVERART comment, typed by its contents
+        let legacy = BASE64.encode(PNG);
+        write_ogg(&path, &[("COVERART", &legacy)], 10);
+        assert_eq!(embedded_artwork(&path).unwrap().mime_type, "image/png");
+
+        write_ogg(&path, &[("TITLE", "Song")], 10);
+        assert!(embedded_artwork(&path).is_none());
+    }
+
+    #[test]
+    fn test_folder_artwork_and_cache() {
+        let dir = tempdir().unwrap();
+        let a = dir.path().join("a.ogg");
+        let b = dir.path().join("b.ogg");
+        write_ogg(&a, &[("TITLE", "A")], 10);
+        write_ogg(&b, &[("TITLE", "B")], 10);
+
+        let cache = ArtworkCache::default();
+        assert!(cache.get(&a).is_none());
+
+        std::fs::write(dir.path().join("Folder.JPG"), JPEG).unwrap();
+        std::fs::write(dir.path().join("cover.png"), PNG).unwrap();
+        let art = folder_artwork(&a).unwrap();
+        assert_eq!(art.mime_type, "image/jpeg");
+
+        // The directory changed, so the cached miss is looked at again
+        let first = cache.get(&a).unwrap();
+        assert_eq!(first.hash, art.hash);
+        // Tracks with the same art share one copy
+        assert!(Arc::ptr_eq(&first, &cache.get(&b).unwrap()));
+
+        // Embedded art wins over the folder image
+        write_ogg(&b, &[("METADATA_BLOCK_PICTURE", &picture_block(3, "image/png", PNG))], 20);
+        assert_eq!(cache.get(&b).unwrap().mime_type, "image/png");
+    }
+
+    #[test]
+    fn test_cache_bounded_by_size() {
+        let dir = tempdir().unwrap();
+        let a = dir.path().join("a.ogg");
+        let b = dir.path().join("b.ogg");
+        write_ogg(&a, &[("METADATA_BLOCK_PICTURE", &picture_block(3, "image/jpeg", JPEG))], 10);
+        write_ogg(&b, &[("METADATA_BLOCK_PICTURE", &picture_block(3, "image/png", PNG))], 10);
+
+        // Room for one image only
+        let cache = ArtworkCache::new(JPEG.len().max(PNG.len()));
+        let first = cache.get(&a).unwrap();
+        assert!(Arc::ptr_eq(&first, &cache.get(&a).unwrap()));
+
+        assert_eq!(cache.get(&b).unwrap().mime_type, "image/png");
+        {
+            let inner = cache.inner.lock().unwrap();
+            assert_eq!(inner.images.len(), 1);
+            assert_eq!(inner.bytes, PNG.len());
+        }
+
+        // The dropped image is read again when next asked for
+        let again = cache.get(&a).unwrap();
+        assert_eq!(again.hash, first.hash);
+        assert!(!Arc::ptr_eq(&first, &again));
+        assert_eq!(cache.inner.lock().unwrap().bytes, JPEG.len());
+
+        // Images too big to keep are still served
+        let tiny = ArtworkCache::new(1);
+        assert!(tiny.get(&a).is_some());
+        assert!(tiny.inner.lock().unwrap().images.is_empty());
+    }
+}
@@ -0,0 +1,466 @@
+// Audit trail of changes made through the API
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::history::{unix_now, SortOrder};
+use crate::store;
+
+/// Number of most recent records kept in memory. With a log file, older
+/// records are read back from it when queried.
+const IN_MEMORY_LIMIT: usize = 1000;
+
+/// Strings in recorded arguments longer than this are replaced by their
+/// length, so imported playlists don't bloat the log.
+const MAX_ARG_STRING: usize = 256;
+
+pub const DEFAULT_QUERY_LIMIT: usize = 100;
+pub const MAX_QUERY_LIMIT: usize = 10000;
+
+/// Outcome of an audited request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditResult {
+    /// HTTP status of the response
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    #[serde(default)]
+    pub id: u64,
+    /// Unix seconds
+    pub timestamp: u64,
+    /// Name of the API token used, if authentication is enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_addr: Option<String>,
+    /// What was done, named as in the WebSocket API (e.g. `queue.clear`)
+    pub action: String,
+    pub method: String,
+    pub path: String,
+    /// Query parameters and request body
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub args: Value,
+    pub result: AuditResult,
+}
+
+impl AuditRecord {
+    pub fn succeeded(&self) -> bool {
+        (200..300).contains(&self.result.status)
+    }
+}
+
+/// Replace long strings in `args` with a note of their length.
+pub fn shorten_args(args: Value) -> Value {
+    match args {
+        Value::String(s) if s.len() > MAX_ARG_STRING => Value::String(format!("<{} bytes>", s.len())),
+        Value::Array(items) => Value::Array(items.into_iter().map(shorten_args).collect()),
+        Value::Object(map) => Value::Object(map.into_iter().map(|(k, v)| (k, shorten_args(v))).collect()),
+        other => other,
+    }
+}
+
+/// Filters applied to an audit query. Timestamps are Unix seconds.
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub actor: Option<String>,
+    /// Action name, or a prefix ending in `.` such as `queue.`
+    pub action: Option<String>,
+    pub failed: Option<bool>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        if self.since.is_some_and(|since| record.timestamp < since) {
+            return false;
+        }
+        if self.until.is_some_and(|until| record.timestamp >= until) {
+            return false;
+        }
+        if self.failed.is_some_and(|failed| record.succeeded() == failed) {
+            return false;
+        }
+        if let Some(ref actor) = self.actor {
+            if record.actor.as_deref() != Some(actor.as_str()) {
+                return false;
+            }
+        }
+        if let Some(ref action) = self.action {
+            let matched = if action.ends_with('.') {
+                record.action.starts_with(action.as_str())
+            } else {
+                record.action == *action
+            };
+            if !matched {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// One page of audit query results.
+#[derive(Debug, Clone)]
+pub struct AuditPage {
+    pub records: Vec<AuditRecord>,
+    /// Cursor for the following page, if there are more results.
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct AuditLog {
+    /// The most recent records; older ones are only in the log file
+    records: VecDeque<AuditRecord>,
+    next_id: u64,
+    log_path: Option<PathBuf>,
+    appender: Option<store::LineAppender<AuditRecord>>,
+    /// Whether the log file holds records older than `records`
+    older_in_file: bool,
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self {
+            records: VecDeque::new(),
+            next_id: 1,
+            log_path: None,
+            appender: None,
+            older_in_file: false,
+        }
+    }
+}
+
+impl AuditLog {
+    /// Create an audit log backed by an append-only JSON lines file.
+    /// Records are never removed from the file; the most recent are also
+    /// kept in memory.
+    ///
+    /// Fails if the file exists but can't be read, rather than starting the
+    /// IDs over.
+    pub fn with_log_file(path: PathBuf) -> std::io::Result<Self> {
+        let mut log = Self::default();
+        let mut count = 0;
+        store::scan_json_lines(&path, |record: AuditRecord| {
+            count += 1;
+            log.keep(record);
+            true
+        })?;
+        // IDs only increase, so the last record has the highest
+        log.next_id = log.records.back().map_or(0, |r| r.id) + 1;
+        if count > 0 {
+            info!("Loaded {} audit records", count);
+        }
+        log.older_in_file = count > log.records.len();
+        log.appender = Some(store::LineAppender::spawn(path.clone(), "audit log"));
+        log.log_path = Some(path);
+        Ok(log)
+    }
+
+    /// Add `record` to the in-memory tail, dropping the oldest if it is full.
+    fn keep(&mut self, record: AuditRecord) {
+        if self.records.len() >= IN_MEMORY_LIMIT {
+            self.records.pop_front();
+            self.older_in_file = self.log_path.is_some();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Record an action, assigning it the next audit ID and the current
+    /// time if none is set. The record is appended to the log file in the
+    /// background.
+    pub fn record(&mut self, mut record: AuditRecord) -> AuditRecord {
+        record.id = self.next_id;
+        self.next_id += 1;
+        if record.timestamp == 0 {
+            record.timestamp = unix_now();
+        }
+        info!(
+            target: "audit",
+            action = %record.action,
+            actor = record.actor.as_deref().unwrap_or(""),
+            remote_addr = record.remote_addr.as_deref().unwrap_or(""),
+            status = record.result.status,
+            "{} {} -> {}",
+            record.method,
+            record.path,
+            record.result.status
+        );
+
+        if let Some(ref appender) = self.appender {
+            appender.append(record.clone());
+        }
+        self.keep(record.clone());
+        record
+    }
+
+    /// Block until every record so far is in the log file.
+    pub fn flush(&self) {
+        if let Some(ref appender) = self.appender {
+            appender.flush();
+        }
+    }
+
+    /// Copy what a query needs, so the file can be read without holding
+    /// the log's lock.
+    pub fn snapshot(&self) -> AuditSnapshot {
+        AuditSnapshot {
+            records: self.records.clone(),
+            next_id: self.next_id,
+            log_path: self.log_path.clone(),
+            older_in_file: self.older_in_file,
+        }
+    }
+
+}
+
+/// The state of an [`AuditLog`] at one moment, for querying.
+#[derive(Debug, Clone)]
+pub struct AuditSnapshot {
+    records: VecDeque<AuditRecord>,
+    next_id: u64,
+    log_path: Option<PathBuf>,
+    older_in_file: bool,
+}
+
+impl AuditSnapshot {
+    /// Return records matching `filter`, ordered by ID.
+    ///
+    /// `cursor` is the ID of the last record of the previous page. Records
+    /// older than those kept in memory are read from the log file.
+    pub fn query(&self, filter: &AuditFilter, order: SortOrder, cursor: Option<u64>, limit: usize) -> AuditPage {
+        let limit = limit.clamp(1, MAX_QUERY_LIMIT);
+        let matching = |r: &&AuditRecord| filter.matches(r);
+        let oldest_kept = self.records.front().map_or(self.next_id, |r| r.id);
+
+        let mut records: Vec<AuditRecord> = match order {
+            SortOrder::Asc => {
+                let mut records = Vec::new();
+                if cursor.is_none_or(|c| c + 1 < oldest_kept) {
+                    self.scan_older(oldest_kept, |r| {
+                        if cursor.is_none_or(|c| r.id > c) && filter.matches(&r) {
+                            records.push(r);
+                        }
+                        records.len() <= limit
+                    });
+                }
+                let wanted = (limit + 1).saturating_sub(records.len());
+                records.extend(
+                    self.records
+                        .iter()
+                        .filter(|r| cursor.is_none_or(|c| r.id > c))
+                        .filter(matching)
+                        .take(wanted)
+                        .cloned(),
+                );
+                records
+            }
+            SortOrder::Desc => {
+                let mut records: Vec<AuditRecord> = self
+                    .records
+                    .iter()
+                    .rev()
+                    .filter(|r| cursor.is_none_or(|c| r.id < c))
+                    .filter(matching)
+                    .take(limit + 1)
+                    .cloned()
+                    .collect();
+                if records.len() <= limit {
+                    // Keep only the newest of the older matches
+                    let wanted = limit + 1 - records.len();
+                    let mut older = VecDeque::with_capacity(wanted);
+                    self.scan_older(oldest_kept, |r| {
+                        if cursor.is_none_or(|c| r.id < c) && filter.matches(&r) {
+                            if older.len() == wanted {
+                                older.pop_front();
+                            }
+                            older.push_back(r);
+                        }
+                        true
+                    });
+                    records.extend(older.into_iter().rev());
+                }
+                records
+            }
+        };
+
+        let next_cursor = if records.len() > limit {
+            records.truncate(limit);
+            records.last().map(|r| r.id)
+        } else {
+            None
+        };
+
+        AuditPage { records, next_cursor }
+    }
+
+    /// Pass records from the log file older than `before` to `visit`, oldest
+    /// first, until `visit` returns false.
+    fn scan_older(&self, before: u64, mut visit: impl FnMut(AuditRecord) -> bool) {
+        let Some(ref path) = self.log_path else {
+            return;
+        };
+        if !self.older_in_file {
+            return;
+        }
+        let result = store::scan_json_lines(path, |r: AuditRecord| r.id < before && visit(r));
+        if let Err(e) = result {
+            warn!("Failed to read audit log {}: {}", path.display(), e);
+        }
+    }
+}
+
+pub type SharedAuditLog = Arc<RwLock<AuditLog>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn record(action: &str, actor: Option<&str>, status: u16) -> AuditRecord {
+        AuditRecord {
+            id: 0,
+            timestamp: 0,
+            actor: actor.map(str::to_string),
+            remote_addr: None,
+            action: action.to_string(),
+            method: "POST".to_string(),
+            path: "/api/queue".to_string(),
+            args: Value::Null,
+            result: AuditResult {
+                status,
+                error: None,
+                code: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_log_file_is_appended_and_reloaded() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+
+        let mut log = AuditLog::with_log_file(path.clone()).unwrap();
+        log.record(record("queue.add", Some("dj"), 201));
+        log.record(record("queue.clear", Some("admin"), 204));
+        log.flush();
+
+        let mut log = AuditLog::with_log_file(path.clone()).unwrap();
+        let page = log.snapshot().query(&AuditFilter::default(), SortOrder::Asc, None, 10);
+        let ids: Vec<u64> = page.records.iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert!(page.records[0].timestamp > 0);
+
+        assert_eq!(log.record(record("player.skip", None, 200)).id, 3);
+        log.flush();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+
+        // A record cut short by a crash doesn't reset the IDs
+        std::fs::write(&path, std::fs::read_to_string(&path).unwrap() + "{\"id\": 4, \"time").unwrap();
+        let mut log = AuditLog::with_log_file(path.clone()).unwrap();
+        assert_eq!(log.record(record("player.skip", None, 200)).id, 4);
+
+        // Nor does a log that can't be read at all
+        assert!(AuditLog::with_log_file(dir.path().to_path_buf()).is_err());
+    }
+
+    #[test]
+    fn test_older_records_queried_from_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let total = IN_MEMORY_LIMIT as u64 + 10;
+
+        let mut log = AuditLog::with_log_file(path.clone()).unwrap();
+        for i in 0..total {
+            let actor = if i % 2 == 0 { "dj" } else { "admin" };
+            log.record(record("player.skip", Some(actor), 200));
+        }
+        log.flush();
+        assert_eq!(log.records.len(), IN_MEMORY_LIMIT);
+        assert_eq!(log.records[0].id, 11);
+
+        let ids = |page: AuditPage| -> Vec<u64> { page.records.iter().map(|r| r.id).collect() };
+        let all = AuditFilter::default();
+        let page = log.snapshot().query(&all, SortOrder::Asc, None, 3);
+        assert_eq!(page.next_cursor, Some(3));
+        assert_eq!(ids(page), vec![1, 2, 3]);
+        assert_eq!(ids(log.snapshot().query(&all, SortOrder::Asc, Some(8), 4)), vec![9, 10, 11, 12]);
+        assert_eq!(ids(log.snapshot().query(&all, SortOrder::Desc, Some(13), 4)), vec![12, 11, 10, 9]);
+        let page = log.snapshot().query(&all, SortOrder::Desc, Some(4), 10);
+        assert_eq!(page.next_cursor, None);
+        assert_eq!(ids(page), vec![3, 2, 1]);
+
+        let dj = AuditFilter {
+            actor: Some("dj".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ids(log.snapshot().query(&dj, SortOrder::Desc, Some(14), 4)), vec![13, 11, 9, 7]);
+
+        // Reloading keeps only the tail in memory too
+        drop(log);
+        let log = AuditLog::with_log_file(path).unwrap();
+        assert_eq!(log.records.len(), IN_MEMORY_LIMIT);
+        assert_eq!(ids(log.snapshot().query(&all, SortOrder::Asc, None, 2)), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_filters() {
+        let mut log = AuditLog::default();
+        log.record(record("queue.add", Some("dj"), 201));
+        log.record(record("queue.clear", Some("dj"), 403));
+        log.record(record("player.skip", Some("admin"), 200));
+
+        let query = |filter: AuditFilter| -> Vec<u64> {
+            log.snapshot().query(&filter, SortOrder::Asc, None, 10).records.iter().map(|r| r.id).collect()
+        };
+        let by_actor = AuditFilter {
+            actor: Some("dj".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(query(by_actor), vec![1, 2]);
+        let by_prefix = AuditFilter {
+            action: Some("queue.".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(query(by_prefix), vec![1, 2]);
+        let failed = AuditFilter {
+            failed: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(query(failed), vec![2]);
+    }
+
+    #[test]
+    fn test_pagination_newest_first() {
+        let mut log = AuditLog::default();
+        for _ in 0..5 {
+            log.record(record("player.skip", None, 200));
+        }
+        let page = log.snapshot().query(&AuditFilter::default(), SortOrder::Desc, None, 2);
+        assert_eq!(page.records[0].id, 5);
+        assert_eq!(page.next_cursor, Some(4));
+        let page = log.snapshot().query(&AuditFilter::default(), SortOrder::Desc, Some(4), 10);
+        assert_eq!(page.records.len(), 3);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn test_shorten_args() {
+        let long = "x".repeat(1000);
+        let args = serde_json::json!({ "content": long, "paths": ["/music/a.ogg"] });
+        let short = shorten_args(args);
+        assert_eq!(short["content"], "<1000 bytes>");
+        assert_eq!(short["paths"][0], "/music/a.ogg");
+    }
+}
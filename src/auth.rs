@@ -0,0 +1,460 @@
+// API tokens with scopes, failed-attempt lockouts, and the request actor
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::http::Method;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::errors::{ErrorCode, Result, SnowbootError};
+
+/// What a token may do. `admin` implies every other scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Status, queue, history and events
+    Read,
+    /// Add, move and remove queued tracks
+    Queue,
+    /// Skip and playback mode
+    Control,
+    /// Clearing the queue, playlists, schedule and jingle configuration
+    Admin,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    /// Recorded in logs and events for requests made with this token
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub token: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl ApiToken {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+}
+
+/// Layout of a tokens file.
+#[derive(Debug, Deserialize)]
+struct TokensFile {
+    #[serde(default)]
+    tokens: Vec<ApiToken>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TokenStore {
+    tokens: Vec<ApiToken>,
+    /// SHA-256 of each token's secret, in the same order
+    digests: Vec<[u8; 32]>,
+}
+
+impl TokenStore {
+    /// Check names and secrets are present and unique.
+    pub fn new(tokens: Vec<ApiToken>) -> Result<Self> {
+        let invalid = |message: String| SnowbootError::Config {
+            message,
+            code: ErrorCode::InvalidConfig,
+            source: None,
+        };
+        let mut names = HashSet::new();
+        let mut secrets = HashSet::new();
+        for token in &tokens {
+            if token.name.trim().is_empty() {
+                return Err(invalid("API token with an empty name".to_string()));
+            }
+            if token.token.is_empty() {
+                return Err(invalid(format!("API token '{}' has no secret", token.name)));
+            }
+            if token.scopes.is_empty() {
+                return Err(invalid(format!("API token '{}' has no scopes", token.name)));
+            }
+            if !names.insert(token.name.as_str()) {
+                return Err(invalid(format!("Duplicate API token name '{}'", token.name)));
+            }
+            if !secrets.insert(token.token.as_str()) {
+                return Err(invalid(format!("API token '{}' reuses another token's secret", token.name)));
+            }
+        }
+        let digests = tokens.iter().map(|t| digest(&t.token)).collect();
+        Ok(Self { tokens, digests })
+    }
+
+    /// Load tokens from a TOML file of `[[tokens]]` tables.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let contents = fs::read_to_string(path.as_ref()).map_err(|e| SnowbootError::Config {
+            message: format!("Failed to read tokens file: {}", e),
+            code: ErrorCode::ConfigFileNotFound,
+            source: Some(Box::new(e)),
+        })?;
+        let file: TokensFile = toml::from_str(&contents).map_err(|e| SnowbootError::Config {
+            message: format!("Failed to parse tokens file: {}", e),
+            code: ErrorCode::ConfigParseFailed,
+            source: Some(Box::new(e)),
+        })?;
+        Self::new(file.tokens)
+    }
+
+    /// Add the `--api-token` secret as an admin token named `default`.
+    pub fn with_default_token(mut self, token: String) -> Result<Self> {
+        self.tokens.push(ApiToken {
+            name: "default".to_string(),
+            token,
+            scopes: vec![Scope::Admin],
+        });
+        Self::new(self.tokens)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Find the token with `secret`. Secrets are compared as hashes in
+    /// constant time, and every token is checked, so timing reveals
+    /// neither how much of a guess was right nor which token matched.
+    pub fn authenticate(&self, secret: &str) -> Option<&ApiToken> {
+        let presented = digest(secret);
+        let mut found = None;
+        for (token, expected) in self.tokens.iter().zip(&self.digests) {
+            if bool::from(presented.ct_eq(expected)) {
+                found = Some(token);
+            }
+        }
+        found
+    }
+}
+
+fn digest(secret: &str) -> [u8; 32] {
+    Sha256::digest(secret.as_bytes()).into()
+}
+
+/// When repeated authentication failures lock a client out.
+#[derive(Debug, Clone, Copy)]
+pub struct LockoutPolicy {
+    /// Failed attempts allowed before a lockout; 0 disables lockouts
+    pub max_failures: u32,
+    /// Length of the first lockout, doubled for each one after
+    pub lockout: Duration,
+    /// Longest lockout, and how long failures are remembered
+    pub max_lockout: Duration,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            lockout: Duration::from_secs(60),
+            max_lockout: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// Most clients tracked at once; the one that failed least recently is
+/// forgotten to make room for a new one.
+const MAX_TRACKED_CLIENTS: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    lockouts: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+    /// Key in `Clients::by_recency`
+    seen: u64,
+}
+
+#[derive(Debug, Default)]
+struct Clients {
+    failures: HashMap<IpAddr, Failures>,
+    /// Tracked clients, least recently failed first
+    by_recency: BTreeMap<u64, IpAddr>,
+    next_seen: u64,
+}
+
+/// Tracks failed authentication attempts per client address.
+#[derive(Debug, Default)]
+pub struct AuthLimiter {
+    policy: LockoutPolicy,
+    clients: Mutex<Clients>,
+}
+
+impl AuthLimiter {
+    pub fn new(policy: LockoutPolicy) -> Self {
+        Self {
+            policy,
+            clients: Mutex::new(Clients::default()),
+        }
+    }
+
+    /// Time left on `ip`'s lockout, if it is locked out.
+    pub fn locked_for(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let clients = self.clients.lock().unwrap();
+        let until = clients.failures.get(&ip)?.locked_until?;
+        (until > now).then(|| until - now)
+    }
+
+    /// Record a failed attempt from `ip`. Returns the length of the
+    /// lockout if this attempt started one.
+    pub fn record_failure(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        if self.policy.max_failures == 0 {
+            return None;
+        }
+        let mut clients = self.clients.lock().unwrap();
+        let Clients { failures, by_recency, next_seen } = &mut *clients;
+        let seen = *next_seen;
+        *next_seen += 1;
+        match failures.get(&ip) {
+            Some(known) => {
+                by_recency.remove(&known.seen);
+            }
+            None if failures.len() >= MAX_TRACKED_CLIENTS => {
+                if let Some((_, oldest)) = by_recency.pop_first() {
+                    failures.remove(&oldest);
+                }
+            }
+            None => {}
+        }
+        by_recency.insert(seen, ip);
+
+        let failures = failures.entry(ip).or_insert(Failures {
+            count: 0,
+            lockouts: 0,
+            last: now,
+            locked_until: None,
+            seen,
+        });
+        failures.seen = seen;
+        // Forgive clients that have behaved for a while
+        if now.duration_since(failures.last) >= self.policy.max_lockout {
+            failures.count = 0;
+            failures.lockouts = 0;
+        }
+        failures.count += 1;
+        failures.last = now;
+        if failures.count < self.policy.max_failures {
+            return None;
+        }
+
+        failures.count = 0;
+        failures.lockouts += 1;
+        let factor = 2u32.saturating_pow(failures.lockouts - 1);
+        let lockout = self
+            .policy
+            .lockout
+            .saturating_mul(factor)
+            .min(self.policy.max_lockout);
+        failures.locked_until = Some(now + lockout);
+        Some(lockout)
+    }
+
+    pub fn record_success(&self, ip: IpAddr) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(known) = clients.failures.remove(&ip) {
+            clients.by_recency.remove(&known.seen);
+        }
+    }
+}
+
+/// Scope needed for a route. Reads need `read`; writes need `queue` or
+/// `control` where listed, and `admin` otherwise, so new routes are
+/// admin-only until placed.
+pub fn required_scope(method: &Method, path: &str) -> Scope {
+    if method == Method::GET {
+        // The audit log names tokens and client addresses
+        if path == "/api/audit" {
+            return Scope::Admin;
+        }
+        return Scope::Read;
+    }
+    match (method.as_str(), path) {
+        ("POST", "/api/skip")
+        | ("PUT", "/api/playback/mode")
+        | ("POST", "/api/playback/resume")
+        | ("PATCH", "/api/now-playing/metadata") => Scope::Control,
+        ("DELETE", "/api/queue") => Scope::Admin,
+        ("POST", "/api/playlists/{name}/enqueue") => Scope::Queue,
+        (_, path) if path.starts_with("/api/requests/") => Scope::Queue,
+        (_, path) if path.starts_with("/api/queue") => Scope::Queue,
+        _ => Scope::Admin,
+    }
+}
+
+tokio::task_local! {
+    static ACTOR: String;
+}
+
+/// Run `fut` on behalf of the named token, so events it causes record it.
+pub async fn with_actor<F: std::future::Future>(name: String, fut: F) -> F::Output {
+    ACTOR.scope(name, fut).await
+}
+
+/// Name of the token behind the current request, if any.
+pub fn current_actor() -> Option<String> {
+    ACTOR.try_with(|name| name.clone()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(name: &str, secret: &str, scopes: Vec<Scope>) -> ApiToken {
+        ApiToken {
+            name: name.to_string(),
+            token: secret.to_string(),
+            scopes,
+        }
+    }
+
+    #[test]
+    fn test_admin_implies_all_scopes() {
+        let admin = token("admin", "a", vec![Scope::Admin]);
+        let dj = token("dj", "d", vec![Scope::Read, Scope::Queue]);
+        assert!(admin.allows(Scope::Control));
+        assert!(dj.allows(Scope::Queue));
+        assert!(!dj.allows(Scope::Control));
+        assert!(!dj.allows(Scope::Admin));
+    }
+
+    #[test]
+    fn test_route_scopes() {
+        assert_eq!(required_scope(&Method::GET, "/api/queue"), Scope::Read);
+        assert_eq!(required_scope(&Method::POST, "/api/queue"), Scope::Queue);
+        assert_eq!(required_scope(&Method::PUT, "/api/queue/{id}/position"), Scope::Queue);
+        assert_eq!(required_scope(&Method::DELETE, "/api/queue"), Scope::Admin);
+        assert_eq!(required_scope(&Method::POST, "/api/skip"), Scope::Control);
+        assert_eq!(required_scope(&Method::PUT, "/api/jingles"), Scope::Admin);
+        assert_eq!(required_scope(&Method::POST, "/api/playlists/{name}/enqueue"), Scope::Queue);
+        assert_eq!(required_scope(&Method::GET, "/api/audit"), Scope::Admin);
+        assert_eq!(required_scope(&Method::POST, "/api/requests/{id}/approve"), Scope::Queue);
+    }
+
+    #[test]
+    fn test_rejects_duplicates() {
+        let tokens = vec![token("a", "x", vec![Scope::Read]), token("a", "y", vec![Scope::Read])];
+        assert!(TokenStore::new(tokens).is_err());
+        let tokens = vec![token("a", "x", vec![Scope::Read]), token("b", "x", vec![Scope::Read])];
+        assert!(TokenStore::new(tokens).is_err());
+        assert!(TokenStore::new(vec![token("a", "x", vec![])]).is_err());
+    }
+
+    #[test]
+    fn test_authenticate_ignores_length_and_prefix() {
+        let store = TokenStore::new(vec![token("a", "secret", vec![Scope::Read])]).unwrap();
+        assert!(store.authenticate("secret").is_some());
+        assert!(store.authenticate("secre").is_none());
+        assert!(store.authenticate("secret2").is_none());
+        assert!(store.authenticate("").is_none());
+    }
+
+    #[test]
+    fn test_lockout_after_failures() {
+        let limiter = AuthLimiter::new(LockoutPolicy {
+            max_failures: 3,
+            lockout: Duration::from_secs(10),
+            max_lockout: Duration::from_secs(60),
+        });
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let start = Instant::now();
+
+        assert_eq!(limiter.record_failure(ip, start), None);
+        assert_eq!(limiter.record_failure(ip, start), None);
+        assert_eq!(limiter.record_failure(ip, start), Some(Duration::from_secs(10)));
+        assert_eq!(limiter.locked_for(ip, start + Duration::from_secs(4)), Some(Duration::from_secs(6)));
+        assert_eq!(limiter.locked_for(other, start), None);
+        assert_eq!(limiter.locked_for(ip, start + Duration::from_secs(10)), None);
+
+        // Each further lockout doubles, up to the maximum
+        let later = start + Duration::from_secs(11);
+        for _ in 0..2 {
+            limiter.record_failure(ip, later);
+        }
+        assert_eq!(limiter.record_failure(ip, later), Some(Duration::from_secs(20)));
+        for _ in 0..6 {
+            limiter.record_failure(ip, later);
+        }
+        assert_eq!(limiter.locked_for(ip, later), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_success_and_time_reset_failures() {
+        let limiter = AuthLimiter::new(LockoutPolicy {
+            max_failures: 2,
+            lockout: Duration::from_secs(10),
+            max_lockout: Duration::from_secs(60),
+        });
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        let start = Instant::now();
+
+        limiter.record_failure(ip, start);
+        limiter.record_success(ip);
+        assert_eq!(limiter.record_failure(ip, start), None);
+
+        // An old failure is forgotten
+        assert_eq!(limiter.record_failure(ip, start + Duration::from_secs(61)), None);
+    }
+
+    #[test]
+    fn test_least_recent_client_forgotten() {
+        let limiter = AuthLimiter::new(LockoutPolicy {
+            max_failures: 2,
+            ..LockoutPolicy::default()
+        });
+        let start = Instant::now();
+        let first: IpAddr = "192.0.2.1".parse().unwrap();
+        let second: IpAddr = "192.0.2.2".parse().unwrap();
+        limiter.record_failure(first, start);
+        limiter.record_failure(second, start);
+        // Failing again makes the first client the most recent
+        limiter.record_failure(first, start);
+        assert!(limiter.locked_for(first, start).is_some());
+
+        for i in 0..MAX_TRACKED_CLIENTS as u32 - 1 {
+            limiter.record_failure(IpAddr::from((0x0a00_0000 + i).to_be_bytes()), start);
+        }
+        let clients = limiter.clients.lock().unwrap();
+        assert_eq!(clients.failures.len(), MAX_TRACKED_CLIENTS);
+        assert_eq!(clients.by_recency.len(), MAX_TRACKED_CLIENTS);
+        assert!(clients.failures.contains_key(&first));
+        assert!(!clients.failures.contains_key(&second));
+    }
+
+    #[test]
+    fn test_tokens_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens.toml");
+        fs::write(
+            &path,
+            r#"
+[[tokens]]
+name = "widget"
+token = "w1"
+scopes = ["read"]
+
+[[tokens]]
+name = "breakfast-dj"
+token = "d1"
+scopes = ["read", "queue", "control"]
+"#,
+        )
+        .unwrap();
+
+        let store = TokenStore::from_file(&path).unwrap().with_default_token("root".to_string()).unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.authenticate("d1").unwrap().name, "breakfast-dj");
+        assert!(store.authenticate("root").unwrap().allows(Scope::Admin));
+        assert!(store.authenticate("nope").is_none());
+    }
+}
@@ -33,6 +33,9 @@ pub enum ErrorCode {
     FileNotFound = 3010,
     FileReadFailed = 3011,
     InvalidFileFormat = 3012,
+    ArtworkNotFound = 3015,
+    StateDirUnavailable = 3020,
+    StateFileUnreadable = 3021,
 
     /// Protocol errors (4000-4999)
     HttpParseFailed = 4000,
@@ -47,6 +50,33 @@ pub enum ErrorCode {
     /// Queue errors (6000-6999)
     TrackNotFound = 6001,
     InvalidPosition = 6002,
+    QueueRevisionMismatch = 6003,
+
+    /// API request errors (7000-7999)
+    InvalidRequest = 7000,
+    InvalidTimestamp = 7001,
+    UnknownMethod = 7002,
+    InsufficientScope = 7003,
+
+    /// Playlist and programming errors (8000-8999)
+    PlaylistNotFound = 8001,
+    PlaylistExists = 8002,
+    InvalidPlaylistName = 8003,
+    NoPlayableTracks = 8004,
+    ScheduleEntryNotFound = 8101,
+    InvalidScheduleEntry = 8102,
+    TimedInsertNotFound = 8103,
+    InvalidTimedInsert = 8104,
+    ListenerRequestsDisabled = 8200,
+    ListenerRequestNotFound = 8201,
+    RequestCooldown = 8202,
+    TooManyPendingRequests = 8203,
+    AlreadyRequested = 8204,
+    RecentlyPlayed = 8205,
+    LibraryUnavailable = 8300,
+    LibraryTrackNotFound = 8301,
+    QuarantinedTrackNotFound = 8400,
+    TrackQuarantined = 8401,
 
     /// Generic error
     Unknown = 9999,
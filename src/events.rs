@@ -0,0 +1,261 @@
+// Numbered player events with a replay buffer for resuming event streams
+
+use std::collections::VecDeque;
+
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::history::unix_now;
+use crate::player::{PlayerEvent, PlayerHandle};
+
+/// Number of recent events kept for clients that reconnect.
+pub const REPLAY_BUFFER_SIZE: usize = 256;
+
+/// Low bits of an event ID that count events within one run. The high bits
+/// hold the time the process started, so IDs keep increasing across
+/// restarts and an ID from an earlier run is never mistaken for one of ours.
+const SEQUENCE_BITS: u32 = 20;
+
+/// Capacity of the live channel; slower subscribers lag and catch up from
+/// the replay buffer.
+const CHANNEL_CAPACITY: usize = 64;
+
+/// A player event with its position in the event sequence.
+#[derive(Debug, Clone, Serialize)]
+pub struct SequencedEvent {
+    pub id: u64,
+    #[serde(flatten)]
+    pub event: PlayerEvent,
+    /// Name of the API token whose request caused the event
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+}
+
+/// Assigns increasing IDs to events, keeps the most recent ones and
+/// broadcasts them to live subscribers.
+#[derive(Debug)]
+pub struct EventLog {
+    /// ID of the first event of this run
+    first_id: u64,
+    next_id: u64,
+    buffer: VecDeque<SequencedEvent>,
+    capacity: usize,
+    tx: broadcast::Sender<SequencedEvent>,
+}
+
+impl EventLog {
+    /// A log whose IDs start from the time the process started.
+    pub fn new(capacity: usize) -> Self {
+        Self::starting_after(capacity, unix_now() << SEQUENCE_BITS)
+    }
+
+    /// A log whose first event gets ID `last_id + 1`.
+    pub fn starting_after(capacity: usize, last_id: u64) -> Self {
+        let (tx, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            first_id: last_id + 1,
+            next_id: last_id + 1,
+            buffer: VecDeque::with_capacity(capacity),
+            capacity,
+            tx,
+        }
+    }
+
+    /// ID of the latest event, or the one before the first before any.
+    pub fn last_id(&self) -> u64 {
+        self.next_id - 1
+    }
+
+    pub fn publish(&mut self, event: PlayerEvent, actor: Option<String>) -> u64 {
+        let event = SequencedEvent {
+            id: self.next_id,
+            event,
+            actor,
+        };
+        self.next_id += 1;
+        if self.buffer.len() == self.capacity {
+            self.buffer.pop_front();
+        }
+        self.buffer.push_back(event.clone());
+        let _ = self.tx.send(event);
+        self.last_id()
+    }
+
+    /// Buffered events after `last_id`, or `None` if some of them have
+    /// already been dropped or `last_id` was never issued by this run (for
+    /// example before a restart).
+    pub fn since(&self, last_id: u64) -> Option<Vec<SequencedEvent>> {
+        if last_id + 1 < self.first_id || last_id > self.last_id() {
+            return None;
+        }
+        let oldest = self.buffer.front().map_or(self.next_id, |e| e.id);
+        if last_id + 1 < oldest {
+            return None;
+        }
+        Some(self.buffer.iter().filter(|e| e.id > last_id).cloned().collect())
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<SequencedEvent> {
+        self.tx.subscribe()
+    }
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        Self::new(REPLAY_BUFFER_SIZE)
+    }
+}
+
+/// What a subscriber receives next.
+#[derive(Debug, Clone)]
+pub enum Delivery {
+    Event(Box<SequencedEvent>),
+    /// Events were lost; the client should refetch its state. Events
+    /// continue after `id`.
+    Resync { id: u64, reason: &'static str },
+}
+
+/// One subscriber's position in the event sequence. Events missed
+/// because the subscriber lagged are replayed from the buffer where
+/// possible.
+pub struct EventCursor {
+    player: PlayerHandle,
+    rx: broadcast::Receiver<SequencedEvent>,
+    pending: VecDeque<Delivery>,
+    /// Events up to this ID have been delivered or are pending
+    last_id: u64,
+}
+
+impl EventCursor {
+    /// Deliver events after `last_id`, or only new events if `None`.
+    pub fn new(player: &PlayerHandle, last_id: Option<u64>) -> Self {
+        let (replay, rx) = match last_id {
+            Some(id) => player.subscribe_since(id),
+            None => (Some(Vec::new()), player.subscribe()),
+        };
+        let mut cursor = Self {
+            player: player.clone(),
+            rx,
+            pending: VecDeque::new(),
+            last_id: last_id.unwrap_or(0),
+        };
+        match replay {
+            Some(events) => cursor.push(events),
+            None => cursor.resync("expired"),
+        }
+        cursor
+    }
+
+    fn push(&mut self, events: Vec<SequencedEvent>) {
+        for event in events {
+            self.last_id = event.id;
+            self.pending.push_back(Delivery::Event(Box::new(event)));
+        }
+    }
+
+    /// Skip to the present, telling the subscriber events were lost.
+    fn resync(&mut self, reason: &'static str) {
+        self.last_id = self.player.last_event_id();
+        self.pending.push_back(Delivery::Resync {
+            id: self.last_id,
+            reason,
+        });
+    }
+
+    /// Wait for the next delivery. Returns `None` if the channel closes.
+    /// Safe to cancel.
+    pub async fn next(&mut self) -> Option<Delivery> {
+        loop {
+            if let Some(delivery) = self.pending.pop_front() {
+                return Some(delivery);
+            }
+            match self.rx.recv().await {
+                Ok(event) if event.id <= self.last_id => continue,
+                Ok(event) => self.push(vec![event]),
+                Err(RecvError::Lagged(_)) => match self.player.events_since(self.last_id) {
+                    Some(missed) => self.push(missed),
+                    None => self.resync("lagged"),
+                },
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ids_increase() {
+        let mut log = EventLog::starting_after(REPLAY_BUFFER_SIZE, 0);
+        assert_eq!(log.last_id(), 0);
+        assert_eq!(log.publish(PlayerEvent::PlaybackStopped, None), 1);
+        assert_eq!(log.publish(PlayerEvent::PlaybackResumed, None), 2);
+        assert_eq!(log.last_id(), 2);
+    }
+
+    #[test]
+    fn test_since_replays_missed_events() {
+        let mut log = EventLog::starting_after(REPLAY_BUFFER_SIZE, 0);
+        for _ in 0..3 {
+            log.publish(PlayerEvent::PlaybackStopped, None);
+        }
+        let ids: Vec<u64> = log.since(1).unwrap().iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert!(log.since(3).unwrap().is_empty());
+        assert_eq!(log.since(0).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_since_detects_gaps() {
+        let mut log = EventLog::starting_after(2, 0);
+        for _ in 0..4 {
+            log.publish(PlayerEvent::PlaybackStopped, None);
+        }
+        // Events 1 and 2 have been dropped
+        assert!(log.since(1).is_none());
+        assert_eq!(log.since(2).unwrap().len(), 2);
+        // An ID from before a restart
+        assert!(log.since(10).is_none());
+    }
+
+    #[test]
+    fn test_empty_log() {
+        let log = EventLog::starting_after(REPLAY_BUFFER_SIZE, 0);
+        assert!(log.since(0).unwrap().is_empty());
+        assert!(log.since(1).is_none());
+    }
+
+    #[test]
+    fn test_serializes_with_id() {
+        let mut log = EventLog::starting_after(REPLAY_BUFFER_SIZE, 0);
+        let mut rx = log.subscribe();
+        log.publish(PlayerEvent::PlaybackStopped, None);
+        let value = serde_json::to_value(rx.try_recv().unwrap()).unwrap();
+        assert_eq!(value["id"], 1);
+        assert_eq!(value["event"], "playback_stopped");
+    }
+
+    #[test]
+    fn test_ids_from_an_earlier_run() {
+        let mut before = EventLog::starting_after(REPLAY_BUFFER_SIZE, 1000 << SEQUENCE_BITS);
+        for _ in 0..40 {
+            before.publish(PlayerEvent::PlaybackStopped, None);
+        }
+        let seen = before.last_id();
+
+        // Restarted a second later, with more events since than the client saw
+        let mut after = EventLog::starting_after(REPLAY_BUFFER_SIZE, 1001 << SEQUENCE_BITS);
+        for _ in 0..120 {
+            after.publish(PlayerEvent::PlaybackStopped, None);
+        }
+        assert!(after.last_id() > seen);
+        assert!(after.since(seen).is_none());
+        assert_eq!(after.since(1001 << SEQUENCE_BITS).unwrap().len(), 120);
+
+        // The real clock gives each run its own range
+        let log = EventLog::default();
+        assert!(log.last_id() >= unix_now().saturating_sub(1) << SEQUENCE_BITS);
+    }
+}
@@ -0,0 +1,492 @@
+// Playback history with optional on-disk persistence and querying
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::queue::{Track, TrackSource};
+use crate::store;
+
+/// Number of entries kept when history is not persisted.
+const IN_MEMORY_LIMIT: usize = 1000;
+
+/// Number of expired entries tolerated in the log file before it is compacted.
+const COMPACT_THRESHOLD: usize = 1000;
+
+pub const DEFAULT_QUERY_LIMIT: usize = 1000;
+pub const MAX_QUERY_LIMIT: usize = 10000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    #[serde(default)]
+    pub id: u64,
+    pub track: Track,
+    pub started_at: u64,
+    pub duration_secs: u64,
+    pub skipped: bool,
+    /// Peak Icecast listener count observed while the track played.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listeners: Option<u64>,
+    /// Why the track could not be played, if it failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<TrackError>,
+}
+
+/// Why a track failed to play.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrackError {
+    pub code: u32,
+    pub message: String,
+}
+
+impl HistoryEntry {
+    pub fn new(track: Track, started_at: u64, duration_secs: u64, skipped: bool) -> Self {
+        Self {
+            id: 0,
+            track,
+            started_at,
+            duration_secs,
+            skipped,
+            listeners: None,
+            error: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Filters applied to a history query. Timestamps are Unix seconds.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryFilter {
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub artist: Option<String>,
+    pub skipped: Option<bool>,
+    pub failed: Option<bool>,
+    pub source: Option<TrackSource>,
+}
+
+impl HistoryFilter {
+    pub fn matches(&self, entry: &HistoryEntry) -> bool {
+        if self.since.is_some_and(|since| entry.started_at < since) {
+            return false;
+        }
+        if self.until.is_some_and(|until| entry.started_at >= until) {
+            return false;
+        }
+        if self.skipped.is_some_and(|skipped| entry.skipped != skipped) {
+            return false;
+        }
+        if self.failed.is_some_and(|failed| entry.error.is_some() != failed) {
+            return false;
+        }
+        if self.source.is_some_and(|source| entry.track.source != source) {
+            return false;
+        }
+        if let Some(ref artist) = self.artist {
+            let wanted = artist.to_lowercase();
+            match entry.track.artist {
+                Some(ref a) if a.to_lowercase().contains(&wanted) => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// One page of history query results.
+#[derive(Debug, Clone)]
+pub struct HistoryPage {
+    pub entries: Vec<HistoryEntry>,
+    /// Cursor for the following page, if there are more results.
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackPlays {
+    pub path: PathBuf,
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    pub plays: u64,
+    pub skips: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ArtistPlays {
+    pub artist: String,
+    pub plays: u64,
+    pub skips: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryStats {
+    pub total_plays: u64,
+    pub tracks: Vec<TrackPlays>,
+    pub artists: Vec<ArtistPlays>,
+}
+
+#[derive(Debug)]
+pub struct History {
+    entries: Vec<HistoryEntry>,
+    next_id: u64,
+    /// Appends to and compacts the log off the async threads, if there is one
+    appender: Option<store::LineAppender<HistoryEntry>>,
+    retention_secs: Option<u64>,
+    expired_in_log: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            next_id: 1,
+            appender: None,
+            retention_secs: None,
+            expired_in_log: 0,
+        }
+    }
+}
+
+impl History {
+    /// Create a history backed by an append-only JSON lines file.
+    ///
+    /// Entries older than `retention_secs` are dropped on load and as new
+    /// entries are recorded; `None` keeps everything.
+    pub fn with_log_file(path: PathBuf, retention_secs: Option<u64>) -> Self {
+        let mut entries: Vec<HistoryEntry> = store::read_json_lines(&path).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable history log {}: {}", path.display(), e);
+            Vec::new()
+        });
+        // Pruning expects start order, which a clock change can upset
+        entries.sort_by_key(|e| e.started_at);
+        let mut history = Self {
+            entries,
+            next_id: 1,
+            appender: Some(store::LineAppender::spawn(path, "history log")),
+            retention_secs,
+            expired_in_log: 0,
+        };
+
+        // Entries written before IDs existed get fresh ones here
+        let mut id = 0;
+        for entry in history.entries.iter_mut() {
+            id = entry.id.max(id + 1);
+            entry.id = id;
+        }
+        history.next_id = id + 1;
+
+        history.prune(unix_now());
+        history.compact();
+        if !history.entries.is_empty() {
+            info!("Loaded {} history entries", history.entries.len());
+        }
+        history
+    }
+
+    /// Record a finished play, assigning it the next history ID.
+    pub fn record(&mut self, mut entry: HistoryEntry) -> HistoryEntry {
+        entry.id = self.next_id;
+        self.next_id += 1;
+        self.entries.push(entry.clone());
+
+        match self.appender {
+            Some(ref appender) => {
+                appender.append(entry.clone());
+                self.prune(unix_now());
+                if self.expired_in_log >= COMPACT_THRESHOLD {
+                    self.compact();
+                }
+            }
+            None => {
+                let len = self.entries.len();
+                if len > IN_MEMORY_LIMIT {
+                    self.entries.drain(..len - IN_MEMORY_LIMIT);
+                }
+            }
+        }
+
+        entry
+    }
+
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    /// When the file at `path` last started playing, if it is in the history.
+    pub fn last_played(&self, path: &Path) -> Option<u64> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|e| e.track.path == path && e.error.is_none())
+            .map(|e| e.started_at)
+    }
+
+    /// Return entries matching `filter`, ordered by play time.
+    ///
+    /// `cursor` is the ID of the last entry of the previous page.
+    pub fn query(
+        &self,
+        filter: &HistoryFilter,
+        order: SortOrder,
+        cursor: Option<u64>,
+        limit: usize,
+    ) -> HistoryPage {
+        let limit = limit.clamp(1, MAX_QUERY_LIMIT);
+        let matching = |e: &&HistoryEntry| filter.matches(e);
+
+        let mut entries: Vec<HistoryEntry> = match order {
+            SortOrder::Asc => self
+                .entries
+                .iter()
+                .filter(|e| cursor.is_none_or(|c| e.id > c))
+                .filter(matching)
+                .take(limit + 1)
+                .cloned()
+                .collect(),
+            SortOrder::Desc => self
+                .entries
+                .iter()
+                .rev()
+                .filter(|e| cursor.is_none_or(|c| e.id < c))
+                .filter(matching)
+                .take(limit + 1)
+                .cloned()
+                .collect(),
+        };
+
+        let next_cursor = if entries.len() > limit {
+            entries.truncate(limit);
+            entries.last().map(|e| e.id)
+        } else {
+            None
+        };
+
+        HistoryPage { entries, next_cursor }
+    }
+
+    /// Plays per track and per artist for entries matching `filter`, most
+    /// played first.
+    pub fn stats(&self, filter: &HistoryFilter) -> HistoryStats {
+        let mut tracks: HashMap<PathBuf, TrackPlays> = HashMap::new();
+        let mut artists: HashMap<String, ArtistPlays> = HashMap::new();
+        let mut total_plays = 0;
+
+        // Failed tracks were never heard, so don't count as plays
+        for entry in self.entries.iter().filter(|e| e.error.is_none() && filter.matches(e)) {
+            total_plays += 1;
+            let skip = entry.skipped as u64;
+
+            let t = tracks
+                .entry(entry.track.path.clone())
+                .or_insert_with(|| TrackPlays {
+                    path: entry.track.path.clone(),
+                    title: entry.track.title.clone(),
+                    artist: entry.track.artist.clone(),
+                    plays: 0,
+                    skips: 0,
+                });
+            t.plays += 1;
+            t.skips += skip;
+
+            if let Some(ref artist) = entry.track.artist {
+                let a = artists.entry(artist.clone()).or_insert_with(|| ArtistPlays {
+                    artist: artist.clone(),
+                    plays: 0,
+                    skips: 0,
+                });
+                a.plays += 1;
+                a.skips += skip;
+            }
+        }
+
+        let mut tracks: Vec<TrackPlays> = tracks.into_values().collect();
+        tracks.sort_by(|a, b| b.plays.cmp(&a.plays).then_with(|| a.title.cmp(&b.title)));
+        let mut artists: Vec<ArtistPlays> = artists.into_values().collect();
+        artists.sort_by(|a, b| b.plays.cmp(&a.plays).then_with(|| a.artist.cmp(&b.artist)));
+
+        HistoryStats {
+            total_plays,
+            tracks,
+            artists,
+        }
+    }
+
+    /// Drop entries that have fallen outside the retention window.
+    fn prune(&mut self, now: u64) {
+        let Some(retention) = self.retention_secs else {
+            return;
+        };
+        let cutoff = now.saturating_sub(retention);
+        let expired = self.entries.partition_point(|e| e.started_at < cutoff);
+        if expired > 0 {
+            self.entries.drain(..expired);
+            self.expired_in_log += expired;
+        }
+    }
+
+    /// Rewrite the log file with only the retained entries. The rewrite
+    /// happens in the background, in order with later appends.
+    fn compact(&mut self) {
+        if let Some(ref appender) = self.appender {
+            appender.rewrite(self.entries.clone());
+        }
+        self.expired_in_log = 0;
+    }
+
+    /// Wait until every recorded entry is in the log file.
+    pub fn flush(&self) {
+        if let Some(ref appender) = self.appender {
+            appender.flush();
+        }
+    }
+}
+
+pub type SharedHistory = Arc<std::sync::RwLock<History>>;
+
+/// Parse a timestamp given either as Unix seconds or as RFC 3339.
+pub fn parse_timestamp(value: &str) -> Option<u64> {
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(secs);
+    }
+    chrono::DateTime::parse_from_rfc3339(value)
+        .ok()
+        .and_then(|dt| u64::try_from(dt.timestamp()).ok())
+}
+
+/// The current time as Unix seconds.
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn track(title: &str, artist: Option<&str>) -> Track {
+        Track {
+            id: 0,
+            path: PathBuf::from(format!("/music/{}.ogg", title)),
+            title: title.to_string(),
+            artist: artist.map(str::to_string),
+            source: Default::default(),
+            requested_by: None,
+            tags: Default::default(),
+            overrides: Default::default(),
+        }
+    }
+
+    fn sample_history() -> History {
+        let mut history = History::default();
+        history.record(HistoryEntry::new(track("one", Some("Alpha")), 100, 10, false));
+        history.record(HistoryEntry::new(track("two", Some("Beta")), 200, 10, true));
+        history.record(HistoryEntry::new(track("one", Some("Alpha")), 300, 10, false));
+        history.record(HistoryEntry::new(track("three", None), 400, 10, false));
+        history
+    }
+
+    #[test]
+    fn test_query_filters() {
+        let history = sample_history();
+
+        let filter = HistoryFilter { since: Some(150), until: Some(400), ..Default::default() };
+        let page = history.query(&filter, SortOrder::Asc, None, 100);
+        let times: Vec<u64> = page.entries.iter().map(|e| e.started_at).collect();
+        assert_eq!(times, vec![200, 300]);
+
+        let filter = HistoryFilter { artist: Some("alp".to_string()), ..Default::default() };
+        assert_eq!(history.query(&filter, SortOrder::Asc, None, 100).entries.len(), 2);
+
+        let filter = HistoryFilter { skipped: Some(true), ..Default::default() };
+        assert_eq!(history.query(&filter, SortOrder::Asc, None, 100).entries.len(), 1);
+    }
+
+    #[test]
+    fn test_query_pagination() {
+        let history = sample_history();
+        let filter = HistoryFilter::default();
+
+        let first = history.query(&filter, SortOrder::Desc, None, 3);
+        assert_eq!(first.entries.len(), 3);
+        assert_eq!(first.entries[0].started_at, 400);
+
+        let second = history.query(&filter, SortOrder::Desc, first.next_cursor, 3);
+        assert_eq!(second.entries.len(), 1);
+        assert_eq!(second.entries[0].started_at, 100);
+        assert!(second.next_cursor.is_none());
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = sample_history().stats(&HistoryFilter::default());
+        assert_eq!(stats.total_plays, 4);
+        assert_eq!(stats.tracks[0].title, "one");
+        assert_eq!(stats.tracks[0].plays, 2);
+        assert_eq!(stats.artists[0].artist, "Alpha");
+        assert_eq!(stats.artists[1].skips, 1);
+    }
+
+    #[test]
+    fn test_failed_entries() {
+        let mut history = sample_history();
+        let mut failed = HistoryEntry::new(track("three", None), 500, 0, false);
+        failed.error = Some(TrackError { code: 3010, message: "missing".to_string() });
+        history.record(failed);
+
+        let filter = HistoryFilter { failed: Some(true), ..Default::default() };
+        let page = history.query(&filter, SortOrder::Asc, None, 100);
+        assert_eq!(page.entries.len(), 1);
+        assert_eq!(page.entries[0].error.as_ref().unwrap().code, 3010);
+
+        // Not a play
+        assert_eq!(history.stats(&HistoryFilter::default()).total_plays, 4);
+        assert_eq!(history.last_played(Path::new("/music/three.ogg")), Some(400));
+    }
+
+    #[test]
+    fn test_persistence_and_retention() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        let now = unix_now();
+
+        {
+            let mut history = History::with_log_file(path.clone(), None);
+            history.record(HistoryEntry::new(track("old", None), now - 10 * 86400, 10, false));
+            history.record(HistoryEntry::new(track("new", None), now - 60, 10, false));
+            // Out of start order, as after the clock is set back
+            history.record(HistoryEntry::new(track("older", None), now - 20 * 86400, 10, false));
+        }
+
+        let history = History::with_log_file(path.clone(), None);
+        assert_eq!(history.entries().len(), 3);
+
+        let history = History::with_log_file(path.clone(), Some(86400));
+        assert_eq!(history.entries().len(), 1);
+        assert_eq!(history.entries()[0].track.title, "new");
+        history.flush();
+
+        // Compaction removed the expired entry from disk too
+        let history = History::with_log_file(path, None);
+        assert_eq!(history.entries().len(), 1);
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("1700000000"), Some(1_700_000_000));
+        assert_eq!(parse_timestamp("2023-11-14T22:13:20Z"), Some(1_700_000_000));
+        assert_eq!(parse_timestamp("last tuesday"), None);
+    }
+}
@@ -179,6 +179,62 @@ impl IcecastClient {
         Ok(())
     }
 
+    /// Fetch the listener count for our mount from the server's public
+    /// status page (`/status-json.xsl`).
+    ///
+    /// Returns `Ok(None)` if the server does not list the mount.
+    pub async fn fetch_listeners(&self) -> Result<Option<u64>> {
+        let addr = format!("{}:{}", self.config.host, self.config.port);
+        let mut stream = TcpStream::connect(&addr).await
+            .map_err(|e| SnowbootError::connection_failed(&self.config.host, self.config.port, e))?;
+
+        let request = format!(
+            "GET /status-json.xsl HTTP/1.0\r\n\
+             Host: {}:{}\r\n\
+             User-Agent: Snowboot/0.1.0\r\n\
+             \r\n",
+            self.config.host,
+            self.config.port
+        );
+        stream.write_all(request.as_bytes()).await
+            .map_err(|e| SnowbootError::Connection {
+                message: "Failed to send status request".to_string(),
+                code: ErrorCode::ConnectionFailed,
+                source: Some(e),
+            })?;
+
+        let mut response = Vec::new();
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            stream.read_to_end(&mut response)
+        ).await
+            .map_err(|_| SnowbootError::Connection {
+                message: "Timeout reading status response".to_string(),
+                code: ErrorCode::ConnectionTimeout,
+                source: None,
+            })?
+            .map_err(|e| SnowbootError::Connection {
+                message: "Failed to read status response".to_string(),
+                code: ErrorCode::ConnectionFailed,
+                source: Some(e),
+            })?;
+
+        let header_end = response.windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(|| SnowbootError::http_parse_failed("Incomplete status response".to_string()))?;
+        let headers = String::from_utf8_lossy(&response[..header_end + 4]);
+
+        let status_code = self.parse_http_status(&headers)?;
+        if status_code != 200 {
+            return Err(SnowbootError::unexpected_response(&headers));
+        }
+
+        let stats: serde_json::Value = serde_json::from_slice(&response[header_end + 4..])
+            .map_err(|e| SnowbootError::http_parse_failed(format!("Invalid status JSON: {}", e)))?;
+
+        Ok(listeners_for_mount(&stats, &self.config.mount))
+    }
+
     /// Check if the client is still running
     pub fn is_running(&self) -> bool {
         self.running.load(Ordering::SeqCst)
@@ -253,3 +309,43 @@ impl IcecastClient {
         }
     }
 }
+
+/// Find the listener count for `mount` in Icecast's status JSON.
+///
+/// `icestats.source` is an object when one mount is active and an array
+/// when there are several.
+fn listeners_for_mount(stats: &serde_json::Value, mount: &str) -> Option<u64> {
+    let sources = &stats["icestats"]["source"];
+    let sources: Vec<&serde_json::Value> = match sources {
+        serde_json::Value::Array(list) => list.iter().collect(),
+        serde_json::Value::Object(_) => vec![sources],
+        _ => Vec::new(),
+    };
+
+    sources
+        .into_iter()
+        .find(|s| s["listenurl"].as_str().is_some_and(|url| url.ends_with(mount)))
+        .and_then(|s| s["listeners"].as_u64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listeners_for_mount() {
+        let single = serde_json::json!({
+            "icestats": { "source": { "listenurl": "http://host:8000/stream.ogg", "listeners": 12 } }
+        });
+        assert_eq!(listeners_for_mount(&single, "/stream.ogg"), Some(12));
+
+        let multiple = serde_json::json!({
+            "icestats": { "source": [
+                { "listenurl": "http://host:8000/other.ogg", "listeners": 3 },
+                { "listenurl": "http://host:8000/stream.ogg", "listeners": 7 }
+            ] }
+        });
+        assert_eq!(listeners_for_mount(&multiple, "/stream.ogg"), Some(7));
+        assert_eq!(listeners_for_mount(&multiple, "/missing.ogg"), None);
+    }
+}
@@ -0,0 +1,242 @@
+// Station ID and jingle insertion rules
+
+use std::path::{Path, PathBuf};
+
+use rand::seq::IndexedRandom;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::queue::{Track, TrackSource};
+
+/// When to insert a jingle between queued tracks. A jingle is due when
+/// either threshold is reached; zero disables that threshold.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JingleRules {
+    /// Directory of Ogg Vorbis jingles to choose from at random
+    pub directory: Option<PathBuf>,
+    /// Insert a jingle after this many tracks
+    #[serde(default)]
+    pub every_tracks: u32,
+    /// Insert a jingle once this many minutes have passed since the last one
+    #[serde(default)]
+    pub every_minutes: u64,
+}
+
+impl JingleRules {
+    pub fn is_enabled(&self) -> bool {
+        self.directory.is_some() && (self.every_tracks > 0 || self.every_minutes > 0)
+    }
+}
+
+/// Tracks rule state between queue pops.
+#[derive(Debug)]
+pub struct JingleEngine {
+    rules: JingleRules,
+    tracks_since: u32,
+    last_played_at: u64,
+    last_path: Option<PathBuf>,
+}
+
+impl JingleEngine {
+    /// Create an engine whose minute interval counts from `now`.
+    pub fn new(rules: JingleRules, now: u64) -> Self {
+        Self {
+            rules,
+            tracks_since: 0,
+            last_played_at: now,
+            last_path: None,
+        }
+    }
+
+    pub fn rules(&self) -> &JingleRules {
+        &self.rules
+    }
+
+    pub fn set_rules(&mut self, rules: JingleRules) {
+        self.rules = rules;
+    }
+
+    /// Count a queued track towards the every-N-tracks rule.
+    pub fn track_played(&mut self) {
+        self.tracks_since = self.tracks_since.saturating_add(1);
+    }
+
+    pub fn is_due(&self, now: u64) -> bool {
+        if !self.rules.is_enabled() {
+            return false;
+        }
+        let by_tracks = self.rules.every_tracks > 0 && self.tracks_since >= self.rules.every_tracks;
+        let by_time = self.rules.every_minutes > 0
+            && now.saturating_sub(self.last_played_at) >= self.rules.every_minutes * 60;
+        by_tracks || by_time
+    }
+
+    /// Pick the next jingle if one is due, never repeating the previous one
+    /// while there is another to choose from. Files for which `excluded`
+    /// returns true are passed over.
+    pub fn next_jingle(&mut self, now: u64, excluded: impl Fn(&Path) -> bool) -> Option<Track> {
+        if !self.is_due(now) {
+            return None;
+        }
+        let directory = self.rules.directory.as_deref()?;
+        let mut candidates = jingle_files(directory);
+        candidates.retain(|p| !excluded(p));
+        let fresh: Vec<&PathBuf> = candidates
+            .iter()
+            .filter(|p| Some(*p) != self.last_path.as_ref())
+            .collect();
+        let path = match fresh.choose(&mut rand::rng()) {
+            Some(p) => (*p).clone(),
+            None => candidates.first()?.clone(),
+        };
+
+        self.tracks_since = 0;
+        self.last_played_at = now;
+        self.last_path = Some(path.clone());
+
+        Some(Track {
+            source: TrackSource::Jingle,
+            ..Track::from_file(path)
+        })
+    }
+}
+
+/// Ogg files directly inside `dir`, sorted by path.
+fn jingle_files(dir: &Path) -> Vec<PathBuf> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("Cannot read jingle directory {}: {}", dir.display(), e);
+            return Vec::new();
+        }
+    };
+
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            p.is_file()
+                && p.extension()
+                    .and_then(|e| e.to_str())
+                    .is_some_and(|e| e.eq_ignore_ascii_case("ogg"))
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn jingle_dir(names: &[&str]) -> tempfile::TempDir {
+        let dir = tempdir().unwrap();
+        for name in names {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_every_n_tracks() {
+        let dir = jingle_dir(&["id.ogg"]);
+        let rules = JingleRules {
+            directory: Some(dir.path().to_path_buf()),
+            every_tracks: 2,
+            every_minutes: 0,
+        };
+        let mut engine = JingleEngine::new(rules, 0);
+
+        assert!(engine.next_jingle(0, |_| false).is_none());
+        engine.track_played();
+        assert!(engine.next_jingle(0, |_| false).is_none());
+        engine.track_played();
+        let jingle = engine.next_jingle(0, |_| false).unwrap();
+        assert_eq!(jingle.source, TrackSource::Jingle);
+
+        // The counter resets, so jingles never play back to back
+        assert!(engine.next_jingle(0, |_| false).is_none());
+    }
+
+    #[test]
+    fn test_every_m_minutes() {
+        let dir = jingle_dir(&["id.ogg"]);
+        let rules = JingleRules {
+            directory: Some(dir.path().to_path_buf()),
+            every_tracks: 0,
+            every_minutes: 15,
+        };
+        let mut engine = JingleEngine::new(rules, 1000);
+
+        assert!(!engine.is_due(1000 + 14 * 60));
+        assert!(engine.next_jingle(1000 + 15 * 60, |_| false).is_some());
+        assert!(!engine.is_due(1000 + 16 * 60));
+    }
+
+    #[test]
+    fn test_never_same_jingle_twice() {
+        let dir = jingle_dir(&["a.ogg", "b.ogg", "c.ogg", "notes.txt"]);
+        let rules = JingleRules {
+            directory: Some(dir.path().to_path_buf()),
+            every_tracks: 1,
+            every_minutes: 0,
+        };
+        let mut engine = JingleEngine::new(rules, 0);
+
+        let mut previous: Option<PathBuf> = None;
+        for _ in 0..50 {
+            engine.track_played();
+            let jingle = engine.next_jingle(0, |_| false).unwrap();
+            assert_ne!(Some(&jingle.path), previous.as_ref());
+            assert_eq!(jingle.path.extension().unwrap(), "ogg");
+            previous = Some(jingle.path);
+        }
+    }
+
+    #[test]
+    fn test_single_jingle_repeats() {
+        let dir = jingle_dir(&["only.ogg"]);
+        let rules = JingleRules {
+            directory: Some(dir.path().to_path_buf()),
+            every_tracks: 1,
+            every_minutes: 0,
+        };
+        let mut engine = JingleEngine::new(rules, 0);
+        for _ in 0..2 {
+            engine.track_played();
+            assert!(engine.next_jingle(0, |_| false).is_some());
+        }
+    }
+
+    #[test]
+    fn test_excluded_jingles_passed_over() {
+        let dir = jingle_dir(&["a.ogg", "b.ogg"]);
+        let rules = JingleRules {
+            directory: Some(dir.path().to_path_buf()),
+            every_tracks: 1,
+            every_minutes: 0,
+        };
+        let mut engine = JingleEngine::new(rules, 0);
+        let bad = dir.path().join("a.ogg");
+        for _ in 0..5 {
+            engine.track_played();
+            let jingle = engine.next_jingle(0, |p| p == bad).unwrap();
+            assert_eq!(jingle.path, dir.path().join("b.ogg"));
+        }
+        engine.track_played();
+        assert!(engine.next_jingle(0, |_| true).is_none());
+    }
+
+    #[test]
+    fn test_disabled_without_directory() {
+        let rules = JingleRules {
+            directory: None,
+            every_tracks: 1,
+            every_minutes: 0,
+        };
+        let mut engine = JingleEngine::new(rules, 0);
+        engine.track_played();
+        assert!(engine.next_jingle(0, |_| false).is_none());
+    }
+}
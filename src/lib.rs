@@ -1,9 +1,26 @@
 pub mod api;
+pub mod artwork;
+pub mod audit;
+pub mod auth;
 pub mod config;
 pub mod connection;
 pub mod errors;
+pub mod events;
+pub mod history;
 pub mod icecast;
+pub mod jingle;
+pub mod library;
 pub mod metrics;
 pub mod player;
+pub mod playlist;
+pub mod playlist_format;
+pub mod quarantine;
 pub mod queue;
+pub mod report;
+pub mod requests;
+pub mod scheduler;
+pub mod shuffle;
+pub mod store;
 pub mod validation;
+pub mod watcher;
+pub mod ws;
@@ -0,0 +1,603 @@
+// Index of the tracks in the media directory, with search
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::metrics;
+use crate::queue::{read_duration, read_vorbis_comments};
+use crate::store;
+
+pub const DEFAULT_QUERY_LIMIT: usize = 50;
+pub const MAX_QUERY_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LibraryTrack {
+    pub id: u64,
+    pub path: PathBuf,
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub genre: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<f64>,
+    /// File modification time (Unix seconds) and size when last read,
+    /// so unchanged files are skipped on rescan
+    pub modified: u64,
+    pub size: u64,
+}
+
+impl LibraryTrack {
+    /// Read a track's tags and duration.
+    fn read(id: u64, path: PathBuf, modified: u64, size: u64) -> Self {
+        let mut comments = read_vorbis_comments(&path).unwrap_or_default();
+        let title = comments.remove("TITLE").unwrap_or_else(|| {
+            path.file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or("Unknown")
+                .to_string()
+        });
+        Self {
+            id,
+            title,
+            artist: comments.remove("ARTIST"),
+            album: comments.remove("ALBUM"),
+            genre: comments.remove("GENRE"),
+            duration_secs: read_duration(&path),
+            modified,
+            size,
+            path,
+        }
+    }
+}
+
+/// Modification time and size of a file, if it can be read.
+fn file_stamp(path: &Path) -> Option<(u64, u64)> {
+    let meta = std::fs::metadata(path).ok()?;
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    Some((modified, meta.len()))
+}
+
+fn is_ogg(path: &Path) -> bool {
+    matches!(path.extension().and_then(|e| e.to_str()), Some("ogg") | Some("oga"))
+}
+
+/// Ogg Vorbis files in `dir`, sorted by path.
+pub fn scan_directory(dir: &Path, recursive: bool) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return files,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() && recursive {
+            files.extend(scan_directory(&path, true));
+        } else if path.is_file() && is_ogg(&path) {
+            files.push(path);
+        }
+    }
+
+    files.sort();
+    files
+}
+
+/// Search terms. All given fields must match.
+#[derive(Debug, Clone, Default)]
+pub struct LibraryQuery {
+    /// Words that must each appear in the title, artist, album, genre or
+    /// file name
+    pub q: Option<String>,
+    /// Case-insensitive substrings of the tags
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+}
+
+fn contains_ci(field: Option<&str>, needle: &str) -> bool {
+    field.is_some_and(|f| f.to_lowercase().contains(needle))
+}
+
+impl LibraryQuery {
+    fn matcher(&self) -> impl Fn(&LibraryTrack) -> bool + '_ {
+        let words: Vec<String> = self
+            .q
+            .as_deref()
+            .unwrap_or("")
+            .split_whitespace()
+            .map(str::to_lowercase)
+            .collect();
+        let artist = self.artist.as_deref().map(str::to_lowercase);
+        let album = self.album.as_deref().map(str::to_lowercase);
+        let genre = self.genre.as_deref().map(str::to_lowercase);
+
+        move |track: &LibraryTrack| {
+            if artist.as_deref().is_some_and(|a| !contains_ci(track.artist.as_deref(), a))
+                || album.as_deref().is_some_and(|a| !contains_ci(track.album.as_deref(), a))
+                || genre.as_deref().is_some_and(|g| !contains_ci(track.genre.as_deref(), g))
+            {
+                return false;
+            }
+            if words.is_empty() {
+                return true;
+            }
+            let file_name = track.path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+            let haystack = [
+                track.title.as_str(),
+                track.artist.as_deref().unwrap_or(""),
+                track.album.as_deref().unwrap_or(""),
+                track.genre.as_deref().unwrap_or(""),
+                &file_name,
+            ]
+            .join(" ")
+            .to_lowercase();
+            words.iter().all(|w| haystack.contains(w.as_str()))
+        }
+    }
+}
+
+/// One page of search results.
+#[derive(Debug, Clone)]
+pub struct LibraryPage {
+    pub tracks: Vec<LibraryTrack>,
+    /// Number of matching tracks across all pages
+    pub total: usize,
+    /// Cursor for the following page, if there are more results.
+    pub next_cursor: Option<u64>,
+}
+
+/// What a scan changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ScanSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub total: usize,
+}
+
+/// Tracks affected by files changing on disk.
+#[derive(Debug, Clone, Default)]
+pub struct LibraryChanges {
+    pub added: Vec<LibraryTrack>,
+    /// Retagged tracks
+    pub updated: Vec<LibraryTrack>,
+    /// Renamed tracks with their old paths; IDs are kept
+    pub moved: Vec<(PathBuf, LibraryTrack)>,
+    pub removed: Vec<LibraryTrack>,
+}
+
+impl LibraryChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.updated.is_empty() && self.moved.is_empty() && self.removed.is_empty()
+    }
+
+    pub fn merge(&mut self, other: LibraryChanges) {
+        self.added.extend(other.added);
+        self.updated.extend(other.updated);
+        self.moved.extend(other.moved);
+        self.removed.extend(other.removed);
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct IndexFile {
+    next_id: u64,
+    tracks: Vec<LibraryTrack>,
+}
+
+#[derive(Debug)]
+pub struct Library {
+    root: PathBuf,
+    tracks: BTreeMap<u64, LibraryTrack>,
+    by_path: HashMap<PathBuf, u64>,
+    next_id: u64,
+    /// Saves the index off the async threads, if an index file is configured
+    writer: Option<store::StateWriter<IndexFile>>,
+    /// Held for the length of a rescan, so two can't run at once
+    scan_lock: Arc<Mutex<()>>,
+}
+
+impl Library {
+    /// An empty library of the files under `root`; `rescan` fills it.
+    ///
+    /// `root` is made canonical where possible, so indexed paths match
+    /// those of queued tracks.
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root: root.canonicalize().unwrap_or(root),
+            tracks: BTreeMap::new(),
+            by_path: HashMap::new(),
+            next_id: 1,
+            writer: None,
+            scan_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// A library whose index is saved to `path`, loading the previous
+    /// index so that IDs survive restarts.
+    pub fn with_index_file(root: PathBuf, path: PathBuf) -> Self {
+        let index: IndexFile = match store::read_json(&path) {
+            Ok(Some(index)) => index,
+            Ok(None) => IndexFile::default(),
+            Err(e) => {
+                warn!("Ignoring unreadable library index {}: {}", path.display(), e);
+                IndexFile::default()
+            }
+        };
+        let mut library = Self::new(root);
+        library.writer = Some(store::StateWriter::spawn(path, "library index"));
+        library.install(index.tracks, index.next_id);
+        if !library.is_empty() {
+            info!("Loaded library index of {} tracks", library.len());
+        }
+        library
+    }
+
+    fn install(&mut self, tracks: Vec<LibraryTrack>, next_id: u64) {
+        let max_id = tracks.iter().map(|t| t.id).max().unwrap_or(0);
+        self.next_id = next_id.max(max_id + 1);
+        self.by_path = tracks.iter().map(|t| (t.path.clone(), t.id)).collect();
+        self.tracks = tracks.into_iter().map(|t| (t.id, t)).collect();
+        metrics::LIBRARY_TRACKS.set(self.tracks.len() as i64);
+    }
+
+    /// Save a snapshot of the index, if an index file is configured. The
+    /// write happens in the background.
+    fn persist(&self) {
+        let Some(ref writer) = self.writer else {
+            return;
+        };
+        writer.write(IndexFile {
+            next_id: self.next_id,
+            tracks: self.tracks.values().cloned().collect(),
+        });
+    }
+
+    /// Wait until the latest index has been written.
+    pub fn flush(&self) {
+        if let Some(ref writer) = self.writer {
+            writer.flush();
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    pub fn get(&self, id: u64) -> Option<&LibraryTrack> {
+        self.tracks.get(&id)
+    }
+
+    pub fn get_by_path(&self, path: &Path) -> Option<&LibraryTrack> {
+        self.by_path.get(path).and_then(|id| self.tracks.get(id))
+    }
+
+    /// Tracks at `path` or, if it is a directory, anywhere below it.
+    fn tracks_under(&self, path: &Path) -> Vec<LibraryTrack> {
+        self.tracks
+            .values()
+            .filter(|t| t.path.starts_with(path))
+            .cloned()
+            .collect()
+    }
+
+    fn insert(&mut self, track: LibraryTrack) {
+        if let Some(old) = self.tracks.get(&track.id) {
+            self.by_path.remove(&old.path);
+        }
+        self.next_id = self.next_id.max(track.id + 1);
+        self.by_path.insert(track.path.clone(), track.id);
+        self.tracks.insert(track.id, track);
+    }
+
+    fn remove(&mut self, id: u64) {
+        if let Some(track) = self.tracks.remove(&id) {
+            self.by_path.remove(&track.path);
+        }
+    }
+
+    /// Record changes found on disk in the index.
+    fn apply(&mut self, changes: &LibraryChanges) {
+        if changes.is_empty() {
+            return;
+        }
+        for track in &changes.removed {
+            self.remove(track.id);
+        }
+        let changed = changes.added.iter().chain(&changes.updated).chain(changes.moved.iter().map(|(_, t)| t));
+        for track in changed {
+            self.insert(track.clone());
+        }
+        metrics::LIBRARY_TRACKS.set(self.tracks.len() as i64);
+        self.persist();
+    }
+
+    /// Tracks matching `query` in ID order. `cursor` is the ID of the last
+    /// track of the previous page.
+    pub fn search(&self, query: &LibraryQuery, cursor: Option<u64>, limit: usize) -> LibraryPage {
+        let limit = limit.clamp(1, MAX_QUERY_LIMIT);
+        let matches = query.matcher();
+        let mut total = 0;
+        let mut tracks = Vec::new();
+        for track in self.tracks.values().filter(|t| matches(t)) {
+            total += 1;
+            if cursor.is_none_or(|c| track.id > c) && tracks.len() <= limit {
+                tracks.push(track.clone());
+            }
+        }
+        let next_cursor = if tracks.len() > limit {
+            tracks.truncate(limit);
+            tracks.last().map(|t| t.id)
+        } else {
+            None
+        };
+        LibraryPage {
+            tracks,
+            total,
+            next_cursor,
+        }
+    }
+}
+
+pub type SharedLibrary = Arc<RwLock<Library>>;
+
+/// Rescan the media directory, rereading only new and changed files.
+///
+/// The index is read up front and replaced at the end, so searches carry
+/// on against the old index while files are read.
+pub fn rescan(library: &SharedLibrary) -> ScanSummary {
+    let scan_lock = library.read().unwrap().scan_lock.clone();
+    let _scanning = scan_lock.lock().unwrap();
+    let (root, old, mut next_id) = {
+        let lib = library.read().unwrap();
+        (lib.root.clone(), lib.tracks.clone(), lib.next_id)
+    };
+    let mut old_by_path: HashMap<PathBuf, LibraryTrack> =
+        old.into_values().map(|t| (t.path.clone(), t)).collect();
+
+    let mut summary = ScanSummary::default();
+    let mut tracks = Vec::new();
+    for path in scan_directory(&root, true) {
+        let Some((modified, size)) = file_stamp(&path) else {
+            continue;
+        };
+        let track = match old_by_path.remove(&path) {
+            Some(t) if t.modified == modified && t.size == size => t,
+            Some(t) => {
+                summary.updated += 1;
+                LibraryTrack::read(t.id, path, modified, size)
+            }
+            None => {
+                summary.added += 1;
+                next_id += 1;
+                LibraryTrack::read(next_id - 1, path, modified, size)
+            }
+        };
+        tracks.push(track);
+    }
+    summary.removed = old_by_path.len();
+    summary.total = tracks.len();
+
+    let mut lib = library.write().unwrap();
+    lib.install(tracks, next_id);
+    lib.persist();
+    info!(
+        "Library scan of {}: {} tracks ({} added, {} updated, {} removed)",
+        root.display(),
+        summary.total,
+        summary.added,
+        summary.updated,
+        summary.removed
+    );
+    summary
+}
+
+/// Bring the index up to date for `paths`, which changed on disk.
+/// Directories are scanned, and paths that no longer exist drop every
+/// track at or below them. Paths outside the media directory are ignored.
+pub fn refresh_paths(library: &SharedLibrary, paths: &[PathBuf]) -> LibraryChanges {
+    let scan_lock = library.read().unwrap().scan_lock.clone();
+    let _scanning = scan_lock.lock().unwrap();
+    let root = library.read().unwrap().root.clone();
+
+    let mut changes = LibraryChanges::default();
+    let mut next_id = library.read().unwrap().next_id;
+    let mut seen = HashSet::new();
+    for path in paths.iter().filter(|p| p.starts_with(&root)) {
+        let indexed = library.read().unwrap().tracks_under(path);
+        let files = if path.is_dir() {
+            scan_directory(path, true)
+        } else if path.is_file() && is_ogg(path) {
+            vec![path.clone()]
+        } else {
+            Vec::new()
+        };
+
+        for track in indexed.iter().filter(|t| !files.contains(&t.path)) {
+            if seen.insert(track.path.clone()) {
+                changes.removed.push(track.clone());
+            }
+        }
+        for file in files {
+            if !seen.insert(file.clone()) {
+                continue;
+            }
+            let Some((modified, size)) = file_stamp(&file) else {
+                continue;
+            };
+            match indexed.iter().find(|t| t.path == file) {
+                Some(t) if t.modified == modified && t.size == size => {}
+                Some(t) => changes.updated.push(LibraryTrack::read(t.id, file, modified, size)),
+                None => {
+                    changes.added.push(LibraryTrack::read(next_id, file, modified, size));
+                    next_id += 1;
+                }
+            }
+        }
+    }
+
+    library.write().unwrap().apply(&changes);
+    changes
+}
+
+/// Follow a file or directory renamed from `from` to `to`, keeping the
+/// library IDs of the tracks it held.
+pub fn rename_path(library: &SharedLibrary, from: &Path, to: &Path) -> LibraryChanges {
+    let mut changes = LibraryChanges::default();
+    {
+        let scan_lock = library.read().unwrap().scan_lock.clone();
+        let _scanning = scan_lock.lock().unwrap();
+        let (root, moved) = {
+            let lib = library.read().unwrap();
+            (lib.root.clone(), lib.tracks_under(from))
+        };
+        for track in moved {
+            let new_path = match track.path.strip_prefix(from) {
+                Ok(rest) if !rest.as_os_str().is_empty() => to.join(rest),
+                _ => to.to_path_buf(),
+            };
+            match file_stamp(&new_path) {
+                Some((modified, size)) if new_path.starts_with(&root) && is_ogg(&new_path) => {
+                    let renamed = LibraryTrack::read(track.id, new_path, modified, size);
+                    changes.moved.push((track.path, renamed));
+                }
+                _ => changes.removed.push(track),
+            }
+        }
+        library.write().unwrap().apply(&changes);
+    }
+
+    // Anything else that arrived with the rename
+    changes.merge(refresh_paths(library, &[to.to_path_buf()]));
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::test_files::write_ogg;
+    use tempfile::tempdir;
+
+    fn shared(library: Library) -> SharedLibrary {
+        Arc::new(RwLock::new(library))
+    }
+
+    #[test]
+    fn test_scan_reads_tags_and_keeps_ids() {
+        let dir = tempdir().unwrap();
+        let music = dir.path().join("music");
+        std::fs::create_dir_all(music.join("album")).unwrap();
+        write_ogg(&music.join("a.ogg"), &[("TITLE", "Alpha"), ("ARTIST", "Band"), ("GENRE", "Jazz")], 60);
+        write_ogg(&music.join("album/b.ogg"), &[("TITLE", "Beta"), ("ALBUM", "Second")], 90);
+        let index = dir.path().join("library.json");
+
+        let library = shared(Library::with_index_file(music.clone(), index.clone()));
+        let summary = rescan(&library);
+        assert_eq!(summary, ScanSummary { added: 2, updated: 0, removed: 0, total: 2 });
+        let beta_id = {
+            let lib = library.read().unwrap();
+            let beta = lib.get_by_path(&music.join("album/b.ogg")).unwrap();
+            assert_eq!(beta.album.as_deref(), Some("Second"));
+            assert_eq!(beta.duration_secs, Some(90.0));
+            beta.id
+        };
+        library.read().unwrap().flush();
+
+        std::fs::remove_file(music.join("a.ogg")).unwrap();
+        write_ogg(&music.join("c.ogg"), &[("TITLE", "Gamma")], 30);
+        write_ogg(&music.join("album/b.ogg"), &[("TITLE", "Beta (Remaster)")], 91);
+
+        // Reloaded from the index file, so IDs carry over
+        let library = shared(Library::with_index_file(music.clone(), index));
+        let summary = rescan(&library);
+        assert_eq!(summary, ScanSummary { added: 1, updated: 1, removed: 1, total: 2 });
+        let lib = library.read().unwrap();
+        assert_eq!(lib.get(beta_id).unwrap().title, "Beta (Remaster)");
+        assert_eq!(lib.get_by_path(&music.join("c.ogg")).unwrap().id, 3);
+    }
+
+    #[test]
+    fn test_search() {
+        let dir = tempdir().unwrap();
+        write_ogg(&dir.path().join("1.ogg"), &[("TITLE", "Blue Monday"), ("ARTIST", "New Order")], 10);
+        write_ogg(&dir.path().join("2.ogg"), &[("TITLE", "Monday Morning"), ("ARTIST", "Other")], 10);
+        write_ogg(&dir.path().join("3.ogg"), &[("TITLE", "Ceremony"), ("ARTIST", "New Order"), ("GENRE", "Post-Punk")], 10);
+        let library = shared(Library::new(dir.path().to_path_buf()));
+        rescan(&library);
+        let lib = library.read().unwrap();
+
+        let titles = |query: LibraryQuery| -> Vec<String> {
+            lib.search(&query, None, 10).tracks.into_iter().map(|t| t.title).collect()
+        };
+        assert_eq!(titles(LibraryQuery { q: Some("monday".into()), ..Default::default() }).len(), 2);
+        assert_eq!(
+            titles(LibraryQuery { q: Some("monday order".into()), ..Default::default() }),
+            vec!["Blue Monday"]
+        );
+        assert_eq!(
+            titles(LibraryQuery { artist: Some("new order".into()), genre: Some("punk".into()), ..Default::default() }),
+            vec!["Ceremony"]
+        );
+
+        let page = lib.search(&LibraryQuery::default(), None, 2);
+        assert_eq!(page.total, 3);
+        assert_eq!(page.next_cursor, Some(2));
+        let page = lib.search(&LibraryQuery::default(), page.next_cursor, 2);
+        assert_eq!(page.tracks.len(), 1);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn test_refresh_and_rename_paths() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        std::fs::create_dir(root.join("old")).unwrap();
+        write_ogg(&root.join("old/a.ogg"), &[("TITLE", "A")], 10);
+        write_ogg(&root.join("b.ogg"), &[("TITLE", "B")], 10);
+        let library = shared(Library::new(root.clone()));
+        rescan(&library);
+        let a_id = library.read().unwrap().get_by_path(&root.join("old/a.ogg")).unwrap().id;
+
+        write_ogg(&root.join("b.ogg"), &[("TITLE", "B (Live)")], 12);
+        write_ogg(&root.join("c.ogg"), &[("TITLE", "C")], 10);
+        let changes = refresh_paths(&library, &[root.join("b.ogg"), root.join("c.ogg"), PathBuf::from("/elsewhere.ogg")]);
+        assert_eq!(changes.updated[0].title, "B (Live)");
+        assert_eq!(changes.added[0].title, "C");
+        assert_eq!(library.read().unwrap().len(), 3);
+
+        // A renamed directory keeps its tracks' IDs
+        std::fs::rename(root.join("old"), root.join("new")).unwrap();
+        let changes = rename_path(&library, &root.join("old"), &root.join("new"));
+        assert_eq!(changes.moved.len(), 1);
+        assert_eq!(changes.moved[0].0, root.join("old/a.ogg"));
+        let lib = library.read().unwrap();
+        assert_eq!(lib.get_by_path(&root.join("new/a.ogg")).unwrap().id, a_id);
+        assert!(lib.get_by_path(&root.join("old/a.ogg")).is_none());
+        drop(lib);
+
+        std::fs::remove_file(root.join("c.ogg")).unwrap();
+        let changes = refresh_paths(&library, &[root.join("c.ogg")]);
+        assert_eq!(changes.removed[0].title, "C");
+        assert_eq!(library.read().unwrap().len(), 2);
+    }
+}
@@ -1,12 +1,29 @@
 mod api;
+mod artwork;
+mod audit;
+mod auth;
 mod config;
 mod connection;
 mod errors;
+mod events;
+mod history;
 mod icecast;
+mod jingle;
+mod library;
 mod metrics;
 mod player;
+mod playlist;
+mod playlist_format;
+mod quarantine;
 mod queue;
+mod report;
+mod requests;
+mod scheduler;
+mod shuffle;
+mod store;
 mod validation;
+mod watcher;
+mod ws;
 
 use std::net::SocketAddr;
 use std::path::PathBuf;
@@ -14,17 +31,28 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use crate::errors::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use tokio::net::TcpListener;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 use oggmux::{OggMux, VorbisConfig, VorbisBitrateMode, BufferConfig};
 
 use crate::api::AppState;
+use crate::artwork::ArtworkCache;
+use crate::audit::AuditLog;
+use crate::auth::{AuthLimiter, LockoutPolicy, TokenStore};
 use crate::connection::ConnectionState;
+use crate::history::{History, HistoryEntry, HistoryFilter};
 use crate::icecast::{IcecastClient, IcecastConfig};
-use crate::player::PlayerHandle;
-use crate::queue::{Queue, SharedQueue};
+use crate::jingle::JingleRules;
+use crate::library::{Library, SharedLibrary};
+use crate::player::{FailurePolicy, PlayerHandle};
+use crate::playlist::PlaylistStore;
+use crate::quarantine::Quarantine;
+use crate::queue::{Queue, SharedQueue, TrackSource};
+use crate::report::ReportFormat;
+use crate::requests::{RequestBox, RequestRules};
+use crate::scheduler::{CutPolicy, Schedule, SystemClock};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = "A tool to help with remuxing and streaming Ogg content over Icecast")]
@@ -65,24 +93,160 @@ struct Args {
     #[arg(long, value_name = "ADDR", default_value = "0.0.0.0")]
     api_bind: String,
 
-    /// Restrict file paths to this directory
+    /// Restrict file paths to this directory and index it as the media library
     #[arg(long, value_name = "DIR")]
     media_dir: Option<String>,
 
-    /// Bearer token for API authentication
+    /// Bearer token for API authentication, with full access
     #[arg(long, value_name = "TOKEN")]
     api_token: Option<String>,
 
+    /// TOML file of named API tokens and their scopes
+    #[arg(long, value_name = "FILE")]
+    tokens_file: Option<PathBuf>,
+
+    /// Accept song requests from listeners (needs --media-dir)
+    #[arg(long)]
+    listener_requests: bool,
+
+    /// Seconds a listener must wait between requests
+    #[arg(long, value_name = "SECONDS", default_value = "600")]
+    request_client_cooldown_secs: u64,
+
+    /// Seconds before the same track can be requested again
+    #[arg(long, value_name = "SECONDS", default_value = "3600")]
+    request_track_cooldown_secs: u64,
+
+    /// Requests awaiting moderation before new ones are refused
+    #[arg(long, value_name = "N", default_value = "20")]
+    request_max_pending: usize,
+
+    /// Refuse requests for tracks played within this many hours
+    #[arg(long, value_name = "HOURS", default_value = "3")]
+    request_replay_hours: u64,
+
+    /// Failed API authentication attempts before a client is locked out (0 disables lockouts)
+    #[arg(long, value_name = "COUNT", default_value = "5")]
+    auth_max_failures: u32,
+
+    /// Length of the first API lockout in seconds, doubling for each repeat
+    #[arg(long, value_name = "SECONDS", default_value = "60")]
+    auth_lockout_secs: u64,
+
+    /// Directory for persistent state (queue, history)
+    #[arg(long, value_name = "DIR", global = true)]
+    state_dir: Option<String>,
+
+    /// Re-queue the track that was playing when snowboot last stopped
+    #[arg(long)]
+    resume_interrupted: bool,
+
+    /// Days of playback history to keep in the state directory (0 keeps everything)
+    #[arg(long, value_name = "DAYS", default_value = "90")]
+    history_retention_days: u64,
+
+    /// Seconds between Icecast listener count polls (0 disables polling)
+    #[arg(long, value_name = "SECONDS", default_value = "30")]
+    listener_poll_interval: u64,
+
+    /// Default minimum tracks between two by the same artist when shuffling (0 disables)
+    #[arg(long, value_name = "N", default_value = "0")]
+    min_artist_gap: usize,
+
+    /// Directory of station IDs and jingles to insert between tracks
+    #[arg(long, value_name = "DIR")]
+    jingle_dir: Option<PathBuf>,
+
+    /// Insert a jingle after this many tracks (0 disables)
+    #[arg(long, value_name = "N", default_value = "0")]
+    jingle_every_tracks: u32,
+
+    /// Insert a jingle when this many minutes have passed since the last (0 disables)
+    #[arg(long, value_name = "MINUTES", default_value = "0")]
+    jingle_every_minutes: u64,
+
+    /// What hard timed inserts do with the track they cut: requeue or drop
+    #[arg(long, value_name = "POLICY", default_value = "requeue", value_parser = parse_cut_policy)]
+    insert_cut_track: CutPolicy,
+
+    /// Vorbis comments sent in-band to listeners, comma-separated
+    #[arg(long, value_name = "TAGS", value_delimiter = ',', default_values_t = queue::DEFAULT_STREAM_TAGS.iter().map(|t| t.to_string()))]
+    stream_tags: Vec<String>,
+
+    /// Times a track that fails to play is tried again before moving on
+    #[arg(long, value_name = "N", default_value = "0")]
+    track_retries: u32,
+
+    /// Set tracks that still fail aside in the quarantine list instead of skipping them
+    #[arg(long)]
+    quarantine_failed_tracks: bool,
+
+    /// IANA time zone for schedule blocks, e.g. Europe/London
+    #[arg(long, value_name = "TZ", default_value = "UTC", value_parser = parse_timezone)]
+    timezone: chrono_tz::Tz,
+
     /// Log level
     #[arg(long, value_name = "LEVEL", default_value = "info")]
     log_level: String,
+
+    /// Log format (text or json)
+    #[arg(long, value_name = "FORMAT", default_value = "text")]
+    log_format: String,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Generate a play-log report from the persisted history and exit
+    Report {
+        /// Output format: csv, tsv, json or soundexchange
+        #[arg(long, value_name = "FORMAT", default_value = "csv", value_parser = parse_report_format)]
+        format: ReportFormat,
+
+        /// Only plays starting at or after this time (Unix seconds or RFC 3339)
+        #[arg(long, value_name = "TIME")]
+        from: Option<String>,
+
+        /// Only plays starting before this time (Unix seconds or RFC 3339)
+        #[arg(long, value_name = "TIME")]
+        to: Option<String>,
+
+        /// Write the report to this file instead of stdout
+        #[arg(long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+}
+
+fn parse_cut_policy(value: &str) -> std::result::Result<CutPolicy, String> {
+    match value {
+        "requeue" => Ok(CutPolicy::Requeue),
+        "drop" => Ok(CutPolicy::Drop),
+        _ => Err(format!("unknown cut track policy '{}' (expected requeue or drop)", value)),
+    }
+}
+
+fn parse_timezone(value: &str) -> std::result::Result<chrono_tz::Tz, String> {
+    value
+        .parse()
+        .map_err(|_| format!("unknown time zone '{}' (expected an IANA name such as Europe/London)", value))
+}
+
+fn parse_report_format(value: &str) -> std::result::Result<ReportFormat, String> {
+    ReportFormat::parse(value)
+        .ok_or_else(|| format!("unknown report format '{}' (expected csv, tsv, json or soundexchange)", value))
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
-    setup_logging(&args.log_level, "text");
+    if let Some(Command::Report { format, from, to, output }) = args.command {
+        return run_report(args.state_dir.as_deref(), format, from, to, output);
+    }
+
+    setup_logging(&args.log_level, &args.log_format);
 
     let (host, port) = validation::parse_host_port(&args.host)?;
 
@@ -116,11 +280,64 @@ async fn main() -> Result<()> {
     }
 
     // Create queue and player (before mux so we can wire up metadata callback)
-    let queue: SharedQueue = Arc::new(tokio::sync::RwLock::new(Queue::default()));
-    let player_handle = PlayerHandle::new(queue.clone());
+    let state_dir = args.state_dir.map(PathBuf::from);
+    let (queue, history, playlists, schedule, audit, quarantine) = match state_dir {
+        Some(ref dir) => {
+            std::fs::create_dir_all(dir).map_err(|e| errors::SnowbootError::Io {
+                message: format!("Failed to create state directory {}", dir.display()),
+                code: errors::ErrorCode::StateDirUnavailable,
+                source: e,
+            })?;
+            info!("State directory: {}", dir.display());
+            let retention = match args.history_retention_days {
+                0 => None,
+                days => Some(days * 86400),
+            };
+            (
+                Queue::with_state_file(dir.join("queue.json"), args.resume_interrupted),
+                History::with_log_file(dir.join("history.jsonl"), retention),
+                PlaylistStore::with_state_file(dir.join("playlists.json")),
+                Schedule::with_state_file(dir.join("schedule.json"), args.timezone)
+                    .with_cut_policy(args.insert_cut_track),
+                AuditLog::with_log_file(dir.join("audit.jsonl")).map_err(|e| errors::SnowbootError::Io {
+                    message: format!("Failed to read audit log {}", dir.join("audit.jsonl").display()),
+                    code: errors::ErrorCode::StateFileUnreadable,
+                    source: e,
+                })?,
+                Quarantine::with_state_file(dir.join("quarantine.json")),
+            )
+        }
+        None => (
+            Queue::default(),
+            History::default(),
+            PlaylistStore::default(),
+            Schedule::new(args.timezone).with_cut_policy(args.insert_cut_track),
+            AuditLog::default(),
+            Quarantine::default(),
+        ),
+    };
+    metrics::QUEUE_LENGTH.set(queue.len() as i64);
+    let queue: SharedQueue = Arc::new(tokio::sync::RwLock::new(queue));
+    let jingle_rules = JingleRules {
+        directory: args.jingle_dir,
+        every_tracks: args.jingle_every_tracks,
+        every_minutes: args.jingle_every_minutes,
+    };
+    if jingle_rules.is_enabled() {
+        info!("Jingle rules: {:?}", jingle_rules);
+    }
+    let player_handle = PlayerHandle::new(queue.clone())
+        .with_history(history)
+        .with_quarantine(quarantine)
+        .with_failure_policy(FailurePolicy {
+            retries: args.track_retries,
+            quarantine: args.quarantine_failed_tracks,
+        })
+        .with_jingles(jingle_rules);
 
     // Configure and spawn OggMux with metadata callback
     let metadata_player = player_handle.clone();
+    let stream_tags = args.stream_tags;
     let mux = OggMux::new()
         .with_vorbis_config(VorbisConfig {
             sample_rate: args.sample_rate,
@@ -132,7 +349,7 @@ async fn main() -> Result<()> {
         })
         .with_metadata_callback(move |_granule_pos| {
             metadata_player.now_playing()
-                .map(|track| track.metadata_comments())
+                .map(|track| track.metadata_comments(&stream_tags))
         });
 
     let (input_tx, mut output_rx, _shutdown_tx, _mux_handle) = mux.spawn();
@@ -192,6 +409,29 @@ async fn main() -> Result<()> {
         })
     };
 
+    // Poll Icecast for the listener count recorded in history
+    if args.listener_poll_interval > 0 {
+        let client = icecast_client.clone();
+        let handle = player_handle.clone();
+        let shutdown = shutdown.clone();
+        let interval = Duration::from_secs(args.listener_poll_interval);
+        tokio::spawn(async move {
+            loop {
+                match client.fetch_listeners().await {
+                    Ok(listeners) => handle.set_listeners(listeners),
+                    Err(e) => {
+                        debug!("Listener count unavailable: {}", e);
+                        handle.set_listeners(None);
+                    }
+                }
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = tokio::time::sleep(interval) => {}
+                }
+            }
+        });
+    }
+
     // Spawn player task
     let player_task = {
         let handle = player_handle.clone();
@@ -201,25 +441,97 @@ async fn main() -> Result<()> {
         })
     };
 
+    // Spawn scheduler task
+    let playlists = Arc::new(tokio::sync::RwLock::new(playlists));
+    let schedule = Arc::new(tokio::sync::RwLock::new(schedule));
+    info!("Schedule time zone: {}", args.timezone);
+    {
+        let schedule = schedule.clone();
+        let playlists = playlists.clone();
+        let handle = player_handle.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            scheduler::run_scheduler(schedule, playlists, handle, Arc::new(SystemClock), shutdown).await;
+        });
+    }
+
     // Build and start the API server
     let start_time = Instant::now();
     let media_dir = args.media_dir.map(PathBuf::from);
-    let api_token = args.api_token;
+    let mut tokens = match args.tokens_file {
+        Some(ref path) => TokenStore::from_file(path)?,
+        None => TokenStore::default(),
+    };
+    if let Some(token) = args.api_token {
+        tokens = tokens.with_default_token(token)?;
+    }
 
-    if let Some(ref dir) = media_dir {
+    // The library is indexed in the background; searches see the saved
+    // index until the scan finishes
+    let library = media_dir.as_ref().map(|dir| {
         info!("Media directory: {}", dir.display());
-    }
-    if api_token.is_some() {
-        info!("API authentication enabled");
-    }
+        let library = match state_dir {
+            Some(ref state) => Library::with_index_file(dir.clone(), state.join("library.json")),
+            None => Library::new(dir.clone()),
+        };
+        let library: SharedLibrary = Arc::new(std::sync::RwLock::new(library));
+        let scanning = library.clone();
+        tokio::task::spawn_blocking(move || library::rescan(&scanning));
+        if let Err(e) = watcher::spawn_watcher(library.clone(), player_handle.clone(), shutdown.clone()) {
+            warn!("Not watching {} for changes: {}", dir.display(), e);
+        }
+        library
+    });
+    let requests = if args.listener_requests {
+        if media_dir.is_none() {
+            return Err(errors::SnowbootError::Config {
+                message: "--listener-requests needs --media-dir".to_string(),
+                code: errors::ErrorCode::InvalidConfig,
+                source: None,
+            });
+        }
+        let rules = RequestRules {
+            client_cooldown_secs: args.request_client_cooldown_secs,
+            track_cooldown_secs: args.request_track_cooldown_secs,
+            max_pending: args.request_max_pending,
+            replay_window_secs: args.request_replay_hours * 3600,
+        };
+        info!("Listener requests enabled: {:?}", rules);
+        let requests = match state_dir {
+            Some(ref dir) => RequestBox::with_state_file(rules, dir.join("requests.json")),
+            None => RequestBox::new(rules),
+        };
+        Some(Arc::new(tokio::sync::RwLock::new(requests)))
+    } else {
+        None
+    };
+    let tokens = if tokens.is_empty() {
+        None
+    } else {
+        info!("API authentication enabled ({} tokens)", tokens.len());
+        Some(Arc::new(tokens))
+    };
 
+    let audit = Arc::new(std::sync::RwLock::new(audit));
     let app_state = AppState {
         queue: queue.clone(),
         player: player_handle.clone(),
+        playlists,
+        schedule,
         start_time,
         connection_state: connection_state.clone(),
         media_dir,
-        api_token,
+        library: library.clone(),
+        tokens,
+        auth_limiter: Arc::new(AuthLimiter::new(LockoutPolicy {
+            max_failures: args.auth_max_failures,
+            lockout: Duration::from_secs(args.auth_lockout_secs),
+            ..LockoutPolicy::default()
+        })),
+        audit: audit.clone(),
+        requests: requests.clone(),
+        min_artist_gap: args.min_artist_gap,
+        artwork: Arc::new(ArtworkCache::default()),
     };
 
     let app = api::router(app_state);
@@ -237,7 +549,7 @@ async fn main() -> Result<()> {
     let api_server = {
         let shutdown = shutdown.clone();
         tokio::spawn(async move {
-            axum::serve(listener, app)
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
                 .with_graceful_shutdown(async move { shutdown.cancelled().await })
                 .await
                 .ok();
@@ -257,6 +569,19 @@ async fn main() -> Result<()> {
         let _ = api_server.await;
     }).await.ok();
 
+    // Queue changes, history, the quarantine, audit records, listener requests
+    // and the library index are saved in the background
+    queue.read().await.flush();
+    player_handle.history.read().unwrap().flush();
+    player_handle.quarantine.read().unwrap().flush();
+    audit.read().unwrap().flush();
+    if let Some(ref requests) = requests {
+        requests.read().await.flush();
+    }
+    if let Some(ref library) = library {
+        library.read().unwrap().flush();
+    }
+
     // Disconnect from Icecast
     if let Err(e) = icecast_client.disconnect().await {
         error!("Error disconnecting from Icecast: {}", e);
@@ -266,6 +591,62 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Generate a play-log report from the history in `state_dir`.
+fn run_report(
+    state_dir: Option<&str>,
+    format: ReportFormat,
+    from: Option<String>,
+    to: Option<String>,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    let state_dir = state_dir.ok_or_else(|| errors::SnowbootError::Config {
+        message: "--state-dir is required to generate a report".to_string(),
+        code: errors::ErrorCode::InvalidConfig,
+        source: None,
+    })?;
+
+    let parse = |value: Option<String>| -> Result<Option<u64>> {
+        value
+            .map(|v| {
+                history::parse_timestamp(&v).ok_or_else(|| errors::SnowbootError::Config {
+                    message: format!("Invalid timestamp '{}': use Unix seconds or RFC 3339", v),
+                    code: errors::ErrorCode::InvalidTimestamp,
+                    source: None,
+                })
+            })
+            .transpose()
+    };
+    let filter = HistoryFilter {
+        since: parse(from)?,
+        until: parse(to)?,
+        failed: Some(false),
+        source: Some(TrackSource::Queue),
+        ..Default::default()
+    };
+
+    let log_path = PathBuf::from(state_dir).join("history.jsonl");
+    let entries: Vec<HistoryEntry> = store::read_json_lines(&log_path)
+        .map_err(|e| errors::SnowbootError::Io {
+            message: format!("Failed to read history log {}", log_path.display()),
+            code: errors::ErrorCode::FileReadFailed,
+            source: e,
+        })?
+        .into_iter()
+        .filter(|e| filter.matches(e))
+        .collect();
+
+    let body = report::render(&report::build_records(&entries), format);
+    match output {
+        Some(path) => std::fs::write(&path, body).map_err(|e| errors::SnowbootError::Io {
+            message: format!("Failed to write report {}", path.display()),
+            code: errors::ErrorCode::PermissionDenied,
+            source: e,
+        })?,
+        None => print!("{}", body),
+    }
+    Ok(())
+}
+
 fn setup_logging(log_level: &str, format: &str) {
     use tracing_subscriber::{fmt, EnvFilter, prelude::*};
 
@@ -1,7 +1,7 @@
 // Metrics and health check module
 
 use prometheus::{
-    IntCounter, IntGauge, Histogram, HistogramOpts, Registry,
+    IntCounter, IntCounterVec, IntGauge, Histogram, HistogramOpts, Opts, Registry,
     Encoder, TextEncoder,
 };
 use lazy_static::lazy_static;
@@ -87,10 +87,34 @@ lazy_static! {
         "Total number of tracks skipped"
     ).unwrap();
 
+    pub static ref TRACKS_FAILED: IntCounter = IntCounter::new(
+        "snowboot_tracks_failed_total",
+        "Total number of tracks that could not be played"
+    ).unwrap();
+
     pub static ref QUEUE_LENGTH: IntGauge = IntGauge::new(
         "snowboot_queue_length",
         "Current number of tracks in the queue"
     ).unwrap();
+
+    pub static ref LIBRARY_TRACKS: IntGauge = IntGauge::new(
+        "snowboot_library_tracks",
+        "Number of tracks in the media library index"
+    ).unwrap();
+
+    pub static ref LISTENERS: IntGauge = IntGauge::new(
+        "snowboot_listeners",
+        "Current number of Icecast listeners on the mount"
+    ).unwrap();
+
+    // API metrics
+    pub static ref AUTH_FAILURES: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "snowboot_api_auth_failures_total",
+            "Total number of rejected API requests by reason (missing_token, invalid_token, forbidden), and of lockouts (locked_out)"
+        ),
+        &["reason"]
+    ).unwrap();
 }
 
 /// Initialize metrics registry
@@ -110,7 +134,11 @@ pub fn init_metrics() {
     REGISTRY.register(Box::new(UPTIME_SECONDS.clone())).unwrap();
     REGISTRY.register(Box::new(TRACKS_PLAYED.clone())).unwrap();
     REGISTRY.register(Box::new(TRACKS_SKIPPED.clone())).unwrap();
+    REGISTRY.register(Box::new(TRACKS_FAILED.clone())).unwrap();
     REGISTRY.register(Box::new(QUEUE_LENGTH.clone())).unwrap();
+    REGISTRY.register(Box::new(LIBRARY_TRACKS.clone())).unwrap();
+    REGISTRY.register(Box::new(LISTENERS.clone())).unwrap();
+    REGISTRY.register(Box::new(AUTH_FAILURES.clone())).unwrap();
 }
 
 /// Get metrics as text in Prometheus format
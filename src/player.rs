@@ -1,7 +1,7 @@
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
 use bytes::Bytes;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 use tokio::sync::{broadcast, mpsc, RwLock};
@@ -9,70 +9,466 @@ use tokio::time::{sleep, Duration};
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 
+use crate::auth;
+use crate::errors::ErrorCode;
+use crate::events::{EventLog, SequencedEvent};
+use crate::history::{unix_now, History, HistoryEntry, SharedHistory, TrackError};
+use crate::jingle::{JingleEngine, JingleRules};
+use crate::library::LibraryTrack;
 use crate::metrics;
-use crate::queue::{SharedQueue, Track};
+use crate::quarantine::{Quarantine, SharedQuarantine};
+use crate::queue::{MetadataOverride, Queue, SharedQueue, Track, TrackSource};
+use crate::requests::ListenerRequest;
+use crate::scheduler::{CutPolicy, ScheduleEntry};
 
 #[derive(Debug, Clone, Serialize)]
 #[serde(tag = "event", content = "data")]
 pub enum PlayerEvent {
     #[serde(rename = "track_started")]
     TrackStarted(Track),
+    /// The metadata of the track on air was changed
+    #[serde(rename = "now_playing_updated")]
+    NowPlayingUpdated(Track),
     #[serde(rename = "track_finished")]
     TrackFinished { track: Track, duration_secs: u64 },
     #[serde(rename = "track_skipped")]
     TrackSkipped { track: Track, duration_secs: u64 },
+    /// The track could not be played; `action` is what happens to it next
+    #[serde(rename = "track_failed")]
+    TrackFailed {
+        track: Track,
+        error_code: u32,
+        message: String,
+        action: FailureAction,
+    },
     #[serde(rename = "queue_changed")]
-    QueueChanged { length: usize },
+    QueueChanged {
+        kind: QueueChangeKind,
+        /// Affected tracks. For `shuffled`, `replaced` and `batch` this is
+        /// the whole new order.
+        tracks: Vec<TrackPosition>,
+        length: usize,
+        revision: u64,
+        /// The queue after this change, taken only while a client wants it
+        #[serde(skip)]
+        snapshot: Option<Arc<Vec<Track>>>,
+    },
+    #[serde(rename = "playback_mode_changed")]
+    PlaybackModeChanged { mode: PlaybackMode },
+    #[serde(rename = "playback_stopped")]
+    PlaybackStopped,
+    #[serde(rename = "playback_resumed")]
+    PlaybackResumed,
+    #[serde(rename = "schedule_block_started")]
+    ScheduleBlockStarted { entry: ScheduleEntry, tracks: usize },
+    #[serde(rename = "schedule_block_ended")]
+    ScheduleBlockEnded { entry: ScheduleEntry },
+    /// Files in the media directory were added, changed or deleted
+    #[serde(rename = "library_changed")]
+    LibraryChanged {
+        added: Vec<LibraryTrack>,
+        updated: Vec<LibraryTrack>,
+        removed: Vec<u64>,
+    },
+    /// A listener request is waiting for moderation
+    #[serde(rename = "request_received")]
+    RequestReceived(ListenerRequest),
 }
 
+/// What kind of change a `QueueChanged` event describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueChangeKind {
+    Added,
+    Removed,
+    Moved,
+    Cleared,
+    Shuffled,
+    Replaced,
+    Batch,
+    /// The player took the front track to play it
+    Played,
+    /// Tracks were retagged or renamed on disk; `tracks` have the new
+    /// details
+    Updated,
+    /// Tracks were removed because their files were deleted
+    Vanished,
+}
+
+/// A track affected by a queue change. `position` is where the track is
+/// now, or where it was for removals. Newly queued tracks include the
+/// full track so clients don't need to fetch the queue.
 #[derive(Debug, Clone, Serialize)]
-pub struct HistoryEntry {
-    pub track: Track,
-    pub started_at: u64,
-    pub duration_secs: u64,
-    pub skipped: bool,
+pub struct TrackPosition {
+    pub id: u64,
+    pub position: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track: Option<Track>,
 }
 
-pub type SharedHistory = Arc<std::sync::RwLock<Vec<HistoryEntry>>>;
+impl TrackPosition {
+    pub fn new(id: u64, position: usize) -> Self {
+        Self { id, position, track: None }
+    }
+}
+
+impl PlayerEvent {
+    pub fn queue_changed(queue: &Queue, kind: QueueChangeKind, tracks: Vec<TrackPosition>) -> Self {
+        PlayerEvent::QueueChanged {
+            kind,
+            tracks,
+            length: queue.len(),
+            revision: queue.revision(),
+            snapshot: queue.is_watched().then(|| Arc::new(queue.list())),
+        }
+    }
+
+    /// `added` have just been put in `queue`; report where they ended up.
+    pub fn tracks_added(queue: &Queue, kind: QueueChangeKind, added: &[Track]) -> Self {
+        let ids: HashSet<u64> = added.iter().map(|t| t.id).collect();
+        let tracks = queue
+            .list()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, t)| ids.contains(&t.id))
+            .map(|(position, t)| TrackPosition { id: t.id, position, track: Some(t) })
+            .collect();
+        Self::queue_changed(queue, kind, tracks)
+    }
+
+    /// Report the whole new order of `queue`, with full tracks for `added`.
+    pub fn queue_reordered(queue: &Queue, kind: QueueChangeKind, added: &[Track]) -> Self {
+        let ids: HashSet<u64> = added.iter().map(|t| t.id).collect();
+        let tracks = queue
+            .list()
+            .into_iter()
+            .enumerate()
+            .map(|(position, t)| TrackPosition {
+                id: t.id,
+                position,
+                track: ids.contains(&t.id).then_some(t),
+            })
+            .collect();
+        Self::queue_changed(queue, kind, tracks)
+    }
+
+    /// Full queue after this event, for `QueueChanged` made while the queue
+    /// was watched.
+    pub fn queue_snapshot(&self) -> Option<&[Track]> {
+        match self {
+            PlayerEvent::QueueChanged { snapshot, .. } => snapshot.as_deref().map(Vec::as_slice),
+            _ => None,
+        }
+    }
+}
+
+/// What happens to a queued track once it has played.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaybackMode {
+    /// Played tracks leave the queue
+    #[default]
+    Normal,
+    /// Played and skipped tracks go back to the end of the queue
+    RepeatQueue,
+    /// The current track plays again until skipped
+    RepeatOne,
+    /// Stop at the end of the current track and play silence until resumed
+    StopAfterCurrent,
+}
+
+/// What the player does with tracks that fail to play.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FailurePolicy {
+    /// Times a queued track is tried again before the player gives up on it
+    pub retries: u32,
+    /// Whether tracks the player gives up on are quarantined rather than
+    /// just skipped
+    pub quarantine: bool,
+}
+
+/// What happens to a track after a failed attempt to play it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureAction {
+    /// Move on to the next track
+    Skip,
+    /// Try the same queue entry again, from the front of the queue
+    Retry,
+    /// Move on, and set the file aside in the quarantine list
+    Quarantine,
+}
+
+/// `attempts` counts failures of this queue entry so far, including the
+/// latest. Jingles and inserts are never retried.
+fn failure_action(policy: FailurePolicy, track: &Track, attempts: u32) -> FailureAction {
+    if track.source == TrackSource::Queue && attempts <= policy.retries {
+        FailureAction::Retry
+    } else if policy.quarantine {
+        FailureAction::Quarantine
+    } else {
+        FailureAction::Skip
+    }
+}
+
+/// Where a track goes back into the queue after it has played.
+#[derive(Debug, PartialEq, Eq)]
+enum Requeue {
+    /// Same queue entry back at the front, after being cut by an insert
+    Resume,
+    /// New copy at the front
+    Front,
+    /// New copy at the back
+    Back,
+}
+
+fn requeue_for(
+    mode: PlaybackMode,
+    track: &Track,
+    was_skipped: bool,
+    cut_by_insert: Option<CutPolicy>,
+) -> Option<Requeue> {
+    if track.source != TrackSource::Queue {
+        return None;
+    }
+    if let Some(policy) = cut_by_insert {
+        return (policy == CutPolicy::Requeue).then_some(Requeue::Resume);
+    }
+    match mode {
+        // Skipping ends the repeat
+        PlaybackMode::RepeatOne if !was_skipped => Some(Requeue::Front),
+        PlaybackMode::RepeatQueue => Some(Requeue::Back),
+        _ => None,
+    }
+}
+
+/// A timed insert waiting to interrupt the queue.
+#[derive(Debug, Clone)]
+pub struct PendingInsert {
+    pub track: Track,
+    pub cut_track: CutPolicy,
+    /// Whether the current track is cut rather than allowed to finish
+    pub hard: bool,
+}
 
 #[derive(Clone)]
 pub struct PlayerHandle {
     skip_token: Arc<RwLock<CancellationToken>>,
     pub queue: SharedQueue,
     now_playing: Arc<std::sync::RwLock<Option<Track>>>,
-    pub event_tx: broadcast::Sender<PlayerEvent>,
+    events: Arc<std::sync::Mutex<EventLog>>,
     pub history: SharedHistory,
+    pub quarantine: SharedQuarantine,
+    failure_policy: FailurePolicy,
+    listeners: Arc<std::sync::RwLock<Option<u64>>>,
+    /// Highest listener count seen since the current track started
+    peak_listeners: Arc<std::sync::Mutex<Option<u64>>>,
+    jingles: Arc<std::sync::Mutex<JingleEngine>>,
+    pending_insert: Arc<std::sync::Mutex<Option<PendingInsert>>>,
+    mode: Arc<std::sync::RwLock<PlaybackMode>>,
+    stopped: Arc<std::sync::atomic::AtomicBool>,
 }
 
 impl PlayerHandle {
     pub fn new(queue: SharedQueue) -> Self {
-        let (event_tx, _) = broadcast::channel(64);
         Self {
             skip_token: Arc::new(RwLock::new(CancellationToken::new())),
             queue,
             now_playing: Arc::new(std::sync::RwLock::new(None)),
-            event_tx,
-            history: Arc::new(std::sync::RwLock::new(Vec::new())),
+            events: Arc::new(std::sync::Mutex::new(EventLog::default())),
+            history: Arc::new(std::sync::RwLock::new(History::default())),
+            quarantine: Arc::new(std::sync::RwLock::new(Quarantine::default())),
+            failure_policy: FailurePolicy::default(),
+            listeners: Arc::new(std::sync::RwLock::new(None)),
+            peak_listeners: Arc::new(std::sync::Mutex::new(None)),
+            jingles: Arc::new(std::sync::Mutex::new(JingleEngine::new(
+                JingleRules::default(),
+                unix_now(),
+            ))),
+            pending_insert: Arc::new(std::sync::Mutex::new(None)),
+            mode: Arc::new(std::sync::RwLock::new(PlaybackMode::default())),
+            stopped: Arc::new(std::sync::atomic::AtomicBool::new(false)),
         }
     }
 
+    /// Use `history` instead of an empty in-memory history.
+    pub fn with_history(mut self, history: History) -> Self {
+        self.history = Arc::new(std::sync::RwLock::new(history));
+        self
+    }
+
+    /// Use `quarantine` instead of an empty in-memory quarantine.
+    pub fn with_quarantine(mut self, quarantine: Quarantine) -> Self {
+        self.quarantine = Arc::new(std::sync::RwLock::new(quarantine));
+        self
+    }
+
+    /// Whether the file at `path` is quarantined and must not be played.
+    pub fn is_quarantined(&self, path: &std::path::Path) -> bool {
+        self.quarantine.read().unwrap().contains(path)
+    }
+
+    pub fn with_failure_policy(mut self, policy: FailurePolicy) -> Self {
+        self.failure_policy = policy;
+        self
+    }
+
+    pub fn with_jingles(self, rules: JingleRules) -> Self {
+        self.set_jingle_rules(rules);
+        self
+    }
+
+    pub fn jingle_rules(&self) -> JingleRules {
+        self.jingles.lock().unwrap().rules().clone()
+    }
+
+    pub fn set_jingle_rules(&self, rules: JingleRules) {
+        self.jingles.lock().unwrap().set_rules(rules);
+    }
+
     pub async fn skip(&self) {
         let token = self.skip_token.read().await;
         token.cancel();
         info!("Skip requested");
     }
 
+    /// Play `insert` next, replacing any insert still waiting. Hard inserts
+    /// cut the current track immediately.
+    pub async fn interrupt(&self, insert: PendingInsert) {
+        let hard = insert.hard;
+        info!(
+            "Timed insert: {} ({})",
+            insert.track.title,
+            if hard { "hard" } else { "soft" }
+        );
+        *self.pending_insert.lock().unwrap() = Some(insert);
+        if hard {
+            self.skip().await;
+        }
+    }
+
+    pub fn has_pending_insert(&self) -> bool {
+        self.pending_insert.lock().unwrap().is_some()
+    }
+
+    /// The insert waiting to play, if any.
+    pub fn pending_insert(&self) -> Option<PendingInsert> {
+        self.pending_insert.lock().unwrap().clone()
+    }
+
+    /// Stand in for the player starting a track, returning the token a skip
+    /// cancels.
+    #[cfg(test)]
+    pub(crate) async fn start_test_track(&self) -> CancellationToken {
+        let token = CancellationToken::new();
+        *self.skip_token.write().await = token.clone();
+        token
+    }
+
+    /// Turn a waiting soft insert into a hard one.
+    pub async fn force_pending_insert(&self) {
+        let forced = match self.pending_insert.lock().unwrap().as_mut() {
+            Some(insert) if !insert.hard => {
+                insert.hard = true;
+                true
+            }
+            _ => false,
+        };
+        if forced {
+            info!("Soft insert tolerance passed, cutting current track");
+            self.skip().await;
+        }
+    }
+
+    pub fn playback_mode(&self) -> PlaybackMode {
+        *self.mode.read().unwrap()
+    }
+
+    pub fn set_playback_mode(&self, mode: PlaybackMode) {
+        *self.mode.write().unwrap() = mode;
+        info!("Playback mode: {:?}", mode);
+        self.send_event(PlayerEvent::PlaybackModeChanged { mode });
+    }
+
+    /// Whether playback has stopped after `stop_after_current`.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Resume playback after a stop. Returns false if it wasn't stopped.
+    pub fn resume(&self) -> bool {
+        let was_stopped = self.stopped.swap(false, std::sync::atomic::Ordering::Relaxed);
+        if was_stopped {
+            info!("Playback resumed");
+            self.send_event(PlayerEvent::PlaybackResumed);
+        }
+        was_stopped
+    }
+
     pub fn now_playing(&self) -> Option<Track> {
         self.now_playing.read().unwrap().clone()
     }
 
+    /// Change the metadata of the track on air. The stream picks it up the
+    /// next time it asks for metadata.
+    pub fn override_now_playing(&self, edit: &MetadataOverride) -> Option<Track> {
+        let mut now_playing = self.now_playing.write().unwrap();
+        let track = now_playing.as_mut()?;
+        track.override_metadata(edit);
+        Some(track.clone())
+    }
+
+    /// Current Icecast listener count, if known.
+    pub fn listeners(&self) -> Option<u64> {
+        *self.listeners.read().unwrap()
+    }
+
+    pub fn set_listeners(&self, listeners: Option<u64>) {
+        *self.listeners.write().unwrap() = listeners;
+        let mut peak = self.peak_listeners.lock().unwrap();
+        *peak = (*peak).max(listeners);
+        metrics::LISTENERS.set(listeners.unwrap_or(0) as i64);
+    }
+
+    /// Start tracking the peak listener count from the current count.
+    fn reset_peak_listeners(&self) {
+        *self.peak_listeners.lock().unwrap() = self.listeners();
+    }
+
+    /// Highest listener count seen since [`Self::reset_peak_listeners`].
+    fn peak_listeners(&self) -> Option<u64> {
+        *self.peak_listeners.lock().unwrap()
+    }
+
     pub fn send_event(&self, event: PlayerEvent) {
-        let _ = self.event_tx.send(event);
+        self.events.lock().unwrap().publish(event, auth::current_actor());
+    }
+
+    /// Receive events as they happen.
+    pub fn subscribe(&self) -> broadcast::Receiver<SequencedEvent> {
+        self.events.lock().unwrap().subscribe()
     }
-}
 
-fn unix_now() -> u64 {
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+    /// Receive events after `last_id`. Buffered events between `last_id`
+    /// and now are returned to be delivered first, or `None` if they are
+    /// no longer all available.
+    pub fn subscribe_since(
+        &self,
+        last_id: u64,
+    ) -> (Option<Vec<SequencedEvent>>, broadcast::Receiver<SequencedEvent>) {
+        let events = self.events.lock().unwrap();
+        (events.since(last_id), events.subscribe())
+    }
+
+    /// Buffered events after `last_id`, see [`EventLog::since`].
+    pub fn events_since(&self, last_id: u64) -> Option<Vec<SequencedEvent>> {
+        self.events.lock().unwrap().since(last_id)
+    }
+
+    /// ID of the latest event.
+    pub fn last_event_id(&self) -> u64 {
+        self.events.lock().unwrap().last_id()
+    }
 }
 
 pub async fn run_player(
@@ -82,14 +478,46 @@ pub async fn run_player(
 ) {
     info!("Player task started");
 
+    // Failed attempts per queue entry, for retries
+    let mut failures: HashMap<u64, u32> = HashMap::new();
+
     loop {
         if shutdown.is_cancelled() {
             break;
         }
 
-        let track = {
-            let mut q = handle.queue.write().await;
-            q.pop_front()
+        // Timed inserts come first; jingles only go between tracks, never
+        // into silence
+        let insert = handle.pending_insert.lock().unwrap().take().map(|i| i.track);
+        if insert.is_none() && handle.is_stopped() {
+            sleep(Duration::from_millis(200)).await;
+            continue;
+        }
+        let jingle = if insert.is_some() || handle.queue.read().await.is_empty() {
+            None
+        } else {
+            handle
+                .jingles
+                .lock()
+                .unwrap()
+                .next_jingle(unix_now(), |path| handle.is_quarantined(path))
+        };
+
+        let track = match insert.or(jingle) {
+            Some(track) => Some(track),
+            None => {
+                let mut q = handle.queue.write().await;
+                let track = q.pop_front();
+                if let Some(t) = &track {
+                    q.set_current(track.clone());
+                    handle.send_event(PlayerEvent::queue_changed(
+                        &q,
+                        QueueChangeKind::Played,
+                        vec![TrackPosition::new(t.id, 0)],
+                    ));
+                }
+                track
+            }
         };
 
         let track = match track {
@@ -110,92 +538,372 @@ pub async fn run_player(
 
         *handle.now_playing.write().unwrap() = Some(track.clone());
         let started_at = unix_now();
+        handle.reset_peak_listeners();
         handle.send_event(PlayerEvent::TrackStarted(track.clone()));
 
         let track_token = CancellationToken::new();
         *handle.skip_token.write().await = track_token.clone();
 
-        let was_skipped = stream_file(&track.path, &input_tx, &track_token, &shutdown).await;
+        let end = stream_file(&track.path, &input_tx, &track_token, &shutdown).await;
         let duration_secs = unix_now() - started_at;
+        // Its metadata may have been edited while it played
+        let track = handle.now_playing().filter(|t| t.id == track.id).unwrap_or(track);
 
-        if was_skipped {
-            metrics::TRACKS_SKIPPED.inc();
-            handle.send_event(PlayerEvent::TrackSkipped {
-                track: track.clone(),
-                duration_secs,
-            });
-            info!("Skipped: {}", track.title);
-        } else {
-            handle.send_event(PlayerEvent::TrackFinished {
-                track: track.clone(),
-                duration_secs,
-            });
+        // Leave the interrupted track recorded as current so it can be
+        // resumed after a restart
+        if shutdown.is_cancelled() {
+            break;
         }
 
-        metrics::TRACKS_PLAYED.inc();
+        let mode = handle.playback_mode();
+        if let StreamEnd::Failed(error) = end {
+            fail_track(&handle, &track, error, started_at, &mut failures, &shutdown).await;
+        } else {
+            let was_skipped = end == StreamEnd::Skipped;
+            failures.remove(&track.id);
 
-        // Record history
-        {
-            let mut history = handle.history.write().unwrap();
-            history.push(HistoryEntry {
-                track: track.clone(),
-                started_at,
-                duration_secs,
-                skipped: was_skipped,
-            });
-            // Keep last 1000 entries
-            let len = history.len();
-            if len > 1000 {
-                history.drain(..len - 1000);
+            // A hard insert cut this track short
+            let cut_by_insert = match handle.pending_insert.lock().unwrap().as_ref() {
+                Some(insert) if was_skipped && insert.hard => Some(insert.cut_track),
+                _ => None,
+            };
+            // A copy of the file may have been quarantined while this played
+            let requeue = requeue_for(mode, &track, was_skipped, cut_by_insert)
+                .filter(|_| !handle.is_quarantined(&track.path));
+            if let Some(requeue) = requeue {
+                let mut q = handle.queue.write().await;
+                let requeued = match requeue {
+                    Requeue::Resume => track.clone(),
+                    Requeue::Front | Requeue::Back => track.with_new_id(),
+                };
+                match requeue {
+                    Requeue::Resume | Requeue::Front => q.push_front(requeued.clone()),
+                    Requeue::Back => q.push_back(requeued.clone()),
+                }
+                metrics::QUEUE_LENGTH.set(q.len() as i64);
+                handle.send_event(PlayerEvent::tracks_added(&q, QueueChangeKind::Added, &[requeued]));
+            }
+
+            if was_skipped {
+                metrics::TRACKS_SKIPPED.inc();
+                handle.send_event(PlayerEvent::TrackSkipped {
+                    track: track.clone(),
+                    duration_secs,
+                });
+                info!("Skipped: {}", track.title);
+            } else {
+                handle.send_event(PlayerEvent::TrackFinished {
+                    track: track.clone(),
+                    duration_secs,
+                });
+            }
+
+            metrics::TRACKS_PLAYED.inc();
+            if track.source == TrackSource::Queue {
+                handle.jingles.lock().unwrap().track_played();
             }
+
+            // Record history
+            let mut entry = HistoryEntry::new(track.clone(), started_at, duration_secs, was_skipped);
+            entry.listeners = handle.peak_listeners();
+            handle.history.write().unwrap().record(entry);
         }
 
         *handle.now_playing.write().unwrap() = None;
+        handle.queue.write().await.set_current(None);
+
+        // Stopping is one-shot: resuming goes back to normal playback
+        if mode == PlaybackMode::StopAfterCurrent {
+            handle.stopped.store(true, std::sync::atomic::Ordering::Relaxed);
+            handle.set_playback_mode(PlaybackMode::Normal);
+            info!("Playback stopped after {}", track.title);
+            handle.send_event(PlayerEvent::PlaybackStopped);
+        }
     }
 
     debug!("Player task finished");
 }
 
+/// Wait before a retry, multiplied by the number of failed attempts.
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Record a track that could not be played and act on the failure policy.
+async fn fail_track(
+    handle: &PlayerHandle,
+    track: &Track,
+    error: TrackError,
+    started_at: u64,
+    failures: &mut HashMap<u64, u32>,
+    shutdown: &CancellationToken,
+) {
+    let attempts = {
+        let count = failures.entry(track.id).or_insert(0);
+        *count += 1;
+        *count
+    };
+    let action = failure_action(handle.failure_policy, track, attempts);
+    if action != FailureAction::Retry {
+        failures.remove(&track.id);
+    }
+
+    metrics::TRACKS_FAILED.inc();
+    handle.send_event(PlayerEvent::TrackFailed {
+        track: track.clone(),
+        error_code: error.code,
+        message: error.message.clone(),
+        action,
+    });
+
+    let mut entry = HistoryEntry::new(track.clone(), started_at, 0, false);
+    entry.error = Some(error.clone());
+    handle.history.write().unwrap().record(entry);
+
+    match action {
+        FailureAction::Retry => {
+            // Give a file that is still being copied or remounted a moment
+            let delay = RETRY_BACKOFF * attempts;
+            info!("Retrying {} in {:?} (attempt {} failed)", track.title, delay, attempts);
+            tokio::select! {
+                _ = sleep(delay) => {}
+                _ = shutdown.cancelled() => {}
+            }
+            let mut q = handle.queue.write().await;
+            q.push_front(track.clone());
+            metrics::QUEUE_LENGTH.set(q.len() as i64);
+            handle.send_event(PlayerEvent::tracks_added(
+                &q,
+                QueueChangeKind::Added,
+                std::slice::from_ref(track),
+            ));
+        }
+        FailureAction::Quarantine => {
+            warn!("Quarantined {} after {} failed attempts", track.path.display(), attempts);
+            handle.quarantine.write().unwrap().add(track.clone(), error, attempts, unix_now());
+        }
+        FailureAction::Skip => {}
+    }
+}
+
+/// How streaming a file ended.
+#[derive(Debug, PartialEq, Eq)]
+enum StreamEnd {
+    /// Played to the end, or stopped by shutdown
+    Finished,
+    Skipped,
+    Failed(TrackError),
+}
+
+fn track_error(code: ErrorCode, message: String) -> StreamEnd {
+    error!("{}", message);
+    StreamEnd::Failed(TrackError { code: code.as_u32(), message })
+}
+
 async fn stream_file(
     path: &std::path::Path,
     input_tx: &mpsc::Sender<Bytes>,
     skip_token: &CancellationToken,
     shutdown: &CancellationToken,
-) -> bool {
+) -> StreamEnd {
     let mut file = match File::open(path).await {
         Ok(f) => f,
         Err(e) => {
-            error!("Failed to open file {}: {}", path.display(), e);
-            return false;
+            let code = match e.kind() {
+                std::io::ErrorKind::NotFound => ErrorCode::FileNotFound,
+                std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+                _ => ErrorCode::FileReadFailed,
+            };
+            return track_error(code, format!("Failed to open file {}: {}", path.display(), e));
         }
     };
 
     let mut buf = [0u8; 8192];
+    let mut streamed = 0;
 
     loop {
         tokio::select! {
             _ = skip_token.cancelled() => {
-                return true;
+                return StreamEnd::Skipped;
             }
             _ = shutdown.cancelled() => {
-                return false;
+                return StreamEnd::Finished;
             }
             result = file.read(&mut buf) => {
                 match result {
-                    Ok(0) => return false,
+                    Ok(0) if streamed == 0 => {
+                        return track_error(ErrorCode::InvalidFileFormat, format!("File {} is empty", path.display()));
+                    }
+                    Ok(0) => return StreamEnd::Finished,
                     Ok(n) => {
+                        streamed += n;
                         let data = Bytes::copy_from_slice(&buf[..n]);
                         if input_tx.send(data).await.is_err() {
                             warn!("oggmux channel closed");
-                            return false;
+                            return StreamEnd::Finished;
                         }
                     }
                     Err(e) => {
-                        error!("Error reading file {}: {}", path.display(), e);
-                        return false;
+                        return track_error(
+                            ErrorCode::FileReadFailed,
+                            format!("Error reading file {}: {}", path.display(), e),
+                        );
                     }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn track(source: TrackSource) -> Track {
+        Track {
+            id: 1,
+            path: PathBuf::from("/music/a.ogg"),
+            title: "a".to_string(),
+            artist: None,
+            source,
+            requested_by: None,
+            tags: Default::default(),
+            overrides: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_repeat_modes() {
+        let queued = track(TrackSource::Queue);
+        assert_eq!(requeue_for(PlaybackMode::Normal, &queued, false, None), None);
+        assert_eq!(
+            requeue_for(PlaybackMode::RepeatQueue, &queued, true, None),
+            Some(Requeue::Back)
+        );
+        assert_eq!(
+            requeue_for(PlaybackMode::RepeatOne, &queued, false, None),
+            Some(Requeue::Front)
+        );
+        assert_eq!(requeue_for(PlaybackMode::RepeatOne, &queued, true, None), None);
+        assert_eq!(requeue_for(PlaybackMode::StopAfterCurrent, &queued, false, None), None);
+    }
+
+    #[test]
+    fn test_repeat_ignores_jingles_and_inserts() {
+        for source in [TrackSource::Jingle, TrackSource::Insert] {
+            let t = track(source);
+            assert_eq!(requeue_for(PlaybackMode::RepeatQueue, &t, false, None), None);
+            assert_eq!(
+                requeue_for(PlaybackMode::Normal, &t, true, Some(CutPolicy::Requeue)),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn test_insert_cut_takes_precedence() {
+        let queued = track(TrackSource::Queue);
+        assert_eq!(
+            requeue_for(PlaybackMode::RepeatQueue, &queued, true, Some(CutPolicy::Requeue)),
+            Some(Requeue::Resume)
+        );
+        assert_eq!(
+            requeue_for(PlaybackMode::RepeatQueue, &queued, true, Some(CutPolicy::Drop)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_failure_policy() {
+        let queued = track(TrackSource::Queue);
+        let policy = FailurePolicy { retries: 2, quarantine: false };
+        assert_eq!(failure_action(policy, &queued, 1), FailureAction::Retry);
+        assert_eq!(failure_action(policy, &queued, 2), FailureAction::Retry);
+        assert_eq!(failure_action(policy, &queued, 3), FailureAction::Skip);
+
+        let policy = FailurePolicy { retries: 1, quarantine: true };
+        assert_eq!(failure_action(policy, &queued, 2), FailureAction::Quarantine);
+        assert_eq!(failure_action(policy, &track(TrackSource::Jingle), 1), FailureAction::Quarantine);
+        assert_eq!(failure_action(FailurePolicy::default(), &queued, 1), FailureAction::Skip);
+    }
+
+    #[tokio::test]
+    async fn test_missing_file_is_retried_then_quarantined() {
+        let mut queue = Queue::default();
+        queue.push_back(Track {
+            path: PathBuf::from("/nonexistent/snowboot-test.ogg"),
+            ..track(TrackSource::Queue)
+        });
+        let handle = PlayerHandle::new(Arc::new(RwLock::new(queue)))
+            .with_failure_policy(FailurePolicy { retries: 1, quarantine: true });
+        let mut events = handle.subscribe();
+        let (input_tx, _input_rx) = mpsc::channel(16);
+        let shutdown = CancellationToken::new();
+        let player = tokio::spawn(run_player(handle.clone(), input_tx, shutdown.clone()));
+
+        let mut actions = Vec::new();
+        while actions.len() < 2 {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match event.event {
+                PlayerEvent::TrackFailed { error_code, action, .. } => {
+                    assert_eq!(error_code, ErrorCode::FileNotFound.as_u32());
+                    actions.push(action);
+                }
+                PlayerEvent::TrackFinished { .. } => panic!("failed track reported as finished"),
+                _ => {}
+            }
+        }
+        shutdown.cancel();
+        player.await.unwrap();
+
+        assert_eq!(actions, vec![FailureAction::Retry, FailureAction::Quarantine]);
+        assert!(handle.queue.read().await.is_empty());
+        let quarantined = handle.quarantine.read().unwrap().list().to_vec();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].failures, 2);
+        let history = handle.history.read().unwrap();
+        assert_eq!(history.entries().len(), 2);
+        assert!(history.entries().iter().all(|e| e.error.is_some()));
+    }
+
+    #[test]
+    fn test_override_now_playing() {
+        let handle = PlayerHandle::new(Arc::new(RwLock::new(Queue::default())));
+        let edit = MetadataOverride { title: Some("Live".to_string()), ..Default::default() };
+        assert!(handle.override_now_playing(&edit).is_none());
+
+        *handle.now_playing.write().unwrap() = Some(track(TrackSource::Queue));
+        let updated = handle.override_now_playing(&edit).unwrap();
+        assert_eq!(updated.title, "Live");
+        let allowed = vec!["TITLE".to_string()];
+        assert_eq!(
+            handle.now_playing().unwrap().metadata_comments(&allowed),
+            vec![("TITLE".to_string(), "Live".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_resume_only_when_stopped() {
+        let handle = PlayerHandle::new(Arc::new(RwLock::new(Queue::default())));
+        assert!(!handle.resume());
+        handle.stopped.store(true, std::sync::atomic::Ordering::Relaxed);
+        assert!(handle.resume());
+        assert!(!handle.is_stopped());
+    }
+
+    #[test]
+    fn test_peak_listeners() {
+        let handle = PlayerHandle::new(Arc::new(RwLock::new(Queue::default())));
+        handle.set_listeners(Some(5));
+        handle.reset_peak_listeners();
+        handle.set_listeners(Some(12));
+        handle.set_listeners(None);
+        handle.set_listeners(Some(3));
+        assert_eq!(handle.peak_listeners(), Some(12));
+
+        handle.reset_peak_listeners();
+        assert_eq!(handle.peak_listeners(), Some(3));
+    }
+}
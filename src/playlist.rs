@@ -0,0 +1,209 @@
+// Named, saved playlists
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::history::unix_now;
+use crate::queue::Track;
+use crate::store;
+
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Playlist {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub tracks: Vec<Track>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+/// Playlist listing without the track list.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlaylistSummary {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub track_count: usize,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl From<&Playlist> for PlaylistSummary {
+    fn from(p: &Playlist) -> Self {
+        Self {
+            name: p.name.clone(),
+            description: p.description.clone(),
+            track_count: p.tracks.len(),
+            created_at: p.created_at,
+            updated_at: p.updated_at,
+        }
+    }
+}
+
+/// How a playlist is added to the queue.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnqueueMode {
+    /// Add after the existing queue
+    #[default]
+    Append,
+    /// Add before the existing queue
+    Prepend,
+    /// Clear the queue first
+    Replace,
+    /// Add after the existing queue in random order
+    Shuffle,
+}
+
+#[derive(Debug, Default)]
+pub struct PlaylistStore {
+    playlists: BTreeMap<String, Playlist>,
+    state_path: Option<PathBuf>,
+}
+
+impl PlaylistStore {
+    /// Create a store backed by a state file, loading any saved playlists.
+    pub fn with_state_file(path: PathBuf) -> Self {
+        let playlists: Vec<Playlist> = match store::read_json(&path) {
+            Ok(Some(p)) => p,
+            Ok(None) => Vec::new(),
+            Err(e) => {
+                warn!("Ignoring unreadable playlists {}: {}", path.display(), e);
+                Vec::new()
+            }
+        };
+
+        if !playlists.is_empty() {
+            info!("Loaded {} saved playlists", playlists.len());
+        }
+
+        Self {
+            playlists: playlists.into_iter().map(|p| (p.name.clone(), p)).collect(),
+            state_path: Some(path),
+        }
+    }
+
+    fn persist(&self) {
+        let Some(ref path) = self.state_path else {
+            return;
+        };
+        let playlists: Vec<&Playlist> = self.playlists.values().collect();
+        if let Err(e) = store::write_json_atomic(path, &playlists) {
+            warn!("Failed to write playlists {}: {}", path.display(), e);
+        }
+    }
+
+    pub fn list(&self) -> Vec<PlaylistSummary> {
+        self.playlists.values().map(PlaylistSummary::from).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Playlist> {
+        self.playlists.get(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.playlists.contains_key(name)
+    }
+
+    /// Create or overwrite a playlist, keeping its creation time if it
+    /// already existed.
+    pub fn save(&mut self, name: &str, description: Option<String>, tracks: Vec<Track>) -> Playlist {
+        let now = unix_now();
+        let created_at = self.playlists.get(name).map(|p| p.created_at).unwrap_or(now);
+        let playlist = Playlist {
+            name: name.to_string(),
+            description,
+            tracks,
+            created_at,
+            updated_at: now,
+        };
+        self.playlists.insert(name.to_string(), playlist.clone());
+        self.persist();
+        playlist
+    }
+
+    pub fn delete(&mut self, name: &str) -> Option<Playlist> {
+        let removed = self.playlists.remove(name);
+        if removed.is_some() {
+            self.persist();
+        }
+        removed
+    }
+}
+
+pub type SharedPlaylists = Arc<RwLock<PlaylistStore>>;
+
+/// Playlist names appear in URLs, so keep them to a safe character set.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        && name != "."
+        && name != ".."
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn track(title: &str) -> Track {
+        Track {
+            id: 1,
+            path: PathBuf::from(format!("/music/{}.ogg", title)),
+            title: title.to_string(),
+            artist: None,
+            source: Default::default(),
+            requested_by: None,
+            tags: Default::default(),
+            overrides: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_valid_name() {
+        assert!(valid_name("breakfast-show_2024.v2"));
+        assert!(!valid_name(""));
+        assert!(!valid_name(".."));
+        assert!(!valid_name("a/b"));
+        assert!(!valid_name("with space"));
+        assert!(!valid_name(&"x".repeat(MAX_NAME_LEN + 1)));
+    }
+
+    #[test]
+    fn test_save_keeps_created_at() {
+        let mut store = PlaylistStore::default();
+        let first = store.save("show", None, vec![track("a")]);
+        let second = store.save("show", Some("Updated".to_string()), vec![track("b")]);
+        assert_eq!(first.created_at, second.created_at);
+        assert_eq!(store.get("show").unwrap().tracks[0].title, "b");
+        assert_eq!(store.list().len(), 1);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("playlists.json");
+
+        {
+            let mut store = PlaylistStore::with_state_file(path.clone());
+            store.save("one", None, vec![track("a"), track("b")]);
+            store.save("two", None, vec![]);
+            store.delete("two");
+        }
+
+        let store = PlaylistStore::with_state_file(path);
+        assert!(store.contains("one"));
+        assert!(!store.contains("two"));
+        assert_eq!(store.get("one").unwrap().tracks.len(), 2);
+    }
+}
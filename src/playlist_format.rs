@@ -0,0 +1,463 @@
+// Playlist file formats: M3U/M3U8, PLS and XSPF
+
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaylistFormat {
+    M3u,
+    M3u8,
+    Pls,
+    Xspf,
+}
+
+impl PlaylistFormat {
+    /// Guess the format from a file extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "m3u" => Some(Self::M3u),
+            "m3u8" => Some(Self::M3u8),
+            "pls" => Some(Self::Pls),
+            "xspf" => Some(Self::Xspf),
+            _ => None,
+        }
+    }
+
+    /// Guess the format from the playlist content.
+    pub fn detect(content: &str) -> Self {
+        let head = content.trim_start_matches('\u{feff}').trim_start();
+        if head.starts_with("<?xml") || head.starts_with("<playlist") {
+            Self::Xspf
+        } else if head.to_lowercase().starts_with("[playlist]") {
+            Self::Pls
+        } else {
+            Self::M3u
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::M3u => "audio/x-mpegurl",
+            Self::M3u8 => "audio/x-mpegurl; charset=utf-8",
+            Self::Pls => "audio/x-scpls",
+            Self::Xspf => "application/xspf+xml",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::M3u => "m3u",
+            Self::M3u8 => "m3u8",
+            Self::Pls => "pls",
+            Self::Xspf => "xspf",
+        }
+    }
+}
+
+/// One entry read from, or written to, a playlist file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PlaylistEntry {
+    /// Path or URL exactly as it appears in the playlist
+    pub location: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub duration_secs: Option<u64>,
+}
+
+impl PlaylistEntry {
+    /// Resolve the location to a local path, relative to `base_dir`.
+    ///
+    /// Returns `None` for remote URLs, which cannot be queued.
+    pub fn resolve(&self, base_dir: Option<&Path>) -> Option<PathBuf> {
+        let location = self.location.trim();
+        let path = if let Some(rest) = location.strip_prefix("file://") {
+            // file:///abs/path or file://localhost/abs/path
+            let rest = rest.strip_prefix("localhost").unwrap_or(rest);
+            PathBuf::from(percent_decode(rest))
+        } else if location.contains("://") {
+            return None;
+        } else {
+            PathBuf::from(location)
+        };
+
+        if path.is_relative() {
+            base_dir.map(|base| base.join(&path))
+        } else {
+            Some(path)
+        }
+    }
+}
+
+/// Parse playlist content in the given format.
+pub fn parse(content: &str, format: PlaylistFormat) -> Vec<PlaylistEntry> {
+    let content = content.trim_start_matches('\u{feff}');
+    match format {
+        PlaylistFormat::M3u | PlaylistFormat::M3u8 => parse_m3u(content),
+        PlaylistFormat::Pls => parse_pls(content),
+        PlaylistFormat::Xspf => parse_xspf(content),
+    }
+}
+
+/// Render entries as a playlist in the given format.
+pub fn render(entries: &[PlaylistEntry], format: PlaylistFormat) -> String {
+    match format {
+        PlaylistFormat::M3u | PlaylistFormat::M3u8 => render_m3u(entries),
+        PlaylistFormat::Pls => render_pls(entries),
+        PlaylistFormat::Xspf => render_xspf(entries),
+    }
+}
+
+fn parse_m3u(content: &str) -> Vec<PlaylistEntry> {
+    let mut entries = Vec::new();
+    let mut pending: Option<PlaylistEntry> = None;
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<seconds>[ attributes],<Artist - Title>
+            let (duration, display) = info.split_once(',').unwrap_or((info, ""));
+            let duration = duration
+                .split_whitespace()
+                .next()
+                .and_then(|d| d.parse::<i64>().ok())
+                .and_then(|d| u64::try_from(d).ok());
+            let (artist, title) = match display.split_once(" - ") {
+                Some((artist, title)) => (Some(artist.trim().to_string()), title.trim().to_string()),
+                None => (None, display.trim().to_string()),
+            };
+            pending = Some(PlaylistEntry {
+                location: String::new(),
+                title: Some(title).filter(|t| !t.is_empty()),
+                artist: artist.filter(|a| !a.is_empty()),
+                duration_secs: duration,
+            });
+        } else if line.starts_with('#') {
+            continue;
+        } else {
+            let mut entry = pending.take().unwrap_or_default();
+            entry.location = line.to_string();
+            entries.push(entry);
+        }
+    }
+
+    entries
+}
+
+fn parse_pls(content: &str) -> Vec<PlaylistEntry> {
+    // Keys are numbered (File1, Title1, ...) and may appear in any order
+    let mut entries: Vec<(u32, PlaylistEntry)> = Vec::new();
+
+    for line in content.lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_lowercase();
+        let value = value.trim().to_string();
+
+        let (field, n) = match key.find(|c: char| c.is_ascii_digit()) {
+            Some(i) => (&key[..i], key[i..].parse::<u32>().ok()),
+            None => continue,
+        };
+        let Some(n) = n else { continue };
+
+        let pos = match entries.iter().position(|(i, _)| *i == n) {
+            Some(pos) => pos,
+            None => {
+                entries.push((n, PlaylistEntry::default()));
+                entries.len() - 1
+            }
+        };
+        let entry = &mut entries[pos].1;
+        match field {
+            "file" => entry.location = value,
+            "title" => entry.title = Some(value).filter(|v| !v.is_empty()),
+            "length" => entry.duration_secs = value.parse::<u64>().ok(),
+            _ => {}
+        }
+    }
+
+    entries.sort_by_key(|(n, _)| *n);
+    entries
+        .into_iter()
+        .map(|(_, e)| e)
+        .filter(|e| !e.location.is_empty())
+        .collect()
+}
+
+fn parse_xspf(content: &str) -> Vec<PlaylistEntry> {
+    let mut entries = Vec::new();
+    let mut rest = content;
+
+    while let Some(start) = find_tag(rest, "track") {
+        let body_start = match rest[start..].find('>') {
+            Some(i) => start + i + 1,
+            None => break,
+        };
+        let body_end = match rest[body_start..].find("</track>") {
+            Some(i) => body_start + i,
+            None => break,
+        };
+        let body = &rest[body_start..body_end];
+        rest = &rest[body_end + "</track>".len()..];
+
+        let Some(location) = xml_element_text(body, "location") else {
+            continue;
+        };
+        entries.push(PlaylistEntry {
+            location,
+            title: xml_element_text(body, "title"),
+            artist: xml_element_text(body, "creator"),
+            duration_secs: xml_element_text(body, "duration")
+                .and_then(|d| d.parse::<u64>().ok())
+                .map(|ms| ms / 1000),
+        });
+    }
+
+    entries
+}
+
+/// Find the next `<name>` or `<name ...>` opening tag.
+fn find_tag(haystack: &str, name: &str) -> Option<usize> {
+    let open = format!("<{}", name);
+    let mut offset = 0;
+    while let Some(i) = haystack[offset..].find(&open) {
+        let at = offset + i;
+        match haystack[at + open.len()..].chars().next() {
+            Some('>') | Some(' ') | Some('\t') | Some('\n') | Some('\r') => return Some(at),
+            _ => offset = at + open.len(),
+        }
+    }
+    None
+}
+
+fn xml_element_text(body: &str, name: &str) -> Option<String> {
+    let start = find_tag(body, name)?;
+    let text_start = start + body[start..].find('>')? + 1;
+    let text_end = text_start + body[text_start..].find(&format!("</{}>", name))?;
+    let text = body[text_start..text_end].trim();
+    let text = text
+        .strip_prefix("<![CDATA[")
+        .and_then(|t| t.strip_suffix("]]>"))
+        .map(str::to_string)
+        .unwrap_or_else(|| xml_unescape(text));
+    Some(text).filter(|t| !t.is_empty())
+}
+
+fn render_m3u(entries: &[PlaylistEntry]) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    for e in entries {
+        let duration = e.duration_secs.map(|d| d as i64).unwrap_or(-1);
+        let display = match (&e.artist, &e.title) {
+            (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+            (None, Some(title)) => title.clone(),
+            _ => String::new(),
+        };
+        out.push_str(&format!("#EXTINF:{},{}\n{}\n", duration, display, e.location));
+    }
+    out
+}
+
+fn render_pls(entries: &[PlaylistEntry]) -> String {
+    let mut out = String::from("[playlist]\n");
+    for (i, e) in entries.iter().enumerate() {
+        let n = i + 1;
+        out.push_str(&format!("File{}={}\n", n, e.location));
+        if let Some(ref title) = e.title {
+            let title = match e.artist {
+                Some(ref artist) => format!("{} - {}", artist, title),
+                None => title.clone(),
+            };
+            out.push_str(&format!("Title{}={}\n", n, title));
+        }
+        let length = e.duration_secs.map(|d| d as i64).unwrap_or(-1);
+        out.push_str(&format!("Length{}={}\n", n, length));
+    }
+    out.push_str(&format!("NumberOfEntries={}\nVersion=2\n", entries.len()));
+    out
+}
+
+fn render_xspf(entries: &[PlaylistEntry]) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n  <trackList>\n",
+    );
+    for e in entries {
+        out.push_str("    <track>\n");
+        out.push_str(&format!("      <location>{}</location>\n", xml_escape(&file_url(&e.location))));
+        if let Some(ref title) = e.title {
+            out.push_str(&format!("      <title>{}</title>\n", xml_escape(title)));
+        }
+        if let Some(ref artist) = e.artist {
+            out.push_str(&format!("      <creator>{}</creator>\n", xml_escape(artist)));
+        }
+        if let Some(duration) = e.duration_secs {
+            out.push_str(&format!("      <duration>{}</duration>\n", duration * 1000));
+        }
+        out.push_str("    </track>\n");
+    }
+    out.push_str("  </trackList>\n</playlist>\n");
+    out
+}
+
+/// XSPF locations are URIs, so absolute paths become `file://` URLs.
+fn file_url(location: &str) -> String {
+    if !location.starts_with('/') {
+        return location.to_string();
+    }
+    let mut url = String::from("file://");
+    for b in location.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                url.push(b as char)
+            }
+            _ => url.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    url
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(b) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn xml_unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let Some(end) = rest.find(';') else { break };
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_m3u_extinf() {
+        let content = "#EXTM3U\n#EXTINF:215,Artist - Song Title\nmusic/song.ogg\n\n# comment\n/abs/other.ogg\n";
+        let entries = parse(content, PlaylistFormat::detect(content));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].location, "music/song.ogg");
+        assert_eq!(entries[0].artist.as_deref(), Some("Artist"));
+        assert_eq!(entries[0].title.as_deref(), Some("Song Title"));
+        assert_eq!(entries[0].duration_secs, Some(215));
+        assert_eq!(entries[1].title, None);
+    }
+
+    #[test]
+    fn test_parse_pls() {
+        let content = "[playlist]\nFile2=b.ogg\nFile1=a.ogg\nTitle1=First\nLength1=-1\nNumberOfEntries=2\n";
+        let entries = parse(content, PlaylistFormat::detect(content));
+        let locations: Vec<&str> = entries.iter().map(|e| e.location.as_str()).collect();
+        assert_eq!(locations, vec!["a.ogg", "b.ogg"]);
+        assert_eq!(entries[0].title.as_deref(), Some("First"));
+        assert_eq!(entries[0].duration_secs, None);
+    }
+
+    #[test]
+    fn test_parse_xspf() {
+        let content = r#"<?xml version="1.0" encoding="UTF-8"?>
+<playlist version="1" xmlns="http://xspf.org/ns/0/">
+  <trackList>
+    <track>
+      <location>file:///srv/music/Rock%20%26%20Roll.ogg</location>
+      <title>Rock &amp; Roll</title>
+      <creator>Band</creator>
+      <duration>180000</duration>
+    </track>
+    <track><location>relative.ogg</location></track>
+  </trackList>
+</playlist>"#;
+        let entries = parse(content, PlaylistFormat::detect(content));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].title.as_deref(), Some("Rock & Roll"));
+        assert_eq!(entries[0].duration_secs, Some(180));
+        assert_eq!(
+            entries[0].resolve(None),
+            Some(PathBuf::from("/srv/music/Rock & Roll.ogg"))
+        );
+    }
+
+    #[test]
+    fn test_resolve_relative_and_remote() {
+        let entry = PlaylistEntry { location: "sub/a.ogg".to_string(), ..Default::default() };
+        assert_eq!(entry.resolve(Some(Path::new("/lists"))), Some(PathBuf::from("/lists/sub/a.ogg")));
+        assert_eq!(entry.resolve(None), None);
+
+        let remote = PlaylistEntry { location: "http://example.com/a.ogg".to_string(), ..Default::default() };
+        assert_eq!(remote.resolve(Some(Path::new("/lists"))), None);
+    }
+
+    #[test]
+    fn test_render_roundtrip() {
+        let entries = vec![PlaylistEntry {
+            location: "/srv/music/a b.ogg".to_string(),
+            title: Some("Title <1>".to_string()),
+            artist: Some("Artist".to_string()),
+            duration_secs: Some(42),
+        }];
+        for format in [PlaylistFormat::M3u8, PlaylistFormat::Pls, PlaylistFormat::Xspf] {
+            let parsed = parse(&render(&entries, format), format);
+            assert_eq!(parsed.len(), 1, "{:?}", format);
+            assert_eq!(parsed[0].resolve(None), Some(PathBuf::from("/srv/music/a b.ogg")));
+            assert_eq!(parsed[0].duration_secs, Some(42));
+        }
+    }
+}
@@ -0,0 +1,180 @@
+// Tracks set aside after failing to play
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::history::TrackError;
+use crate::queue::Track;
+use crate::store;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarantinedTrack {
+    pub id: u64,
+    pub track: Track,
+    /// The last failure
+    pub error: TrackError,
+    /// Attempts to play the track, including retries
+    pub failures: u32,
+    pub quarantined_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct QuarantineState {
+    next_id: u64,
+    tracks: Vec<QuarantinedTrack>,
+}
+
+/// Tracks the player gave up on, kept for someone to look into.
+#[derive(Debug)]
+pub struct Quarantine {
+    tracks: Vec<QuarantinedTrack>,
+    next_id: u64,
+    /// Saves the list off the async threads, if a state file is configured
+    writer: Option<store::StateWriter<QuarantineState>>,
+}
+
+impl Quarantine {
+    /// Create a quarantine saved to `path`.
+    pub fn with_state_file(path: PathBuf) -> Self {
+        let state: Option<QuarantineState> = store::read_json(&path).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable quarantine {}: {}", path.display(), e);
+            None
+        });
+        let mut quarantine = Self::default();
+        if let Some(state) = state {
+            if !state.tracks.is_empty() {
+                info!("Loaded {} quarantined tracks", state.tracks.len());
+            }
+            quarantine.next_id = state.next_id;
+            quarantine.tracks = state.tracks;
+        }
+        quarantine.writer = Some(store::StateWriter::spawn(path, "quarantine"));
+        quarantine
+    }
+
+    /// Save a snapshot to the state file, if one is configured. The write
+    /// happens in the background.
+    fn persist(&self) {
+        let Some(ref writer) = self.writer else {
+            return;
+        };
+        writer.write(QuarantineState {
+            next_id: self.next_id,
+            tracks: self.tracks.clone(),
+        });
+    }
+
+    /// Wait until the latest state has been written.
+    pub fn flush(&self) {
+        if let Some(ref writer) = self.writer {
+            writer.flush();
+        }
+    }
+
+    pub fn list(&self) -> &[QuarantinedTrack] {
+        &self.tracks
+    }
+
+    /// Whether the file at `path` is set aside. Quarantined files are not
+    /// queued or played until they are released.
+    pub fn contains(&self, path: &Path) -> bool {
+        self.tracks.iter().any(|t| t.track.path == path)
+    }
+
+    /// Set `track` aside. A file that is already quarantined has its entry
+    /// updated instead.
+    pub fn add(&mut self, track: Track, error: TrackError, failures: u32, now: u64) -> QuarantinedTrack {
+        let entry = match self.tracks.iter_mut().find(|t| t.track.path == track.path) {
+            Some(existing) => {
+                existing.track = track;
+                existing.error = error;
+                existing.failures += failures;
+                existing.quarantined_at = now;
+                existing.clone()
+            }
+            None => {
+                let entry = QuarantinedTrack {
+                    id: self.next_id,
+                    track,
+                    error,
+                    failures,
+                    quarantined_at: now,
+                };
+                self.next_id += 1;
+                self.tracks.push(entry.clone());
+                entry
+            }
+        };
+        self.persist();
+        entry
+    }
+
+    /// Release a track from quarantine.
+    pub fn remove(&mut self, id: u64) -> Option<QuarantinedTrack> {
+        let pos = self.tracks.iter().position(|t| t.id == id)?;
+        let entry = self.tracks.remove(pos);
+        self.persist();
+        Some(entry)
+    }
+}
+
+impl Default for Quarantine {
+    fn default() -> Self {
+        Self {
+            tracks: Vec::new(),
+            next_id: 1,
+            writer: None,
+        }
+    }
+}
+
+pub type SharedQuarantine = Arc<RwLock<Quarantine>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn track(name: &str) -> Track {
+        Track {
+            id: 1,
+            path: PathBuf::from(format!("/music/{}.ogg", name)),
+            title: name.to_string(),
+            artist: None,
+            source: Default::default(),
+            requested_by: None,
+            tags: Default::default(),
+            overrides: Default::default(),
+        }
+    }
+
+    fn error(message: &str) -> TrackError {
+        TrackError { code: 3011, message: message.to_string() }
+    }
+
+    #[test]
+    fn test_add_merges_by_path_and_persists() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("quarantine.json");
+        {
+            let mut quarantine = Quarantine::with_state_file(path.clone());
+            let a = quarantine.add(track("a"), error("first"), 2, 100);
+            quarantine.add(track("b"), error("other"), 1, 100);
+            let again = quarantine.add(track("a"), error("second"), 3, 200);
+            assert_eq!(again.id, a.id);
+            assert_eq!(again.failures, 5);
+            assert_eq!(quarantine.list().len(), 2);
+        }
+        let mut quarantine = Quarantine::with_state_file(path);
+        assert_eq!(quarantine.list()[0].error.message, "second");
+        let b = quarantine.list()[1].id;
+        assert!(quarantine.contains(Path::new("/music/b.ogg")));
+        assert_eq!(quarantine.remove(b).unwrap().track.title, "b");
+        assert!(!quarantine.contains(Path::new("/music/b.ogg")));
+        assert!(quarantine.remove(b).is_none());
+        assert_eq!(quarantine.add(track("c"), error("x"), 1, 300).id, 3);
+    }
+}
@@ -1,12 +1,35 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::store;
 
 static NEXT_TRACK_ID: AtomicU64 = AtomicU64::new(1);
 
+/// Tags sent in-band to listeners unless configured otherwise.
+pub const DEFAULT_STREAM_TAGS: &[&str] = &["TITLE", "ARTIST", "ALBUM", "DATE"];
+
+/// Comments holding embedded cover art, which are too large to keep with
+/// every track.
+const PICTURE_TAGS: &[&str] = &["METADATA_BLOCK_PICTURE", "COVERART"];
+
+/// Where a played track came from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackSource {
+    /// Queued music
+    #[default]
+    Queue,
+    /// Station ID or jingle inserted by the jingle rules
+    Jingle,
+    /// Timed insert such as the news or a time signal
+    Insert,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Track {
     pub id: u64,
@@ -14,11 +37,93 @@ pub struct Track {
     pub title: String,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub artist: Option<String>,
+    #[serde(default)]
+    pub source: TrackSource,
+    /// Name given by the listener who requested this track
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requested_by: Option<String>,
+    /// Every Vorbis comment in the file, keyed by upper-case name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    /// Metadata given through the API in place of the file's own, kept so
+    /// it survives the file being retagged
+    #[serde(default, skip_serializing_if = "MetadataOverride::is_empty")]
+    pub overrides: MetadataOverride,
+}
+
+/// Longest title, artist or tag value accepted as an override.
+pub const MAX_OVERRIDE_LEN: usize = 1024;
+
+/// Metadata to use instead of what a file's tags say.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetadataOverride {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    /// Comments to set; an empty value removes the comment
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl MetadataOverride {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none() && self.artist.is_none() && self.tags.is_empty()
+    }
+
+    /// Check the override can be sent as Vorbis comments. Tag names are
+    /// normalised to upper case, and `TITLE` and `ARTIST` tags become the
+    /// fields of the same name; an empty `ARTIST` tag clears the artist.
+    pub fn validate(mut self) -> Result<Self, String> {
+        let values = self.title.iter().chain(&self.artist).chain(self.tags.values());
+        if values.into_iter().any(|v| v.len() > MAX_OVERRIDE_LEN) {
+            return Err(format!("Metadata values are limited to {} bytes", MAX_OVERRIDE_LEN));
+        }
+        if self.title.as_deref().is_some_and(|t| t.trim().is_empty()) {
+            return Err("Title can't be empty".to_string());
+        }
+        let mut tags = BTreeMap::new();
+        for (name, value) in self.tags {
+            // Vorbis field names are printable ASCII other than '='
+            if name.is_empty() || !name.bytes().all(|b| (0x20..=0x7d).contains(&b) && b != b'=') {
+                return Err(format!("Invalid tag name '{}'", name));
+            }
+            tags.insert(name.to_uppercase(), value);
+        }
+        if let Some(title) = tags.remove("TITLE").filter(|t| !t.trim().is_empty()) {
+            self.title.get_or_insert(title);
+        }
+        if tags.get("ARTIST").is_some_and(|a| !a.is_empty()) {
+            let artist = tags.remove("ARTIST");
+            self.artist = self.artist.take().or(artist);
+        }
+        if self.artist.is_some() {
+            tags.remove("ARTIST");
+        }
+        self.tags = tags;
+        Ok(self)
+    }
+
+    /// Layer `other` on top of this override.
+    pub fn merge(&mut self, other: &MetadataOverride) {
+        if other.title.is_some() {
+            self.title = other.title.clone();
+        }
+        if other.artist.is_some() {
+            self.artist = other.artist.clone();
+            self.tags.remove("ARTIST");
+        }
+        if other.tags.contains_key("ARTIST") {
+            self.artist = None;
+        }
+        self.tags.extend(other.tags.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
 }
 
 impl Track {
     pub fn from_file(path: PathBuf) -> Self {
-        let comments = read_vorbis_comments(&path).unwrap_or_default();
+        let mut comments = read_vorbis_comments(&path).unwrap_or_default();
+        comments.retain(|key, _| !PICTURE_TAGS.contains(&key.as_str()));
 
         let title = comments.get("TITLE")
             .cloned()
@@ -36,25 +141,117 @@ impl Track {
             path,
             title,
             artist,
+            source: TrackSource::Queue,
+            requested_by: None,
+            tags: comments.into_iter().collect(),
+            overrides: MetadataOverride::default(),
+        }
+    }
+
+    /// Apply `edit` to the track's metadata and remember it, so it can be
+    /// applied again if the file's tags are reread.
+    pub fn override_metadata(&mut self, edit: &MetadataOverride) {
+        for (name, value) in &edit.tags {
+            if name == "ARTIST" {
+                self.artist = None;
+            }
+            if value.is_empty() {
+                self.tags.remove(name);
+            } else {
+                self.tags.insert(name.clone(), value.clone());
+            }
+        }
+        if let Some(ref title) = edit.title {
+            self.title = title.clone();
+            self.tags.insert("TITLE".to_string(), title.clone());
         }
+        if let Some(ref artist) = edit.artist {
+            self.artist = Some(artist.clone());
+            self.tags.insert("ARTIST".to_string(), artist.clone());
+        }
+        self.overrides.merge(edit);
     }
 
-    pub fn metadata_comments(&self) -> Vec<(String, String)> {
-        let mut comments = vec![
-            ("TITLE".to_string(), self.title.clone()),
-        ];
-        if let Some(ref artist) = self.artist {
-            comments.push(("ARTIST".to_string(), artist.clone()));
+    /// Copy of this track with a fresh ID, for queueing it again.
+    pub fn with_new_id(&self) -> Self {
+        Self {
+            id: NEXT_TRACK_ID.fetch_add(1, Ordering::Relaxed),
+            ..self.clone()
         }
-        comments
+    }
+
+    /// Value of the comment `name`, which is matched case-insensitively.
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.get(&name.to_uppercase()).map(String::as_str)
+    }
+
+    pub fn album(&self) -> Option<&str> {
+        self.tag("ALBUM")
+    }
+
+    pub fn genre(&self) -> Option<&str> {
+        self.tag("GENRE")
+    }
+
+    /// Release date as tagged, e.g. `1997` or `1997-05-21`.
+    pub fn date(&self) -> Option<&str> {
+        self.tag("DATE")
+    }
+
+    /// Release year, from the start of the `DATE` comment.
+    pub fn year(&self) -> Option<i32> {
+        let date = self.date()?.trim();
+        let digits = date.find(|c: char| !c.is_ascii_digit()).unwrap_or(date.len());
+        if digits != 4 {
+            return None;
+        }
+        date[..4].parse().ok()
+    }
+
+    pub fn isrc(&self) -> Option<&str> {
+        self.tag("ISRC")
+    }
+
+    pub fn comment(&self) -> Option<&str> {
+        self.tag("COMMENT").or_else(|| self.tag("DESCRIPTION"))
+    }
+
+    /// Position on the album, from `TRACKNUMBER` (`3` or `3/12`).
+    pub fn track_number(&self) -> Option<u32> {
+        self.tag("TRACKNUMBER")?.split('/').next()?.trim().parse().ok()
+    }
+
+    /// Comments to send in-band for this track: those named in `allowed`
+    /// that it has, in that order.
+    pub fn metadata_comments(&self, allowed: &[String]) -> Vec<(String, String)> {
+        allowed
+            .iter()
+            .filter_map(|name| {
+                let name = name.to_uppercase();
+                let value = match name.as_str() {
+                    "TITLE" => Some(self.title.clone()),
+                    "ARTIST" => self.artist.clone(),
+                    _ => self.tag(&name).map(str::to_string),
+                }?;
+                Some((name, value))
+            })
+            .collect()
     }
 }
 
-/// Parse vorbis comments from an Ogg Vorbis file.
+/// Vorbis comments from an Ogg Vorbis file by key. The last of a repeated
+/// key wins.
+pub fn read_vorbis_comments(path: &Path) -> Option<HashMap<String, String>> {
+    Some(read_comment_list(path)?.into_iter().collect())
+}
+
+/// Parse vorbis comments from an Ogg Vorbis file, in file order with keys
+/// uppercased. Repeated keys, such as several `METADATA_BLOCK_PICTURE`s,
+/// are all kept.
 ///
 /// Reads the second packet (comment header) which starts with \x03vorbis,
 /// then contains a vendor string followed by key=value comment pairs.
-fn read_vorbis_comments(path: &Path) -> Option<HashMap<String, String>> {
+pub fn read_comment_list(path: &Path) -> Option<Vec<(String, String)>> {
     use ogg::reading::PacketReader;
     use std::fs::File;
     use std::io::BufReader;
@@ -86,7 +283,7 @@ fn read_vorbis_comments(path: &Path) -> Option<HashMap<String, String>> {
     let count = u32::from_le_bytes(data[pos..pos+4].try_into().ok()?) as usize;
     pos += 4;
 
-    let mut comments = HashMap::new();
+    let mut comments = Vec::new();
     for _ in 0..count {
         if pos + 4 > data.len() { break; }
         let len = u32::from_le_bytes(data[pos..pos+4].try_into().ok()?) as usize;
@@ -95,7 +292,7 @@ fn read_vorbis_comments(path: &Path) -> Option<HashMap<String, String>> {
 
         if let Ok(s) = std::str::from_utf8(&data[pos..pos+len]) {
             if let Some((key, value)) = s.split_once('=') {
-                comments.insert(key.to_uppercase(), value.to_string());
+                comments.push((key.to_uppercase(), value.to_string()));
             }
         }
         pos += len;
@@ -104,22 +301,262 @@ fn read_vorbis_comments(path: &Path) -> Option<HashMap<String, String>> {
     Some(comments)
 }
 
+/// Bytes read from the end of a file to find its last Ogg page.
+const DURATION_TAIL_BYTES: u64 = 64 * 1024;
+
+/// Length of an Ogg Vorbis file in seconds.
+///
+/// Takes the sample rate from the identification header and the sample
+/// count from the granule position of the last page.
+pub fn read_duration(path: &Path) -> Option<f64> {
+    use ogg::reading::PacketReader;
+    use std::fs::File;
+    use std::io::{BufReader, Read, Seek, SeekFrom};
+
+    let file = File::open(path).ok()?;
+    let mut reader = PacketReader::new(BufReader::new(file));
+    let ident = reader.read_packet().ok()??;
+    let data = &ident.data;
+    if data.len() < 16 || &data[0..7] != b"\x01vorbis" {
+        return None;
+    }
+    let sample_rate = u32::from_le_bytes(data[12..16].try_into().ok()?);
+    if sample_rate == 0 {
+        return None;
+    }
+
+    let mut file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    let start = len.saturating_sub(DURATION_TAIL_BYTES);
+    file.seek(SeekFrom::Start(start)).ok()?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail).ok()?;
+
+    // Page header: "OggS", version, flags, then the granule position (i64 LE)
+    let granule = (0..tail.len().saturating_sub(14))
+        .rev()
+        .filter(|&i| &tail[i..i + 4] == b"OggS")
+        .map(|i| i64::from_le_bytes(tail[i + 6..i + 14].try_into().unwrap()))
+        .find(|&g| g >= 0)?;
+    Some(granule as f64 / sample_rate as f64)
+}
+
+/// Where a moved track goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveTarget {
+    Position(usize),
+    Before(u64),
+    After(u64),
+}
+
+/// One step of a batch edit, see [`Queue::apply_batch`].
+#[derive(Debug, Clone)]
+pub enum QueueOp {
+    Add(Track),
+    InsertAt(Track, usize),
+    Remove(u64),
+    Move(u64, MoveTarget),
+    Clear,
+}
+
+/// Result of one batch step.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum QueueOpOutcome {
+    Added { track: Track, position: usize },
+    Removed { track: Track },
+    Moved { id: u64, position: usize },
+    Cleared { removed: usize },
+}
+
+/// Why a batch was rejected: the index of the failing step and the
+/// missing track ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueOpError {
+    pub index: usize,
+    pub missing_id: u64,
+}
+
+/// Snapshot of the queue as written to the state file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QueueState {
+    next_track_id: u64,
+    #[serde(default)]
+    revision: u64,
+    #[serde(default)]
+    current: Option<Track>,
+    #[serde(default)]
+    tracks: Vec<Track>,
+}
+
 #[derive(Debug, Default)]
 pub struct Queue {
     tracks: VecDeque<Track>,
+    current: Option<Track>,
+    revision: u64,
+    /// Saves the queue off the async threads, if a state file is configured
+    writer: Option<store::StateWriter<QueueState>>,
+    /// Clients that want the whole queue with every change
+    watchers: Arc<AtomicUsize>,
+}
+
+/// Keeps [`Queue::is_watched`] true while held.
+#[derive(Debug)]
+pub struct QueueWatch(Arc<AtomicUsize>);
+
+impl Drop for QueueWatch {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl Queue {
+    /// Create a queue backed by a state file, restoring any previously saved
+    /// tracks.
+    ///
+    /// Tracks whose files have disappeared since the state was written are
+    /// dropped. If `resume_interrupted` is set, the track that was playing
+    /// when the process stopped is put back at the front of the queue.
+    pub fn with_state_file(path: PathBuf, resume_interrupted: bool) -> Self {
+        let state: QueueState = match store::read_json(&path) {
+            Ok(Some(state)) => state,
+            Ok(None) => QueueState::default(),
+            Err(e) => {
+                warn!("Ignoring unreadable queue state {}: {}", path.display(), e);
+                QueueState::default()
+            }
+        };
+
+        let max_id = state
+            .tracks
+            .iter()
+            .chain(state.current.iter())
+            .map(|t| t.id)
+            .max()
+            .unwrap_or(0);
+        NEXT_TRACK_ID.fetch_max(state.next_track_id.max(max_id + 1), Ordering::Relaxed);
+
+        let mut tracks = VecDeque::new();
+        let interrupted = state.current.filter(|_| resume_interrupted);
+        for track in interrupted.into_iter().chain(state.tracks) {
+            if track.path.is_file() {
+                tracks.push_back(track);
+            } else {
+                warn!("Dropping queued track {}: file no longer exists", track.path.display());
+            }
+        }
+
+        if !tracks.is_empty() {
+            info!("Restored {} queued tracks from {}", tracks.len(), path.display());
+        }
+
+        let queue = Self {
+            tracks,
+            current: None,
+            // Loading may drop tracks, so never reuse a saved revision
+            revision: state.revision + 1,
+            writer: Some(store::StateWriter::spawn(path, "queue state")),
+            watchers: Arc::default(),
+        };
+        queue.persist();
+        queue
+    }
+
+    /// Save a snapshot of the queue to its state file, if one is configured.
+    /// The write happens in the background.
+    fn persist(&self) {
+        let Some(ref writer) = self.writer else {
+            return;
+        };
+
+        writer.write(QueueState {
+            next_track_id: NEXT_TRACK_ID.load(Ordering::Relaxed),
+            revision: self.revision,
+            current: self.current.clone(),
+            tracks: self.tracks.iter().cloned().collect(),
+        });
+    }
+
+    /// Wait until the latest state has been written.
+    pub fn flush(&self) {
+        if let Some(ref writer) = self.writer {
+            writer.flush();
+        }
+    }
+
+    /// Record a change to the track list: bump the revision and persist.
+    fn changed(&mut self) {
+        self.revision += 1;
+        self.persist();
+    }
+
+    /// Ask for queue events to carry the whole queue until the returned
+    /// guard is dropped.
+    pub fn watch(&self) -> QueueWatch {
+        self.watchers.fetch_add(1, Ordering::Relaxed);
+        QueueWatch(self.watchers.clone())
+    }
+
+    /// Whether any client wants the whole queue with every change.
+    pub fn is_watched(&self) -> bool {
+        self.watchers.load(Ordering::Relaxed) > 0
+    }
+
+    /// Revision of the track list, increased by every change to it.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.tracks.iter().any(|t| t.id == id)
+    }
+
+    pub fn position(&self, id: u64) -> Option<usize> {
+        self.tracks.iter().position(|t| t.id == id)
+    }
+
     pub fn push_back(&mut self, track: Track) {
         self.tracks.push_back(track);
+        self.changed();
     }
 
     pub fn push_front(&mut self, track: Track) {
         self.tracks.push_front(track);
+        self.changed();
+    }
+
+    /// Append several tracks, writing the state file once.
+    pub fn extend_back(&mut self, tracks: impl IntoIterator<Item = Track>) {
+        self.tracks.extend(tracks);
+        self.changed();
+    }
+
+    /// Insert several tracks at the front, keeping their order.
+    pub fn extend_front(&mut self, tracks: Vec<Track>) {
+        for track in tracks.into_iter().rev() {
+            self.tracks.push_front(track);
+        }
+        self.changed();
+    }
+
+    /// Replace the whole queue.
+    pub fn replace(&mut self, tracks: Vec<Track>) {
+        self.tracks = tracks.into();
+        self.changed();
     }
 
     pub fn pop_front(&mut self) -> Option<Track> {
-        self.tracks.pop_front()
+        let track = self.tracks.pop_front();
+        if track.is_some() {
+            self.changed();
+        }
+        track
+    }
+
+    /// Record the track currently being played so it survives a restart.
+    pub fn set_current(&mut self, track: Option<Track>) {
+        self.current = track;
+        self.persist();
     }
 
     pub fn list(&self) -> Vec<Track> {
@@ -128,14 +565,51 @@ impl Queue {
 
     pub fn remove(&mut self, id: u64) -> Option<Track> {
         if let Some(pos) = self.tracks.iter().position(|t| t.id == id) {
-            self.tracks.remove(pos)
+            let track = self.tracks.remove(pos);
+            self.changed();
+            track
         } else {
             None
         }
     }
 
+    /// Apply `f` to every queued track, returning the IDs of those it
+    /// changed.
+    pub fn update_tracks(&mut self, mut f: impl FnMut(&mut Track) -> bool) -> Vec<u64> {
+        let mut changed = Vec::new();
+        for track in self.tracks.iter_mut() {
+            if f(track) {
+                changed.push(track.id);
+            }
+        }
+        if !changed.is_empty() {
+            self.changed();
+        }
+        changed
+    }
+
+    /// Remove every track matching `pred`, returning each with the
+    /// position it had before the removal.
+    pub fn remove_where(&mut self, pred: impl Fn(&Track) -> bool) -> Vec<(usize, Track)> {
+        let mut removed = Vec::new();
+        let mut kept = VecDeque::with_capacity(self.tracks.len());
+        for (position, track) in self.tracks.drain(..).enumerate() {
+            if pred(&track) {
+                removed.push((position, track));
+            } else {
+                kept.push_back(track);
+            }
+        }
+        self.tracks = kept;
+        if !removed.is_empty() {
+            self.changed();
+        }
+        removed
+    }
+
     pub fn clear(&mut self) {
         self.tracks.clear();
+        self.changed();
     }
 
     pub fn move_track(&mut self, id: u64, position: usize) -> bool {
@@ -143,12 +617,87 @@ impl Queue {
             let track = self.tracks.remove(pos).unwrap();
             let insert_at = position.min(self.tracks.len());
             self.tracks.insert(insert_at, track);
+            self.changed();
             true
         } else {
             false
         }
     }
 
+    /// Move track `id` directly before or after track `anchor`.
+    pub fn move_next_to(&mut self, id: u64, anchor: u64, after: bool) -> bool {
+        if !self.contains(id) || !self.contains(anchor) {
+            return false;
+        }
+        if id != anchor {
+            let pos = self.tracks.iter().position(|t| t.id == id).unwrap();
+            let track = self.tracks.remove(pos).unwrap();
+            let anchor_pos = self.tracks.iter().position(|t| t.id == anchor).unwrap();
+            let insert_at = if after { anchor_pos + 1 } else { anchor_pos };
+            self.tracks.insert(insert_at, track);
+        }
+        self.changed();
+        true
+    }
+
+    /// Apply `ops` in order as a single change. If any step refers to a
+    /// track that isn't queued (at that point in the batch), nothing is
+    /// changed.
+    pub fn apply_batch(&mut self, ops: Vec<QueueOp>) -> Result<Vec<QueueOpOutcome>, QueueOpError> {
+        let mut tracks = self.tracks.clone();
+        let mut outcomes = Vec::with_capacity(ops.len());
+        let position = |tracks: &VecDeque<Track>, id: u64, index: usize| {
+            tracks
+                .iter()
+                .position(|t| t.id == id)
+                .ok_or(QueueOpError { index, missing_id: id })
+        };
+
+        for (index, op) in ops.into_iter().enumerate() {
+            let outcome = match op {
+                QueueOp::Add(track) => {
+                    tracks.push_back(track.clone());
+                    QueueOpOutcome::Added { track, position: tracks.len() - 1 }
+                }
+                QueueOp::InsertAt(track, at) => {
+                    let at = at.min(tracks.len());
+                    tracks.insert(at, track.clone());
+                    QueueOpOutcome::Added { track, position: at }
+                }
+                QueueOp::Remove(id) => {
+                    let pos = position(&tracks, id, index)?;
+                    QueueOpOutcome::Removed { track: tracks.remove(pos).unwrap() }
+                }
+                QueueOp::Move(id, target) => {
+                    let pos = position(&tracks, id, index)?;
+                    if let MoveTarget::Before(anchor) | MoveTarget::After(anchor) = target {
+                        position(&tracks, anchor, index)?;
+                    }
+                    let track = tracks.remove(pos).unwrap();
+                    let at = match target {
+                        MoveTarget::Position(at) => at.min(tracks.len()),
+                        MoveTarget::Before(anchor) if anchor == id => pos,
+                        MoveTarget::After(anchor) if anchor == id => pos,
+                        MoveTarget::Before(anchor) => position(&tracks, anchor, index)?,
+                        MoveTarget::After(anchor) => position(&tracks, anchor, index)? + 1,
+                    };
+                    tracks.insert(at, track);
+                    QueueOpOutcome::Moved { id, position: at }
+                }
+                QueueOp::Clear => {
+                    let removed = tracks.len();
+                    tracks.clear();
+                    QueueOpOutcome::Cleared { removed }
+                }
+            };
+            outcomes.push(outcome);
+        }
+
+        self.tracks = tracks;
+        self.changed();
+        Ok(outcomes)
+    }
+
     pub fn len(&self) -> usize {
         self.tracks.len()
     }
@@ -157,11 +706,339 @@ impl Queue {
         self.tracks.is_empty()
     }
 
+    /// Put the tracks with `ids` first, in that order. Tracks not listed
+    /// (for example added since `ids` was computed) keep their relative
+    /// order after them.
+    pub fn reorder(&mut self, ids: &[u64]) {
+        let old: Vec<Track> = self.tracks.drain(..).collect();
+        let order: Vec<u64> = old.iter().map(|t| t.id).collect();
+        let mut by_id: HashMap<u64, Track> = old.into_iter().map(|t| (t.id, t)).collect();
+
+        for id in ids.iter().chain(order.iter()) {
+            if let Some(track) = by_id.remove(id) {
+                self.tracks.push_back(track);
+            }
+        }
+        self.changed();
+    }
+
     pub fn shuffle(&mut self) {
         use rand::seq::SliceRandom;
         let mut rng = rand::rng();
         self.tracks.make_contiguous().shuffle(&mut rng);
+        self.changed();
     }
 }
 
 pub type SharedQueue = Arc<RwLock<Queue>>;
+
+/// Minimal Ogg Vorbis files for tests: real headers, no audio.
+#[cfg(test)]
+pub(crate) mod test_files {
+    use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+    use std::path::Path;
+
+    pub const SAMPLE_RATE: u32 = 44100;
+
+    /// Write a file with `comments` that lasts `secs` seconds.
+    pub fn write_ogg(path: &Path, comments: &[(&str, &str)], secs: u64) {
+        let mut ident = b"\x01vorbis".to_vec();
+        ident.extend_from_slice(&0u32.to_le_bytes());
+        ident.push(2);
+        ident.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+        ident.extend_from_slice(&[0; 13]);
+
+        let mut comment = b"\x03vorbis".to_vec();
+        comment.extend_from_slice(&4u32.to_le_bytes());
+        comment.extend_from_slice(b"test");
+        comment.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for (key, value) in comments {
+            let entry = format!("{}={}", key, value);
+            comment.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+            comment.extend_from_slice(entry.as_bytes());
+        }
+
+        let mut out = Vec::new();
+        let mut writer = PacketWriter::new(&mut out);
+        writer.write_packet(ident.into_boxed_slice(), 1, PacketWriteEndInfo::EndPage, 0).unwrap();
+        writer.write_packet(comment.into_boxed_slice(), 1, PacketWriteEndInfo::EndPage, 0).unwrap();
+        writer
+            .write_packet(vec![0u8; 16].into_boxed_slice(), 1, PacketWriteEndInfo::EndStream, secs * SAMPLE_RATE as u64)
+            .unwrap();
+        drop(writer);
+        std::fs::write(path, out).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_read_tags_and_duration() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("song.ogg");
+        test_files::write_ogg(&path, &[("title", "Song"), ("ARTIST", "Band")], 185);
+
+        let comments = read_vorbis_comments(&path).unwrap();
+        assert_eq!(comments["TITLE"], "Song");
+        assert_eq!(comments["ARTIST"], "Band");
+        assert_eq!(read_duration(&path), Some(185.0));
+
+        std::fs::write(&path, b"").unwrap();
+        assert_eq!(read_duration(&path), None);
+    }
+
+    #[test]
+    fn test_track_tags() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("song.ogg");
+        test_files::write_ogg(
+            &path,
+            &[
+                ("TITLE", "Song"),
+                ("ALBUM", "Record"),
+                ("date", "1997-05-21"),
+                ("TRACKNUMBER", "3/12"),
+                ("DESCRIPTION", "Live take"),
+                ("MOOD", "Calm"),
+                ("METADATA_BLOCK_PICTURE", "AAAA"),
+            ],
+            10,
+        );
+
+        let track = Track::from_file(path);
+        assert_eq!(track.album(), Some("Record"));
+        assert_eq!(track.year(), Some(1997));
+        assert_eq!(track.track_number(), Some(3));
+        assert_eq!(track.comment(), Some("Live take"));
+        assert_eq!(track.tag("mood"), Some("Calm"));
+        assert_eq!(track.isrc(), None);
+        assert!(!track.tags.contains_key("METADATA_BLOCK_PICTURE"));
+
+        let allowed: Vec<String> = ["title", "ARTIST", "DATE", "MOOD"].iter().map(|t| t.to_string()).collect();
+        assert_eq!(
+            track.metadata_comments(&allowed),
+            vec![
+                ("TITLE".to_string(), "Song".to_string()),
+                ("DATE".to_string(), "1997-05-21".to_string()),
+                ("MOOD".to_string(), "Calm".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metadata_overrides() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("song.ogg");
+        test_files::write_ogg(&path, &[("TITLE", "song (final) v2"), ("ARTIST", "Unknwon"), ("ALBUM", "Demos")], 10);
+        let mut track = Track::from_file(path);
+
+        let mut tags = BTreeMap::new();
+        tags.insert("album".to_string(), "Sessions".to_string());
+        tags.insert("title".to_string(), "Song".to_string());
+        let edit = MetadataOverride { artist: Some("Band".to_string()), tags, ..Default::default() };
+        track.override_metadata(&edit.validate().unwrap());
+        assert_eq!(track.title, "Song");
+        assert_eq!(track.artist.as_deref(), Some("Band"));
+        assert_eq!(track.tag("ARTIST"), Some("Band"));
+        assert_eq!(track.album(), Some("Sessions"));
+        assert_eq!(track.overrides.title.as_deref(), Some("Song"));
+
+        // An empty value removes a tag; an empty ARTIST clears the artist
+        let mut tags = BTreeMap::new();
+        tags.insert("ALBUM".to_string(), String::new());
+        tags.insert("ARTIST".to_string(), String::new());
+        let edit = MetadataOverride { tags, ..Default::default() }.validate().unwrap();
+        track.override_metadata(&edit);
+        assert_eq!(track.album(), None);
+        assert_eq!(track.artist, None);
+        assert_eq!(track.overrides.artist, None);
+
+        let mut tags = BTreeMap::new();
+        tags.insert("BAD=NAME".to_string(), "x".to_string());
+        assert!(MetadataOverride { tags, ..Default::default() }.validate().is_err());
+        let blank = MetadataOverride { title: Some(" ".to_string()), ..Default::default() };
+        assert!(blank.validate().is_err());
+    }
+
+    fn track_at(path: PathBuf) -> Track {
+        Track {
+            id: NEXT_TRACK_ID.fetch_add(1, Ordering::Relaxed),
+            path,
+            title: "test".to_string(),
+            artist: None,
+            source: Default::default(),
+            requested_by: None,
+            tags: Default::default(),
+            overrides: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_state_survives_reload() {
+        let dir = tempdir().unwrap();
+        let state_path = dir.path().join("queue.json");
+        let a = dir.path().join("a.ogg");
+        let b = dir.path().join("b.ogg");
+        std::fs::write(&a, b"").unwrap();
+        std::fs::write(&b, b"").unwrap();
+
+        let (id_a, id_b) = {
+            let mut q = Queue::with_state_file(state_path.clone(), false);
+            let (ta, tb) = (track_at(a), track_at(b));
+            let ids = (ta.id, tb.id);
+            q.push_back(ta);
+            q.push_back(tb);
+            ids
+        };
+
+        let q = Queue::with_state_file(state_path, false);
+        let ids: Vec<u64> = q.list().iter().map(|t| t.id).collect();
+        assert_eq!(ids, vec![id_a, id_b]);
+        assert!(NEXT_TRACK_ID.load(Ordering::Relaxed) > id_b);
+    }
+
+    #[test]
+    fn test_reload_drops_missing_files() {
+        let dir = tempdir().unwrap();
+        let state_path = dir.path().join("queue.json");
+        let kept = dir.path().join("kept.ogg");
+        let gone = dir.path().join("gone.ogg");
+        std::fs::write(&kept, b"").unwrap();
+        std::fs::write(&gone, b"").unwrap();
+
+        {
+            let mut q = Queue::with_state_file(state_path.clone(), false);
+            q.push_back(track_at(gone.clone()));
+            q.push_back(track_at(kept.clone()));
+        }
+        std::fs::remove_file(&gone).unwrap();
+
+        let q = Queue::with_state_file(state_path, false);
+        let paths: Vec<PathBuf> = q.list().into_iter().map(|t| t.path).collect();
+        assert_eq!(paths, vec![kept]);
+    }
+
+    #[test]
+    fn test_resume_interrupted_track() {
+        let dir = tempdir().unwrap();
+        let state_path = dir.path().join("queue.json");
+        let playing = dir.path().join("playing.ogg");
+        let next = dir.path().join("next.ogg");
+        std::fs::write(&playing, b"").unwrap();
+        std::fs::write(&next, b"").unwrap();
+
+        {
+            let mut q = Queue::with_state_file(state_path.clone(), false);
+            q.push_back(track_at(playing.clone()));
+            q.push_back(track_at(next.clone()));
+            let current = q.pop_front();
+            q.set_current(current);
+        }
+
+        let q = Queue::with_state_file(state_path.clone(), false);
+        assert_eq!(q.len(), 1);
+
+        // Reloading without resume rewrote the state, so set it up again
+        {
+            let mut q = Queue::with_state_file(state_path.clone(), false);
+            q.push_front(track_at(playing.clone()));
+            let current = q.pop_front();
+            q.set_current(current);
+        }
+
+        let q = Queue::with_state_file(state_path, true);
+        let paths: Vec<PathBuf> = q.list().into_iter().map(|t| t.path).collect();
+        assert_eq!(paths, vec![playing, next]);
+    }
+
+    #[test]
+    fn test_reorder_keeps_unlisted_tracks() {
+        let mut q = Queue::default();
+        let tracks: Vec<Track> = (0..4).map(|i| track_at(PathBuf::from(format!("{}.ogg", i)))).collect();
+        let ids: Vec<u64> = tracks.iter().map(|t| t.id).collect();
+        q.extend_back(tracks);
+
+        // ids[1] is not listed, and 999 is not queued
+        q.reorder(&[ids[3], 999, ids[0], ids[2]]);
+        let order: Vec<u64> = q.list().iter().map(|t| t.id).collect();
+        assert_eq!(order, vec![ids[3], ids[0], ids[2], ids[1]]);
+    }
+
+    #[test]
+    fn test_revision_and_relative_moves() {
+        let dir = tempdir().unwrap();
+        let state_path = dir.path().join("queue.json");
+        let paths: Vec<PathBuf> = (0..3).map(|i| dir.path().join(format!("{}.ogg", i))).collect();
+        for p in &paths {
+            std::fs::write(p, b"").unwrap();
+        }
+
+        let ids: Vec<u64>;
+        let revision;
+        {
+            let mut q = Queue::with_state_file(state_path.clone(), false);
+            let tracks: Vec<Track> = paths.iter().cloned().map(track_at).collect();
+            ids = tracks.iter().map(|t| t.id).collect();
+            q.extend_back(tracks);
+            let before = q.revision();
+
+            assert!(q.move_next_to(ids[0], ids[2], true));
+            assert!(q.move_next_to(ids[1], ids[2], false));
+            assert!(!q.move_next_to(ids[1], 999, false));
+            let order: Vec<u64> = q.list().iter().map(|t| t.id).collect();
+            assert_eq!(order, vec![ids[1], ids[2], ids[0]]);
+            assert_eq!(q.revision(), before + 2);
+
+            // Changing the current track doesn't change the list
+            q.set_current(None);
+            revision = q.revision();
+        }
+
+        // The revision keeps increasing across restarts
+        let q = Queue::with_state_file(state_path, false);
+        assert!(q.revision() > revision);
+    }
+
+    #[test]
+    fn test_apply_batch() {
+        let mut q = Queue::default();
+        let a = track_at(PathBuf::from("a.ogg"));
+        let b = track_at(PathBuf::from("b.ogg"));
+        let c = track_at(PathBuf::from("c.ogg"));
+        q.push_back(a.clone());
+        let revision = q.revision();
+
+        let outcomes = q
+            .apply_batch(vec![
+                QueueOp::Add(b.clone()),
+                QueueOp::InsertAt(c.clone(), 0),
+                QueueOp::Move(a.id, MoveTarget::After(b.id)),
+                QueueOp::Remove(c.id),
+            ])
+            .unwrap();
+        assert_eq!(outcomes.len(), 4);
+        assert!(matches!(outcomes[2], QueueOpOutcome::Moved { position: 2, .. }));
+        let order: Vec<u64> = q.list().iter().map(|t| t.id).collect();
+        assert_eq!(order, vec![b.id, a.id]);
+        assert_eq!(q.revision(), revision + 1);
+    }
+
+    #[test]
+    fn test_apply_batch_is_all_or_nothing() {
+        let mut q = Queue::default();
+        let a = track_at(PathBuf::from("a.ogg"));
+        q.push_back(a.clone());
+        let revision = q.revision();
+
+        // The second step removes a track the first step already removed
+        let err = q
+            .apply_batch(vec![QueueOp::Remove(a.id), QueueOp::Remove(a.id), QueueOp::Clear])
+            .unwrap_err();
+        assert_eq!(err, QueueOpError { index: 1, missing_id: a.id });
+        assert_eq!(q.len(), 1);
+        assert_eq!(q.revision(), revision);
+    }
+}
@@ -0,0 +1,273 @@
+// Royalty and compliance play-log reports
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use chrono::{DateTime, SecondsFormat};
+use serde::{Deserialize, Serialize};
+
+use crate::history::HistoryEntry;
+
+/// Name reported in the service column of SoundExchange-style reports.
+pub const SERVICE_NAME: &str = "Snowboot";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    #[default]
+    Csv,
+    Tsv,
+    Json,
+    /// Tab-delimited per-recording totals in the SoundExchange column layout
+    SoundExchange,
+}
+
+impl ReportFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "csv" => Some(Self::Csv),
+            "tsv" => Some(Self::Tsv),
+            "json" => Some(Self::Json),
+            "soundexchange" => Some(Self::SoundExchange),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Tsv | Self::SoundExchange => "text/tab-separated-values; charset=utf-8",
+            Self::Json => "application/json",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Tsv | Self::SoundExchange => "tsv",
+            Self::Json => "json",
+        }
+    }
+}
+
+/// One reported play, with the tags the track had when it played.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayRecord {
+    pub started_at: String,
+    pub started_at_unix: u64,
+    pub duration_secs: u64,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub isrc: Option<String>,
+    pub label: Option<String>,
+    pub path: PathBuf,
+    pub skipped: bool,
+    pub listeners: Option<u64>,
+}
+
+const PLAY_COLUMNS: &[&str] = &[
+    "started_at",
+    "duration_secs",
+    "title",
+    "artist",
+    "album",
+    "isrc",
+    "label",
+    "path",
+    "skipped",
+    "listeners",
+];
+
+const SOUNDEXCHANGE_COLUMNS: &[&str] = &[
+    "NAME_OF_SERVICE",
+    "FEATURED_ARTIST",
+    "SOUND_RECORDING_TITLE",
+    "ISRC",
+    "ALBUM_TITLE",
+    "MARKETING_LABEL",
+    "ACTUAL_TOTAL_PERFORMANCES",
+];
+
+/// Build report records for `entries`, taking album, ISRC and label from
+/// the tags recorded with each play, including any metadata overrides.
+pub fn build_records(entries: &[HistoryEntry]) -> Vec<PlayRecord> {
+    entries
+        .iter()
+        .map(|entry| {
+            let track = &entry.track;
+            PlayRecord {
+                started_at: format_timestamp(entry.started_at),
+                started_at_unix: entry.started_at,
+                duration_secs: entry.duration_secs,
+                title: track.title.clone(),
+                artist: track.artist.clone(),
+                album: track.album().map(str::to_string),
+                isrc: track.isrc().map(str::to_string),
+                label: track.tag("LABEL").or_else(|| track.tag("ORGANIZATION")).map(str::to_string),
+                path: track.path.clone(),
+                skipped: entry.skipped,
+                listeners: entry.listeners,
+            }
+        })
+        .collect()
+}
+
+/// Render records in the requested format.
+pub fn render(records: &[PlayRecord], format: ReportFormat) -> String {
+    match format {
+        ReportFormat::Json => serde_json::to_string_pretty(records).unwrap_or_default(),
+        ReportFormat::Csv => render_plays(records, ',', csv_field),
+        ReportFormat::Tsv => render_plays(records, '\t', tsv_field),
+        ReportFormat::SoundExchange => render_soundexchange(records),
+    }
+}
+
+fn render_plays(records: &[PlayRecord], sep: char, field: fn(&str) -> String) -> String {
+    let mut out = join_row(PLAY_COLUMNS.iter().map(|c| c.to_string()), sep);
+    for r in records {
+        let row = [
+            r.started_at.clone(),
+            r.duration_secs.to_string(),
+            r.title.clone(),
+            r.artist.clone().unwrap_or_default(),
+            r.album.clone().unwrap_or_default(),
+            r.isrc.clone().unwrap_or_default(),
+            r.label.clone().unwrap_or_default(),
+            r.path.display().to_string(),
+            r.skipped.to_string(),
+            r.listeners.map(|l| l.to_string()).unwrap_or_default(),
+        ];
+        out.push_str(&join_row(row.iter().map(|v| field(v)), sep));
+    }
+    out
+}
+
+/// Aggregate plays per recording, summing listeners into actual total
+/// performances.
+fn render_soundexchange(records: &[PlayRecord]) -> String {
+    let mut totals: Vec<(&PlayRecord, u64)> = Vec::new();
+    let mut index: HashMap<(&str, Option<&str>, Option<&str>), usize> = HashMap::new();
+
+    for r in records {
+        let key = (r.title.as_str(), r.artist.as_deref(), r.isrc.as_deref());
+        let performances = r.listeners.unwrap_or(0);
+        match index.get(&key) {
+            Some(&i) => totals[i].1 += performances,
+            None => {
+                index.insert(key, totals.len());
+                totals.push((r, performances));
+            }
+        }
+    }
+
+    let mut out = join_row(SOUNDEXCHANGE_COLUMNS.iter().map(|c| c.to_string()), '\t');
+    for (r, performances) in totals {
+        let row = [
+            SERVICE_NAME.to_string(),
+            r.artist.clone().unwrap_or_default(),
+            r.title.clone(),
+            r.isrc.clone().unwrap_or_default(),
+            r.album.clone().unwrap_or_default(),
+            r.label.clone().unwrap_or_default(),
+            performances.to_string(),
+        ];
+        out.push_str(&join_row(row.iter().map(|v| tsv_field(v)), '\t'));
+    }
+    out
+}
+
+fn join_row(fields: impl Iterator<Item = String>, sep: char) -> String {
+    let mut row = fields.collect::<Vec<_>>().join(&sep.to_string());
+    row.push_str("\r\n");
+    row
+}
+
+/// Quote a CSV field per RFC 4180 when it contains separators or quotes.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// TSV has no quoting, so tabs and line breaks become spaces.
+fn tsv_field(value: &str) -> String {
+    value.replace(['\t', '\n', '\r'], " ")
+}
+
+fn format_timestamp(secs: u64) -> String {
+    i64::try_from(secs)
+        .ok()
+        .and_then(|s| DateTime::from_timestamp(s, 0))
+        .map(|dt| dt.to_rfc3339_opts(SecondsFormat::Secs, true))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::Track;
+
+    fn entry(title: &str, started_at: u64, listeners: Option<u64>) -> HistoryEntry {
+        let track = Track {
+            id: 1,
+            path: PathBuf::from("/nonexistent/track.ogg"),
+            title: title.to_string(),
+            artist: Some("Artist, The".to_string()),
+            source: Default::default(),
+            requested_by: None,
+            tags: Default::default(),
+            overrides: Default::default(),
+        };
+        let mut entry = HistoryEntry::new(track, started_at, 180, false);
+        entry.listeners = listeners;
+        entry
+    }
+
+    #[test]
+    fn test_csv_escaping() {
+        let records = build_records(&[entry("Say \"Hi\"", 1_700_000_000, Some(5))]);
+        let csv = render(&records, ReportFormat::Csv);
+        let mut lines = csv.lines();
+        assert_eq!(lines.next().unwrap(), PLAY_COLUMNS.join(","));
+        assert_eq!(
+            lines.next().unwrap(),
+            "2023-11-14T22:13:20Z,180,\"Say \"\"Hi\"\"\",\"Artist, The\",,,,/nonexistent/track.ogg,false,5"
+        );
+    }
+
+    #[test]
+    fn test_tags_from_history() {
+        let mut played = entry("Song", 100, None);
+        played.track.tags.insert("ALBUM".to_string(), "Album".to_string());
+        played.track.tags.insert("ISRC".to_string(), "USRC17607839".to_string());
+        played.track.tags.insert("ORGANIZATION".to_string(), "Label".to_string());
+        let record = &build_records(&[played])[0];
+        assert_eq!(record.album.as_deref(), Some("Album"));
+        assert_eq!(record.isrc.as_deref(), Some("USRC17607839"));
+        assert_eq!(record.label.as_deref(), Some("Label"));
+    }
+
+    #[test]
+    fn test_soundexchange_totals() {
+        let records = build_records(&[
+            entry("Song", 100, Some(5)),
+            entry("Song", 200, Some(7)),
+            entry("Other", 300, None),
+        ]);
+        let report = render(&records, ReportFormat::SoundExchange);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("Snowboot\tArtist, The\tSong\t"));
+        assert!(lines[1].ends_with("\t12"));
+        assert!(lines[2].ends_with("\t0"));
+    }
+
+    #[test]
+    fn test_format_parse() {
+        assert_eq!(ReportFormat::parse("TSV"), Some(ReportFormat::Tsv));
+        assert_eq!(ReportFormat::parse("xlsx"), None);
+    }
+}
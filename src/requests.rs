@@ -0,0 +1,359 @@
+// Listener song requests, held for DJs to approve into the queue
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::errors::ErrorCode;
+use crate::queue::Track;
+use crate::store;
+
+/// Longest name a listener can give with a request.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Limits on what listeners can request and how often.
+#[derive(Debug, Clone)]
+pub struct RequestRules {
+    /// Seconds a client address must wait between requests
+    pub client_cooldown_secs: u64,
+    /// Seconds before the same track can be requested again
+    pub track_cooldown_secs: u64,
+    /// Requests awaiting moderation before new ones are refused
+    pub max_pending: usize,
+    /// Tracks played within this many seconds can't be requested
+    pub replay_window_secs: u64,
+}
+
+impl Default for RequestRules {
+    fn default() -> Self {
+        Self {
+            client_cooldown_secs: 600,
+            track_cooldown_secs: 3600,
+            max_pending: 20,
+            replay_window_secs: 3 * 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListenerRequest {
+    pub id: u64,
+    pub track: Track,
+    /// Name the listener gave, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requested_by: Option<String>,
+    pub requested_at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum RequestError {
+    #[error("Too many requests are waiting; try again later")]
+    TooManyPending,
+    #[error("You can make another request in {retry_after} seconds")]
+    ClientCooldown { retry_after: u64 },
+    #[error("This track was requested recently; try again in {retry_after} seconds")]
+    TrackCooldown { retry_after: u64 },
+    #[error("This track is already requested or queued")]
+    AlreadyRequested,
+    #[error("This track has played recently")]
+    RecentlyPlayed,
+}
+
+impl RequestError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            RequestError::TooManyPending => ErrorCode::TooManyPendingRequests,
+            RequestError::ClientCooldown { .. } => ErrorCode::RequestCooldown,
+            RequestError::TrackCooldown { .. } => ErrorCode::RequestCooldown,
+            RequestError::AlreadyRequested => ErrorCode::AlreadyRequested,
+            RequestError::RecentlyPlayed => ErrorCode::RecentlyPlayed,
+        }
+    }
+
+    /// Seconds until the request could succeed, for cooldowns.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            RequestError::ClientCooldown { retry_after }
+            | RequestError::TrackCooldown { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+}
+
+/// What the rules need to know about a requested track.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TrackStatus {
+    /// When the track last started playing
+    pub last_played: Option<u64>,
+    /// Whether the track is already in the queue
+    pub queued: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RequestState {
+    next_id: u64,
+    pending: Vec<ListenerRequest>,
+    /// Cooldowns, so a restart doesn't lift them
+    #[serde(default)]
+    last_by_client: HashMap<IpAddr, u64>,
+    #[serde(default)]
+    last_by_track: HashMap<PathBuf, u64>,
+}
+
+/// Requests awaiting moderation, and the recent activity the cooldowns
+/// are checked against.
+#[derive(Debug)]
+pub struct RequestBox {
+    rules: RequestRules,
+    pending: Vec<ListenerRequest>,
+    next_id: u64,
+    last_by_client: HashMap<IpAddr, u64>,
+    last_by_track: HashMap<PathBuf, u64>,
+    /// Saves the requests off the async threads, if a state file is configured
+    writer: Option<store::StateWriter<RequestState>>,
+}
+
+impl RequestBox {
+    pub fn new(rules: RequestRules) -> Self {
+        Self {
+            rules,
+            pending: Vec::new(),
+            next_id: 1,
+            last_by_client: HashMap::new(),
+            last_by_track: HashMap::new(),
+            writer: None,
+        }
+    }
+
+    /// Create a request box whose pending requests are saved to `path`.
+    pub fn with_state_file(rules: RequestRules, path: PathBuf) -> Self {
+        let state: Option<RequestState> = store::read_json(&path).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable listener requests {}: {}", path.display(), e);
+            None
+        });
+        let mut requests = Self::new(rules);
+        if let Some(state) = state {
+            if !state.pending.is_empty() {
+                info!("Loaded {} pending listener requests", state.pending.len());
+            }
+            requests.next_id = state.next_id;
+            requests.pending = state.pending;
+            requests.last_by_client = state.last_by_client;
+            requests.last_by_track = state.last_by_track;
+        }
+        requests.writer = Some(store::StateWriter::spawn(path, "listener requests"));
+        requests
+    }
+
+    /// Save a snapshot to the state file, if one is configured. The write
+    /// happens in the background.
+    fn persist(&self) {
+        let Some(ref writer) = self.writer else {
+            return;
+        };
+        writer.write(RequestState {
+            next_id: self.next_id,
+            pending: self.pending.clone(),
+            last_by_client: self.last_by_client.clone(),
+            last_by_track: self.last_by_track.clone(),
+        });
+    }
+
+    /// Wait until the latest state has been written.
+    pub fn flush(&self) {
+        if let Some(ref writer) = self.writer {
+            writer.flush();
+        }
+    }
+
+    pub fn rules(&self) -> &RequestRules {
+        &self.rules
+    }
+
+    pub fn pending(&self) -> &[ListenerRequest] {
+        &self.pending
+    }
+
+    /// Check `track` against the rules and add it to the pending list.
+    pub fn submit(
+        &mut self,
+        track: Track,
+        requested_by: Option<String>,
+        remote_addr: IpAddr,
+        status: TrackStatus,
+        now: u64,
+    ) -> Result<ListenerRequest, RequestError> {
+        let rules = &self.rules;
+        self.last_by_client
+            .retain(|_, at| now.saturating_sub(*at) < rules.client_cooldown_secs);
+        self.last_by_track
+            .retain(|_, at| now.saturating_sub(*at) < rules.track_cooldown_secs);
+
+        if self.pending.len() >= rules.max_pending {
+            return Err(RequestError::TooManyPending);
+        }
+        if let Some(at) = self.last_by_client.get(&remote_addr) {
+            return Err(RequestError::ClientCooldown {
+                retry_after: at + rules.client_cooldown_secs - now,
+            });
+        }
+        if status.queued || self.pending.iter().any(|r| r.track.path == track.path) {
+            return Err(RequestError::AlreadyRequested);
+        }
+        if let Some(at) = self.last_by_track.get(&track.path) {
+            return Err(RequestError::TrackCooldown {
+                retry_after: at + rules.track_cooldown_secs - now,
+            });
+        }
+        if status
+            .last_played
+            .is_some_and(|at| now.saturating_sub(at) < rules.replay_window_secs)
+        {
+            return Err(RequestError::RecentlyPlayed);
+        }
+
+        let request = ListenerRequest {
+            id: self.next_id,
+            track,
+            requested_by,
+            requested_at: now,
+        };
+        self.next_id += 1;
+        self.last_by_client.insert(remote_addr, now);
+        self.last_by_track.insert(request.track.path.clone(), now);
+        self.pending.push(request.clone());
+        self.persist();
+        Ok(request)
+    }
+
+    /// Take a request off the pending list, to queue or reject it.
+    pub fn take(&mut self, id: u64) -> Option<ListenerRequest> {
+        let pos = self.pending.iter().position(|r| r.id == id)?;
+        let request = self.pending.remove(pos);
+        self.persist();
+        Some(request)
+    }
+}
+
+impl Default for RequestBox {
+    fn default() -> Self {
+        Self::new(RequestRules::default())
+    }
+}
+
+pub type SharedRequests = Arc<RwLock<RequestBox>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn track(name: &str) -> Track {
+        Track {
+            id: 1,
+            path: PathBuf::from(format!("/music/{}.ogg", name)),
+            title: name.to_string(),
+            artist: None,
+            source: Default::default(),
+            requested_by: None,
+            tags: Default::default(),
+            overrides: Default::default(),
+        }
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    #[test]
+    fn test_client_and_track_cooldowns() {
+        let mut requests = RequestBox::new(RequestRules {
+            client_cooldown_secs: 60,
+            track_cooldown_secs: 300,
+            ..RequestRules::default()
+        });
+        let a = requests.submit(track("a"), None, ip(1), TrackStatus::default(), 1000).unwrap();
+
+        let err = requests.submit(track("b"), None, ip(1), TrackStatus::default(), 1010).unwrap_err();
+        assert_eq!(err, RequestError::ClientCooldown { retry_after: 50 });
+        assert!(requests.submit(track("b"), None, ip(1), TrackStatus::default(), 1060).is_ok());
+
+        // Still pending
+        let err = requests.submit(track("a"), None, ip(2), TrackStatus::default(), 1100).unwrap_err();
+        assert_eq!(err, RequestError::AlreadyRequested);
+
+        // Rejected, but the track cooldown still applies
+        requests.take(a.id).unwrap();
+        let err = requests.submit(track("a"), None, ip(2), TrackStatus::default(), 1100).unwrap_err();
+        assert_eq!(err, RequestError::TrackCooldown { retry_after: 200 });
+        assert!(requests.submit(track("a"), None, ip(2), TrackStatus::default(), 1300).is_ok());
+    }
+
+    #[test]
+    fn test_max_pending() {
+        let mut requests = RequestBox::new(RequestRules {
+            max_pending: 2,
+            ..RequestRules::default()
+        });
+        requests.submit(track("a"), None, ip(1), TrackStatus::default(), 0).unwrap();
+        requests.submit(track("b"), None, ip(2), TrackStatus::default(), 0).unwrap();
+        let err = requests.submit(track("c"), None, ip(3), TrackStatus::default(), 0).unwrap_err();
+        assert_eq!(err, RequestError::TooManyPending);
+    }
+
+    #[test]
+    fn test_recently_played_and_queued() {
+        let mut requests = RequestBox::default();
+        let played = TrackStatus {
+            last_played: Some(100_000 - 3600),
+            queued: false,
+        };
+        let err = requests.submit(track("a"), None, ip(1), played, 100_000).unwrap_err();
+        assert_eq!(err, RequestError::RecentlyPlayed);
+
+        let played_long_ago = TrackStatus {
+            last_played: Some(100_000 - 4 * 3600),
+            queued: false,
+        };
+        assert!(requests.submit(track("a"), None, ip(1), played_long_ago, 100_000).is_ok());
+
+        let queued = TrackStatus {
+            last_played: None,
+            queued: true,
+        };
+        let err = requests.submit(track("b"), None, ip(2), queued, 100_000).unwrap_err();
+        assert_eq!(err, RequestError::AlreadyRequested);
+    }
+
+    #[test]
+    fn test_pending_requests_persist() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("requests.json");
+        {
+            let mut requests = RequestBox::with_state_file(RequestRules::default(), path.clone());
+            requests
+                .submit(track("a"), Some("Sam".to_string()), ip(1), TrackStatus::default(), 0)
+                .unwrap();
+            let b = requests.submit(track("b"), None, ip(2), TrackStatus::default(), 0).unwrap();
+            requests.take(b.id);
+        }
+        let mut requests = RequestBox::with_state_file(RequestRules::default(), path);
+        assert_eq!(requests.pending().len(), 1);
+        assert_eq!(requests.pending()[0].requested_by.as_deref(), Some("Sam"));
+
+        // Cooldowns survive the restart
+        let err = requests.submit(track("c"), None, ip(1), TrackStatus::default(), 10).unwrap_err();
+        assert!(matches!(err, RequestError::ClientCooldown { .. }));
+        let err = requests.submit(track("b"), None, ip(4), TrackStatus::default(), 10).unwrap_err();
+        assert!(matches!(err, RequestError::TrackCooldown { .. }));
+
+        let c = requests.submit(track("c"), None, ip(3), TrackStatus::default(), 10).unwrap();
+        assert_eq!(c.id, 3);
+    }
+}
@@ -0,0 +1,1004 @@
+// Time-based scheduling of programming blocks
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, NaiveDate, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Notify, RwLock};
+use tokio::time::{sleep, Duration};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::metrics;
+use crate::player::{PendingInsert, PlayerEvent, PlayerHandle, QueueChangeKind};
+use crate::playlist::{self, SharedPlaylists};
+use crate::queue::{Track, TrackSource};
+use crate::store;
+
+/// How often the scheduler checks for block boundaries.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest gap between checks for which missed block starts are still fired.
+const MAX_CATCH_UP_DAYS: i64 = 7;
+
+/// Source of the current time, so the scheduler can be driven by a test clock.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Wait until the time is next worth checking.
+    fn tick(&self) -> BoxFuture<'_, ()>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn tick(&self) -> BoxFuture<'_, ()> {
+        Box::pin(sleep(TICK_INTERVAL))
+    }
+}
+
+/// Clock that only moves when told to. Each move wakes the scheduler.
+pub struct ManualClock {
+    now: std::sync::Mutex<DateTime<Utc>>,
+    moved: Notify,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now: std::sync::Mutex::new(now),
+            moved: Notify::new(),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+        self.moved.notify_one();
+    }
+
+    pub fn advance(&self, by: ChronoDuration) {
+        *self.now.lock().unwrap() += by;
+        self.moved.notify_one();
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+
+    fn tick(&self) -> BoxFuture<'_, ()> {
+        Box::pin(self.moved.notified())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Day {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl From<Day> for Weekday {
+    fn from(day: Day) -> Self {
+        match day {
+            Day::Mon => Weekday::Mon,
+            Day::Tue => Weekday::Tue,
+            Day::Wed => Weekday::Wed,
+            Day::Thu => Weekday::Thu,
+            Day::Fri => Weekday::Fri,
+            Day::Sat => Weekday::Sat,
+            Day::Sun => Weekday::Sun,
+        }
+    }
+}
+
+/// What happens to the queue when a block starts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockAction {
+    /// Append the playlist to the queue
+    #[default]
+    Enqueue,
+    /// Replace the queue; the current track finishes first
+    Replace,
+    /// Replace the queue and cut the current track immediately
+    HardCut,
+}
+
+/// A weekly programming block, e.g. Mon–Fri 07:00–10:00 play `breakfast`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub days: Vec<Day>,
+    /// Local start time, `HH:MM` or `HH:MM:SS`
+    pub start: String,
+    /// Local end time; blocks ending before they start run past midnight.
+    /// A `schedule_block_ended` event is sent when it passes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<String>,
+    pub playlist: String,
+    #[serde(default)]
+    pub action: BlockAction,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl ScheduleSpec {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.days.is_empty() {
+            return Err("At least one day is required".to_string());
+        }
+        if parse_time(&self.start).is_none() {
+            return Err(format!("Invalid start time '{}': use HH:MM", self.start));
+        }
+        if let Some(ref end) = self.end {
+            if parse_time(end).is_none() {
+                return Err(format!("Invalid end time '{}': use HH:MM", end));
+            }
+        }
+        if !playlist::valid_name(&self.playlist) {
+            return Err(format!("Invalid playlist name '{}'", self.playlist));
+        }
+        Ok(())
+    }
+
+    fn start_time(&self) -> NaiveTime {
+        parse_time(&self.start).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleEntry {
+    pub id: u64,
+    #[serde(flatten)]
+    pub spec: ScheduleSpec,
+}
+
+/// What happens to the track cut off by a hard insert.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CutPolicy {
+    /// Put the cut track back at the front of the queue
+    #[default]
+    Requeue,
+    /// Discard the cut track
+    Drop,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InsertMode {
+    /// Cut the current track at the exact time
+    #[default]
+    Hard,
+    /// Wait for the current track to end, cutting it only once the
+    /// tolerance window has passed
+    Soft,
+}
+
+/// A file played at an exact wall-clock time, such as the news at the top
+/// of the hour.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InsertSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub path: PathBuf,
+    /// Local time `HH:MM[:SS]`, or `*:MM[:SS]` for every hour
+    pub at: String,
+    /// Days to run on; empty means every day
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<Day>,
+    #[serde(default)]
+    pub mode: InsertMode,
+    /// Seconds a soft insert may wait for the current track to end
+    #[serde(default = "default_tolerance_secs")]
+    pub tolerance_secs: u64,
+    /// Overrides the configured policy for the cut track
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cut_track: Option<CutPolicy>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_tolerance_secs() -> u64 {
+    60
+}
+
+impl InsertSpec {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.insert_times().is_empty() {
+            return Err(format!(
+                "Invalid insert time '{}': use HH:MM[:SS] or *:MM[:SS]",
+                self.at
+            ));
+        }
+        Ok(())
+    }
+
+    /// Local times of day the insert fires at.
+    fn insert_times(&self) -> Vec<NaiveTime> {
+        match self.at.strip_prefix("*:") {
+            Some(rest) => (0..24)
+                .filter_map(|hour| parse_time(&format!("{:02}:{}", hour, rest)))
+                .collect(),
+            None => parse_time(&self.at).into_iter().collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimedInsert {
+    pub id: u64,
+    #[serde(flatten)]
+    pub spec: InsertSpec,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ScheduleState {
+    next_id: u64,
+    entries: Vec<ScheduleEntry>,
+    #[serde(default)]
+    inserts: Vec<TimedInsert>,
+}
+
+#[derive(Debug)]
+pub struct Schedule {
+    entries: Vec<ScheduleEntry>,
+    inserts: Vec<TimedInsert>,
+    next_id: u64,
+    timezone: Tz,
+    cut_policy: CutPolicy,
+    state_path: Option<PathBuf>,
+}
+
+impl Schedule {
+    pub fn new(timezone: Tz) -> Self {
+        Self {
+            entries: Vec::new(),
+            inserts: Vec::new(),
+            next_id: 1,
+            timezone,
+            cut_policy: CutPolicy::default(),
+            state_path: None,
+        }
+    }
+
+    /// Set what hard inserts do with the track they cut, unless an insert
+    /// overrides it.
+    pub fn with_cut_policy(mut self, policy: CutPolicy) -> Self {
+        self.cut_policy = policy;
+        self
+    }
+
+    /// Create a schedule backed by a state file, loading any saved entries.
+    pub fn with_state_file(path: PathBuf, timezone: Tz) -> Self {
+        let state: ScheduleState = match store::read_json(&path) {
+            Ok(Some(state)) => state,
+            Ok(None) => ScheduleState::default(),
+            Err(e) => {
+                warn!("Ignoring unreadable schedule {}: {}", path.display(), e);
+                ScheduleState::default()
+            }
+        };
+
+        if !state.entries.is_empty() || !state.inserts.is_empty() {
+            info!(
+                "Loaded {} schedule entries and {} timed inserts",
+                state.entries.len(),
+                state.inserts.len()
+            );
+        }
+
+        let max_id = state
+            .entries
+            .iter()
+            .map(|e| e.id)
+            .chain(state.inserts.iter().map(|i| i.id))
+            .max()
+            .unwrap_or(0);
+        Self {
+            next_id: state.next_id.max(max_id + 1),
+            entries: state.entries,
+            inserts: state.inserts,
+            timezone,
+            cut_policy: CutPolicy::default(),
+            state_path: Some(path),
+        }
+    }
+
+    fn persist(&self) {
+        let Some(ref path) = self.state_path else {
+            return;
+        };
+        let state = ScheduleState {
+            next_id: self.next_id,
+            entries: self.entries.clone(),
+            inserts: self.inserts.clone(),
+        };
+        if let Err(e) = store::write_json_atomic(path, &state) {
+            warn!("Failed to write schedule {}: {}", path.display(), e);
+        }
+    }
+
+    pub fn timezone(&self) -> Tz {
+        self.timezone
+    }
+
+    pub fn list(&self) -> &[ScheduleEntry] {
+        &self.entries
+    }
+
+    pub fn get(&self, id: u64) -> Option<&ScheduleEntry> {
+        self.entries.iter().find(|e| e.id == id)
+    }
+
+    pub fn add(&mut self, spec: ScheduleSpec) -> ScheduleEntry {
+        let entry = ScheduleEntry {
+            id: self.next_id,
+            spec,
+        };
+        self.next_id += 1;
+        self.entries.push(entry.clone());
+        self.persist();
+        entry
+    }
+
+    pub fn update(&mut self, id: u64, spec: ScheduleSpec) -> Option<ScheduleEntry> {
+        let entry = self.entries.iter_mut().find(|e| e.id == id)?;
+        entry.spec = spec;
+        let entry = entry.clone();
+        self.persist();
+        Some(entry)
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<ScheduleEntry> {
+        let pos = self.entries.iter().position(|e| e.id == id)?;
+        let entry = self.entries.remove(pos);
+        self.persist();
+        Some(entry)
+    }
+
+    pub fn cut_policy(&self) -> CutPolicy {
+        self.cut_policy
+    }
+
+    pub fn inserts(&self) -> &[TimedInsert] {
+        &self.inserts
+    }
+
+    pub fn add_insert(&mut self, spec: InsertSpec) -> TimedInsert {
+        let insert = TimedInsert {
+            id: self.next_id,
+            spec,
+        };
+        self.next_id += 1;
+        self.inserts.push(insert.clone());
+        self.persist();
+        insert
+    }
+
+    pub fn remove_insert(&mut self, id: u64) -> Option<TimedInsert> {
+        let pos = self.inserts.iter().position(|i| i.id == id)?;
+        let insert = self.inserts.remove(pos);
+        self.persist();
+        Some(insert)
+    }
+
+    /// Enabled inserts that fire in `(since, now]`.
+    pub fn due_inserts(&self, since: DateTime<Utc>, now: DateTime<Utc>) -> Vec<TimedInsert> {
+        if now <= since {
+            return Vec::new();
+        }
+        // Inserts are only worth airing close to their time
+        let since = since.max(now - ChronoDuration::minutes(1));
+
+        self.inserts
+            .iter()
+            .filter(|i| i.spec.enabled)
+            .filter(|i| {
+                let from = since.with_timezone(&self.timezone).date_naive();
+                self.times_on_or_after(&i.spec.days, i.spec.insert_times(), from)
+                    .take_while(|t| *t <= now)
+                    .any(|t| t > since)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// The next time the insert fires strictly after `after`.
+    pub fn next_insert(&self, spec: &InsertSpec, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if !spec.enabled {
+            return None;
+        }
+        let from = after.with_timezone(&self.timezone).date_naive();
+        self.times_on_or_after(&spec.days, spec.insert_times(), from)
+            .find(|t| *t > after)
+    }
+
+    /// Enabled entries with a block start in `(since, now]`.
+    pub fn due(&self, since: DateTime<Utc>, now: DateTime<Utc>) -> Vec<ScheduleEntry> {
+        if now <= since {
+            return Vec::new();
+        }
+        let since = since.max(now - ChronoDuration::days(MAX_CATCH_UP_DAYS));
+
+        self.entries
+            .iter()
+            .filter(|e| e.spec.enabled)
+            .filter(|e| {
+                let from = since.with_timezone(&self.timezone).date_naive();
+                self.times_on_or_after(&e.spec.days, vec![e.spec.start_time()], from)
+                    .take_while(|start| *start <= now)
+                    .any(|start| start > since)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// The next block start strictly after `after`.
+    pub fn next_start(&self, spec: &ScheduleSpec, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if !spec.enabled {
+            return None;
+        }
+        let from = after.with_timezone(&self.timezone).date_naive();
+        self.times_on_or_after(&spec.days, vec![spec.start_time()], from)
+            .find(|start| *start > after)
+    }
+
+    /// Whether `now` falls inside the block's start/end window.
+    pub fn is_active(&self, spec: &ScheduleSpec, now: DateTime<Utc>) -> bool {
+        if !spec.enabled {
+            return false;
+        }
+
+        // Look back a day to catch blocks that run past midnight
+        let today = now.with_timezone(&self.timezone).date_naive();
+        [today.pred_opt(), Some(today)]
+            .into_iter()
+            .flatten()
+            .filter_map(|date| self.block_window(spec, date))
+            .any(|(start, end)| start <= now && now < end)
+    }
+
+    /// Enabled entries with a block end in `(since, now]`.
+    pub fn due_ends(&self, since: DateTime<Utc>, now: DateTime<Utc>) -> Vec<ScheduleEntry> {
+        if now <= since {
+            return Vec::new();
+        }
+        let since = since.max(now - ChronoDuration::days(MAX_CATCH_UP_DAYS));
+        // A block ending after midnight started the day before
+        let from = since.with_timezone(&self.timezone).date_naive();
+        let from = from.pred_opt().unwrap_or(from);
+
+        self.entries
+            .iter()
+            .filter(|e| e.spec.enabled && e.spec.end.is_some())
+            .filter(|e| {
+                from.iter_days()
+                    .take((MAX_CATCH_UP_DAYS + 3) as usize)
+                    .filter_map(|date| self.block_window(&e.spec, date))
+                    .map(|(_, end)| end)
+                    .take_while(|end| *end <= now)
+                    .any(|end| end > since)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Start and end in UTC of the block starting on local `date`, if it
+    /// runs that day and has an end.
+    fn block_window(&self, spec: &ScheduleSpec, date: NaiveDate) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let end = spec.end.as_deref().and_then(parse_time)?;
+        if !runs_on(&spec.days, date) {
+            return None;
+        }
+        let start_local = date.and_time(spec.start_time());
+        let mut end_local = date.and_time(end);
+        if end_local <= start_local {
+            end_local += ChronoDuration::days(1);
+        }
+        Some((self.local_to_utc(start_local)?, self.local_to_utc(end_local)?))
+    }
+
+    /// Occurrences of the local `times` in UTC, in order, from the start of
+    /// `from` (local date) onwards.
+    fn times_on_or_after<'a>(
+        &'a self,
+        days: &'a [Day],
+        times: Vec<NaiveTime>,
+        from: NaiveDate,
+    ) -> impl Iterator<Item = DateTime<Utc>> + 'a {
+        from.iter_days()
+            .take((MAX_CATCH_UP_DAYS + 2) as usize)
+            .filter(move |date| runs_on(days, *date))
+            .flat_map(move |date| {
+                let mut starts: Vec<_> = times
+                    .iter()
+                    .filter_map(|t| self.local_to_utc(date.and_time(*t)))
+                    .collect();
+                starts.sort();
+                starts
+            })
+    }
+
+    /// Convert local wall-clock time to UTC. Times skipped by a DST change
+    /// move forward an hour; repeated times use the first occurrence.
+    fn local_to_utc(&self, local: chrono::NaiveDateTime) -> Option<DateTime<Utc>> {
+        self.timezone
+            .from_local_datetime(&local)
+            .earliest()
+            .or_else(|| {
+                self.timezone
+                    .from_local_datetime(&(local + ChronoDuration::hours(1)))
+                    .earliest()
+            })
+            .map(|dt| dt.with_timezone(&Utc))
+    }
+}
+
+pub type SharedSchedule = Arc<RwLock<Schedule>>;
+
+/// Whether `date` falls on one of `days`; no days means every day.
+fn runs_on(days: &[Day], date: NaiveDate) -> bool {
+    days.is_empty() || days.iter().any(|d| Weekday::from(*d) == date.weekday())
+}
+
+fn parse_time(value: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(value, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(value, "%H:%M:%S"))
+        .ok()
+}
+
+/// Load the block's playlist into the queue according to its action.
+///
+/// Returns the number of tracks queued.
+pub async fn start_block(entry: &ScheduleEntry, playlists: &SharedPlaylists, player: &PlayerHandle) -> usize {
+    let tracks = match playlists.read().await.get(&entry.spec.playlist) {
+        Some(p) => p.tracks.clone(),
+        None => {
+            warn!(
+                "Schedule entry {} refers to missing playlist '{}'",
+                entry.id, entry.spec.playlist
+            );
+            return 0;
+        }
+    };
+
+    let tracks: Vec<_> = tracks
+        .iter()
+        .filter(|t| t.path.is_file() && !player.is_quarantined(&t.path))
+        .map(|t| t.with_new_id())
+        .collect();
+    let count = tracks.len();
+
+    {
+        let mut q = player.queue.write().await;
+        let kind = match entry.spec.action {
+            BlockAction::Enqueue => {
+                q.extend_back(tracks.clone());
+                QueueChangeKind::Added
+            }
+            BlockAction::Replace | BlockAction::HardCut => {
+                q.replace(tracks.clone());
+                QueueChangeKind::Replaced
+            }
+        };
+        metrics::QUEUE_LENGTH.set(q.len() as i64);
+        player.send_event(PlayerEvent::tracks_added(&q, kind, &tracks));
+    }
+
+    if entry.spec.action == BlockAction::HardCut {
+        player.skip().await;
+    }
+
+    info!(
+        "Schedule block {} started: playlist '{}' ({} tracks, {:?})",
+        entry.id, entry.spec.playlist, count, entry.spec.action
+    );
+    player.send_event(PlayerEvent::ScheduleBlockStarted {
+        entry: entry.clone(),
+        tracks: count,
+    });
+
+    count
+}
+
+/// Announce that a block's time is over. The queue is left as it is.
+pub fn end_block(entry: &ScheduleEntry, player: &PlayerHandle) {
+    info!("Schedule block {} ended: playlist '{}'", entry.id, entry.spec.playlist);
+    player.send_event(PlayerEvent::ScheduleBlockEnded { entry: entry.clone() });
+}
+
+/// Hand a timed insert to the player.
+pub async fn fire_insert(insert: &TimedInsert, default_cut: CutPolicy, player: &PlayerHandle) {
+    if !insert.spec.path.is_file() {
+        warn!(
+            "Timed insert {} skipped: {} not found",
+            insert.id,
+            insert.spec.path.display()
+        );
+        return;
+    }
+    if player.is_quarantined(&insert.spec.path) {
+        warn!(
+            "Timed insert {} skipped: {} is quarantined",
+            insert.id,
+            insert.spec.path.display()
+        );
+        return;
+    }
+
+    let mut track = Track::from_file(insert.spec.path.clone());
+    track.source = TrackSource::Insert;
+    if let Some(ref name) = insert.spec.name {
+        track.title = name.clone();
+    }
+
+    player
+        .interrupt(PendingInsert {
+            track,
+            cut_track: insert.spec.cut_track.unwrap_or(default_cut),
+            hard: insert.spec.mode == InsertMode::Hard,
+        })
+        .await;
+}
+
+/// Fire schedule blocks and timed inserts as their times pass.
+pub async fn run_scheduler(
+    schedule: SharedSchedule,
+    playlists: SharedPlaylists,
+    player: PlayerHandle,
+    clock: Arc<dyn Clock>,
+    shutdown: CancellationToken,
+) {
+    info!("Scheduler started");
+    let mut last_check = clock.now();
+    let mut soft_deadline: Option<DateTime<Utc>> = None;
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = clock.tick() => {}
+        }
+
+        let now = clock.now();
+        let (ended, due, inserts, cut_policy) = {
+            let schedule = schedule.read().await;
+            (
+                schedule.due_ends(last_check, now),
+                schedule.due(last_check, now),
+                schedule.due_inserts(last_check, now),
+                schedule.cut_policy(),
+            )
+        };
+        last_check = now;
+
+        // End blocks first, so a block following straight on starts after
+        for entry in ended {
+            end_block(&entry, &player);
+        }
+
+        for entry in due {
+            start_block(&entry, &playlists, &player).await;
+        }
+
+        for insert in inserts {
+            fire_insert(&insert, cut_policy, &player).await;
+            soft_deadline = (insert.spec.mode == InsertMode::Soft)
+                .then(|| now + ChronoDuration::seconds(insert.spec.tolerance_secs as i64));
+        }
+
+        // Soft inserts still waiting after their tolerance cut the track
+        if let Some(deadline) = soft_deadline {
+            if !player.has_pending_insert() {
+                soft_deadline = None;
+            } else if now >= deadline {
+                player.force_pending_insert().await;
+                soft_deadline = None;
+            }
+        }
+    }
+
+    debug!("Scheduler finished");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(days: Vec<Day>, start: &str, end: Option<&str>) -> ScheduleSpec {
+        ScheduleSpec {
+            name: None,
+            days,
+            start: start.to_string(),
+            end: end.map(str::to_string),
+            playlist: "breakfast".to_string(),
+            action: BlockAction::Replace,
+            enabled: true,
+        }
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(spec(vec![Day::Mon], "07:00", Some("10:00")).validate().is_ok());
+        assert!(spec(vec![], "07:00", None).validate().is_err());
+        assert!(spec(vec![Day::Mon], "7am", None).validate().is_err());
+        assert!(spec(vec![Day::Mon], "07:00", Some("25:00")).validate().is_err());
+    }
+
+    #[test]
+    fn test_due_with_manual_clock() {
+        let mut schedule = Schedule::new(chrono_tz::UTC);
+        let weekdays = vec![Day::Mon, Day::Tue, Day::Wed, Day::Thu, Day::Fri];
+        schedule.add(spec(weekdays, "07:00", Some("10:00")));
+
+        // 2024-01-01 was a Monday
+        let clock = ManualClock::new(utc("2024-01-01T06:59:00Z"));
+        let before = clock.now();
+        clock.advance(ChronoDuration::seconds(59));
+        assert!(schedule.due(before, clock.now()).is_empty());
+
+        let before = clock.now();
+        clock.advance(ChronoDuration::seconds(1));
+        assert_eq!(schedule.due(before, clock.now()).len(), 1);
+
+        // Already fired; the next tick must not fire again
+        let before = clock.now();
+        clock.advance(ChronoDuration::seconds(1));
+        assert!(schedule.due(before, clock.now()).is_empty());
+
+        // Saturday does not run
+        clock.set(utc("2024-01-06T06:59:59Z"));
+        let before = clock.now();
+        clock.advance(ChronoDuration::seconds(2));
+        assert!(schedule.due(before, clock.now()).is_empty());
+    }
+
+    #[test]
+    fn test_timezone_evaluation() {
+        let mut schedule = Schedule::new(chrono_tz::Europe::London);
+        let entry = schedule.add(spec(vec![Day::Mon], "07:00", None));
+
+        // In British Summer Time, 07:00 local is 06:00 UTC
+        let next = schedule.next_start(&entry.spec, utc("2024-07-01T00:00:00Z"));
+        assert_eq!(next, Some(utc("2024-07-01T06:00:00Z")));
+
+        // In winter it is 07:00 UTC
+        let next = schedule.next_start(&entry.spec, utc("2024-01-01T00:00:00Z"));
+        assert_eq!(next, Some(utc("2024-01-01T07:00:00Z")));
+    }
+
+    #[test]
+    fn test_active_past_midnight() {
+        let mut schedule = Schedule::new(chrono_tz::UTC);
+        let entry = schedule.add(spec(vec![Day::Fri], "22:00", Some("02:00")));
+
+        // 2024-01-05 was a Friday
+        assert!(schedule.is_active(&entry.spec, utc("2024-01-05T23:00:00Z")));
+        assert!(schedule.is_active(&entry.spec, utc("2024-01-06T01:30:00Z")));
+        assert!(!schedule.is_active(&entry.spec, utc("2024-01-06T02:00:00Z")));
+        assert!(!schedule.is_active(&entry.spec, utc("2024-01-05T21:00:00Z")));
+    }
+
+    #[test]
+    fn test_block_ends_due() {
+        let mut schedule = Schedule::new(chrono_tz::UTC);
+        schedule.add(spec(vec![Day::Fri], "22:00", Some("02:00")));
+        schedule.add(spec(vec![Day::Fri], "23:00", None));
+
+        // The Friday block ends early on Saturday
+        assert!(schedule
+            .due_ends(utc("2024-01-06T01:59:00Z"), utc("2024-01-06T01:59:59Z"))
+            .is_empty());
+        let ended = schedule.due_ends(utc("2024-01-06T01:59:59Z"), utc("2024-01-06T02:00:00Z"));
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].id, 1);
+        assert!(schedule
+            .due_ends(utc("2024-01-06T02:00:00Z"), utc("2024-01-06T02:00:01Z"))
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_scheduler_with_manual_clock() {
+        use crate::playlist::PlaylistStore;
+        use crate::queue::Queue;
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.ogg");
+        std::fs::write(&file, b"").unwrap();
+        let mut store = PlaylistStore::default();
+        store.save("breakfast", None, vec![Track::from_file(file)]);
+        let playlists: SharedPlaylists = Arc::new(RwLock::new(store));
+
+        let mut schedule = Schedule::new(chrono_tz::UTC);
+        let mut block = spec(vec![Day::Mon], "07:00", Some("07:30"));
+        block.action = BlockAction::Enqueue;
+        schedule.add(block);
+        let schedule: SharedSchedule = Arc::new(RwLock::new(schedule));
+
+        let queue = Arc::new(RwLock::new(Queue::default()));
+        let player = PlayerHandle::new(queue.clone());
+        let mut events = player.subscribe();
+        let clock = Arc::new(ManualClock::new(utc("2024-01-01T06:59:59Z")));
+        let shutdown = CancellationToken::new();
+        let task = tokio::spawn(run_scheduler(
+            schedule,
+            playlists,
+            player,
+            clock.clone(),
+            shutdown.clone(),
+        ));
+        // Let the scheduler read the starting time
+        tokio::task::yield_now().await;
+
+        async fn next_event(events: &mut tokio::sync::broadcast::Receiver<crate::events::SequencedEvent>) -> PlayerEvent {
+            tokio::time::timeout(Duration::from_secs(1), events.recv())
+                .await
+                .unwrap()
+                .unwrap()
+                .event
+        }
+
+        clock.advance(ChronoDuration::seconds(1));
+        assert!(matches!(next_event(&mut events).await, PlayerEvent::QueueChanged { length: 1, .. }));
+        assert!(matches!(next_event(&mut events).await, PlayerEvent::ScheduleBlockStarted { tracks: 1, .. }));
+
+        clock.advance(ChronoDuration::minutes(30));
+        assert!(matches!(next_event(&mut events).await, PlayerEvent::ScheduleBlockEnded { .. }));
+        assert_eq!(queue.read().await.len(), 1);
+
+        shutdown.cancel();
+        task.await.unwrap();
+    }
+
+    #[test]
+    fn test_disabled_entry_never_due() {
+        let mut schedule = Schedule::new(chrono_tz::UTC);
+        let mut s = spec(vec![Day::Mon], "07:00", None);
+        s.enabled = false;
+        schedule.add(s);
+        assert!(schedule
+            .due(utc("2024-01-01T06:00:00Z"), utc("2024-01-01T08:00:00Z"))
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_start_block_replaces_queue() {
+        use crate::playlist::PlaylistStore;
+        use crate::queue::{Queue, Track};
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.ogg");
+        let bad = dir.path().join("b.ogg");
+        std::fs::write(&file, b"").unwrap();
+        std::fs::write(&bad, b"").unwrap();
+
+        let mut store = PlaylistStore::default();
+        let tracks = vec![Track::from_file(file.clone()), Track::from_file(bad.clone()), Track::from_file(file)];
+        store.save("breakfast", None, tracks);
+        let playlists: SharedPlaylists = Arc::new(RwLock::new(store));
+
+        let queue = Arc::new(RwLock::new(Queue::default()));
+        let player = PlayerHandle::new(queue.clone());
+        let error = crate::history::TrackError { code: 3011, message: "unreadable".to_string() };
+        player.quarantine.write().unwrap().add(Track::from_file(bad), error, 1, 0);
+        let mut events = player.subscribe();
+
+        let entry = ScheduleEntry {
+            id: 1,
+            spec: spec(vec![Day::Mon], "07:00", None),
+        };
+        assert_eq!(start_block(&entry, &playlists, &player).await, 2);
+        assert_eq!(queue.read().await.len(), 2);
+
+        assert!(matches!(events.recv().await.unwrap().event, PlayerEvent::QueueChanged { length: 2, .. }));
+        assert!(matches!(
+            events.recv().await.unwrap().event,
+            PlayerEvent::ScheduleBlockStarted { tracks: 2, .. }
+        ));
+    }
+
+    fn insert_spec(at: &str, mode: InsertMode) -> InsertSpec {
+        InsertSpec {
+            name: Some("News".to_string()),
+            path: PathBuf::from("/srv/news.ogg"),
+            at: at.to_string(),
+            days: vec![],
+            mode,
+            tolerance_secs: 60,
+            cut_track: None,
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn test_insert_times() {
+        assert_eq!(insert_spec("*:00", InsertMode::Hard).insert_times().len(), 24);
+        assert_eq!(insert_spec("12:30:15", InsertMode::Hard).insert_times().len(), 1);
+        assert!(insert_spec("*:75", InsertMode::Hard).validate().is_err());
+        assert!(insert_spec("noon", InsertMode::Hard).validate().is_err());
+    }
+
+    #[test]
+    fn test_hourly_insert_due() {
+        let mut schedule = Schedule::new(chrono_tz::UTC);
+        schedule.add_insert(insert_spec("*:00", InsertMode::Hard));
+
+        assert!(schedule
+            .due_inserts(utc("2024-01-01T08:59:58Z"), utc("2024-01-01T08:59:59Z"))
+            .is_empty());
+        assert_eq!(
+            schedule
+                .due_inserts(utc("2024-01-01T08:59:59Z"), utc("2024-01-01T09:00:00Z"))
+                .len(),
+            1
+        );
+        assert_eq!(
+            schedule.next_insert(&schedule.inserts()[0].spec, utc("2024-01-01T09:00:00Z")),
+            Some(utc("2024-01-01T10:00:00Z"))
+        );
+
+        // Long-missed inserts are not aired late
+        assert!(schedule
+            .due_inserts(utc("2024-01-01T08:30:00Z"), utc("2024-01-01T09:30:00Z"))
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fire_insert_modes() {
+        use crate::queue::Queue;
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("news.ogg");
+        std::fs::write(&file, b"").unwrap();
+
+        let player = PlayerHandle::new(Arc::new(RwLock::new(Queue::default())));
+        let mut spec = insert_spec("*:00", InsertMode::Soft);
+        spec.path = file;
+        let insert = TimedInsert { id: 1, spec };
+
+        let current = player.start_test_track().await;
+        fire_insert(&insert, CutPolicy::Drop, &player).await;
+        assert!(!player.pending_insert().unwrap().hard);
+        assert!(!current.is_cancelled());
+
+        // Forcing a soft insert makes it hard and cuts the current track
+        player.force_pending_insert().await;
+        assert!(player.pending_insert().unwrap().hard);
+        assert!(current.is_cancelled());
+
+        let mut missing = insert.clone();
+        missing.spec.path = dir.path().join("missing.ogg");
+        let player = PlayerHandle::new(Arc::new(RwLock::new(Queue::default())));
+        fire_insert(&missing, CutPolicy::Drop, &player).await;
+        assert!(!player.has_pending_insert());
+
+        let error = crate::history::TrackError { code: 3011, message: "unreadable".to_string() };
+        let track = crate::queue::Track::from_file(insert.spec.path.clone());
+        player.quarantine.write().unwrap().add(track, error, 1, 0);
+        fire_insert(&insert, CutPolicy::Drop, &player).await;
+        assert!(!player.has_pending_insert());
+    }
+}
@@ -0,0 +1,187 @@
+// Queue shuffle strategies
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::path::PathBuf;
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::queue::Track;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShuffleMode {
+    /// Uniform random order
+    #[default]
+    Random,
+    /// Keep tracks by the same artist as far apart as possible
+    ArtistSpread,
+    /// Keep tracks from the same album as far apart as possible
+    AlbumSpread,
+    /// Favour tracks that have been played less often
+    Weighted,
+}
+
+/// Spread tracks that share a key evenly through the list.
+///
+/// Each group is placed at evenly spaced points with a random offset, so
+/// a group of k tracks lands roughly every n/k positions. Tracks without a
+/// key are placed at random.
+pub fn spread_by<K, F>(tracks: Vec<Track>, key: F) -> Vec<Track>
+where
+    K: Eq + Hash,
+    F: Fn(&Track) -> Option<K>,
+{
+    let mut rng = rand::rng();
+    let mut groups: HashMap<K, Vec<Track>> = HashMap::new();
+    let mut placed: Vec<(f64, Track)> = Vec::with_capacity(tracks.len());
+
+    for track in tracks {
+        match key(&track) {
+            Some(k) => groups.entry(k).or_default().push(track),
+            None => placed.push((rng.random::<f64>(), track)),
+        }
+    }
+
+    for (_, mut group) in groups {
+        group.shuffle(&mut rng);
+        let k = group.len() as f64;
+        let offset = rng.random::<f64>();
+        for (i, track) in group.into_iter().enumerate() {
+            // Small jitter so equal-sized groups don't interleave in lockstep
+            let jitter = (rng.random::<f64>() - 0.5) * 0.1;
+            placed.push(((i as f64 + offset + jitter) / k, track));
+        }
+    }
+
+    placed.sort_by(|a, b| a.0.total_cmp(&b.0));
+    placed.into_iter().map(|(_, t)| t).collect()
+}
+
+/// Random order biased towards tracks with fewer plays.
+///
+/// Uses weighted sampling without replacement with weight 1 / (1 + plays).
+pub fn weighted(tracks: Vec<Track>, plays: &HashMap<PathBuf, u64>) -> Vec<Track> {
+    let mut rng = rand::rng();
+    let mut keyed: Vec<(f64, Track)> = tracks
+        .into_iter()
+        .map(|t| {
+            let count = plays.get(&t.path).copied().unwrap_or(0);
+            // u^(1/w) with w = 1 / (1 + plays)
+            let key = rng.random::<f64>().powf(1.0 + count as f64);
+            (key, t)
+        })
+        .collect();
+
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+    keyed.into_iter().map(|(_, t)| t).collect()
+}
+
+/// Reorder so that at least `gap` tracks separate two tracks by the same
+/// artist, keeping the existing order wherever the rule allows. When no
+/// remaining track satisfies the rule the next one is taken anyway.
+pub fn enforce_artist_gap(tracks: Vec<Track>, gap: usize) -> Vec<Track> {
+    if gap == 0 {
+        return tracks;
+    }
+
+    let artist = |t: &Track| t.artist.as_ref().map(|a| a.to_lowercase());
+    let mut remaining = tracks;
+    let mut result: Vec<Track> = Vec::with_capacity(remaining.len());
+
+    while !remaining.is_empty() {
+        let recent: Vec<String> = result
+            .iter()
+            .rev()
+            .take(gap)
+            .filter_map(artist)
+            .collect();
+        let pick = remaining
+            .iter()
+            .position(|t| artist(t).is_none_or(|a| !recent.contains(&a)))
+            .unwrap_or(0);
+        result.push(remaining.remove(pick));
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(id: u64, artist: &str) -> Track {
+        Track {
+            id,
+            path: PathBuf::from(format!("/music/{}.ogg", id)),
+            title: id.to_string(),
+            artist: Some(artist.to_string()),
+            source: Default::default(),
+            requested_by: None,
+            tags: Default::default(),
+            overrides: Default::default(),
+        }
+    }
+
+    fn min_gap(tracks: &[Track]) -> usize {
+        let mut best = usize::MAX;
+        for (i, a) in tracks.iter().enumerate() {
+            for (j, b) in tracks.iter().enumerate().skip(i + 1) {
+                if a.artist == b.artist {
+                    best = best.min(j - i - 1);
+                }
+            }
+        }
+        best
+    }
+
+    #[test]
+    fn test_artist_spread_separates_artists() {
+        let tracks: Vec<Track> = (0..12)
+            .map(|i| track(i, ["A", "B", "C"][(i / 4) as usize]))
+            .collect();
+
+        // A random order averages three adjacent same-artist pairs here
+        let mut adjacent = 0;
+        for _ in 0..50 {
+            let spread = spread_by(tracks.clone(), |t| t.artist.clone());
+            assert_eq!(spread.len(), 12);
+            adjacent += spread.windows(2).filter(|w| w[0].artist == w[1].artist).count();
+        }
+        assert!(adjacent < 50, "{} adjacent pairs in 50 runs", adjacent);
+    }
+
+    #[test]
+    fn test_enforce_gap_interleaves() {
+        let tracks: Vec<Track> = (0..12)
+            .map(|i| track(i, ["A", "B", "C"][(i / 4) as usize]))
+            .collect();
+        assert_eq!(min_gap(&tracks), 0);
+        assert_eq!(min_gap(&enforce_artist_gap(tracks, 2)), 2);
+    }
+
+    #[test]
+    fn test_enforce_gap_is_best_effort() {
+        let tracks = vec![track(1, "A"), track(2, "A"), track(3, "B")];
+        let result = enforce_artist_gap(tracks, 1);
+        let ids: Vec<u64> = result.iter().map(|t| t.id).collect();
+        assert_eq!(ids, vec![1, 3, 2]);
+
+        // Impossible constraints still keep every track
+        let tracks = vec![track(1, "A"), track(2, "A")];
+        assert_eq!(enforce_artist_gap(tracks, 3).len(), 2);
+    }
+
+    #[test]
+    fn test_weighted_prefers_unplayed() {
+        let tracks: Vec<Track> = (0..2).map(|i| track(i, "A")).collect();
+        let plays: HashMap<PathBuf, u64> = [(tracks[0].path.clone(), 50)].into_iter().collect();
+
+        let unplayed_first = (0..200)
+            .filter(|_| weighted(tracks.clone(), &plays)[0].id == 1)
+            .count();
+        assert!(unplayed_first > 150, "unplayed first {} of 200", unplayed_first);
+    }
+}
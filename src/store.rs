@@ -0,0 +1,375 @@
+// On-disk state persistence helpers
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::warn;
+
+/// Write `value` as JSON to `path`, replacing any existing file atomically.
+///
+/// The data is written to a temporary sibling file, synced, and then renamed
+/// over the target so a crash mid-write never leaves a truncated state file.
+pub fn write_json_atomic<T: Serialize + ?Sized>(path: &Path, value: &T) -> io::Result<()> {
+    let json = serde_json::to_vec_pretty(value)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_bytes_atomic(path, &json)
+}
+
+/// Read a JSON document from `path`.
+///
+/// Returns `Ok(None)` if the file does not exist yet.
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> io::Result<Option<T>> {
+    let data = match fs::read(path) {
+        Ok(d) => d,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    serde_json::from_slice(&data)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Append `value` as a single JSON line to `path`, creating the file if needed.
+///
+/// If the last line was cut short by a crash it is ended first, so only that
+/// line is lost and not the new one too.
+pub fn append_json_line<T: Serialize + ?Sized>(path: &Path, value: &T) -> io::Result<()> {
+    let mut line = serde_json::to_vec(value)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    line.push(b'\n');
+
+    let mut file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+    let len = file.metadata()?.len();
+    if len > 0 {
+        let mut last = [0u8; 1];
+        file.seek(SeekFrom::Start(len - 1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            line.insert(0, b'\n');
+        }
+    }
+    file.write_all(&line)
+}
+
+/// Read every JSON line from `path`.
+///
+/// Lines that fail to parse (for example a record truncated by a crash) are
+/// skipped. Returns an empty list if the file does not exist yet.
+pub fn read_json_lines<T: DeserializeOwned>(path: &Path) -> io::Result<Vec<T>> {
+    let mut records = Vec::new();
+    scan_json_lines(path, |record| {
+        records.push(record);
+        true
+    })?;
+    Ok(records)
+}
+
+/// Pass each JSON line in `path` to `visit` in order, without holding the
+/// whole file in memory, until `visit` returns false.
+///
+/// Lines are skipped as by [`read_json_lines`]; a missing file has none.
+pub fn scan_json_lines<T: DeserializeOwned>(path: &Path, mut visit: impl FnMut(T) -> bool) -> io::Result<()> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Ok(record) = serde_json::from_str(&line) {
+            if !visit(record) {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replace the contents of a JSON lines file atomically.
+pub fn write_json_lines_atomic<T: Serialize>(path: &Path, values: &[T]) -> io::Result<()> {
+    let mut data = Vec::new();
+    for value in values {
+        serde_json::to_writer(&mut data, value)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        data.push(b'\n');
+    }
+    write_bytes_atomic(path, &data)
+}
+
+fn write_bytes_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+    }
+
+    fs::rename(&tmp_path, path)
+}
+
+enum WriterMsg<T> {
+    Write(T),
+    Flush(mpsc::Sender<()>),
+}
+
+/// Writes snapshots of some state to a file on a background thread, so
+/// callers holding locks never wait on the disk.
+///
+/// Snapshots sent faster than they can be written are coalesced and only the
+/// latest is written. Dropping the writer waits for pending writes.
+pub struct StateWriter<T> {
+    tx: Option<mpsc::Sender<WriterMsg<T>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<T: Serialize + Send + 'static> StateWriter<T> {
+    /// Start a writer for `path`; `what` names the state in warnings.
+    pub fn spawn(path: PathBuf, what: &'static str) -> Self {
+        let (tx, rx) = mpsc::channel::<WriterMsg<T>>();
+        let thread = std::thread::spawn(move || {
+            while let Ok(msg) = rx.recv() {
+                let mut latest = None;
+                let mut flushed = Vec::new();
+                for msg in std::iter::once(msg).chain(rx.try_iter()) {
+                    match msg {
+                        WriterMsg::Write(value) => latest = Some(value),
+                        WriterMsg::Flush(done) => flushed.push(done),
+                    }
+                }
+                if let Some(value) = latest {
+                    if let Err(e) = write_json_atomic(&path, &value) {
+                        warn!("Failed to write {} {}: {}", what, path.display(), e);
+                    }
+                }
+                for done in flushed {
+                    let _ = done.send(());
+                }
+            }
+        });
+        Self { tx: Some(tx), thread: Some(thread) }
+    }
+
+    /// Queue `value` to be written.
+    pub fn write(&self, value: T) {
+        if let Some(ref tx) = self.tx {
+            let _ = tx.send(WriterMsg::Write(value));
+        }
+    }
+
+    /// Block until everything queued so far is on disk.
+    pub fn flush(&self) {
+        let (done_tx, done_rx) = mpsc::channel();
+        if let Some(ref tx) = self.tx {
+            if tx.send(WriterMsg::Flush(done_tx)).is_ok() {
+                let _ = done_rx.recv();
+            }
+        }
+    }
+}
+
+impl<T> Drop for StateWriter<T> {
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for StateWriter<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StateWriter").finish_non_exhaustive()
+    }
+}
+
+enum AppendMsg<T> {
+    Append(T),
+    Rewrite(Vec<T>),
+    Flush(mpsc::Sender<()>),
+}
+
+/// Appends JSON lines to a file on a background thread, in the order they
+/// are sent. Dropping the appender waits for pending lines.
+pub struct LineAppender<T> {
+    tx: Option<mpsc::Sender<AppendMsg<T>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<T: Serialize + Send + 'static> LineAppender<T> {
+    /// Start an appender for `path`; `what` names the file in warnings.
+    pub fn spawn(path: PathBuf, what: &'static str) -> Self {
+        let (tx, rx) = mpsc::channel::<AppendMsg<T>>();
+        let thread = std::thread::spawn(move || {
+            while let Ok(msg) = rx.recv() {
+                match msg {
+                    AppendMsg::Append(value) => {
+                        if let Err(e) = append_json_line(&path, &value) {
+                            warn!("Failed to append to {} {}: {}", what, path.display(), e);
+                        }
+                    }
+                    AppendMsg::Rewrite(values) => {
+                        if let Err(e) = write_json_lines_atomic(&path, &values) {
+                            warn!("Failed to rewrite {} {}: {}", what, path.display(), e);
+                        }
+                    }
+                    AppendMsg::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+        Self { tx: Some(tx), thread: Some(thread) }
+    }
+
+    /// Queue `value` to be appended.
+    pub fn append(&self, value: T) {
+        if let Some(ref tx) = self.tx {
+            let _ = tx.send(AppendMsg::Append(value));
+        }
+    }
+
+    /// Queue a replacement of the whole file with `values`, such as when
+    /// compacting it. Lines appended afterwards follow them.
+    pub fn rewrite(&self, values: Vec<T>) {
+        if let Some(ref tx) = self.tx {
+            let _ = tx.send(AppendMsg::Rewrite(values));
+        }
+    }
+
+    /// Block until everything queued so far is on disk.
+    pub fn flush(&self) {
+        let (done_tx, done_rx) = mpsc::channel();
+        if let Some(ref tx) = self.tx {
+            if tx.send(AppendMsg::Flush(done_tx)).is_ok() {
+                let _ = done_rx.recv();
+            }
+        }
+    }
+}
+
+impl<T> Drop for LineAppender<T> {
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for LineAppender<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LineAppender").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_roundtrip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state.json");
+
+        write_json_atomic(&path, &vec![1u32, 2, 3]).unwrap();
+        let loaded: Option<Vec<u32>> = read_json(&path).unwrap();
+        assert_eq!(loaded, Some(vec![1, 2, 3]));
+        assert!(!dir.path().join("state.json.tmp").exists());
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let dir = tempdir().unwrap();
+        let loaded: Option<Vec<u32>> = read_json(&dir.path().join("absent.json")).unwrap();
+        assert!(loaded.is_none());
+    }
+
+    #[test]
+    fn test_corrupt_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        fs::write(&path, b"{not json").unwrap();
+        assert!(read_json::<Vec<u32>>(&path).is_err());
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("log.jsonl");
+
+        append_json_line(&path, &1u32).unwrap();
+        append_json_line(&path, &2u32).unwrap();
+        fs::OpenOptions::new().append(true).open(&path).unwrap()
+            .write_all(b"{\"trunc").unwrap();
+
+        let records: Vec<u32> = read_json_lines(&path).unwrap();
+        assert_eq!(records, vec![1, 2]);
+
+        // A record appended after a truncated one is kept
+        append_json_line(&path, &4u32).unwrap();
+        let records: Vec<u32> = read_json_lines(&path).unwrap();
+        assert_eq!(records, vec![1, 2, 4]);
+
+        write_json_lines_atomic(&path, &[3u32]).unwrap();
+        let records: Vec<u32> = read_json_lines(&path).unwrap();
+        assert_eq!(records, vec![3]);
+    }
+
+    #[test]
+    fn test_state_writer() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state.json");
+
+        let writer = StateWriter::spawn(path.clone(), "test state");
+        for i in 0..100u32 {
+            writer.write(vec![i]);
+        }
+        writer.flush();
+        assert_eq!(read_json::<Vec<u32>>(&path).unwrap(), Some(vec![99]));
+
+        writer.write(vec![100]);
+        drop(writer);
+        assert_eq!(read_json::<Vec<u32>>(&path).unwrap(), Some(vec![100]));
+    }
+
+    #[test]
+    fn test_line_appender() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("log.jsonl");
+
+        let appender = LineAppender::spawn(path.clone(), "test log");
+        for i in 0..100u32 {
+            appender.append(i);
+        }
+        appender.flush();
+        let lines: Vec<u32> = read_json_lines(&path).unwrap();
+        assert_eq!(lines, (0..100).collect::<Vec<_>>());
+
+        let mut seen = Vec::new();
+        scan_json_lines(&path, |n: u32| {
+            seen.push(n);
+            n < 2
+        })
+        .unwrap();
+        assert_eq!(seen, vec![0, 1, 2]);
+
+        appender.rewrite(vec![7, 8]);
+        appender.append(9);
+        appender.flush();
+        assert_eq!(read_json_lines::<u32>(&path).unwrap(), vec![7, 8, 9]);
+    }
+}
@@ -0,0 +1,239 @@
+// Watches the media directory and keeps the library and queue in step
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::library::{self, LibraryChanges, SharedLibrary};
+use crate::metrics;
+use crate::player::{PlayerEvent, PlayerHandle, QueueChangeKind, TrackPosition};
+use crate::queue::Track;
+
+/// Quiet period after a filesystem event before the batch is applied, so
+/// a file being copied in is read once it is complete.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Start watching the library's media directory.
+///
+/// Fails if the platform watcher can't be set up, e.g. when the inotify
+/// watch limit is reached.
+pub fn spawn_watcher(
+    library: SharedLibrary,
+    player: PlayerHandle,
+    shutdown: CancellationToken,
+) -> notify::Result<()> {
+    let root = library.read().unwrap().root().to_path_buf();
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| match res {
+        Ok(event) => {
+            let _ = tx.send(event);
+        }
+        Err(e) => warn!("Media directory watch error: {}", e),
+    })?;
+    watcher.watch(&root, RecursiveMode::Recursive)?;
+    info!("Watching {} for changes", root.display());
+
+    tokio::spawn(run_watcher(watcher, rx, library, player, shutdown));
+    Ok(())
+}
+
+async fn run_watcher(
+    _watcher: RecommendedWatcher,
+    mut rx: mpsc::UnboundedReceiver<Event>,
+    library: SharedLibrary,
+    player: PlayerHandle,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let first = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            event = rx.recv() => match event {
+                Some(event) => event,
+                None => break,
+            },
+        };
+        let mut events = vec![first];
+        while let Ok(Some(event)) = timeout(DEBOUNCE, rx.recv()).await {
+            events.push(event);
+        }
+
+        let library = library.clone();
+        let changes = match tokio::task::spawn_blocking(move || apply_events(&library, events)).await {
+            Ok(changes) => changes,
+            Err(e) => {
+                warn!("Failed to update library: {}", e);
+                continue;
+            }
+        };
+        if !changes.is_empty() {
+            sync_queue(&player, &changes).await;
+        }
+    }
+    debug!("Media directory watcher stopped");
+}
+
+/// Update the library for a batch of filesystem events.
+pub fn apply_events(library: &SharedLibrary, events: Vec<Event>) -> LibraryChanges {
+    let mut changes = LibraryChanges::default();
+    let mut paths = Vec::new();
+    for event in events {
+        match event.kind {
+            EventKind::Access(_) => {}
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                changes.merge(library::rename_path(library, &event.paths[0], &event.paths[1]));
+            }
+            _ => paths.extend(event.paths),
+        }
+    }
+
+    let mut seen = HashSet::new();
+    paths.retain(|p| seen.insert(p.clone()));
+    if !paths.is_empty() {
+        changes.merge(library::refresh_paths(library, &paths));
+    }
+    if !changes.is_empty() {
+        info!(
+            "Media directory changed: {} added, {} updated, {} moved, {} removed",
+            changes.added.len(),
+            changes.updated.len(),
+            changes.moved.len(),
+            changes.removed.len()
+        );
+    }
+    changes
+}
+
+/// Bring queued tracks in line with `changes` and tell clients.
+///
+/// Retagged and renamed tracks get their new tags and path; tracks whose
+/// files were deleted are dropped from the queue.
+pub async fn sync_queue(player: &PlayerHandle, changes: &LibraryChanges) {
+    let changed = changes
+        .updated
+        .iter()
+        .map(|t| (t.path.clone(), t.path.clone()))
+        .chain(changes.moved.iter().map(|(from, t)| (from.clone(), t.path.clone())));
+    let current: HashMap<PathBuf, Track> = tokio::task::spawn_blocking({
+        let changed: Vec<_> = changed.collect();
+        move || changed.into_iter().map(|(from, to)| (from, Track::from_file(to))).collect()
+    })
+    .await
+    .unwrap_or_default();
+    let vanished: HashSet<&PathBuf> = changes.removed.iter().map(|t| &t.path).collect();
+
+    {
+        let mut q = player.queue.write().await;
+        let updated = q.update_tracks(|track| {
+            let Some(file) = current.get(&track.path) else {
+                return false;
+            };
+            let before = track.clone();
+            let overrides = std::mem::take(&mut track.overrides);
+            track.path = file.path.clone();
+            track.title = file.title.clone();
+            track.artist = file.artist.clone();
+            track.tags = file.tags.clone();
+            track.override_metadata(&overrides);
+            track.path != before.path
+                || track.title != before.title
+                || track.artist != before.artist
+                || track.tags != before.tags
+        });
+        if !updated.is_empty() {
+            let tracks = q
+                .list()
+                .into_iter()
+                .enumerate()
+                .filter(|(_, t)| updated.contains(&t.id))
+                .map(|(position, t)| TrackPosition { id: t.id, position, track: Some(t) })
+                .collect();
+            player.send_event(PlayerEvent::queue_changed(&q, QueueChangeKind::Updated, tracks));
+        }
+
+        let removed = q.remove_where(|track| vanished.contains(&track.path));
+        if !removed.is_empty() {
+            for (_, track) in &removed {
+                warn!("Removed \"{}\" from the queue: {} was deleted", track.title, track.path.display());
+            }
+            metrics::QUEUE_LENGTH.set(q.len() as i64);
+            let tracks = removed.iter().map(|(position, t)| TrackPosition::new(t.id, *position)).collect();
+            player.send_event(PlayerEvent::queue_changed(&q, QueueChangeKind::Vanished, tracks));
+        }
+    }
+
+    player.send_event(PlayerEvent::LibraryChanged {
+        added: changes.added.clone(),
+        updated: changes
+            .updated
+            .iter()
+            .chain(changes.moved.iter().map(|(_, t)| t))
+            .cloned()
+            .collect(),
+        removed: changes.removed.iter().map(|t| t.id).collect(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::Library;
+    use crate::queue::test_files::write_ogg;
+    use crate::queue::Queue;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+    use tokio::sync::RwLock;
+
+    #[tokio::test]
+    async fn test_queue_follows_library_changes() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        write_ogg(&root.join("a.ogg"), &[("TITLE", "A")], 10);
+        write_ogg(&root.join("b.ogg"), &[("TITLE", "B")], 10);
+        write_ogg(&root.join("c.ogg"), &[("TITLE", "C")], 10);
+        let library: SharedLibrary = Arc::new(std::sync::RwLock::new(Library::new(root.clone())));
+        library::rescan(&library);
+
+        let mut queue = Queue::default();
+        for name in ["a", "b", "c"] {
+            queue.push_back(Track::from_file(root.join(format!("{}.ogg", name))));
+        }
+        let player = PlayerHandle::new(Arc::new(RwLock::new(queue)));
+        let mut events = player.subscribe();
+
+        write_ogg(&root.join("a.ogg"), &[("TITLE", "A (Remix)"), ("ARTIST", "X")], 11);
+        std::fs::rename(root.join("b.ogg"), root.join("b2.ogg")).unwrap();
+        std::fs::remove_file(root.join("c.ogg")).unwrap();
+        let mut changes = library::refresh_paths(&library, &[root.join("a.ogg"), root.join("c.ogg")]);
+        changes.merge(library::rename_path(&library, &root.join("b.ogg"), &root.join("b2.ogg")));
+        sync_queue(&player, &changes).await;
+
+        let queue = player.queue.read().await.list();
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue[0].title, "A (Remix)");
+        assert_eq!(queue[0].artist.as_deref(), Some("X"));
+        assert_eq!(queue[1].path, root.join("b2.ogg"));
+
+        let event = events.recv().await.unwrap();
+        assert!(matches!(
+            event.event,
+            PlayerEvent::QueueChanged { kind: QueueChangeKind::Updated, ref tracks, .. } if tracks.len() == 2
+        ));
+        let event = events.recv().await.unwrap();
+        assert!(matches!(
+            event.event,
+            PlayerEvent::QueueChanged { kind: QueueChangeKind::Vanished, ref tracks, .. }
+                if tracks.len() == 1 && tracks[0].position == 2
+        ));
+        let event = events.recv().await.unwrap();
+        assert!(matches!(
+            event.event,
+            PlayerEvent::LibraryChanged { ref updated, ref removed, .. } if updated.len() == 2 && removed.len() == 1
+        ));
+    }
+}
@@ -0,0 +1,327 @@
+// WebSocket control channel: JSON-RPC style calls onto the REST API, with
+// player events pushed on the same socket
+
+use std::net::SocketAddr;
+
+use axum::body::{to_bytes, Body};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, State};
+use axum::http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode};
+use axum::response::Response;
+use axum::{Extension, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tower::ServiceExt;
+use tracing::debug;
+
+use crate::api::{self, AppState};
+use crate::errors::ErrorCode;
+use crate::events::{Delivery, EventCursor};
+
+/// Socket methods and the REST routes they call. Path parameters are
+/// taken from the call's params by name.
+pub const METHODS: &[(&str, &str, &str)] = &[
+    ("queue.list", "GET", "/api/queue"),
+    ("queue.add", "POST", "/api/queue"),
+    ("queue.clear", "DELETE", "/api/queue"),
+    ("queue.add_next", "POST", "/api/queue/next"),
+    ("queue.bulk", "POST", "/api/queue/bulk"),
+    ("queue.batch", "POST", "/api/queue/batch"),
+    ("queue.import", "POST", "/api/queue/import"),
+    ("queue.export", "GET", "/api/queue/export"),
+    ("queue.shuffle", "POST", "/api/queue/shuffle"),
+    ("queue.remove", "DELETE", "/api/queue/{id}"),
+    ("queue.update", "PATCH", "/api/queue/{id}"),
+    ("queue.move", "PUT", "/api/queue/{id}/position"),
+    ("playlists.list", "GET", "/api/playlists"),
+    ("playlists.create", "POST", "/api/playlists"),
+    ("playlists.get", "GET", "/api/playlists/{name}"),
+    ("playlists.update", "PUT", "/api/playlists/{name}"),
+    ("playlists.delete", "DELETE", "/api/playlists/{name}"),
+    ("playlists.enqueue", "POST", "/api/playlists/{name}/enqueue"),
+    ("schedule.list", "GET", "/api/schedule"),
+    ("schedule.create", "POST", "/api/schedule"),
+    ("schedule.get", "GET", "/api/schedule/{id}"),
+    ("schedule.update", "PUT", "/api/schedule/{id}"),
+    ("schedule.delete", "DELETE", "/api/schedule/{id}"),
+    ("inserts.list", "GET", "/api/inserts"),
+    ("inserts.create", "POST", "/api/inserts"),
+    ("inserts.delete", "DELETE", "/api/inserts/{id}"),
+    ("jingles.get", "GET", "/api/jingles"),
+    ("jingles.set", "PUT", "/api/jingles"),
+    ("playback.get", "GET", "/api/playback"),
+    ("playback.set_mode", "PUT", "/api/playback/mode"),
+    ("playback.resume", "POST", "/api/playback/resume"),
+    ("player.skip", "POST", "/api/skip"),
+    ("now_playing.update", "PATCH", "/api/now-playing/metadata"),
+    ("status", "GET", "/api/status"),
+    ("history.list", "GET", "/api/history"),
+    ("history.stats", "GET", "/api/history/stats"),
+    ("reports.plays", "GET", "/api/reports/plays"),
+    ("library.search", "GET", "/api/library"),
+    ("library.get", "GET", "/api/library/{id}"),
+    ("library.scan", "POST", "/api/library/scan"),
+    ("requests.list", "GET", "/api/requests"),
+    ("requests.approve", "POST", "/api/requests/{id}/approve"),
+    ("requests.reject", "DELETE", "/api/requests/{id}"),
+    ("audit.list", "GET", "/api/audit"),
+    ("quarantine.list", "GET", "/api/quarantine"),
+    ("quarantine.release", "DELETE", "/api/quarantine/{id}"),
+];
+
+/// Socket method name for a REST route, as matched by the router.
+pub fn method_name(method: &Method, route: &str) -> Option<&'static str> {
+    METHODS
+        .iter()
+        .find(|(_, m, r)| *m == method.as_str() && *r == route)
+        .map(|(name, _, _)| *name)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RpcRequest {
+    /// Echoed in the response so clients can match replies to calls
+    #[serde(default)]
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Map<String, Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcError {
+    pub code: u32,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcResponse {
+    pub id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+    /// Queue revision, for calls whose REST route returns an ETag
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<u64>,
+}
+
+impl RpcResponse {
+    fn error(id: Value, code: ErrorCode, message: String) -> Self {
+        Self {
+            id,
+            result: None,
+            error: Some(RpcError {
+                code: code.as_u32(),
+                message,
+            }),
+            revision: None,
+        }
+    }
+}
+
+/// Who opened the socket: calls are made with the same credentials and
+/// client address.
+#[derive(Debug, Clone, Default)]
+pub struct Caller {
+    pub auth: Option<HeaderValue>,
+    pub remote_addr: Option<SocketAddr>,
+}
+
+pub async fn ws_handler(
+    State(state): State<AppState>,
+    connect_info: Option<Extension<ConnectInfo<SocketAddr>>>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let caller = Caller {
+        auth: headers.get(header::AUTHORIZATION).cloned(),
+        remote_addr: connect_info.map(|Extension(ConnectInfo(addr))| addr),
+    };
+    ws.on_upgrade(move |socket| run_socket(socket, state, caller))
+}
+
+async fn run_socket(mut socket: WebSocket, state: AppState, caller: Caller) {
+    let app = api::router(state.clone());
+    let mut events = EventCursor::new(&state.player, None);
+    debug!("WebSocket client connected");
+
+    loop {
+        let reply = tokio::select! {
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    let response = match serde_json::from_str::<RpcRequest>(&text) {
+                        Ok(req) => call(&app, &caller, req).await,
+                        Err(e) => RpcResponse::error(
+                            Value::Null,
+                            ErrorCode::InvalidRequest,
+                            format!("Invalid request: {}", e),
+                        ),
+                    };
+                    serde_json::to_string(&response)
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by axum; binary frames aren't used
+                Some(Ok(_)) => continue,
+            },
+            delivery = events.next() => match delivery {
+                Some(delivery) => serde_json::to_string(&notification(delivery)),
+                None => break,
+            },
+        };
+
+        let Ok(reply) = reply else { continue };
+        if socket.send(Message::Text(reply.into())).await.is_err() {
+            break;
+        }
+    }
+    debug!("WebSocket client disconnected");
+}
+
+/// Events are sent as notifications: messages with a method and no id.
+fn notification(delivery: Delivery) -> Value {
+    match delivery {
+        Delivery::Event(event) => serde_json::json!({ "method": "event", "params": event }),
+        Delivery::Resync { id, reason } => {
+            serde_json::json!({ "method": "resync", "params": { "id": id, "reason": reason } })
+        }
+    }
+}
+
+/// Run `req` against the REST API in `app` and translate the response.
+pub async fn call(app: &Router, caller: &Caller, req: RpcRequest) -> RpcResponse {
+    let id = req.id.clone();
+    let request = match build_request(req, caller) {
+        Ok(r) => r,
+        Err((code, message)) => return RpcResponse::error(id, code, message),
+    };
+    match app.clone().oneshot(request).await {
+        Ok(response) => into_rpc(id, response).await,
+        Err(never) => match never {},
+    }
+}
+
+fn build_request(req: RpcRequest, caller: &Caller) -> Result<Request<Body>, (ErrorCode, String)> {
+    let Some(&(_, method, template)) = METHODS.iter().find(|(name, _, _)| *name == req.method)
+    else {
+        return Err((ErrorCode::UnknownMethod, format!("Unknown method '{}'", req.method)));
+    };
+    let method: Method = method.parse().expect("valid HTTP method");
+    let mut params = req.params;
+    let if_match = params.remove("if_match");
+
+    let mut uri = String::new();
+    for segment in template.split('/').skip(1) {
+        uri.push('/');
+        match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+            Some(name) => {
+                let value = params
+                    .remove(name)
+                    .ok_or_else(|| (ErrorCode::InvalidRequest, format!("Missing param '{}'", name)))?;
+                uri.push_str(&encode(&scalar(name, &value)?));
+            }
+            None => uri.push_str(segment),
+        }
+    }
+
+    let mut builder = Request::builder().method(method.clone());
+    if let Some(ref auth) = caller.auth {
+        builder = builder.header(header::AUTHORIZATION, auth);
+    }
+    if let Some(addr) = caller.remote_addr {
+        builder = builder.extension(ConnectInfo(addr));
+    }
+    if let Some(rev) = if_match {
+        let rev = scalar("if_match", &rev)?;
+        builder = builder.header(header::IF_MATCH, format!("\"{}\"", rev.trim_matches('"')));
+    }
+
+    let body = if method == Method::GET || method == Method::DELETE {
+        let query = params
+            .iter()
+            .map(|(k, v)| Ok(format!("{}={}", encode(k), encode(&scalar(k, v)?))))
+            .collect::<Result<Vec<_>, (ErrorCode, String)>>()?;
+        if !query.is_empty() {
+            uri = format!("{}?{}", uri, query.join("&"));
+        }
+        Body::empty()
+    } else {
+        builder = builder.header(header::CONTENT_TYPE, "application/json");
+        Body::from(Value::Object(params).to_string())
+    };
+
+    builder
+        .uri(uri)
+        .body(body)
+        .map_err(|e| (ErrorCode::InvalidRequest, e.to_string()))
+}
+
+/// A param used in a path or query string.
+fn scalar(name: &str, value: &Value) -> Result<String, (ErrorCode, String)> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err((
+            ErrorCode::InvalidRequest,
+            format!("Param '{}' must be a string, number or boolean", name),
+        )),
+    }
+}
+
+/// Percent-encode everything but unreserved characters.
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+async fn into_rpc(id: Value, response: Response) -> RpcResponse {
+    let status = response.status();
+    let revision = response
+        .headers()
+        .get(header::ETAG)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim_matches('"').parse().ok());
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap_or_default();
+    let value = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(&body)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).into_owned()))
+    };
+
+    if status.is_success() {
+        return RpcResponse {
+            id,
+            result: Some(value),
+            error: None,
+            revision,
+        };
+    }
+
+    // API errors carry a message and code; anything else (such as a body
+    // the handler couldn't parse) is mapped from the status
+    let message = value["error"]
+        .as_str()
+        .map(str::to_string)
+        .or_else(|| value.as_str().map(str::to_string))
+        .unwrap_or_else(|| status.canonical_reason().unwrap_or("Error").to_string());
+    let code = match value["code"].as_u64() {
+        Some(code) => code as u32,
+        None if status == StatusCode::FORBIDDEN => ErrorCode::InsufficientScope.as_u32(),
+        None if status.is_client_error() => ErrorCode::InvalidRequest.as_u32(),
+        None => ErrorCode::Unknown.as_u32(),
+    };
+    RpcResponse {
+        id,
+        result: None,
+        error: Some(RpcError { code, message }),
+        revision,
+    }
+}
@@ -6,9 +6,14 @@ use axum::http::{Request, StatusCode};
 use tower::ServiceExt;
 
 use snowboot::api::{AppState, router};
+use snowboot::audit::AuditLog;
+use snowboot::auth::{ApiToken, AuthLimiter, LockoutPolicy, Scope, TokenStore};
 use snowboot::connection::ConnectionState;
-use snowboot::player::PlayerHandle;
+use snowboot::player::{PlaybackMode, PlayerHandle};
+use snowboot::playlist::PlaylistStore;
 use snowboot::queue::{Queue, SharedQueue};
+use snowboot::scheduler::Schedule;
+use snowboot::ws::{self, Caller, RpcRequest};
 
 fn test_state() -> AppState {
     let queue: SharedQueue = Arc::new(tokio::sync::RwLock::new(Queue::default()));
@@ -16,16 +21,24 @@ fn test_state() -> AppState {
     AppState {
         queue,
         player,
+        playlists: Arc::new(tokio::sync::RwLock::new(PlaylistStore::default())),
+        schedule: Arc::new(tokio::sync::RwLock::new(Schedule::new(chrono_tz::UTC))),
         start_time: Instant::now(),
         connection_state: Arc::new(std::sync::Mutex::new(ConnectionState::Connected)),
         media_dir: None,
-        api_token: None,
+        library: None,
+        tokens: None,
+        auth_limiter: Arc::new(AuthLimiter::default()),
+        audit: Arc::new(std::sync::RwLock::new(AuditLog::default())),
+        requests: None,
+        min_artist_gap: 0,
+        artwork: Default::default(),
     }
 }
 
 fn test_state_with_auth(token: &str) -> AppState {
     let mut state = test_state();
-    state.api_token = Some(token.to_string());
+    state.tokens = Some(Arc::new(TokenStore::default().with_default_token(token.to_string()).unwrap()));
     state
 }
 
@@ -179,6 +192,17 @@ async fn test_auth_required_wrong_token() {
     assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
 }
 
+async fn authed_request(app: axum::Router, method: &str, uri: &str, token: &str) -> StatusCode {
+    let req = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("authorization", format!("Bearer {}-secret", token))
+        .header("content-type", "application/json")
+        .body(Body::from("{}"))
+        .unwrap();
+    app.oneshot(req).await.unwrap().status()
+}
+
 #[tokio::test]
 async fn test_auth_correct_token() {
     let app = router(test_state_with_auth("secret123"));
@@ -235,3 +259,1500 @@ async fn test_media_dir_blocks_outside_paths() {
     assert_ne!(resp.status(), StatusCode::OK, "Should reject path outside media dir");
     assert_ne!(resp.status(), StatusCode::CREATED, "Should reject path outside media dir");
 }
+
+// --- History query tests ---
+
+#[tokio::test]
+async fn test_history_filters_accepted() {
+    let app = router(test_state());
+    let resp = app
+        .oneshot(
+            Request::get("/api/history?since=2024-01-02T21:00:00Z&until=1704232800&artist=x&skipped=false&limit=10&order=desc")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers().get("x-next-cursor").is_none());
+}
+
+#[tokio::test]
+async fn test_history_invalid_timestamp() {
+    let app = router(test_state());
+    let resp = app
+        .oneshot(Request::get("/api/history?since=yesterday").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_history_stats_empty() {
+    let app = router(test_state());
+    let resp = app
+        .oneshot(Request::get("/api/history/stats").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(stats["total_plays"], 0);
+}
+
+// --- Report tests ---
+
+#[tokio::test]
+async fn test_play_report_csv_header() {
+    let app = router(test_state());
+    let resp = app
+        .oneshot(Request::get("/api/reports/plays?format=csv&from=0").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers()["content-type"].to_str().unwrap().starts_with("text/csv"));
+
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    assert!(body.starts_with(b"started_at,duration_secs,title,artist"));
+}
+
+#[tokio::test]
+async fn test_play_report_unknown_format() {
+    let app = router(test_state());
+    let resp = app
+        .oneshot(Request::get("/api/reports/plays?format=xlsx").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_play_report_excludes_jingles() {
+    use snowboot::history::HistoryEntry;
+    use snowboot::queue::{Track, TrackSource};
+
+    let state = test_state();
+    {
+        let mut history = state.player.history.write().unwrap();
+        let song = Track {
+            id: 1,
+            path: "/music/song.ogg".into(),
+            title: "Song".to_string(),
+            artist: None,
+            source: TrackSource::Queue,
+            requested_by: None,
+            tags: Default::default(),
+            overrides: Default::default(),
+        };
+        let jingle = Track {
+            title: "Station ID".to_string(),
+            source: TrackSource::Jingle,
+            ..song.clone()
+        };
+        history.record(HistoryEntry::new(song, 100, 180, false));
+        history.record(HistoryEntry::new(jingle, 300, 5, false));
+    }
+
+    let resp = router(state.clone())
+        .oneshot(Request::get("/api/reports/plays?format=json").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let report = body_json(resp).await;
+    assert_eq!(report.as_array().unwrap().len(), 1);
+    assert_eq!(report[0]["title"], "Song");
+
+    let resp = router(state)
+        .oneshot(Request::get("/api/history?source=jingle").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let history = body_json(resp).await;
+    assert_eq!(history.as_array().unwrap().len(), 1);
+    assert_eq!(history[0]["track"]["source"], "jingle");
+}
+
+// --- Playlist import/export tests ---
+
+#[tokio::test]
+async fn test_import_m3u_and_export() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a.ogg"), b"").unwrap();
+    std::fs::write(dir.path().join("b.ogg"), b"").unwrap();
+
+    let state = test_state();
+    let body = serde_json::json!({
+        "content": "#EXTM3U\n#EXTINF:100,Artist - A\na.ogg\nb.ogg\nhttp://example.com/remote.ogg\n",
+        "base_dir": dir.path(),
+    });
+    let resp = router(state.clone())
+        .oneshot(
+            Request::post("/api/queue/import")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let result: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(result["added"].as_array().unwrap().len(), 2);
+    assert_eq!(result["errors"].as_array().unwrap().len(), 1);
+
+    let resp = router(state)
+        .oneshot(Request::get("/api/queue/export?format=pls").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["content-type"], "audio/x-scpls");
+
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let text = String::from_utf8(body.to_vec()).unwrap();
+    assert!(text.contains("a.ogg"));
+    assert!(text.contains("NumberOfEntries=2"));
+}
+
+#[tokio::test]
+async fn test_import_playlist_outside_media_dir() {
+    let media = tempfile::tempdir().unwrap();
+    let other = tempfile::tempdir().unwrap();
+    let playlist = other.path().join("list.m3u");
+    std::fs::write(&playlist, "a.ogg\n").unwrap();
+
+    let mut state = test_state();
+    state.media_dir = Some(media.path().to_path_buf());
+    let body = serde_json::json!({ "path": playlist });
+    let resp = router(state)
+        .oneshot(
+            Request::post("/api/queue/import")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn test_import_playlist_path_is_confined() {
+    let media = tempfile::tempdir().unwrap();
+    let other = tempfile::tempdir().unwrap();
+    let secret = other.path().join("secret.txt");
+    std::fs::write(&secret, "hunter2\n").unwrap();
+    let playlist = other.path().join("list.m3u");
+    std::fs::write(&playlist, "hunter2\n").unwrap();
+
+    // Without a media directory, no file can be imported by path.
+    let resp = json_request(router(test_state()), "POST", "/api/queue/import", serde_json::json!({ "path": playlist })).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let body = body_json(resp).await;
+    assert!(!body.to_string().contains("hunter2"));
+
+    let mut state = test_state();
+    state.media_dir = Some(media.path().to_path_buf());
+
+    // Files that are not playlists are refused, even before the directory check.
+    let resp = json_request(router(state.clone()), "POST", "/api/queue/import", serde_json::json!({ "path": secret })).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body = body_json(resp).await;
+    assert!(!body.to_string().contains("hunter2"));
+
+    // Relative components cannot escape the media directory.
+    let escape = media.path().join("..").join(other.path().file_name().unwrap()).join("list.m3u");
+    let resp = json_request(router(state.clone()), "POST", "/api/queue/import", serde_json::json!({ "path": escape })).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let body = body_json(resp).await;
+    assert!(!body.to_string().contains("hunter2"));
+
+    // Entries of a server-side playlist are reported by number, not echoed.
+    let inside = media.path().join("list.m3u");
+    std::fs::write(&inside, "hunter2.ogg\n").unwrap();
+    let resp = json_request(router(state), "POST", "/api/queue/import", serde_json::json!({ "path": inside })).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body = body_json(resp).await;
+    assert_eq!(body["errors"][0].as_str().unwrap(), "Entry 1: File not found");
+    assert!(!body.to_string().contains("hunter2"));
+}
+
+// --- Saved playlist tests ---
+
+async fn json_request(app: axum::Router, method: &str, uri: &str, body: serde_json::Value) -> axum::response::Response {
+    app.oneshot(
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap(),
+    )
+    .await
+    .unwrap()
+}
+
+async fn body_json(resp: axum::response::Response) -> serde_json::Value {
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn test_playlist_crud_and_enqueue() {
+    let dir = tempfile::tempdir().unwrap();
+    let a = dir.path().join("a.ogg");
+    let b = dir.path().join("b.ogg");
+    std::fs::write(&a, b"").unwrap();
+    std::fs::write(&b, b"").unwrap();
+
+    let state = test_state();
+    let body = serde_json::json!({ "name": "breakfast", "paths": [a, b] });
+    let resp = json_request(router(state.clone()), "POST", "/api/playlists", body.clone()).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    let resp = json_request(router(state.clone()), "POST", "/api/playlists", body).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+    let resp = router(state.clone())
+        .oneshot(Request::get("/api/playlists").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let list = body_json(resp).await;
+    assert_eq!(list[0]["track_count"], 2);
+
+    // Enqueue twice: the second copy must get fresh track IDs
+    for _ in 0..2 {
+        let resp = json_request(
+            router(state.clone()),
+            "POST",
+            "/api/playlists/breakfast/enqueue",
+            serde_json::json!({ "mode": "append" }),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+    }
+    let queued = state.queue.read().await.list();
+    assert_eq!(queued.len(), 4);
+    let mut ids: Vec<u64> = queued.iter().map(|t| t.id).collect();
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), 4);
+
+    let resp = json_request(
+        router(state.clone()),
+        "POST",
+        "/api/playlists/breakfast/enqueue",
+        serde_json::json!({ "mode": "replace" }),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert_eq!(state.queue.read().await.len(), 2);
+
+    // Updating only the description keeps the tracks
+    let resp = json_request(
+        router(state.clone()),
+        "PUT",
+        "/api/playlists/breakfast",
+        serde_json::json!({ "description": "Weekdays" }),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let playlist = body_json(resp).await;
+    assert_eq!(playlist["description"], "Weekdays");
+    assert_eq!(playlist["tracks"].as_array().unwrap().len(), 2);
+
+    let resp = json_request(
+        router(state.clone()),
+        "PUT",
+        "/api/playlists/breakfast",
+        serde_json::json!({ "paths": [a] }),
+    )
+    .await;
+    let playlist = body_json(resp).await;
+    assert_eq!(playlist["description"], "Weekdays");
+    assert_eq!(playlist["tracks"].as_array().unwrap().len(), 1);
+
+    // A replace with every file gone leaves the queue alone
+    std::fs::remove_file(&a).unwrap();
+    let resp = json_request(
+        router(state.clone()),
+        "POST",
+        "/api/playlists/breakfast/enqueue",
+        serde_json::json!({ "mode": "replace" }),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body_json(resp).await["code"], 8004);
+    assert_eq!(state.queue.read().await.len(), 2);
+
+    let resp = router(state.clone())
+        .oneshot(Request::delete("/api/playlists/breakfast").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+    let resp = router(state)
+        .oneshot(Request::get("/api/playlists/breakfast").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_playlist_invalid_name() {
+    let resp = json_request(
+        router(test_state()),
+        "POST",
+        "/api/playlists",
+        serde_json::json!({ "name": "../etc", "paths": [] }),
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_schedule_crud() {
+    let state = test_state();
+    state.playlists.write().await.save("breakfast", None, vec![]);
+
+    let body = serde_json::json!({
+        "name": "Breakfast",
+        "days": ["mon", "tue", "wed", "thu", "fri"],
+        "start": "07:00",
+        "end": "10:00",
+        "playlist": "breakfast",
+        "action": "hard_cut"
+    });
+    let resp = json_request(router(state.clone()), "POST", "/api/schedule", body.clone()).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let entry = body_json(resp).await;
+    assert_eq!(entry["action"], "hard_cut");
+    assert!(entry["next_start"].is_string());
+    let id = entry["id"].as_u64().unwrap();
+
+    let mut updated = body.clone();
+    updated["enabled"] = serde_json::json!(false);
+    let resp = json_request(router(state.clone()), "PUT", &format!("/api/schedule/{}", id), updated).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(body_json(resp).await["next_start"].is_null());
+
+    let resp = router(state.clone())
+        .oneshot(Request::get("/api/schedule").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let schedule = body_json(resp).await;
+    assert_eq!(schedule["timezone"], "UTC");
+    assert_eq!(schedule["entries"].as_array().unwrap().len(), 1);
+
+    let resp = router(state.clone())
+        .oneshot(
+            Request::delete(format!("/api/schedule/{}", id))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert!(state.schedule.read().await.list().is_empty());
+}
+
+#[tokio::test]
+async fn test_schedule_rejects_invalid_entries() {
+    let state = test_state();
+    let body = serde_json::json!({ "days": ["mon"], "start": "07:00", "playlist": "missing" });
+    let resp = json_request(router(state.clone()), "POST", "/api/schedule", body).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let body = serde_json::json!({ "days": ["mon"], "start": "7 o'clock", "playlist": "missing" });
+    let resp = json_request(router(state), "POST", "/api/schedule", body).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(body_json(resp).await["code"], 8102);
+}
+
+#[tokio::test]
+async fn test_jingle_rules() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state();
+
+    let rules = serde_json::json!({ "directory": dir.path(), "every_tracks": 4, "every_minutes": 0 });
+    let resp = json_request(router(state.clone()), "PUT", "/api/jingles", rules).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(state.player.jingle_rules().every_tracks, 4);
+
+    let resp = router(state.clone())
+        .oneshot(Request::get("/api/jingles").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(body_json(resp).await["every_tracks"], 4);
+
+    let rules = serde_json::json!({ "directory": dir.path().join("missing"), "every_tracks": 4 });
+    let resp = json_request(router(state), "PUT", "/api/jingles", rules).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_timed_inserts() {
+    let dir = tempfile::tempdir().unwrap();
+    let news = dir.path().join("news.ogg");
+    std::fs::write(&news, b"").unwrap();
+    let state = test_state();
+
+    let body = serde_json::json!({ "name": "News", "path": news, "at": "*:00", "mode": "soft" });
+    let resp = json_request(router(state.clone()), "POST", "/api/inserts", body).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let insert = body_json(resp).await;
+    assert_eq!(insert["mode"], "soft");
+    assert_eq!(insert["tolerance_secs"], 60);
+    assert!(insert["next_time"].as_str().unwrap().ends_with(":00:00+00:00"));
+
+    let resp = router(state.clone())
+        .oneshot(Request::get("/api/inserts").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(body_json(resp).await.as_array().unwrap().len(), 1);
+
+    let body = serde_json::json!({ "path": news, "at": "half past" });
+    let resp = json_request(router(state.clone()), "POST", "/api/inserts", body).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let id = insert["id"].as_u64().unwrap();
+    let resp = router(state)
+        .oneshot(
+            Request::delete(format!("/api/inserts/{}", id))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn test_shuffle_artist_gap() {
+    use snowboot::queue::Track;
+
+    let state = test_state();
+    {
+        let mut q = state.queue.write().await;
+        for (i, artist) in ["A", "A", "A", "B", "B", "B"].iter().enumerate() {
+            q.push_back(Track {
+                id: i as u64 + 1,
+                path: format!("/music/{}.ogg", i).into(),
+                title: i.to_string(),
+                artist: Some(artist.to_string()),
+                source: Default::default(),
+                requested_by: None,
+                tags: Default::default(),
+                overrides: Default::default(),
+            });
+        }
+    }
+
+    let body = serde_json::json!({ "mode": "artist_spread", "min_artist_gap": 1 });
+    let resp = json_request(router(state.clone()), "POST", "/api/queue/shuffle", body).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let queued = state.queue.read().await.list();
+    assert_eq!(queued.len(), 6);
+    assert!(queued.windows(2).all(|w| w[0].artist != w[1].artist));
+
+    // No body keeps the plain random shuffle
+    let resp = router(state)
+        .oneshot(Request::post("/api/queue/shuffle").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_shuffled_playlist_enqueue_keeps_artist_gap() {
+    use snowboot::queue::Track;
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut tracks = Vec::new();
+    for (i, artist) in ["A", "A", "A", "B", "B", "B"].iter().enumerate() {
+        let path = dir.path().join(format!("{}.ogg", i));
+        std::fs::write(&path, b"").unwrap();
+        tracks.push(Track {
+            id: i as u64 + 1,
+            path,
+            title: i.to_string(),
+            artist: Some(artist.to_string()),
+            source: Default::default(),
+            requested_by: None,
+            tags: Default::default(),
+            overrides: Default::default(),
+        });
+    }
+
+    let mut state = test_state();
+    state.min_artist_gap = 1;
+    state.playlists.write().await.save("mix", None, tracks);
+
+    for _ in 0..10 {
+        state.queue.write().await.clear();
+        let body = serde_json::json!({ "mode": "shuffle" });
+        let resp = json_request(router(state.clone()), "POST", "/api/playlists/mix/enqueue", body).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let queued = state.queue.read().await.list();
+        assert_eq!(queued.len(), 6);
+        assert!(queued.windows(2).all(|w| w[0].artist != w[1].artist));
+    }
+}
+
+#[tokio::test]
+async fn test_shuffle_unknown_mode() {
+    let body = serde_json::json!({ "mode": "chaotic" });
+    let resp = json_request(router(test_state()), "POST", "/api/queue/shuffle", body).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn test_playback_mode() {
+    let state = test_state();
+    let mut events = state.player.subscribe();
+
+    let body = serde_json::json!({ "mode": "repeat_queue" });
+    let resp = json_request(router(state.clone()), "PUT", "/api/playback/mode", body).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(body_json(resp).await["mode"], "repeat_queue");
+
+    let event = serde_json::to_value(events.recv().await.unwrap()).unwrap();
+    assert_eq!(event["event"], "playback_mode_changed");
+    assert_eq!(event["data"]["mode"], "repeat_queue");
+
+    let resp = router(state.clone())
+        .oneshot(Request::get("/api/status").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = body_json(resp).await;
+    assert_eq!(status["playback_mode"], "repeat_queue");
+    assert_eq!(status["stopped"], false);
+
+    let resp = router(state)
+        .oneshot(Request::post("/api/playback/resume").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(body_json(resp).await["stopped"], false);
+}
+
+#[tokio::test]
+async fn test_queue_etag_and_if_match() {
+    let dir = tempfile::tempdir().unwrap();
+    let paths: Vec<_> = (0..3).map(|i| dir.path().join(format!("{}.ogg", i))).collect();
+    for p in &paths {
+        std::fs::write(p, b"").unwrap();
+    }
+    let state = test_state();
+    let resp = json_request(router(state.clone()), "POST", "/api/queue/bulk", serde_json::json!({ "paths": paths })).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    let resp = router(state.clone())
+        .oneshot(Request::get("/api/queue").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let etag = resp.headers()["etag"].to_str().unwrap().to_string();
+    let ids: Vec<u64> = body_json(resp)
+        .await
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|t| t["id"].as_u64().unwrap())
+        .collect();
+
+    // Relative move with a current ETag
+    let resp = router(state.clone())
+        .oneshot(
+            Request::put(format!("/api/queue/{}/position", ids[0]))
+                .header("content-type", "application/json")
+                .header("if-match", &etag)
+                .body(Body::from(serde_json::json!({ "after_id": ids[2] }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_ne!(resp.headers()["etag"].to_str().unwrap(), etag);
+    let order: Vec<u64> = state.queue.read().await.list().iter().map(|t| t.id).collect();
+    assert_eq!(order, vec![ids[1], ids[2], ids[0]]);
+
+    // The old ETag is now stale
+    let resp = router(state.clone())
+        .oneshot(
+            Request::delete(format!("/api/queue/{}", ids[1]))
+                .header("if-match", &etag)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+    assert_eq!(body_json(resp).await["code"], 6003);
+    assert_eq!(state.queue.read().await.len(), 3);
+
+    // Ambiguous move targets are rejected
+    let body = serde_json::json!({ "position": 0, "before_id": ids[1] });
+    let resp = json_request(router(state), "PUT", &format!("/api/queue/{}/position", ids[0]), body).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_queue_changed_carries_revision() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a.ogg");
+    std::fs::write(&path, b"").unwrap();
+    let state = test_state();
+    let mut events = state.player.subscribe();
+
+    json_request(router(state.clone()), "POST", "/api/queue", serde_json::json!({ "path": path })).await;
+    let event = serde_json::to_value(events.recv().await.unwrap()).unwrap();
+    assert_eq!(event["event"], "queue_changed");
+    assert_eq!(event["data"]["revision"], state.queue.read().await.revision());
+}
+
+#[tokio::test]
+async fn test_queue_changed_deltas() {
+    let dir = tempfile::tempdir().unwrap();
+    let a = dir.path().join("a.ogg");
+    let b = dir.path().join("b.ogg");
+    std::fs::write(&a, b"").unwrap();
+    std::fs::write(&b, b"").unwrap();
+    let state = test_state();
+    let mut events = state.player.subscribe();
+    let mut next_event = || serde_json::to_value(events.try_recv().unwrap()).unwrap();
+
+    let body = serde_json::json!({ "paths": [a, b] });
+    json_request(router(state.clone()), "POST", "/api/queue/bulk", body).await;
+    let event = next_event();
+    assert_eq!(event["data"]["kind"], "added");
+    assert_eq!(event["data"]["tracks"][1]["position"], 1);
+    assert_eq!(event["data"]["tracks"][1]["track"]["title"], "b");
+    let a_id = event["data"]["tracks"][0]["id"].as_u64().unwrap();
+    let b_id = event["data"]["tracks"][1]["id"].as_u64().unwrap();
+
+    let body = serde_json::json!({ "position": 0 });
+    json_request(router(state.clone()), "PUT", &format!("/api/queue/{}/position", b_id), body).await;
+    let event = next_event();
+    assert_eq!(event["data"]["kind"], "moved");
+    assert_eq!(event["data"]["tracks"], serde_json::json!([{ "id": b_id, "position": 0 }]));
+
+    let req = Request::builder()
+        .method("DELETE")
+        .uri(format!("/api/queue/{}", a_id))
+        .body(Body::empty())
+        .unwrap();
+    router(state.clone()).oneshot(req).await.unwrap();
+    let event = next_event();
+    assert_eq!(event["data"]["kind"], "removed");
+    assert_eq!(event["data"]["tracks"], serde_json::json!([{ "id": a_id, "position": 1 }]));
+    assert_eq!(event["data"]["length"], 1);
+    assert_eq!(event["data"]["revision"], state.queue.read().await.revision());
+}
+
+#[tokio::test]
+async fn test_queue_batch() {
+    let dir = tempfile::tempdir().unwrap();
+    let a = dir.path().join("a.ogg");
+    let b = dir.path().join("b.ogg");
+    std::fs::write(&a, b"").unwrap();
+    std::fs::write(&b, b"").unwrap();
+    let state = test_state();
+    let mut events = state.player.subscribe();
+
+    let body = serde_json::json!({ "ops": [
+        { "op": "add", "path": a },
+        { "op": "insert_at", "path": b, "position": 0 },
+    ]});
+    let resp = json_request(router(state.clone()), "POST", "/api/queue/batch", body).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let batch = body_json(resp).await;
+    assert_eq!(batch["results"][0]["result"], "added");
+    assert_eq!(batch["results"][1]["position"], 0);
+    let a_id = batch["results"][0]["track"]["id"].as_u64().unwrap();
+    let b_id = batch["results"][1]["track"]["id"].as_u64().unwrap();
+
+    // One event for the whole batch
+    assert!(events.try_recv().is_ok());
+    assert!(events.try_recv().is_err());
+
+    let body = serde_json::json!({ "ops": [
+        { "op": "move", "id": b_id, "after_id": a_id },
+        { "op": "remove", "id": a_id },
+    ]});
+    let resp = json_request(router(state.clone()), "POST", "/api/queue/batch", body).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let order: Vec<u64> = state.queue.read().await.list().iter().map(|t| t.id).collect();
+    assert_eq!(order, vec![b_id]);
+}
+
+#[tokio::test]
+async fn test_queue_batch_all_or_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+    let a = dir.path().join("a.ogg");
+    std::fs::write(&a, b"").unwrap();
+    let state = test_state();
+
+    let body = serde_json::json!({ "ops": [
+        { "op": "add", "path": a },
+        { "op": "remove", "id": 999999 },
+    ]});
+    let resp = json_request(router(state.clone()), "POST", "/api/queue/batch", body).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert!(body_json(resp).await["error"].as_str().unwrap().starts_with("Operation 1:"));
+    assert!(state.queue.read().await.is_empty());
+
+    let body = serde_json::json!({ "ops": [
+        { "op": "clear" },
+        { "op": "add", "path": dir.path().join("missing.ogg") },
+    ]});
+    let resp = json_request(router(state.clone()), "POST", "/api/queue/batch", body).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert!(state.queue.read().await.is_empty());
+}
+
+/// Read SSE frames from `resp` until `n` have arrived.
+async fn sse_frames(resp: axum::response::Response, n: usize) -> Vec<String> {
+    use futures::StreamExt;
+    let mut body = resp.into_body().into_data_stream();
+    let mut text = String::new();
+    while text.matches("\n\n").count() < n {
+        let chunk = tokio::time::timeout(std::time::Duration::from_secs(2), body.next())
+            .await
+            .expect("timed out waiting for events")
+            .unwrap()
+            .unwrap();
+        text.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+    text.split("\n\n").take(n).map(str::to_string).collect()
+}
+
+#[tokio::test]
+async fn test_events_resume_from_last_event_id() {
+    let state = test_state();
+    state.player.set_playback_mode(PlaybackMode::RepeatQueue);
+    let first = state.player.last_event_id();
+    state.player.set_playback_mode(PlaybackMode::RepeatOne);
+    state.player.set_playback_mode(PlaybackMode::Normal);
+
+    let req = Request::builder()
+        .uri("/api/events")
+        .header("Last-Event-ID", first.to_string())
+        .body(Body::empty())
+        .unwrap();
+    let resp = router(state.clone()).oneshot(req).await.unwrap();
+    let frames = sse_frames(resp, 2).await;
+    assert!(frames[0].contains(&format!("id: {}\n", first + 1)), "{}", frames[0]);
+    assert!(frames[0].contains("repeat_one"));
+    assert!(frames[1].contains(&format!("id: {}\n", first + 2)));
+}
+
+#[tokio::test]
+async fn test_events_resync_when_replay_unavailable() {
+    let state = test_state();
+    state.player.set_playback_mode(PlaybackMode::RepeatQueue);
+    let last = state.player.last_event_id();
+
+    // IDs from before a restart, and one the server never issued
+    for id in [999, last + 1] {
+        let req = Request::builder()
+            .uri("/api/events")
+            .header("Last-Event-ID", id.to_string())
+            .body(Body::empty())
+            .unwrap();
+        let resp = router(state.clone()).oneshot(req).await.unwrap();
+        let frames = sse_frames(resp, 1).await;
+        assert!(frames[0].contains("event: resync"), "{}", frames[0]);
+        assert!(frames[0].contains(&format!("id: {}\n", last)), "{}", frames[0]);
+    }
+}
+
+#[tokio::test]
+async fn test_events_include_queue_only_when_asked() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a.ogg");
+    std::fs::write(&path, b"").unwrap();
+    let state = test_state();
+
+    // Nobody has asked for the queue yet
+    json_request(router(state.clone()), "POST", "/api/queue", serde_json::json!({ "path": path })).await;
+    assert!(!state.queue.read().await.is_watched());
+
+    let req = Request::builder()
+        .uri("/api/events?include=queue")
+        .body(Body::empty())
+        .unwrap();
+    let resp = router(state.clone()).oneshot(req).await.unwrap();
+    assert!(state.queue.read().await.is_watched());
+
+    json_request(router(state.clone()), "POST", "/api/queue", serde_json::json!({ "path": path })).await;
+    let frames = sse_frames(resp, 1).await;
+    assert!(frames[0].contains("event: queue_changed"), "{}", frames[0]);
+    assert!(frames[0].contains("\"queue\":["), "{}", frames[0]);
+    assert!(!state.queue.read().await.is_watched());
+}
+
+fn rpc(method: &str, params: serde_json::Value) -> RpcRequest {
+    serde_json::from_value(serde_json::json!({ "id": 1, "method": method, "params": params })).unwrap()
+}
+
+#[tokio::test]
+async fn test_ws_calls_map_to_rest() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a.ogg");
+    std::fs::write(&path, b"").unwrap();
+    let app = router(test_state());
+
+    let resp = ws::call(&app, &Caller::default(), rpc("queue.add", serde_json::json!({ "path": path }))).await;
+    let track = resp.result.unwrap();
+    assert_eq!(resp.revision, Some(1));
+    assert_eq!(track["title"], "a");
+
+    // Path params come from params, the rest from the query or body
+    let params = serde_json::json!({ "id": track["id"], "position": 0, "if_match": 1 });
+    let resp = ws::call(&app, &Caller::default(), rpc("queue.move", params)).await;
+    assert!(resp.error.is_none());
+    assert_eq!(resp.revision, Some(2));
+
+    let params = serde_json::json!({ "id": track["id"], "if_match": 1 });
+    let resp = ws::call(&app, &Caller::default(), rpc("queue.remove", params)).await;
+    assert_eq!(resp.error.unwrap().code, 6003);
+
+    let resp = ws::call(&app, &Caller::default(), rpc("queue.remove", serde_json::json!({ "id": 999 }))).await;
+    assert_eq!(resp.error.unwrap().code, 6001);
+
+    let resp = ws::call(&app, &Caller::default(), rpc("queue.move", serde_json::json!({ "position": 0 }))).await;
+    assert_eq!(resp.error.unwrap().code, 7000);
+
+    let resp = ws::call(&app, &Caller::default(), rpc("queue.teleport", serde_json::json!({}))).await;
+    assert_eq!(resp.error.unwrap().code, 7002);
+
+    let resp = ws::call(&app, &Caller::default(), rpc("queue.list", serde_json::json!({}))).await;
+    assert_eq!(resp.result.unwrap().as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_ws_socket_requests_and_events() {
+    use futures::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let state = test_state_with_auth("secret");
+    let first_id = state.player.last_event_id() + 1;
+    tokio::spawn(async move {
+        axum::serve(listener, router(state)).await.unwrap();
+    });
+
+    let url = format!("ws://{}/api/ws", addr);
+    assert!(tokio_tungstenite::connect_async(url.as_str()).await.is_err());
+
+    let mut req = url.into_client_request().unwrap();
+    req.headers_mut().insert("authorization", "Bearer secret".parse().unwrap());
+    let (mut socket, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+
+    let call = serde_json::json!({ "id": "a", "method": "playback.set_mode", "params": { "mode": "repeat_one" } });
+    socket.send(Message::text(call.to_string())).await.unwrap();
+
+    let mut reply = None;
+    let mut event = None;
+    while reply.is_none() || event.is_none() {
+        let msg = tokio::time::timeout(std::time::Duration::from_secs(2), socket.next())
+            .await
+            .expect("timed out")
+            .unwrap()
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+        if value["id"] == "a" {
+            reply = Some(value);
+        } else {
+            event = Some(value);
+        }
+    }
+    assert_eq!(reply.unwrap()["result"]["mode"], "repeat_one");
+    let event = event.unwrap();
+    assert_eq!(event["method"], "event");
+    assert_eq!(event["params"]["event"], "playback_mode_changed");
+    assert_eq!(event["params"]["id"], first_id);
+}
+
+
+#[tokio::test]
+async fn test_track_artwork() {
+    let dir = tempfile::tempdir().unwrap();
+    let album = dir.path().join("album");
+    std::fs::create_dir(&album).unwrap();
+    let jpeg = [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, b'J', b'F', b'I', b'F'];
+    std::fs::write(album.join("cover.jpg"), jpeg).unwrap();
+    let with_art = album.join("one.ogg");
+    let without_art = dir.path().join("two.ogg");
+    std::fs::write(&with_art, b"").unwrap();
+    std::fs::write(&without_art, b"").unwrap();
+    let app = router(test_state());
+
+    let mut ids = Vec::new();
+    for path in [&with_art, &without_art] {
+        let resp = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/queue")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::json!({"path": path}).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        ids.push(body_json(resp).await["id"].as_u64().unwrap());
+    }
+    let get = |uri: String, etag: Option<&str>| {
+        let mut builder = Request::builder().uri(uri);
+        if let Some(etag) = etag {
+            builder = builder.header("if-none-match", etag);
+        }
+        builder.body(Body::empty()).unwrap()
+    };
+
+    let resp = app.clone().oneshot(get(format!("/api/queue/{}/artwork", ids[0]), None)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()["content-type"], "image/jpeg");
+    let etag = resp.headers()["etag"].to_str().unwrap().to_string();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(&body[..], &jpeg);
+
+    let resp = app.clone().oneshot(get(format!("/api/queue/{}/artwork", ids[0]), Some(&etag))).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(resp.headers()["etag"], etag.as_str());
+
+    let resp = app.clone().oneshot(get(format!("/api/queue/{}/artwork", ids[1]), None)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(body_json(resp).await["code"], 3015);
+
+    let resp = app.clone().oneshot(get("/api/queue/999999/artwork".to_string(), None)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(body_json(resp).await["code"], 6001);
+
+    let resp = app.oneshot(get("/api/now-playing/artwork".to_string(), None)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+// --- Token scope tests ---
+
+fn test_state_with_scoped_tokens() -> AppState {
+    let token = |name: &str, scopes: Vec<Scope>| ApiToken {
+        name: name.to_string(),
+        token: format!("{}-secret", name),
+        scopes,
+    };
+    let tokens = TokenStore::new(vec![
+        token("widget", vec![Scope::Read]),
+        token("dj", vec![Scope::Read, Scope::Queue, Scope::Control]),
+        token("admin", vec![Scope::Admin]),
+    ])
+    .unwrap();
+    let mut state = test_state();
+    state.tokens = Some(Arc::new(tokens));
+    state
+}
+
+#[tokio::test]
+async fn test_token_scopes_enforced_per_route() {
+    let app = router(test_state_with_scoped_tokens());
+
+    assert_eq!(authed_request(app.clone(), "GET", "/api/queue", "widget").await, StatusCode::OK);
+    assert_eq!(authed_request(app.clone(), "DELETE", "/api/queue", "widget").await, StatusCode::FORBIDDEN);
+    assert_eq!(authed_request(app.clone(), "POST", "/api/skip", "widget").await, StatusCode::FORBIDDEN);
+
+    assert_eq!(authed_request(app.clone(), "POST", "/api/skip", "dj").await, StatusCode::OK);
+    assert_eq!(authed_request(app.clone(), "POST", "/api/queue/shuffle", "dj").await, StatusCode::OK);
+    assert_eq!(authed_request(app.clone(), "DELETE", "/api/queue", "dj").await, StatusCode::FORBIDDEN);
+    assert_eq!(authed_request(app.clone(), "PUT", "/api/jingles", "dj").await, StatusCode::FORBIDDEN);
+
+    assert_eq!(authed_request(app.clone(), "DELETE", "/api/queue", "admin").await, StatusCode::NO_CONTENT);
+
+    // Routes that only clear the queue for some bodies need admin for those
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("a.ogg");
+    std::fs::write(&file, b"").unwrap();
+    let send = |uri: &str, token: &str, body: serde_json::Value| {
+        app.clone().oneshot(
+            Request::post(uri)
+                .header("authorization", format!("Bearer {}-secret", token))
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+    };
+    let playlist = serde_json::json!({ "name": "breakfast", "paths": [file] });
+    assert_eq!(send("/api/playlists", "admin", playlist).await.unwrap().status(), StatusCode::CREATED);
+
+    let clear = serde_json::json!({ "ops": [{ "op": "add", "path": file }, { "op": "clear" }] });
+    let resp = send("/api/queue/batch", "dj", clear.clone()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(body_json(resp).await["code"], 7003);
+    assert_eq!(send("/api/queue/batch", "admin", clear).await.unwrap().status(), StatusCode::OK);
+    let add = serde_json::json!({ "ops": [{ "op": "add", "path": file }] });
+    assert_eq!(send("/api/queue/batch", "dj", add).await.unwrap().status(), StatusCode::OK);
+
+    let replace = serde_json::json!({ "mode": "replace" });
+    let resp = send("/api/playlists/breakfast/enqueue", "dj", replace.clone()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(body_json(resp).await["code"], 7003);
+    let append = serde_json::json!({ "mode": "append" });
+    assert_eq!(send("/api/playlists/breakfast/enqueue", "dj", append).await.unwrap().status(), StatusCode::CREATED);
+    assert_eq!(send("/api/playlists/breakfast/enqueue", "admin", replace).await.unwrap().status(), StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn test_events_record_token_name() {
+    let state = test_state_with_scoped_tokens();
+    let mut events = state.player.subscribe();
+
+    authed_request(router(state.clone()), "POST", "/api/queue/shuffle", "dj").await;
+    let event = serde_json::to_value(events.try_recv().unwrap()).unwrap();
+    assert_eq!(event["event"], "queue_changed");
+    assert_eq!(event["actor"], "dj");
+
+    // Events not caused by a request have no actor
+    state.player.set_playback_mode(PlaybackMode::Normal);
+    let event = serde_json::to_value(events.try_recv().unwrap()).unwrap();
+    assert!(event.get("actor").is_none());
+}
+
+// --- Auth lockout tests ---
+
+fn status_request_from(addr: &str, token: &str) -> Request<Body> {
+    let mut req = Request::get("/api/status")
+        .header("authorization", format!("Bearer {}", token))
+        .body(Body::empty())
+        .unwrap();
+    let addr: std::net::SocketAddr = addr.parse().unwrap();
+    req.extensions_mut().insert(axum::extract::ConnectInfo(addr));
+    req
+}
+
+#[tokio::test]
+async fn test_auth_failures_lock_out_client() {
+    let mut state = test_state_with_auth("secret123");
+    state.auth_limiter = Arc::new(AuthLimiter::new(LockoutPolicy {
+        max_failures: 3,
+        ..LockoutPolicy::default()
+    }));
+    let app = router(state.clone());
+
+    for _ in 0..3 {
+        let resp = app.clone().oneshot(status_request_from("192.0.2.7:5000", "guess")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    // Even the right token is refused during the lockout
+    for _ in 0..3 {
+        let resp = app.clone().oneshot(status_request_from("192.0.2.7:5001", "secret123")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()["retry-after"], "60");
+    }
+
+    // The lockout is recorded once, not for every refused request
+    let page = state
+        .audit
+        .read()
+        .unwrap()
+        .snapshot()
+        .query(&Default::default(), snowboot::history::SortOrder::Asc, None, 10);
+    let actions: Vec<&str> = page.records.iter().map(|r| r.action.as_str()).collect();
+    assert_eq!(actions, vec!["auth_failure", "auth_failure", "auth_failure", "auth_lockout"]);
+
+    // Other clients are unaffected
+    let resp = app.clone().oneshot(status_request_from("192.0.2.8:5000", "secret123")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let metrics = snowboot::metrics::AUTH_FAILURES.with_label_values(&["locked_out"]).get();
+    assert!(metrics >= 1);
+}
+
+// --- Audit log tests ---
+
+#[tokio::test]
+async fn test_audit_log_records_changes() {
+    let state = test_state_with_scoped_tokens();
+    let app = router(state.clone());
+
+    let req = Request::post("/api/queue")
+        .header("authorization", "Bearer dj-secret")
+        .header("content-type", "application/json")
+        .extension(axum::extract::ConnectInfo("192.0.2.7:5000".parse::<std::net::SocketAddr>().unwrap()))
+        .body(Body::from(r#"{"path":"/nonexistent.ogg"}"#))
+        .unwrap();
+    assert_eq!(app.clone().oneshot(req).await.unwrap().status(), StatusCode::NOT_FOUND);
+    authed_request(app.clone(), "POST", "/api/skip", "dj").await;
+    authed_request(app.clone(), "GET", "/api/queue", "dj").await;
+
+    // Reading the audit log needs the admin scope
+    assert_eq!(authed_request(app.clone(), "GET", "/api/audit", "dj").await, StatusCode::FORBIDDEN);
+
+    // Requests refused by authentication are recorded too
+    let req = Request::get("/api/audit?actor=dj&order=desc&limit=2")
+        .header("authorization", "Bearer admin-secret")
+        .body(Body::empty())
+        .unwrap();
+    let resp = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(resp.headers()["x-next-cursor"], "2");
+    let records = body_json(resp).await;
+    assert_eq!(records.as_array().unwrap().len(), 2);
+    assert_eq!(records[0]["action"], "auth_failure");
+    assert_eq!(records[0]["path"], "/api/audit");
+    assert_eq!(records[0]["args"]["reason"], "forbidden");
+    assert_eq!(records[0]["result"]["status"], 403);
+    assert_eq!(records[1]["action"], "player.skip");
+    assert_eq!(records[1]["result"]["status"], 200);
+
+    let resp = app.clone().oneshot(Request::post("/api/skip").body(Body::empty()).unwrap()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let page = state
+        .audit
+        .read()
+        .unwrap()
+        .snapshot()
+        .query(&Default::default(), snowboot::history::SortOrder::Desc, None, 1);
+    let record = &page.records[0];
+    assert_eq!(record.action, "auth_failure");
+    assert_eq!(record.actor, None);
+    assert_eq!(record.args["reason"], "missing_token");
+
+    let req = Request::get("/api/audit?failed=true&action=queue.add")
+        .header("authorization", "Bearer admin-secret")
+        .body(Body::empty())
+        .unwrap();
+    let records = body_json(app.oneshot(req).await.unwrap()).await;
+    assert_eq!(records.as_array().unwrap().len(), 1);
+    let record = &records[0];
+    assert_eq!(record["action"], "queue.add");
+    assert_eq!(record["actor"], "dj");
+    assert_eq!(record["remote_addr"], "192.0.2.7");
+    assert_eq!(record["args"]["path"], "/nonexistent.ogg");
+    assert_eq!(record["result"]["status"], 404);
+    assert_eq!(record["result"]["code"], 3010);
+}
+
+// --- Listener request tests ---
+
+fn listener_post(body: serde_json::Value, addr: &str) -> Request<Body> {
+    let addr: std::net::SocketAddr = addr.parse().unwrap();
+    Request::post("/api/listener/requests")
+        .header("content-type", "application/json")
+        .extension(axum::extract::ConnectInfo(addr))
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_listener_requests_moderated_into_queue() {
+    use snowboot::requests::{RequestBox, RequestRules};
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("blue monday.ogg"), b"").unwrap();
+    std::fs::write(dir.path().join("atmosphere.ogg"), b"").unwrap();
+    let mut state = test_state_with_library(dir.path());
+    state.requests = Some(Arc::new(tokio::sync::RwLock::new(RequestBox::new(RequestRules::default()))));
+    let app = router(state.clone());
+
+    // Searching and requesting need no token
+    let resp = app.clone().oneshot(Request::get("/api/listener/search?q=MONDAY").body(Body::empty()).unwrap()).await.unwrap();
+    let results = body_json(resp).await;
+    assert_eq!(results.as_array().unwrap().len(), 1);
+    assert_eq!(results[0]["path"], "blue monday.ogg");
+
+    let resp = app
+        .clone()
+        .oneshot(listener_post(serde_json::json!({"path": "blue monday.ogg", "name": "Sam"}), "192.0.2.7:5000"))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let receipt = body_json(resp).await;
+    assert_eq!(receipt["status"], "pending");
+
+    // The same client must wait before requesting again
+    let resp = app
+        .clone()
+        .oneshot(listener_post(serde_json::json!({"path": "atmosphere.ogg"}), "192.0.2.7:5001"))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(resp.headers().contains_key("retry-after"));
+
+    // Paths outside the media directory are refused
+    let resp = app
+        .clone()
+        .oneshot(listener_post(serde_json::json!({"path": "../elsewhere.ogg"}), "192.0.2.8:5000"))
+        .await
+        .unwrap();
+    assert!(resp.status().is_client_error());
+
+    // Moderators see the request, but not who sent it
+    let req = Request::get("/api/requests")
+        .header("authorization", "Bearer dj-secret")
+        .body(Body::empty())
+        .unwrap();
+    let pending = body_json(app.clone().oneshot(req).await.unwrap()).await;
+    assert_eq!(pending[0]["requested_by"], "Sam");
+    assert!(pending[0].get("remote_addr").is_none());
+
+    // ...but the audit log records every submission and where it came from
+    let req = Request::get("/api/audit?actor=listener")
+        .header("authorization", "Bearer admin-secret")
+        .body(Body::empty())
+        .unwrap();
+    let audit = body_json(app.clone().oneshot(req).await.unwrap()).await;
+    let records = audit.as_array().unwrap();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0]["path"], "/api/listener/requests");
+    assert_eq!(records[0]["remote_addr"], "192.0.2.7");
+    assert_eq!(records[0]["result"]["status"], 201);
+    assert_eq!(records[2]["remote_addr"], "192.0.2.8");
+    assert!(records[2]["result"]["status"].as_u64().unwrap() >= 400);
+
+    let mut events = state.player.subscribe();
+    let uri = format!("/api/requests/{}/approve", receipt["id"]);
+    assert_eq!(authed_request(app.clone(), "POST", &uri, "widget").await, StatusCode::FORBIDDEN);
+    assert_eq!(authed_request(app.clone(), "POST", &uri, "dj").await, StatusCode::CREATED);
+    assert_eq!(authed_request(app.clone(), "POST", &uri, "dj").await, StatusCode::NOT_FOUND);
+
+    let queue = state.queue.read().await.list();
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue[0].requested_by.as_deref(), Some("Sam"));
+    let event = serde_json::to_value(events.try_recv().unwrap()).unwrap();
+    assert_eq!(event["data"]["tracks"][0]["track"]["requested_by"], "Sam");
+}
+
+// --- Media library tests ---
+
+fn test_state_with_library(dir: &std::path::Path) -> AppState {
+    use snowboot::library::{self, Library};
+
+    let library = Arc::new(std::sync::RwLock::new(Library::new(dir.to_path_buf())));
+    library::rescan(&library);
+    let mut state = test_state_with_scoped_tokens();
+    state.media_dir = Some(dir.to_path_buf());
+    state.library = Some(library);
+    state
+}
+
+#[tokio::test]
+async fn test_library_search_and_enqueue_by_id() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("jazz")).unwrap();
+    for name in ["jazz/blue in green.ogg", "jazz/so what.ogg", "blue monday.ogg"] {
+        std::fs::write(dir.path().join(name), b"").unwrap();
+    }
+    let state = test_state_with_library(dir.path());
+    let app = router(state.clone());
+    let get = |uri: &str| {
+        Request::get(uri)
+            .header("authorization", "Bearer widget-secret")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let resp = app.clone().oneshot(get("/api/library?q=blue&limit=1")).await.unwrap();
+    assert_eq!(resp.headers()["x-total-count"], "2");
+    let cursor = resp.headers()["x-next-cursor"].to_str().unwrap().to_string();
+    let first = body_json(resp).await;
+    assert_eq!(first[0]["title"], "blue monday");
+
+    let resp = app.clone().oneshot(get(&format!("/api/library?q=blue&limit=1&cursor={}", cursor))).await.unwrap();
+    assert!(!resp.headers().contains_key("x-next-cursor"));
+    let second = body_json(resp).await;
+    assert_eq!(second[0]["title"], "blue in green");
+
+    let resp = app.clone().oneshot(get("/api/library/999")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(body_json(resp).await["code"], 8301);
+
+    let req = Request::post("/api/queue")
+        .header("authorization", "Bearer dj-secret")
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::json!({"library_id": second[0]["id"]}).to_string()))
+        .unwrap();
+    let resp = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert_eq!(body_json(resp).await["title"], "blue in green");
+
+    // New files appear after a rescan, which needs the admin scope
+    std::fs::write(dir.path().join("jazz/freddie freeloader.ogg"), b"").unwrap();
+    assert_eq!(authed_request(app.clone(), "POST", "/api/library/scan", "dj").await, StatusCode::FORBIDDEN);
+    let req = Request::post("/api/library/scan")
+        .header("authorization", "Bearer admin-secret")
+        .body(Body::empty())
+        .unwrap();
+    let summary = body_json(app.clone().oneshot(req).await.unwrap()).await;
+    assert_eq!(summary["added"], 1);
+    assert_eq!(summary["total"], 4);
+}
+
+#[tokio::test]
+async fn test_watcher_removes_track_queued_by_relative_path() {
+    use snowboot::{library, watcher};
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a.ogg"), b"").unwrap();
+    let state = test_state_with_library(dir.path());
+
+    // The same file, reached from the working directory
+    let cwd = std::env::current_dir().unwrap();
+    let mut relative = std::path::PathBuf::new();
+    for _ in cwd.components().skip(1) {
+        relative.push("..");
+    }
+    relative.push(dir.path().join("a.ogg").strip_prefix("/").unwrap());
+    assert!(relative.is_relative());
+
+    let req = Request::post("/api/queue")
+        .header("authorization", "Bearer dj-secret")
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::json!({ "path": relative }).to_string()))
+        .unwrap();
+    let resp = router(state.clone()).oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert_eq!(state.queue.read().await.len(), 1);
+
+    std::fs::remove_file(dir.path().join("a.ogg")).unwrap();
+    let library = state.library.clone().unwrap();
+    let changes = library::refresh_paths(&library, &[dir.path().join("a.ogg")]);
+    watcher::sync_queue(&state.player, &changes).await;
+    assert_eq!(state.queue.read().await.len(), 0);
+}
+
+// --- Quarantine tests ---
+
+#[tokio::test]
+async fn test_quarantine_list_and_release() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("broken.ogg");
+    std::fs::write(&path, b"").unwrap();
+    let path_str = path.to_string_lossy().to_string();
+
+    let state = test_state();
+    let track = snowboot::queue::Track::from_file(path.clone());
+    let error = snowboot::history::TrackError { code: 3011, message: "Error reading file".to_string() };
+    let id = state.player.quarantine.write().unwrap().add(track.clone(), error, 3, 1000).id;
+    state.playlists.write().await.save("broken", None, vec![track]);
+    let app = router(state);
+
+    let resp = app
+        .clone()
+        .oneshot(Request::get("/api/quarantine").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let list = body_json(resp).await;
+    assert_eq!(list[0]["track"]["title"], "broken");
+    assert_eq!(list[0]["error"]["code"], 3011);
+    assert_eq!(list[0]["failures"], 3);
+
+    // Quarantined files are refused until released
+    let resp = json_request(app.clone(), "POST", "/api/queue", serde_json::json!({ "path": path_str })).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    assert_eq!(body_json(resp).await["code"], 8401);
+    let resp = json_request(app.clone(), "POST", "/api/queue/bulk", serde_json::json!({ "paths": [path_str] })).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(body_json(resp).await["errors"][0].as_str().unwrap().contains("quarantined"));
+    let resp = json_request(app.clone(), "POST", "/api/playlists/broken/enqueue", serde_json::json!({})).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let uri = format!("/api/quarantine/{}", id);
+    let resp = app
+        .clone()
+        .oneshot(Request::delete(uri.as_str()).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    let resp = json_request(app.clone(), "POST", "/api/queue", serde_json::json!({ "path": path_str })).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    let resp = app
+        .oneshot(Request::delete(uri.as_str()).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(body_json(resp).await["code"], 8400);
+}
+
+// --- Metadata override tests ---
+
+#[tokio::test]
+async fn test_metadata_overrides() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("untagged.ogg");
+    std::fs::write(&path, b"").unwrap();
+    let path = path.to_string_lossy().to_string();
+    let state = test_state();
+    let app = router(state.clone());
+    let send = |method: &str, uri: &str, body: serde_json::Value| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let resp = app
+        .clone()
+        .oneshot(send("POST", "/api/queue", serde_json::json!({
+            "path": path, "title": "Station Theme", "tags": {"album": "Idents"}
+        })))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let track = body_json(resp).await;
+    assert_eq!(track["title"], "Station Theme");
+    assert_eq!(track["tags"]["ALBUM"], "Idents");
+    let id = track["id"].as_u64().unwrap();
+
+    let resp = app
+        .clone()
+        .oneshot(send("POST", "/api/queue/bulk", serde_json::json!({
+            "tracks": [{"path": path, "artist": "Someone"}, {"path": path, "tags": {"A=B": "x"}}]
+        })))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let bulk = body_json(resp).await;
+    assert_eq!(bulk["added"][0]["artist"], "Someone");
+    assert_eq!(bulk["added"][0]["title"], "untagged");
+    assert_eq!(bulk["errors"].as_array().unwrap().len(), 1);
+
+    let resp = app
+        .clone()
+        .oneshot(send("PATCH", &format!("/api/queue/{}", id), serde_json::json!({"artist": "The Station"})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let track = body_json(resp).await;
+    assert_eq!(track["title"], "Station Theme");
+    assert_eq!(track["artist"], "The Station");
+
+    // An edit that changes nothing is refused, leaving the revision alone
+    let revision = state.queue.read().await.revision();
+    let resp = app
+        .clone()
+        .oneshot(send("PATCH", &format!("/api/queue/{}", id), serde_json::json!({})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(state.queue.read().await.revision(), revision);
+
+    let resp = app
+        .clone()
+        .oneshot(send("PATCH", "/api/queue/999999", serde_json::json!({"title": "x"})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let resp = app
+        .oneshot(send("PATCH", "/api/now-playing/metadata", serde_json::json!({"title": "x"})))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}